@@ -0,0 +1,195 @@
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use csv_async::AsyncReaderBuilder;
+use rust_transaction_engine::bloom::TxKeyFilter;
+use rust_transaction_engine::config::Rules;
+use rust_transaction_engine::ingest::ParsedRows;
+use rust_transaction_engine::models::{AccountsMap, Transaction, TransactionsMap};
+use rust_transaction_engine::transaction::{
+    handle_run, handle_transaction, handle_transaction_with,
+};
+use rust_transaction_engine::validate::{ParseOptions, RowParser};
+use rust_transaction_engine::workload::{Workload, WorkloadSpec, write_workload};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::BufReader;
+
+const ROWS: u64 = 100_000;
+
+fn spec() -> WorkloadSpec {
+    WorkloadSpec {
+        transactions: ROWS,
+        ..WorkloadSpec::default()
+    }
+}
+
+/// Applying already parsed rows to fresh maps, with and without the bloom
+/// filter in front of the duplicate check
+fn apply(c: &mut Criterion) {
+    let rows: Vec<_> = Workload::new(spec()).collect();
+    let mut group = c.benchmark_group("apply");
+    group.throughput(Throughput::Elements(ROWS));
+    group.bench_function("handle_transaction", |b| {
+        b.iter_batched(
+            || rows.clone(),
+            |rows| {
+                let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+                for row in rows {
+                    let _ = handle_transaction(row, &accounts, &transactions);
+                }
+                accounts
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("handle_transaction_bloom", |b| {
+        b.iter_batched(
+            || rows.clone(),
+            |rows| {
+                let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+                let rules = Rules {
+                    tx_filter: Some(Arc::new(TxKeyFilter::with_capacity(ROWS as usize))),
+                    ..Rules::default()
+                };
+                for row in rows {
+                    let _ = handle_transaction_with(row, &accounts, &transactions, &rules);
+                }
+                accounts
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Applying deposits and withdrawals concentrated on a few clients one row at
+/// a time, and in runs of up to 64 back-to-back rows of one client
+fn hot_account(c: &mut Criterion) {
+    let rows: Vec<_> = Workload::new(WorkloadSpec {
+        clients: 4,
+        zipf: 2.0,
+        dispute_rate: 0.0,
+        ..spec()
+    })
+    .collect();
+    let mut runs: Vec<Vec<Transaction>> = Vec::new();
+    for row in &rows {
+        match runs.last_mut() {
+            Some(run) if run.len() < 64 && run[0].client == row.client => run.push(row.clone()),
+            _ => runs.push(vec![row.clone()]),
+        }
+    }
+    let rules = Rules::default();
+    let mut group = c.benchmark_group("hot_account");
+    group.throughput(Throughput::Elements(ROWS));
+    group.bench_function("per_row", |b| {
+        b.iter_batched(
+            || rows.clone(),
+            |rows| {
+                let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+                for row in rows {
+                    let _ = handle_transaction_with(row, &accounts, &transactions, &rules);
+                }
+                accounts
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("runs", |b| {
+        b.iter_batched(
+            || runs.clone(),
+            |runs| {
+                let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+                for run in runs {
+                    let _ = handle_run(run, &accounts, &transactions, &rules);
+                }
+                accounts
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Reading and parsing a CSV file from memory, then applying its rows
+fn end_to_end(c: &mut Criterion) {
+    let mut csv = Vec::new();
+    write_workload(spec(), &mut csv).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("end_to_end");
+    group.throughput(Throughput::Bytes(csv.len() as u64));
+    group.bench_function("parse_and_apply", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut reader =
+                    AsyncReaderBuilder::new().create_reader(std::io::Cursor::new(csv.clone()));
+                let parser =
+                    RowParser::new(reader.headers().await.unwrap(), ParseOptions::default())
+                        .unwrap();
+                let mut rows = ParsedRows::new(reader, parser, 0, 1);
+                let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+                while let Some(row) = rows.next().await {
+                    if let Ok(transaction) = row.transaction {
+                        let _ = handle_transaction(transaction, &accounts, &transactions);
+                    }
+                }
+                accounts
+            })
+        })
+    });
+    group.finish();
+}
+
+/// Reading and parsing a CSV file on disk the way `process` does by default,
+/// through Tokio's file API, and with `--mmap-input`, off a memory map on
+/// the blocking pool
+fn read_file(c: &mut Criterion) {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write_workload(spec(), &mut file).unwrap();
+    let path = file.path();
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let drain = |mut rows: ParsedRows<_>| async move {
+        let mut parsed = 0;
+        while let Some(row) = rows.next().await {
+            parsed += usize::from(row.transaction.is_ok());
+        }
+        rows.finish().await;
+        parsed
+    };
+    let mut group = c.benchmark_group("read_file");
+    group.throughput(Throughput::Elements(ROWS));
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let file = File::open(path).await.unwrap();
+                let mut reader = AsyncReaderBuilder::new().create_reader(BufReader::new(file));
+                let parser =
+                    RowParser::new(reader.headers().await.unwrap(), ParseOptions::default())
+                        .unwrap();
+                drain(ParsedRows::new(reader, parser, 0, parallelism)).await
+            })
+        })
+    });
+    #[cfg(feature = "mmap-input")]
+    group.bench_function("mapped_blocking_pool", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let map = rust_transaction_engine::ingest::map_input(path).unwrap();
+                let mut reader = AsyncReaderBuilder::new().create_reader(map);
+                let parser =
+                    RowParser::new(reader.headers().await.unwrap(), ParseOptions::default())
+                        .unwrap();
+                drain(ParsedRows::<BufReader<File>>::on_blocking_pool(
+                    reader, parser, 0,
+                ))
+                .await
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, apply, hot_account, end_to_end, read_file);
+criterion_main!(benches);
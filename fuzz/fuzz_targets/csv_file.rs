@@ -0,0 +1,31 @@
+#![no_main]
+
+//! A whole transactions file, header row included, read and applied as a run
+//! would. The first byte picks the amount format, so spreadsheet-style
+//! amounts get parsed as well.
+
+use libfuzzer_sys::fuzz_target;
+use rust_transaction_engine::config::{AmountFormat, Rules};
+use rust_transaction_engine::engine::Engine;
+use rust_transaction_engine::invariants::check_account;
+use rust_transaction_engine::validate::ParseOptions;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&format, file)) = data.split_first() else {
+        return;
+    };
+    let parse = ParseOptions {
+        amount_format: match format % 2 {
+            0 => AmountFormat::Strict,
+            _ => AmountFormat::Lenient,
+        },
+        ..Default::default()
+    };
+    let mut engine = Engine::new(Rules::default()).with_parse_options(parse);
+    if engine.process_csv(file).is_ok() {
+        for account in engine.accounts() {
+            let violations = check_account(&account, None);
+            assert!(violations.is_empty(), "{}", violations[0]);
+        }
+    }
+});
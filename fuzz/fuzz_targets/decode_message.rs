@@ -0,0 +1,26 @@
+#![no_main]
+
+//! One message off a stream, in the format its first byte picks. Decoding
+//! may fail but must never panic.
+
+use libfuzzer_sys::fuzz_target;
+use rust_transaction_engine::decode::MessageFormat;
+use rust_transaction_engine::validate::ParseOptions;
+
+const FORMATS: [MessageFormat; 4] = [
+    MessageFormat::Csv,
+    MessageFormat::Json,
+    MessageFormat::Avro,
+    MessageFormat::Protobuf,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&format, message)) = data.split_first() else {
+        return;
+    };
+    let format = FORMATS[usize::from(format) % FORMATS.len()];
+    let decoder = format
+        .decoder(ParseOptions::default())
+        .expect("the fuzz crate builds with every format");
+    let _ = decoder.decode(message, 1);
+});
@@ -0,0 +1,85 @@
+#![no_main]
+
+//! Sequences of transactions applied one at a time to fresh maps, with every
+//! step checked against the invariants of the `testing` module. IDs and
+//! clients are kept small so rows keep colliding with each other.
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+use rust_transaction_engine::config::{DuplicateTxPolicy, Rules};
+use rust_transaction_engine::models::{
+    AccountsMap, Currency, Transaction, TransactionType, TransactionsMap,
+};
+use rust_transaction_engine::testing::check_step;
+use rust_transaction_engine::transaction::handle_transaction_with;
+
+const TYPES: [TransactionType; 9] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Convert,
+    TransactionType::Hold,
+    TransactionType::Release,
+    TransactionType::Close,
+];
+
+const CURRENCIES: [&str; 2] = ["EUR", "USD"];
+
+#[derive(Debug, Arbitrary)]
+struct Row {
+    tx_type: u8,
+    client: u8,
+    tx: u8,
+    /// Mantissa and scale, so amounts near the limits of `Decimal` come up
+    amount: Option<(i64, u8)>,
+    currency: Option<bool>,
+    to_currency: Option<bool>,
+}
+
+impl Row {
+    fn transaction(&self) -> Transaction {
+        let currency = |pick: Option<bool>| {
+            pick.map(|usd| CURRENCIES[usize::from(usd)].parse::<Currency>().unwrap())
+        };
+        Transaction {
+            tx_type: TYPES[usize::from(self.tx_type) % TYPES.len()].clone(),
+            client: (self.client % 8).into(),
+            tx: (self.tx % 32).into(),
+            amount: self.amount.and_then(|(mantissa, scale)| {
+                Decimal::try_new(mantissa, u32::from(scale % 29)).ok()
+            }),
+            timestamp: None,
+            currency: currency(self.currency),
+            to_currency: currency(self.to_currency),
+            idempotency_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    per_client_ids: bool,
+    rows: Vec<Row>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut rules = Rules::default();
+    if input.per_client_ids {
+        rules.duplicate_tx = DuplicateTxPolicy::PerClient;
+    }
+    let accounts = AccountsMap::new();
+    let transactions = TransactionsMap::new();
+    for row in &input.rows {
+        let transaction = row.transaction();
+        let client = transaction.client;
+        let before = accounts.get(&client).map(|account| account.clone());
+        let outcome =
+            handle_transaction_with(transaction.clone(), &accounts, &transactions, &rules);
+        let after = accounts.get(&client).map(|account| account.clone());
+        let violations = check_step(before.as_ref(), &transaction, &outcome, after.as_ref());
+        assert!(violations.is_empty(), "{}", violations[0]);
+    }
+});
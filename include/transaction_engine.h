@@ -0,0 +1,221 @@
+#ifndef TRANSACTION_ENGINE_H
+#define TRANSACTION_ENGINE_H
+
+/* Generated by cbindgen from src/ffi.rs with `cargo build --features ffi`; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What a call did
+ */
+typedef enum TeStatus {
+  TE_STATUS_OK = 0,
+  /**
+   * The transaction was valid but a business rule turned it down
+   */
+  TE_STATUS_REJECTED = 1,
+  /**
+   * No account for the client, or no balance in the currency
+   */
+  TE_STATUS_NOT_FOUND = 2,
+  /**
+   * A null pointer, an unreadable string or a value out of range
+   */
+  TE_STATUS_INVALID_ARGUMENT = 3,
+} TeStatus;
+
+/**
+ * Type of a transaction; custom types are not available over this
+ * interface
+ */
+typedef enum TeTxType {
+  TE_TX_TYPE_DEPOSIT = 0,
+  TE_TX_TYPE_WITHDRAWAL = 1,
+  TE_TX_TYPE_DISPUTE = 2,
+  TE_TX_TYPE_RESOLVE = 3,
+  TE_TX_TYPE_CHARGEBACK = 4,
+  TE_TX_TYPE_CONVERT = 5,
+  TE_TX_TYPE_HOLD = 6,
+  TE_TX_TYPE_RELEASE = 7,
+  TE_TX_TYPE_CLOSE = 8,
+} TeTxType;
+
+/**
+ * Lifecycle state of an account
+ */
+typedef enum TeAccountStatus {
+  TE_ACCOUNT_STATUS_ACTIVE = 0,
+  TE_ACCOUNT_STATUS_FROZEN = 1,
+  TE_ACCOUNT_STATUS_CHARGEBACK_LOCKED = 2,
+  TE_ACCOUNT_STATUS_CLOSED = 3,
+  TE_ACCOUNT_STATUS_REVIEW_HOLD = 4,
+} TeAccountStatus;
+
+/**
+ * Which dispute-flow rows frozen and chargeback-locked accounts still take,
+ * given as a comma-separated list of `dispute`, `resolve` and `chargeback`,
+ * or `none`
+ */
+typedef struct LockedTypes LockedTypes;
+
+/**
+ * An engine with its own accounts and transactions
+ */
+typedef struct TeEngine TeEngine;
+
+#if !defined(TE_WIDE_IDS)
+/**
+ * Client identifier; 32 bits wide with the `wide-ids` feature, 16 otherwise
+ */
+typedef uint16_t TeClientId;
+#endif
+
+#if defined(TE_WIDE_IDS)
+/**
+ * Client identifier; 32 bits wide with the `wide-ids` feature, 16 otherwise
+ */
+typedef uint32_t TeClientId;
+#endif
+
+#if !defined(TE_WIDE_IDS)
+/**
+ * Transaction identifier; 64 bits wide with the `wide-ids` feature, 32
+ * otherwise
+ */
+typedef uint32_t TeTxId;
+#endif
+
+#if defined(TE_WIDE_IDS)
+/**
+ * Transaction identifier; 64 bits wide with the `wide-ids` feature, 32
+ * otherwise
+ */
+typedef uint64_t TeTxId;
+#endif
+
+/**
+ * One transaction, the fields of an input row
+ */
+typedef struct TeTransaction {
+  enum TeTxType tx_type;
+  TeClientId client;
+  TeTxId tx;
+  bool has_amount;
+  /**
+   * In ten-thousandths
+   */
+  int64_t amount;
+  bool has_timestamp;
+  /**
+   * Unix seconds
+   */
+  int64_t timestamp;
+  /**
+   * Currency code, or null for none
+   */
+  const char *currency;
+  /**
+   * Currency a `convert` moves funds into, or null
+   */
+  const char *to_currency;
+  /**
+   * Idempotency key, or null
+   */
+  const char *idempotency_key;
+} TeTransaction;
+
+/**
+ * One client's balance in one currency, amounts in ten-thousandths
+ */
+typedef struct TeAccount {
+  TeClientId client;
+  int64_t available;
+  int64_t held;
+  int64_t total;
+  bool locked;
+  enum TeAccountStatus status;
+} TeAccount;
+
+/**
+ * Bytes owned by the engine library, released with `te_buffer_free`
+ */
+typedef struct TeBuffer {
+  uint8_t *data;
+  size_t len;
+} TeBuffer;
+
+/**
+ * A new engine with no accounts and the default rules; free it with
+ * `te_engine_free`
+ */
+struct TeEngine *te_engine_new(void);
+
+/**
+ * An engine carrying on from a snapshot written by `te_engine_snapshot` or
+ * by a run's `--save-state`, or null if the bytes are not a snapshot
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes.
+ */
+struct TeEngine *te_engine_restore(const uint8_t *data, size_t len);
+
+/**
+ * # Safety
+ *
+ * `engine` must be null or come from `te_engine_new` or
+ * `te_engine_restore`, and must not be used afterwards.
+ */
+void te_engine_free(struct TeEngine *engine);
+
+/**
+ * Apply one transaction. When it is rejected the reason code, such as
+ * `insufficient_funds`, is copied into `reason` if that is not null.
+ *
+ * # Safety
+ *
+ * `engine` and `transaction` must be valid, the strings `transaction`
+ * points to NUL-terminated, and `reason` null or writable for
+ * `reason_capacity` bytes.
+ */
+enum TeStatus te_engine_submit(struct TeEngine *engine,
+                               const struct TeTransaction *transaction,
+                               char *reason,
+                               size_t reason_capacity);
+
+/**
+ * Look up a client's balance in `currency`, or without a currency if it is
+ * null
+ *
+ * # Safety
+ *
+ * `engine` must be valid, `currency` null or NUL-terminated, and
+ * `account` writable.
+ */
+enum TeStatus te_engine_get_account(const struct TeEngine *engine,
+                                    TeClientId client,
+                                    const char *currency,
+                                    struct TeAccount *account);
+
+/**
+ * Serialize the engine's state in the snapshot format `--resume` reads.
+ * Free the result with `te_buffer_free`; it is empty if serializing failed.
+ *
+ * # Safety
+ *
+ * `engine` must be valid.
+ */
+struct TeBuffer te_engine_snapshot(const struct TeEngine *engine);
+
+/**
+ * # Safety
+ *
+ * `buffer` must come from `te_engine_snapshot` and not be freed twice.
+ */
+void te_buffer_free(struct TeBuffer buffer);
+
+#endif  /* TRANSACTION_ENGINE_H */
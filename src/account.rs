@@ -1,32 +1,241 @@
-use rust_decimal::{Decimal, RoundingStrategy};
-use std::error::Error;
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
 use std::io;
 
-use crate::models::{Account, AccountsMap};
+use crate::config::{
+    AccountColumn, AccountColumns, ClientList, ClosedAccounts, NumberLocale, Rounding,
+};
+use crate::error::EngineError;
+use crate::models::{Account, AccountStatus, AccountsMap, ClientId, Currency};
 
-/// Truncate decimal to 4 digits using zero rounding strategy
-pub fn truncate_to_4(amount: Decimal) -> Decimal {
-    amount.round_dp_with_strategy(4, RoundingStrategy::ToZero)
+/// Round a decimal to the precision and in the direction `rounding` sets,
+/// by default truncating it to 4 digits.
+///
+/// A zero result is always positive; moving a zero amount would otherwise
+/// leave a balance printed as `-0`.
+pub fn round_amount(amount: Decimal, rounding: Rounding) -> Decimal {
+    let mut rounded =
+        amount.round_dp_with_strategy(rounding.precision.places(), rounding.mode.strategy());
+    if rounded.is_zero() {
+        rounded.set_sign_positive(true);
+    }
+    rounded
 }
 
-/// Mutate account balance fields and truncate to 4 digits
+/// Mutate the balance fields for one currency and round them as `rounding`
+/// sets.
+///
+/// Either all three fields change or none do. On arithmetic overflow the
+/// account is frozen (locked) instead of panicking.
 pub fn mutate_account_balance(
     account: &mut Account,
+    currency: Option<Currency>,
     available_delta: Decimal,
     held_delta: Decimal,
     total_delta: Decimal,
-) {
-    account.available = truncate_to_4(account.available + available_delta);
-    account.held = truncate_to_4(account.held + held_delta);
-    account.total = truncate_to_4(account.total + total_delta);
+    rounding: Rounding,
+) -> Result<(), EngineError> {
+    let balance = account.balance(currency);
+    let sums = (
+        balance.available.checked_add(available_delta),
+        balance.held.checked_add(held_delta),
+        balance.total.checked_add(total_delta),
+    );
+    let (Some(available), Some(held), Some(total)) = sums else {
+        account.status = AccountStatus::Frozen;
+        return Err(EngineError::Overflow {
+            client: account.client,
+        });
+    };
+
+    let balance = account.balance_mut(currency);
+    balance.available = round_amount(available, rounding);
+    balance.held = round_amount(held, rounding);
+    balance.total = round_amount(total, rounding);
+    Ok(())
+}
+
+/// How balances are written in the accounts output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub rounding: Rounding,
+    /// Pad every balance to the rounding precision, such as `1.5000`,
+    /// rather than writing only the places it needs
+    pub fixed: bool,
+    pub locale: NumberLocale,
+}
+
+impl NumberFormat {
+    /// Plain numbers rounded as `rounding` sets
+    pub fn rounded(rounding: Rounding) -> Self {
+        NumberFormat {
+            rounding,
+            ..Default::default()
+        }
+    }
+
+    pub fn format(&self, amount: Decimal) -> String {
+        let mut rounded = round_amount(amount, self.rounding);
+        if self.fixed {
+            rounded.rescale(self.rounding.precision.places());
+        }
+        match self.locale {
+            NumberLocale::Plain => rounded.to_string(),
+            locale => locale.localize(&rounded.to_string()),
+        }
+    }
+}
+
+/// A balance serialized as `NumberFormat` writes it
+#[derive(Debug)]
+struct Amount {
+    value: Decimal,
+    format: NumberFormat,
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.format.format(self.value))
+    }
+}
+
+/// One field of a row of the accounts output
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Field {
+    Client(ClientId),
+    Currency(Option<Currency>),
+    Amount(Amount),
+    Flag(bool),
+    Count(u64),
+}
+
+/// Columns `AccountView::extended` adds to the usual layout
+const EXTENDED_COLUMNS: [AccountColumn; 4] = [
+    AccountColumn::Deposits,
+    AccountColumn::Withdrawals,
+    AccountColumn::Disputes,
+    AccountColumn::Chargebacks,
+];
+
+/// Which accounts and columns the accounts output keeps
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountView {
+    /// Only accounts that are locked
+    pub only_locked: bool,
+    /// Only balances with a non-zero `available`, `held` or `total`
+    pub only_nonzero: bool,
+    /// Only these clients
+    pub clients: Option<ClientList>,
+    /// These columns, in this order, instead of the usual layout
+    pub columns: Option<AccountColumns>,
+    /// Add the client's transaction counts after the usual columns
+    pub extended: bool,
 }
 
 /// Output final account balances sorted by client ID
-pub fn output_accounts(accounts: &AccountsMap) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let entries: Vec<_> = accounts.iter().map(|e| e.value().clone()).collect();
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    for entry in entries {
-        wtr.serialize(entry)?;
+pub fn output_accounts(
+    accounts: &AccountsMap,
+    closed: ClosedAccounts,
+    format: NumberFormat,
+    view: &AccountView,
+) -> Result<(), EngineError> {
+    write_accounts(accounts, closed, format, view, io::stdout())
+}
+
+/// Write all accounts as CSV, ordered by client ID so output is stable
+/// across runs.
+///
+/// Input without a `currency` column produces the original
+/// `client,available,held,total,locked` layout. As soon as any balance has a
+/// currency, a `currency` column is added and each client gets one row per
+/// currency.
+///
+/// Closed accounts are listed as locked, left out, or marked in an extra
+/// `closed` column, depending on `closed`.
+///
+/// Balances are written as `format` sets. They are rounded once more, so
+/// state restored from a run with a finer precision is written at the
+/// current one.
+///
+/// With `view.extended` the client's transaction counts follow, repeated on
+/// each of its currency rows.
+///
+/// `view` can leave out rows and pick the columns; the header is written
+/// with the first row, so nothing at all is written when no row is left.
+pub fn write_accounts<W: io::Write>(
+    accounts: &AccountsMap,
+    closed: ClosedAccounts,
+    format: NumberFormat,
+    view: &AccountView,
+    writer: W,
+) -> Result<(), EngineError> {
+    let mut entries: Vec<_> = accounts
+        .iter()
+        .filter(|e| closed != ClosedAccounts::Exclude || e.status != AccountStatus::Closed)
+        .filter(|e| !view.only_locked || e.is_locked())
+        .filter(|e| view.clients.as_ref().is_none_or(|c| c.contains(e.client)))
+        .map(|e| e.value().clone())
+        .collect();
+    entries.sort_by_key(|account| account.client);
+    let with_currency = entries
+        .iter()
+        .any(|account| account.balances.keys().any(Option::is_some));
+    let columns = match &view.columns {
+        Some(columns) => columns.0.clone(),
+        None => [
+            Some(AccountColumn::Client),
+            with_currency.then_some(AccountColumn::Currency),
+            Some(AccountColumn::Available),
+            Some(AccountColumn::Held),
+            Some(AccountColumn::Total),
+            Some(AccountColumn::Locked),
+            (closed == ClosedAccounts::Flag).then_some(AccountColumn::Closed),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(
+            view.extended
+                .then_some(EXTENDED_COLUMNS)
+                .into_iter()
+                .flatten(),
+        )
+        .collect(),
+    };
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    let mut header = true;
+    for account in entries {
+        for (currency, balance) in account.balances() {
+            if view.only_nonzero
+                && balance.available.is_zero()
+                && balance.held.is_zero()
+                && balance.total.is_zero()
+            {
+                continue;
+            }
+            if std::mem::take(&mut header) {
+                wtr.write_record(columns.iter().map(|column| column.name()))?;
+            }
+            let amount = |value| Field::Amount(Amount { value, format });
+            let row: Vec<_> = columns
+                .iter()
+                .map(|column| match column {
+                    AccountColumn::Client => Field::Client(account.client),
+                    AccountColumn::Currency => Field::Currency(currency),
+                    AccountColumn::Available => amount(balance.available),
+                    AccountColumn::Held => amount(balance.held),
+                    AccountColumn::Total => amount(balance.total),
+                    AccountColumn::Locked => Field::Flag(account.is_locked()),
+                    AccountColumn::Closed => Field::Flag(account.status == AccountStatus::Closed),
+                    AccountColumn::Deposits => Field::Count(account.counts.deposits),
+                    AccountColumn::Withdrawals => Field::Count(account.counts.withdrawals),
+                    AccountColumn::Disputes => Field::Count(account.counts.disputes),
+                    AccountColumn::Chargebacks => Field::Count(account.counts.chargebacks),
+                })
+                .collect();
+            wtr.serialize(row)?;
+        }
     }
     wtr.flush()?;
     Ok(())
@@ -35,11 +244,23 @@ pub fn output_accounts(accounts: &AccountsMap) -> Result<(), Box<dyn Error + Sen
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::Balance;
     use rust_decimal::Decimal;
     use std::str::FromStr;
 
+    fn account(available: Decimal, held: Decimal, total: Decimal) -> Account {
+        let mut account = Account::new(1);
+        *account.balance_mut(None) = Balance {
+            available,
+            held,
+            total,
+        };
+        account
+    }
+
     #[test]
     fn test_truncate_to_4() {
+        let truncate_to_4 = |amount| round_amount(amount, Rounding::default());
         assert_eq!(
             truncate_to_4(Decimal::from_str("123.45678").unwrap()),
             Decimal::from_str("123.4567").unwrap()
@@ -52,24 +273,267 @@ mod tests {
     }
 
     #[test]
-    fn test_mutate_account_balance() {
-        let mut account = Account {
-            client: 1,
-            available: Decimal::from(100),
-            held: Decimal::from(50),
-            total: Decimal::from(150),
-            locked: false,
+    fn test_round_amount_modes_and_precision() {
+        let round = |raw: &str, mode: &str, precision: &str| {
+            let rounding = Rounding {
+                mode: mode.parse().unwrap(),
+                precision: precision.parse().unwrap(),
+            };
+            round_amount(Decimal::from_str(raw).unwrap(), rounding).to_string()
         };
+        assert_eq!(round("1.005", "to-zero", "2"), "1.00");
+        assert_eq!(round("1.005", "half-even", "2"), "1.00");
+        assert_eq!(round("1.015", "half-even", "2"), "1.02");
+        assert_eq!(round("1.005", "half-up", "2"), "1.01");
+        assert_eq!(round("-1.005", "half-up", "2"), "-1.01");
+        assert_eq!(round("2.5", "half-even", "0"), "2");
+    }
+
+    #[test]
+    fn test_mutate_account_balance() {
+        let mut account = account(Decimal::from(100), Decimal::from(50), Decimal::from(150));
 
         mutate_account_balance(
             &mut account,
+            None,
             Decimal::from(10),
             Decimal::from(5),
             Decimal::from(15),
+            Rounding::default(),
+        )
+        .unwrap();
+
+        let balance = account.balance(None);
+        assert_eq!(balance.available, Decimal::from(110));
+        assert_eq!(balance.held, Decimal::from(55));
+        assert_eq!(balance.total, Decimal::from(165));
+    }
+
+    #[test]
+    fn test_truncated_zero_has_no_sign() {
+        let truncate_to_4 = |amount| round_amount(amount, Rounding::default());
+        assert_eq!(truncate_to_4(-Decimal::ZERO).to_string(), "0");
+        assert_eq!(truncate_to_4(Decimal::new(-1, 5)).to_string(), "0.0000");
+        assert_eq!(
+            truncate_to_4(Decimal::new(-12345, 5)).to_string(),
+            "-0.1234"
+        );
+    }
+
+    #[test]
+    fn test_mutate_account_balance_overflow_freezes_account() {
+        let mut account = account(Decimal::MAX, Decimal::ZERO, Decimal::MAX);
+
+        let result = mutate_account_balance(
+            &mut account,
+            None,
+            Decimal::ONE,
+            Decimal::ZERO,
+            Decimal::ONE,
+            Rounding::default(),
+        );
+
+        assert!(matches!(result, Err(EngineError::Overflow { client: 1 })));
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert!(account.is_locked());
+        assert_eq!(account.balance(None).available, Decimal::MAX);
+        assert_eq!(account.balance(None).total, Decimal::MAX);
+    }
+
+    #[test]
+    fn test_write_accounts_sorted_by_client() {
+        let accounts = AccountsMap::new();
+        for client in [3, 1, 2] {
+            accounts.insert(client, Account::new(client));
+        }
+        let mut output = Vec::new();
+        write_accounts(
+            &accounts,
+            ClosedAccounts::Include,
+            NumberFormat::default(),
+            &AccountView::default(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0,0,0,false\n2,0,0,0,false\n3,0,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_one_row_per_currency() {
+        let accounts = AccountsMap::new();
+        let mut account = Account::new(1);
+        for (code, amount) in [("USD", 5), ("EUR", 3)] {
+            let balance = account.balance_mut(code.parse().ok());
+            balance.available = Decimal::from(amount);
+            balance.total = Decimal::from(amount);
+        }
+        accounts.insert(1, account);
+        accounts.insert(2, Account::new(2));
+
+        let mut output = Vec::new();
+        write_accounts(
+            &accounts,
+            ClosedAccounts::Include,
+            NumberFormat::default(),
+            &AccountView::default(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,locked\n\
+             1,EUR,3,0,3,false\n1,USD,5,0,5,false\n2,,0,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_fixed_and_localized() {
+        let accounts = AccountsMap::new();
+        let mut account = Account::new(1);
+        *account.balance_mut(None) = Balance {
+            available: Decimal::new(12345, 1),
+            held: Decimal::ZERO,
+            total: Decimal::new(12345, 1),
+        };
+        accounts.insert(1, account);
+
+        let write = |format| {
+            let mut output = Vec::new();
+            write_accounts(
+                &accounts,
+                ClosedAccounts::Include,
+                format,
+                &AccountView::default(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let fixed = NumberFormat {
+            fixed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            write(fixed),
+            "client,available,held,total,locked
+1,1234.5000,0.0000,1234.5000,false
+"
+        );
+        assert_eq!(
+            write(NumberFormat {
+                locale: NumberLocale::De,
+                ..fixed
+            }),
+            "client,available,held,total,locked
+\
+             1,\"1.234,5000\",\"0,0000\",\"1.234,5000\",false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_filters_and_projects() {
+        let accounts = AccountsMap::new();
+        for client in 1..=4 {
+            let mut account = Account::new(client);
+            account.balance_mut(None).total = Decimal::from(client % 2);
+            account.balance_mut(None).available = Decimal::from(client % 2);
+            accounts.insert(client, account);
+        }
+        accounts.get_mut(&3).unwrap().status = AccountStatus::ChargebackLocked;
+        accounts.get_mut(&4).unwrap().status = AccountStatus::Closed;
+
+        let write = |view: AccountView| {
+            let mut output = Vec::new();
+            write_accounts(
+                &accounts,
+                ClosedAccounts::Include,
+                NumberFormat::default(),
+                &view,
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            write(AccountView {
+                only_nonzero: true,
+                columns: "total,client".parse().ok(),
+                ..Default::default()
+            }),
+            "total,client\n1,1\n1,3\n"
+        );
+        assert_eq!(
+            write(AccountView {
+                only_locked: true,
+                clients: "1,3-4".parse().ok(),
+                columns: "client,locked,closed".parse().ok(),
+                ..Default::default()
+            }),
+            "client,locked,closed\n3,true,false\n4,true,true\n"
+        );
+        accounts.get_mut(&2).unwrap().counts.deposits = 2;
+        assert_eq!(
+            write(AccountView {
+                clients: "2".parse().ok(),
+                extended: true,
+                ..Default::default()
+            }),
+            "client,available,held,total,locked,deposits,withdrawals,disputes,chargebacks\n\
+             2,0,0,0,false,2,0,0,0\n"
+        );
+        assert_eq!(
+            write(AccountView {
+                clients: "2".parse().ok(),
+                only_nonzero: true,
+                ..Default::default()
+            }),
+            ""
         );
+    }
+
+    #[test]
+    fn test_write_accounts_closed_modes() {
+        let accounts = AccountsMap::new();
+        for client in [1, 2] {
+            accounts.insert(client, Account::new(client));
+        }
+        accounts.get_mut(&2).unwrap().status = AccountStatus::Closed;
 
-        assert_eq!(account.available, Decimal::from(110));
-        assert_eq!(account.held, Decimal::from(55));
-        assert_eq!(account.total, Decimal::from(165));
+        let write = |closed| {
+            let mut output = Vec::new();
+            write_accounts(
+                &accounts,
+                closed,
+                NumberFormat::default(),
+                &AccountView::default(),
+                &mut output,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            write(ClosedAccounts::Include),
+            "client,available,held,total,locked
+1,0,0,0,false
+2,0,0,0,true
+"
+        );
+        assert_eq!(
+            write(ClosedAccounts::Exclude),
+            "client,available,held,total,locked
+1,0,0,0,false
+"
+        );
+        assert_eq!(
+            write(ClosedAccounts::Flag),
+            "client,available,held,total,locked,closed
+1,0,0,0,false,false
+2,0,0,0,true,true
+"
+        );
     }
 }
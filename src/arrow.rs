@@ -0,0 +1,311 @@
+use arrow_array::cast::AsArray;
+use arrow_array::types::*;
+use arrow_array::{Array, ArrowPrimitiveType, RecordBatch, RecordBatchReader};
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_schema::{DataType, TimeUnit};
+use chrono::{DateTime, SecondsFormat, Utc};
+use csv_async::{Position, StringRecord};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::models::Transaction;
+use crate::outcome::MalformedRow;
+use crate::validate::{COLUMNS, ParseOptions, RowParser};
+
+/// Leading bytes of an Arrow IPC file; a file without them is read as an
+/// IPC stream
+const FILE_MAGIC: &[u8; 6] = b"ARROW1";
+
+/// The transactions in a record batch, in row order.
+///
+/// Columns are found by name, like the header of a CSV file, and every
+/// value goes through the same validation as a CSV field. Strings,
+/// integers, floats, decimals, timestamps and dictionaries of any of them
+/// are accepted; timestamps without a time zone are taken to be UTC.
+/// Rows are numbered from `first_line` in errors.
+pub fn read_batch(
+    batch: &RecordBatch,
+    options: ParseOptions,
+    first_line: u64,
+) -> Result<Vec<Result<Transaction, MalformedRow>>, EngineError> {
+    let schema = batch.schema();
+    let headers: StringRecord = schema
+        .fields()
+        .iter()
+        .map(|field| field.name().as_str())
+        .collect::<Vec<_>>()
+        .into();
+    let parser = RowParser::new(&headers, options)?;
+    let columns = schema
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(
+            |(field, array)| match COLUMNS.contains(&field.name().as_str()) {
+                true => column_text(array.as_ref())
+                    .map_err(|e| EngineError::Arrow(format!("column '{}': {e}", field.name()))),
+                // Other columns are never read
+                false => Ok(Vec::new()),
+            },
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((0..batch.num_rows())
+        .map(|row| {
+            let mut record: StringRecord = columns
+                .iter()
+                .map(|column| column.get(row).map_or("", String::as_str))
+                .collect::<Vec<_>>()
+                .into();
+            let mut position = Position::new();
+            position.set_line(first_line + row as u64);
+            position.set_record(first_line + row as u64);
+            record.set_position(Some(position));
+            parser.validate_row(Ok(record))
+        })
+        .collect())
+}
+
+/// Every value of a column as the text a CSV file would hold, empty for
+/// nulls
+fn column_text(array: &dyn Array) -> Result<Vec<String>, String> {
+    fn each<T: ToString>(array: &dyn Array, value: impl Fn(usize) -> T) -> Vec<String> {
+        (0..array.len())
+            .map(|i| match array.is_valid(i) {
+                true => value(i).to_string(),
+                false => String::new(),
+            })
+            .collect()
+    }
+    fn primitive<T: ArrowPrimitiveType>(array: &dyn Array) -> Vec<String>
+    where
+        T::Native: ToString,
+    {
+        let values = array.as_primitive::<T>();
+        each(array, |i| values.value(i))
+    }
+    fn timestamps<T: ArrowPrimitiveType<Native = i64>>(
+        array: &dyn Array,
+        to_time: fn(i64) -> Option<DateTime<Utc>>,
+    ) -> Vec<String> {
+        let values = array.as_primitive::<T>();
+        each(array, |i| {
+            // Left for the parser to reject as it would garbage in a CSV file
+            to_time(values.value(i)).map_or_else(
+                || values.value(i).to_string(),
+                |time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            )
+        })
+    }
+
+    Ok(match array.data_type() {
+        DataType::Utf8 => each(array, |i| array.as_string::<i32>().value(i)),
+        DataType::LargeUtf8 => each(array, |i| array.as_string::<i64>().value(i)),
+        DataType::Utf8View => each(array, |i| array.as_string_view().value(i)),
+        DataType::Int8 => primitive::<Int8Type>(array),
+        DataType::Int16 => primitive::<Int16Type>(array),
+        DataType::Int32 => primitive::<Int32Type>(array),
+        DataType::Int64 => primitive::<Int64Type>(array),
+        DataType::UInt8 => primitive::<UInt8Type>(array),
+        DataType::UInt16 => primitive::<UInt16Type>(array),
+        DataType::UInt32 => primitive::<UInt32Type>(array),
+        DataType::UInt64 => primitive::<UInt64Type>(array),
+        DataType::Float32 => primitive::<Float32Type>(array),
+        DataType::Float64 => primitive::<Float64Type>(array),
+        DataType::Decimal128(..) => {
+            let values = array.as_primitive::<Decimal128Type>();
+            each(array, |i| values.value_as_string(i))
+        }
+        DataType::Decimal256(..) => {
+            let values = array.as_primitive::<Decimal256Type>();
+            each(array, |i| values.value_as_string(i))
+        }
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            timestamps::<TimestampSecondType>(array, |v| DateTime::from_timestamp(v, 0))
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            timestamps::<TimestampMillisecondType>(array, DateTime::from_timestamp_millis)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            timestamps::<TimestampMicrosecondType>(array, DateTime::from_timestamp_micros)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            timestamps::<TimestampNanosecondType>(array, |v| {
+                Some(DateTime::from_timestamp_nanos(v))
+            })
+        }
+        DataType::Dictionary(..) => {
+            let dictionary = array.as_any_dictionary();
+            let values = column_text(dictionary.values().as_ref())?;
+            let keys = dictionary.normalized_keys();
+            each(array, |i| values[keys[i]].as_str())
+        }
+        other => return Err(format!("type {other} is not supported")),
+    })
+}
+
+/// The rows of an Arrow IPC file or stream, read one batch at a time
+pub struct ArrowRows {
+    batches: Box<dyn RecordBatchReader + Send>,
+    options: ParseOptions,
+    current: std::vec::IntoIter<Result<Transaction, MalformedRow>>,
+    rows_read: u64,
+    failed: bool,
+}
+
+impl ArrowRows {
+    /// Open an Arrow IPC file, which Feather v2 files are, or an IPC stream
+    pub fn open(path: &Path, options: ParseOptions) -> Result<Self, EngineError> {
+        let arrow_error =
+            |e: arrow_schema::ArrowError| EngineError::Arrow(format!("{}: {e}", path.display()));
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; FILE_MAGIC.len()];
+        let is_file = file.read_exact(&mut magic).is_ok() && &magic == FILE_MAGIC;
+        file.seek(SeekFrom::Start(0))?;
+        let batches: Box<dyn RecordBatchReader + Send> = match is_file {
+            true => Box::new(FileReader::try_new(file, None).map_err(arrow_error)?),
+            false => Box::new(StreamReader::try_new(file, None).map_err(arrow_error)?),
+        };
+        Ok(Self::new(batches, options))
+    }
+
+    /// Read the batches of any record batch reader
+    pub fn new(batches: Box<dyn RecordBatchReader + Send>, options: ParseOptions) -> Self {
+        ArrowRows {
+            batches,
+            options,
+            current: Vec::new().into_iter(),
+            rows_read: 0,
+            failed: false,
+        }
+    }
+
+    /// Rows handed out so far
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read
+    }
+}
+
+impl Iterator for ArrowRows {
+    type Item = Result<Transaction, MalformedRow>;
+
+    /// The next row. A batch that cannot be read or converted ends the input
+    /// with one malformed row carrying the error.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.current.next() {
+                self.rows_read += 1;
+                return Some(row);
+            }
+            if self.failed {
+                return None;
+            }
+            let rows = self
+                .batches
+                .next()?
+                .map_err(|e| EngineError::Arrow(e.to_string()))
+                .and_then(|batch| read_batch(&batch, self.options.clone(), self.rows_read + 1));
+            match rows {
+                Ok(rows) => self.current = rows.into_iter(),
+                Err(error) => {
+                    self.failed = true;
+                    self.rows_read += 1;
+                    return Some(Err(MalformedRow {
+                        line: Some(self.rows_read),
+                        fields: Vec::new(),
+                        error,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{
+        ArrayRef, Decimal128Array, DictionaryArray, Int32Array, StringArray, TimestampSecondArray,
+        UInt32Array,
+    };
+    use arrow_ipc::writer::FileWriter;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+
+    use crate::models::TransactionType;
+
+    fn batch() -> RecordBatch {
+        let types: DictionaryArray<Int8Type> = vec!["deposit", "deposit", "withdrawal"]
+            .into_iter()
+            .collect();
+        RecordBatch::try_from_iter([
+            ("type", Arc::new(types) as ArrayRef),
+            (
+                "client",
+                Arc::new(Int32Array::from(vec![1, 2, -1])) as ArrayRef,
+            ),
+            ("tx", Arc::new(UInt32Array::from(vec![1, 2, 3])) as ArrayRef),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![Some(15_000), Some(123_456), None])
+                        .with_precision_and_scale(10, 4)
+                        .unwrap(),
+                ) as ArrayRef,
+            ),
+            (
+                "timestamp",
+                Arc::new(TimestampSecondArray::from(vec![0, 60, 120])) as ArrayRef,
+            ),
+            (
+                "notes",
+                Arc::new(StringArray::from(vec!["a", "b", "c"])) as ArrayRef,
+            ),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_batch_like_csv_rows() {
+        let rows = read_batch(&batch(), ParseOptions::default(), 1).unwrap();
+        let first = rows[0].as_ref().unwrap();
+        assert_eq!(first.tx_type, TransactionType::Deposit);
+        assert_eq!(first.amount, Some(dec!(1.5)));
+        assert_eq!(
+            first.timestamp,
+            Some("1970-01-01T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(rows[1].as_ref().unwrap().amount, Some(dec!(12.3456)));
+        // A negative client ID is malformed, as it would be in CSV
+        let malformed = rows[2].as_ref().unwrap_err();
+        assert_eq!(malformed.line, Some(3));
+        assert_eq!(malformed.fields[..3], ["withdrawal", "-1", "3"]);
+
+        let missing = RecordBatch::try_from_iter([(
+            "type",
+            Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+        )])
+        .unwrap();
+        assert!(read_batch(&missing, ParseOptions::default(), 1).is_err());
+    }
+
+    #[test]
+    fn test_open_ipc_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let batch = batch();
+        let mut writer = FileWriter::try_new(file.reopen().unwrap(), &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+
+        let mut rows = ArrowRows::open(file.path(), ParseOptions::default()).unwrap();
+        let lines: Vec<_> = rows
+            .by_ref()
+            .map(|row| row.map_or_else(|m| m.line, |_| None))
+            .collect();
+        assert_eq!(lines, vec![None, None, Some(3), None, None, Some(6)]);
+        assert_eq!(rows.rows_read(), 6);
+    }
+}
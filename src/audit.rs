@@ -0,0 +1,178 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::models::{Account, ClientId, Currency, TxId};
+use crate::outcome::Applied;
+
+/// One line of the audit log: a client's balance in one currency right after
+/// an applied transaction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub client: ClientId,
+    /// Position of the transaction among the client's applied transactions,
+    /// starting at 1
+    pub seq: u64,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub tx_type: Cow<'static, str>,
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub status: &'static str,
+}
+
+/// Numbers each client's applied transactions in the order the engine
+/// applied them
+#[derive(Debug, Default)]
+pub struct AuditSequencer {
+    last: HashMap<ClientId, u64>,
+}
+
+impl AuditSequencer {
+    /// Give an applied transaction the client's next sequence number and
+    /// capture the balances it left behind.
+    ///
+    /// Returns one record per currency the transaction touched, all sharing
+    /// the same sequence number: a conversion touches two, a close one per
+    /// currency paid out.
+    pub fn record(&mut self, applied: &Applied, account: &Account) -> Vec<AuditRecord> {
+        let transaction = &applied.transaction;
+        let seq = self.last.entry(transaction.client).or_insert(0);
+        *seq += 1;
+
+        applied
+            .currencies()
+            .into_iter()
+            .map(|currency| {
+                let balance = account.balance(currency);
+                AuditRecord {
+                    client: transaction.client,
+                    seq: *seq,
+                    tx: transaction.tx,
+                    tx_type: transaction.tx_type.name(),
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total,
+                    status: account.status.as_str(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// CSV writer for the append-only audit log
+pub struct AuditLogWriter<W: std::io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl AuditLogWriter<File> {
+    /// Open the audit log at `path` for appending, creating it if needed.
+    ///
+    /// The header is only written when the file starts out empty, so runs
+    /// can keep adding to the same log.
+    pub fn open(path: &Path) -> Result<Self, EngineError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+        Ok(AuditLogWriter::new(file, is_new))
+    }
+}
+
+impl<W: std::io::Write> AuditLogWriter<W> {
+    pub fn new(inner: W, write_header: bool) -> Self {
+        AuditLogWriter {
+            writer: csv::WriterBuilder::new()
+                .has_headers(write_header)
+                .from_writer(inner),
+        }
+    }
+
+    /// Append one record
+    pub fn write(&mut self, record: &AuditRecord) -> Result<(), EngineError> {
+        self.writer.serialize(record)?;
+        Ok(())
+    }
+
+    /// Flush buffered rows and return the underlying writer
+    pub fn finish(self) -> Result<W, EngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| EngineError::Io(e.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Conversion, Transaction, TransactionType};
+
+    fn applied(client: ClientId, tx: TxId, tx_type: TransactionType) -> Applied {
+        Applied {
+            transaction: Transaction {
+                currency: "USD".parse().ok(),
+                ..Transaction::new(tx_type, client, tx, Some(Decimal::ONE))
+            },
+            amount: Decimal::ONE,
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    fn account() -> Account {
+        let mut account = Account::new(1);
+        let balance = account.balance_mut("USD".parse().ok());
+        balance.available = Decimal::ONE;
+        balance.total = Decimal::ONE;
+        account
+    }
+
+    #[test]
+    fn test_sequence_numbers_are_per_client() {
+        let mut sequencer = AuditSequencer::default();
+        let account = account();
+        let mut records = Vec::new();
+        for applied in [
+            applied(1, 1, TransactionType::Deposit),
+            applied(2, 2, TransactionType::Deposit),
+            applied(1, 3, TransactionType::Deposit),
+        ] {
+            records.extend(sequencer.record(&applied, &account));
+        }
+        let seqs: Vec<_> = records.iter().map(|r| (r.client, r.seq, r.tx)).collect();
+        assert_eq!(seqs, [(1, 1, 1), (2, 1, 2), (1, 2, 3)]);
+        assert_eq!(records[0].available, Decimal::ONE);
+    }
+
+    #[test]
+    fn test_conversion_records_both_currencies_under_one_seq() {
+        let mut sequencer = AuditSequencer::default();
+        let mut convert = applied(1, 3, TransactionType::Convert);
+        convert.conversion = Some(Conversion {
+            to: "EUR".parse().unwrap(),
+            rate: Decimal::ONE,
+            converted: Decimal::ONE,
+        });
+        let records = sequencer.record(&convert, &account());
+        let seqs: Vec<_> = records.iter().map(|r| (r.seq, r.currency)).collect();
+        assert_eq!(seqs, [(1, "USD".parse().ok()), (1, "EUR".parse().ok())]);
+    }
+
+    #[test]
+    fn test_writer_writes_one_line_per_record() {
+        let mut sequencer = AuditSequencer::default();
+        let records = sequencer.record(&applied(1, 1, TransactionType::Deposit), &account());
+        let mut writer = AuditLogWriter::new(Vec::new(), false);
+        writer.write(&records[0]).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.finish().unwrap()).unwrap(),
+            "1,1,1,deposit,USD,1,0,1,active\n"
+        );
+    }
+}
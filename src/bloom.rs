@@ -0,0 +1,105 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::models::TxKey;
+
+/// Bits per expected key, which with `HASHES` bits per key in one 64-bit
+/// block gives roughly a 2% false positive rate
+const BITS_PER_KEY: usize = 10;
+
+/// Bits set per key
+const HASHES: u32 = 7;
+
+/// Bloom filter of the transaction keys stored so far.
+///
+/// A key the filter has never seen is certainly not stored, so its record can
+/// be inserted without first checking the map. A key it may have seen still
+/// goes through the map's duplicate check. All of a key's bits sit in one
+/// 64-bit word, so adding or testing a key is a single atomic operation on a
+/// single cache line, and the filter is shared between client tasks without
+/// a lock.
+#[derive(Debug)]
+pub struct TxKeyFilter {
+    words: Box<[AtomicU64]>,
+}
+
+impl TxKeyFilter {
+    /// An empty filter sized for `expected` keys
+    pub fn with_capacity(expected: usize) -> Self {
+        let words = (expected.max(1) * BITS_PER_KEY).div_ceil(64);
+        TxKeyFilter {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Add `key`, returning whether it may have been added before
+    pub fn insert(&self, key: &TxKey) -> bool {
+        let (word, mask) = self.locate(key);
+        word.fetch_or(mask, Ordering::Relaxed) & mask == mask
+    }
+
+    /// Whether `key` may have been added
+    pub fn contains(&self, key: &TxKey) -> bool {
+        let (word, mask) = self.locate(key);
+        word.load(Ordering::Relaxed) & mask == mask
+    }
+
+    /// The word holding a key's bits, and which of its bits they are
+    fn locate(&self, key: &TxKey) -> (&AtomicU64, u64) {
+        let mut hasher = rustc_hash::FxHasher::default();
+        key.hash(&mut hasher);
+        let hash = mix(hasher.finish());
+        let word = (hash % self.words.len() as u64) as usize;
+        // Each bit position takes 6 bits of a second, independent hash
+        let bits = mix(hash ^ 0x9E37_79B9_7F4A_7C15);
+        let mask = (0..HASHES).fold(0, |mask, i| mask | 1 << ((bits >> (6 * i)) & 63));
+        (&self.words[word], mask)
+    }
+}
+
+/// Spread the bits of a hash, since FxHash leaves small integer keys
+/// clustered
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A filter holding the keys of IDs 0 to 9,999
+    fn filled() -> TxKeyFilter {
+        let filter = TxKeyFilter::with_capacity(10_000);
+        for tx in 0..10_000 {
+            filter.insert(&TxKey::global(tx));
+        }
+        filter
+    }
+
+    #[test]
+    fn test_insert_reports_new_keys() {
+        let filter = TxKeyFilter::with_capacity(10_000);
+        let fresh = (0..10_000)
+            .filter(|&tx| !filter.insert(&TxKey::global(tx)))
+            .count();
+        assert!(fresh > 9_500, "only {fresh} keys reported as new");
+        assert!(filter.insert(&TxKey::global(5)));
+    }
+
+    #[test]
+    fn test_filter_never_misses_an_inserted_key() {
+        let filter = filled();
+        assert!((0..10_000).all(|tx| filter.contains(&TxKey::global(tx))));
+    }
+
+    #[test]
+    fn test_filter_rarely_reports_false_positives() {
+        let filter = filled();
+        let false_positives = (10_000..20_000)
+            .filter(|&tx| filter.contains(&TxKey::global(tx)))
+            .count();
+        assert!(false_positives < 500, "{false_positives} false positives");
+    }
+}
@@ -0,0 +1,228 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error::EngineError;
+use crate::models::{Account, ClientId, Transaction};
+use crate::outcome::TransactionOutcome;
+use crate::store::StateStore;
+use crate::workload::SplitMix64;
+
+/// Faults to inject into a run's pipeline, parsed from a spec such as
+/// `drop=0.01,duplicate=0.02,delay=0.1,max-delay-ms=20,seed=7`.
+///
+/// Rates are shares of messages between 0 and 1; unset ones are 0, so the
+/// empty spec injects nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Share of input rows lost before they reach the pipeline
+    pub drop_rate: f64,
+    /// Share of input rows delivered twice, as an at-least-once source would
+    pub duplicate_rate: f64,
+    /// Share of channel sends held back for a while first
+    pub delay_rate: f64,
+    /// Longest hold of a delayed send; each one waits a random time up to it
+    pub max_delay: Duration,
+    pub seed: u64,
+}
+
+impl FromStr for ChaosConfig {
+    type Err = EngineError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut config = ChaosConfig::default();
+        for setting in raw.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || EngineError::Usage(format!("invalid chaos setting '{setting}'"));
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let rate = || match value.trim().parse::<f64>() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+                _ => Err(invalid()),
+            };
+            match key.trim() {
+                "drop" => config.drop_rate = rate()?,
+                "duplicate" => config.duplicate_rate = rate()?,
+                "delay" => config.delay_rate = rate()?,
+                "max-delay-ms" => {
+                    let millis = value.trim().parse().map_err(|_| invalid())?;
+                    config.max_delay = Duration::from_millis(millis);
+                }
+                "seed" => config.seed = value.trim().parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+        if config.drop_rate + config.duplicate_rate > 1.0 {
+            return Err(EngineError::Usage(
+                "chaos drop and duplicate rates add up to more than 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Seeded source of the faults in a `ChaosConfig`.
+///
+/// Every decision draws from one generator, so a run that asks in the same
+/// order, such as the single dispatcher of the pipeline, sees the same
+/// faults for the same seed.
+#[derive(Debug)]
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Mutex<SplitMix64>,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        Chaos {
+            config,
+            rng: Mutex::new(SplitMix64(config.seed)),
+        }
+    }
+
+    fn roll(&self) -> f64 {
+        self.rng
+            .lock()
+            .expect("chaos generator poisoned")
+            .next_f64()
+    }
+
+    /// The copies of `message` that arrive: none if it is dropped, two if
+    /// it is duplicated and otherwise just the one
+    pub fn deliver<T: Clone>(&self, message: T) -> Vec<T> {
+        let roll = self.roll();
+        if roll < self.config.drop_rate {
+            Vec::new()
+        } else if roll < self.config.drop_rate + self.config.duplicate_rate {
+            vec![message.clone(), message]
+        } else {
+            vec![message]
+        }
+    }
+
+    /// How long to hold back the next channel send, if at all
+    pub fn send_delay(&self) -> Option<Duration> {
+        (self.roll() < self.config.delay_rate).then(|| self.config.max_delay.mul_f64(self.roll()))
+    }
+}
+
+/// A `StateStore` whose writes fail at random.
+///
+/// Half of the failures happen before the transaction reaches the inner
+/// store; the other half after it was applied, as when the connection drops
+/// before the acknowledgement arrives. A caller retrying until it succeeds
+/// therefore sees both lost writes and writes applied twice. Reads are
+/// passed through untouched.
+#[derive(Debug)]
+pub struct ChaosStore<S> {
+    inner: S,
+    failure_rate: f64,
+    rng: SplitMix64,
+}
+
+impl<S: StateStore> ChaosStore<S> {
+    /// Fail `failure_rate` of the writes to `inner`, drawing from `seed`
+    pub fn new(inner: S, failure_rate: f64, seed: u64) -> Self {
+        ChaosStore {
+            inner,
+            failure_rate,
+            rng: SplitMix64(seed),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: StateStore> StateStore for ChaosStore<S> {
+    fn submit(&mut self, transaction: Transaction) -> Result<TransactionOutcome, EngineError> {
+        let tx = transaction.tx;
+        if self.rng.next_f64() >= self.failure_rate {
+            return self.inner.submit(transaction);
+        }
+        if self.rng.below(2) == 0 {
+            return Err(EngineError::InjectedFault(format!(
+                "write of Tx: {tx} lost"
+            )));
+        }
+        // Applied, but the outcome never reaches the caller
+        let _ = self.inner.submit(transaction)?;
+        Err(EngineError::InjectedFault(format!(
+            "acknowledgement of Tx: {tx} lost"
+        )))
+    }
+
+    fn account(&mut self, client: ClientId) -> Result<Option<Account>, EngineError> {
+        self.inner.account(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Rules;
+    use crate::engine::Engine;
+    use crate::workload::{Workload, WorkloadSpec};
+
+    #[test]
+    fn test_parse_spec() {
+        let config: ChaosConfig = "drop=0.01, duplicate=0.2,delay=1,max-delay-ms=20,seed=7"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            ChaosConfig {
+                drop_rate: 0.01,
+                duplicate_rate: 0.2,
+                delay_rate: 1.0,
+                max_delay: Duration::from_millis(20),
+                seed: 7,
+            }
+        );
+        assert_eq!("".parse::<ChaosConfig>().unwrap(), ChaosConfig::default());
+        assert!("drop=2".parse::<ChaosConfig>().is_err());
+        assert!("drop=0.6,duplicate=0.6".parse::<ChaosConfig>().is_err());
+        assert!("reorder=0.1".parse::<ChaosConfig>().is_err());
+    }
+
+    #[test]
+    fn test_deliver_follows_seed() {
+        let config = ChaosConfig {
+            drop_rate: 0.2,
+            duplicate_rate: 0.2,
+            seed: 3,
+            ..Default::default()
+        };
+        let copies = |chaos: Chaos| (0..200).map(|i| chaos.deliver(i).len()).collect::<Vec<_>>();
+        let first = copies(Chaos::new(config));
+        assert_eq!(first, copies(Chaos::new(config)));
+        assert!(first.contains(&0) && first.contains(&1) && first.contains(&2));
+        assert!(
+            copies(Chaos::new(ChaosConfig::default()))
+                .iter()
+                .all(|&n| n == 1)
+        );
+        assert_eq!(Chaos::new(ChaosConfig::default()).send_delay(), None);
+    }
+
+    #[test]
+    fn test_retried_writes_settle_like_fault_free_ones() {
+        let spec = WorkloadSpec {
+            clients: 10,
+            transactions: 2_000,
+            dispute_rate: 0.1,
+            ..Default::default()
+        };
+        let mut clean = Engine::new(Rules::default());
+        let mut store = ChaosStore::new(Engine::new(Rules::default()), 0.3, 11);
+        let mut faults = 0;
+        for transaction in Workload::new(spec) {
+            let _ = clean.submit(transaction.clone());
+            while let Err(e) = store.submit(transaction.clone()) {
+                assert_eq!(e.reason_code(), "injected_fault");
+                faults += 1;
+            }
+        }
+        assert!(faults > 0);
+        assert_eq!(store.into_inner().accounts(), clean.accounts());
+    }
+}
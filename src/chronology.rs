@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::config::MonotonicPolicy;
+use crate::error::EngineError;
+use crate::models::{ClientId, Transaction};
+
+/// Tracks the latest timestamp seen per client to detect out-of-order rows
+#[derive(Debug, Default)]
+pub struct ChronologyGuard {
+    policy: MonotonicPolicy,
+    last_seen: HashMap<ClientId, DateTime<Utc>>,
+}
+
+impl ChronologyGuard {
+    pub fn new(policy: MonotonicPolicy) -> Self {
+        ChronologyGuard {
+            policy,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Check a transaction against the client's previous timestamp.
+    ///
+    /// Rows without a timestamp are never flagged. Equal timestamps count as
+    /// in order. Only the reject policy returns an error.
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let Some(timestamp) = transaction.timestamp else {
+            return Ok(());
+        };
+        if self.policy == MonotonicPolicy::Off {
+            return Ok(());
+        }
+
+        match self.last_seen.get(&transaction.client) {
+            Some(&previous) if timestamp < previous => {
+                let error = EngineError::OutOfOrder {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                    timestamp,
+                    previous,
+                };
+                match self.policy {
+                    MonotonicPolicy::Reject => Err(error),
+                    _ => {
+                        warn!(
+                            tx = transaction.tx,
+                            client = transaction.client,
+                            reason = error.reason_code(),
+                            "{}",
+                            error
+                        );
+                        Ok(())
+                    }
+                }
+            }
+            _ => {
+                self.last_seen.insert(transaction.client, timestamp);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{TransactionType, TxId};
+
+    fn at(tx: TxId, seconds: i64) -> Transaction {
+        Transaction {
+            timestamp: DateTime::from_timestamp(seconds, 0),
+            ..Transaction::new(TransactionType::Deposit, 1, tx, None)
+        }
+    }
+
+    #[test]
+    fn test_reject_out_of_order_rows() {
+        let mut guard = ChronologyGuard::new(MonotonicPolicy::Reject);
+        guard.check(&at(1, 100)).unwrap();
+        guard.check(&at(2, 100)).unwrap();
+        assert!(matches!(
+            guard.check(&at(3, 50)),
+            Err(EngineError::OutOfOrder { tx: 3, .. })
+        ));
+        // The rejected row does not move the high-water mark back
+        assert!(guard.check(&at(4, 75)).is_err());
+        guard.check(&at(5, 200)).unwrap();
+    }
+
+    #[test]
+    fn test_flag_and_off_never_reject() {
+        for policy in [MonotonicPolicy::Flag, MonotonicPolicy::Off] {
+            let mut guard = ChronologyGuard::new(policy);
+            guard.check(&at(1, 100)).unwrap();
+            guard.check(&at(2, 50)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,1306 @@
+use chrono::Duration;
+use clap::{Args, Parser, Subcommand};
+use rust_decimal::Decimal;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::{
+    AccountColumns, AmountFormat, AmountMode, AmountPrecision, ClientList, ClientMismatchPolicy,
+    ClosePolicy, ClosedAccounts, ColumnAlias, CompactPolicy, CsvDialect, Delimiter,
+    DisputeShortfallPolicy, DuplicateTxPolicy, EngineConfig, ErrorPolicy, LockedTypes, LogFormat,
+    MergeDuplicates, MonotonicPolicy, NumberLocale, OutputFormat, Precision, PriorityTypes,
+    Rounding, RoundingMode, Rules, TypeAlias,
+};
+use crate::encryption::{AdminKey, StateKey};
+use crate::error::EngineError;
+use crate::fraud::FraudRules;
+use crate::fx::FxRates;
+use crate::limits::RateLimit;
+use crate::memory::MemorySize;
+use crate::models::{ClientId, TxId};
+use crate::publish::{PublishKey, PublishTarget};
+use crate::shard::ClientSlice;
+use crate::tenant::Tenant;
+use crate::validate::ParseOptions;
+use crate::workload::WorkloadSpec;
+
+/// Subcommand names, used to tell a bare `transactions.csv` apart from a
+/// subcommand
+const SUBCOMMANDS: [&str; 11] = [
+    "process",
+    "inspect",
+    "statement",
+    "report",
+    "validate",
+    "replay",
+    "generate",
+    "diff",
+    "merge",
+    "test-fixtures",
+    "help",
+];
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Process client transactions and report account balances"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// What the binary was asked to do
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum Command {
+    /// Process a transactions file and print the resulting accounts
+    Process(Box<CliOptions>),
+    /// Print one client's state from a saved snapshot
+    Inspect(InspectOptions),
+    /// Print running-balance statements reconstructed from a transactions file
+    Statement(StatementOptions),
+    /// Print top clients by volume, the largest disputes, the charged-back
+    /// total and the spread of transaction sizes for a transactions file
+    Report(ReportOptions),
+    /// Check a transactions file without producing any output state, reporting
+    /// how many rows would be applied or rejected and why
+    Validate(ValidateOptions),
+    /// Process a transactions file in order up to a breakpoint and print the
+    /// accounts as they stood at that point
+    Replay(ReplayOptions),
+    /// Write a synthetic transactions file to stdout for benchmarking
+    Generate(GenerateOptions),
+    /// Print what changed per client between two accounts files
+    Diff(DiffOptions),
+    /// Combine the accounts files of runs over separate client slices into
+    /// one
+    Merge(MergeOptions),
+    /// Process the `input.csv` of every fixture directory and compare the
+    /// accounts with its `expected.csv`
+    TestFixtures(FixturesOptions),
+}
+
+impl Command {
+    /// Parse arguments, including the program name.
+    ///
+    /// Without a subcommand the arguments are taken as a `process` run, so
+    /// `engine transactions.csv` keeps working.
+    pub fn try_parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        if let Some(first) = args.get(1).and_then(|a| a.to_str())
+            && !SUBCOMMANDS.contains(&first)
+            && !matches!(first, "-h" | "--help" | "-V" | "--version")
+        {
+            args.insert(1, "process".into());
+        }
+        Cli::try_parse_from(args).map(|cli| cli.command)
+    }
+
+    /// Log format requested on the command line
+    pub fn log_format(&self) -> LogFormat {
+        match self {
+            Command::Process(options) => options.log_format.unwrap_or_default(),
+            Command::Statement(options) => options.log_format,
+            Command::Report(options) => options.log_format,
+            Command::Validate(options) => options.log_format,
+            Command::Replay(options) => options.log_format,
+            Command::Inspect(_)
+            | Command::Generate(_)
+            | Command::Diff(_)
+            | Command::Merge(_)
+            | Command::TestFixtures(_) => LogFormat::default(),
+        }
+    }
+}
+
+/// Options for a single processing run.
+///
+/// Settings that can also come from a config file are optional here; unset
+/// ones fall back to the file and then to their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Args)]
+pub struct CliOptions {
+    /// Transactions CSV file
+    #[arg(required_unless_present = "tenants")]
+    pub input: Option<PathBuf>,
+    /// Process this provider's file with its own state, separate from every
+    /// other tenant's, instead of INPUT
+    #[arg(long = "tenant", value_name = "NAME=PATH", conflicts_with = "input")]
+    pub tenants: Vec<Tenant>,
+    /// Write each tenant's accounts to NAME.csv in this directory
+    /// [default: .]
+    #[arg(long, value_name = "DIR")]
+    pub tenant_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub dialect: DialectOptions,
+    /// Read settings from this TOML file; flags override its values
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// The options as given on the command line, before the config was
+    /// applied, so a reload can apply a changed config to them again
+    #[arg(skip)]
+    pub flags: Option<Box<CliOptions>>,
+    /// Async runtime worker threads [default: one per CPU core]
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub worker_threads: Option<usize>,
+    /// Transactions buffered per client before ingestion waits [default: 50]
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub channel_capacity: Option<usize>,
+    /// Apply rows on N shard workers that each own their clients' state,
+    /// instead of one task per client
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub shards: Option<usize>,
+    /// Start at most N client tasks; further clients share the task their
+    /// ID hashes to [default: one task per client]
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub max_client_tasks: Option<usize>,
+    /// Shut a client's task down after N seconds without a row for it,
+    /// starting a new one when the next row arrives
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub client_idle_secs: Option<usize>,
+    /// Only handle clients with IDs FIRST to LAST, skipping every other
+    /// client's rows, so one file can be split across engine instances
+    #[arg(long, value_name = "FIRST-LAST", value_parser = ClientSlice::range)]
+    pub clients: Option<ClientSlice>,
+    /// Only handle clients whose ID modulo COUNT is INDEX, counting from 0
+    #[arg(
+        long,
+        value_name = "INDEX/COUNT",
+        value_parser = ClientSlice::shard,
+        conflicts_with = "clients"
+    )]
+    pub shard: Option<ClientSlice>,
+    /// Apply up to N deposits and withdrawals queued back to back for one
+    /// client under a single account update [default: 1]
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub coalesce_rows: Option<usize>,
+    /// Let rows of these types, such as `chargeback,close`, go ahead of a
+    /// client's queued rows of other types
+    #[arg(long, value_name = "TYPES")]
+    pub priority_types: Option<PriorityTypes>,
+    /// Stop with an error once the account and transaction state is
+    /// estimated to need more than SIZE (e.g. `512M`, `4G`)
+    #[arg(long, value_name = "SIZE")]
+    pub max_memory: Option<MemorySize>,
+    /// Move the oldest undisputed transactions to a temporary file once the
+    /// state is estimated to need more than SIZE, reading them back when
+    /// they are disputed
+    #[arg(long, value_name = "SIZE")]
+    pub spill_over: Option<MemorySize>,
+    /// Move the accounts of clients without recent rows to a temporary file
+    /// once the accounts are estimated to need more than SIZE, reading each
+    /// back at its client's next row
+    #[arg(long, value_name = "SIZE")]
+    pub page_accounts_over: Option<MemorySize>,
+    /// Keep a bloom filter sized for N transaction IDs so that new IDs skip
+    /// the duplicate lookup
+    #[arg(long, value_name = "N", value_parser = parse_positive)]
+    pub bloom_filter_ids: Option<usize>,
+    /// Write every rejected transaction to this CSV file
+    #[arg(long, value_name = "PATH")]
+    pub rejects: Option<PathBuf>,
+    /// How malformed rows and rejected transactions are handled [default: skip]
+    #[arg(long, value_name = "strict|skip|collect")]
+    pub error_policy: Option<ErrorPolicy>,
+    #[command(flatten)]
+    pub parsing: InputOptions,
+    /// Check account invariants after every applied transaction
+    #[arg(long)]
+    pub verify: bool,
+    /// Keep double-entry books and write the trial balance to this CSV file
+    #[arg(long, value_name = "PATH")]
+    pub ledger: Option<PathBuf>,
+    #[command(flatten)]
+    pub rules: RuleOptions,
+    #[command(flatten)]
+    pub fraud: FraudOptions,
+    /// Write every fraud rule verdict to this CSV file
+    #[arg(long, value_name = "PATH")]
+    pub fraud_report: Option<PathBuf>,
+    /// How closed accounts appear in the output [default: include]
+    #[arg(long, value_name = "include|exclude|flag")]
+    pub closed_accounts: Option<ClosedAccounts>,
+    /// Write every balance with exactly `--precision` decimal places, such
+    /// as `1.5000`
+    #[arg(long)]
+    pub fixed_decimals: bool,
+    /// Separators of the numbers in the accounts output [default: plain]
+    #[arg(long, value_name = "plain|en|de|fr|ch")]
+    pub number_locale: Option<NumberLocale>,
+    /// Only write locked accounts
+    #[arg(long)]
+    pub only_locked: bool,
+    /// Leave out balances whose available, held and total are all zero
+    #[arg(long)]
+    pub only_nonzero: bool,
+    /// Only write these clients, as a comma-separated list of IDs and
+    /// FIRST-LAST ranges
+    #[arg(long, value_name = "IDS")]
+    pub output_clients: Option<ClientList>,
+    /// Write only these columns of the accounts output, in this order
+    #[arg(long = "columns", value_name = "COLUMNS")]
+    pub output_columns: Option<AccountColumns>,
+    /// Add each client's deposit, withdrawal, dispute and chargeback counts
+    /// to the accounts output
+    #[arg(long)]
+    pub extended: bool,
+    /// Append every applied transaction, numbered per client, with the
+    /// balances it left to this CSV file
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+    /// Keep a SHA-256 hash chain per client over applied transactions and
+    /// report the digest over all of them in the run stats and snapshot
+    #[arg(long)]
+    pub hash_chain: bool,
+    /// Write an event for every applied state change, with balances before
+    /// and after, as JSON lines to this file
+    #[arg(long, value_name = "PATH")]
+    pub events: Option<PathBuf>,
+    /// Write the accounts, applied transactions, dispute history and
+    /// rejections to this SQLite database at the end of the run
+    #[arg(long, value_name = "PATH")]
+    pub sqlite: Option<PathBuf>,
+    /// Publish the event stream to kafka://BROKERS/TOPIC or
+    /// nats://SERVER/SUBJECT as it is produced
+    #[arg(long, value_name = "URL")]
+    pub publish: Option<PublishTarget>,
+    /// What published messages are keyed by [default: client]
+    #[arg(long, value_name = "client|tx|none")]
+    pub publish_key: Option<PublishKey>,
+    /// POST a JSON notification to this URL whenever a chargeback locks an
+    /// account
+    #[arg(long, value_name = "URL")]
+    pub webhook_url: Option<String>,
+    /// Also notify the webhook of withdrawals of at least this amount
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_cap, requires = "webhook_url")]
+    pub notify_withdrawals_over: Option<Decimal>,
+    /// Keep the balances in the `account_balances` table of this Postgres
+    /// database up to date while the run goes on
+    #[arg(long, value_name = "URL")]
+    pub postgres_url: Option<String>,
+    /// Seconds between Postgres balance syncs [default: 5]
+    #[arg(long, value_name = "N", value_parser = parse_positive, requires = "postgres_url")]
+    pub postgres_interval_secs: Option<usize>,
+    /// Write every balance left below zero, with what the client owes, to
+    /// this CSV file at the end of the run
+    #[arg(long, value_name = "PATH")]
+    pub recovery_report: Option<PathBuf>,
+    /// Also rewrite the recovery report every N seconds while the run goes
+    /// on
+    #[arg(long, value_name = "N", value_parser = parse_positive, requires = "recovery_report")]
+    pub recovery_interval_secs: Option<usize>,
+    /// Client that carries what other clients owe; left out of the recovery
+    /// report
+    #[arg(long, value_name = "CLIENT")]
+    pub suspense_account: Option<ClientId>,
+    /// Write `adjustment` rows moving what each client owes to the suspense
+    /// account to this CSV file
+    #[arg(long, value_name = "PATH", requires = "suspense_account")]
+    pub recovery_adjustments: Option<PathBuf>,
+    /// Save a binary snapshot of all state after processing
+    #[arg(long, value_name = "PATH")]
+    pub save_state: Option<PathBuf>,
+    /// Start from a `--save-state` snapshot, skipping the input rows it
+    /// already covers
+    #[arg(long, value_name = "PATH")]
+    pub resume: Option<PathBuf>,
+    /// Encrypt saved snapshots and decrypt resumed ones with this key; only
+    /// read from the config file or `ENGINE_STATE_KEY`, to keep it out of
+    /// process listings
+    #[arg(skip)]
+    pub state_key: Option<StateKey>,
+    /// Run this command and take the state key from what it prints, such as
+    /// a KMS or secret store client
+    #[arg(long, value_name = "COMMAND")]
+    pub state_key_command: Option<String>,
+    /// Let `--resume` read a plain snapshot although a state key is set, to
+    /// move existing state over to encryption
+    #[arg(long)]
+    pub migrate_plain_state: bool,
+    /// Take hold, release and close rows only from this file, each signed
+    /// with the admin key; needs an admin key
+    #[arg(long, value_name = "PATH")]
+    pub admin_input: Option<PathBuf>,
+    /// Secret admin rows are signed with. Once set, hold, release and close
+    /// rows in the transactions file are refused; only read from the config
+    /// file or `ENGINE_ADMIN_KEY`
+    #[arg(skip)]
+    pub admin_key: Option<AdminKey>,
+    /// Run this command and take the admin key from what it prints
+    #[arg(long, value_name = "COMMAND")]
+    pub admin_key_command: Option<String>,
+    /// Generate recurring deposits and withdrawals from this CSV file as
+    /// the input's timestamps pass their due times
+    #[arg(long, value_name = "PATH")]
+    pub schedule: Option<PathBuf>,
+    /// Compare the final balances against this accounts CSV and exit
+    /// non-zero on any difference
+    #[arg(long, value_name = "PATH")]
+    pub reconcile: Option<PathBuf>,
+    /// Write end-of-run statistics as JSON to this file
+    #[arg(long, value_name = "PATH")]
+    pub stats: Option<PathBuf>,
+    /// Serve Prometheus metrics at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Format of log events on stderr [default: text]
+    #[arg(long, value_name = "text|json")]
+    pub log_format: Option<LogFormat>,
+    /// Periodically log ingestion progress
+    #[arg(long)]
+    pub progress: bool,
+    /// Apply rows strictly in file order on a single task so output is
+    /// identical across runs
+    #[arg(long)]
+    pub deterministic: bool,
+    /// Read the input once to find the transactions disputes refer to, then
+    /// apply it keeping only their records
+    #[arg(long)]
+    pub two_pass: bool,
+    /// What becomes of a transaction's record once it is charged back or
+    /// past --dispute-window-days [default: keep]
+    #[arg(long, value_name = "keep|tombstone|drop")]
+    pub compact_records: Option<CompactPolicy>,
+    /// Read and parse INPUT off a memory map on the blocking pool instead of
+    /// through buffered async reads (needs the `mmap-input` feature)
+    #[arg(long)]
+    pub mmap_input: bool,
+    /// Pause ingestion on SIGUSR1, checkpointing to --save-state once the
+    /// rows already read are applied, and resume it on SIGUSR2
+    #[arg(long)]
+    pub pause_on_signal: bool,
+    /// Reload the limit files, --fx-rates and the config's limits and fraud
+    /// rules on SIGHUP
+    #[arg(long)]
+    pub reload_on_signal: bool,
+    /// Drop, duplicate and delay rows as SPEC says, such as
+    /// `drop=0.01,duplicate=0.02,delay=0.1,max-delay-ms=20,seed=7`; needs a
+    /// build with the chaos feature
+    #[arg(long, value_name = "SPEC", hide = true)]
+    pub chaos: Option<String>,
+}
+
+impl CliOptions {
+    /// Fill in every setting not given on the command line from `config`
+    pub fn apply_config(&mut self, config: EngineConfig) {
+        let output = config.output;
+        self.worker_threads = self.worker_threads.or(config.worker_threads);
+        self.channel_capacity = self.channel_capacity.or(config.channel_capacity);
+        self.shards = self.shards.or(config.shards);
+        self.max_client_tasks = self.max_client_tasks.or(config.max_client_tasks);
+        self.client_idle_secs = self.client_idle_secs.or(config.client_idle_secs);
+        // A slice given on the command line replaces the configured one
+        // whichever form either takes
+        if self.clients.is_none() && self.shard.is_none() {
+            self.clients = config.clients;
+            self.shard = config.shard;
+        }
+        self.coalesce_rows = self.coalesce_rows.or(config.coalesce_rows);
+        self.priority_types = self.priority_types.take().or(config.priority_types);
+        self.max_memory = self.max_memory.or(config.max_memory);
+        self.spill_over = self.spill_over.or(config.spill_over);
+        self.page_accounts_over = self.page_accounts_over.or(config.page_accounts_over);
+        self.bloom_filter_ids = self.bloom_filter_ids.or(config.bloom_filter_ids);
+        self.error_policy = self.error_policy.or(config.error_policy);
+        let parsing = &mut self.parsing;
+        parsing.amount_precision = parsing.amount_precision.or(config.amount_precision);
+        parsing.amount_format = parsing.amount_format.or(config.amount_format);
+        parsing.amount_mode = parsing.amount_mode.or(config.amount_mode);
+        let dialect = &mut self.dialect;
+        dialect.delimiter = dialect.delimiter.or(config.delimiter);
+        dialect.no_headers |= config.no_headers.unwrap_or(false);
+        if let Some(columns) = config.columns {
+            let flags = std::mem::take(&mut dialect.columns);
+            dialect.columns = columns.into_iter().chain(flags).collect();
+        }
+        if let Some(aliases) = config.type_aliases {
+            let flags = std::mem::take(&mut self.parsing.type_aliases);
+            self.parsing.type_aliases = aliases.into_iter().chain(flags).collect();
+        }
+        let rules = &mut self.rules;
+        rules.require_monotonic = rules.require_monotonic.or(config.require_monotonic);
+        rules.dispute_window = rules.dispute_window.or(config
+            .dispute_window_days
+            .map(|days| Duration::days(days.into())));
+        rules.duplicate_tx = rules.duplicate_tx.or(config.duplicate_tx);
+        rules.fx_rates = rules.fx_rates.take().or(config.fx_rates);
+        rules.close_policy = rules.close_policy.or(config.close_policy);
+        rules.client_mismatch = rules.client_mismatch.or(config.client_mismatch);
+        rules.open_on_reference |= config.open_on_reference.unwrap_or(false);
+        rules.dispute_shortfall = rules.dispute_shortfall.or(config.dispute_shortfall);
+        rules.locked_allows = rules.locked_allows.or(config.locked_allows);
+        rules.rounding = rules.rounding.or(config.rounding);
+        rules.precision = rules.precision.or(config.precision);
+        rules.idempotency_window = rules.idempotency_window.or(config
+            .idempotency_window_hours
+            .map(|hours| Duration::hours(hours.into())));
+        let limits = &mut rules.limits;
+        limits.max_tx_amount = limits.max_tx_amount.or(config.max_tx_amount);
+        limits.daily_deposit_limit = limits.daily_deposit_limit.or(config.daily_deposit_limit);
+        limits.daily_withdrawal_limit = limits
+            .daily_withdrawal_limit
+            .or(config.daily_withdrawal_limit);
+        limits.rate_limit = limits.rate_limit.or(config.rate_limit);
+        limits.total_deposit_limit = limits.total_deposit_limit.or(config.total_deposit_limit);
+        limits.client_limits = limits.client_limits.take().or(config.client_limits);
+        limits.segment_limits = limits.segment_limits.take().or(config.segment_limits);
+        limits.account_meta = limits.account_meta.take().or(config.account_meta);
+        self.verify |= config.verify.unwrap_or(false);
+        self.deterministic |= config.deterministic.unwrap_or(false);
+        self.two_pass |= config.two_pass.unwrap_or(false);
+        self.compact_records = self.compact_records.or(config.compact_records);
+        self.mmap_input |= config.mmap_input.unwrap_or(false);
+        self.pause_on_signal |= config.pause_on_signal.unwrap_or(false);
+        self.reload_on_signal |= config.reload_on_signal.unwrap_or(false);
+        self.resume = self.resume.take().or(config.resume);
+        self.state_key = self.state_key.take().or(config.state_key);
+        self.state_key_command = self.state_key_command.take().or(config.state_key_command);
+        self.migrate_plain_state |= config.migrate_plain_state.unwrap_or(false);
+        self.admin_input = self.admin_input.take().or(config.admin_input);
+        self.admin_key = self.admin_key.take().or(config.admin_key);
+        self.admin_key_command = self.admin_key_command.take().or(config.admin_key_command);
+        self.schedule = self.schedule.take().or(config.schedule);
+        self.reconcile = self.reconcile.take().or(config.reconcile);
+        self.suspense_account = self.suspense_account.or(config.suspense_account);
+        self.tenant_dir = self.tenant_dir.take().or(output.tenant_dir);
+        self.rejects = self.rejects.take().or(output.rejects);
+        self.ledger = self.ledger.take().or(output.ledger);
+        self.save_state = self.save_state.take().or(output.save_state);
+        self.stats = self.stats.take().or(output.stats);
+        self.metrics_addr = self.metrics_addr.or(output.metrics_addr);
+        self.log_format = self.log_format.or(output.log_format);
+        self.progress |= output.progress.unwrap_or(false);
+        self.fraud_report = self.fraud_report.take().or(output.fraud_report);
+        self.closed_accounts = self.closed_accounts.or(output.closed_accounts);
+        self.fixed_decimals |= output.fixed_decimals.unwrap_or(false);
+        self.number_locale = self.number_locale.or(output.number_locale);
+        self.only_locked |= output.only_locked.unwrap_or(false);
+        self.only_nonzero |= output.only_nonzero.unwrap_or(false);
+        self.output_clients = self.output_clients.take().or(output.clients);
+        self.output_columns = self.output_columns.take().or(output.columns);
+        self.extended |= output.extended.unwrap_or(false);
+        self.audit_log = self.audit_log.take().or(output.audit_log);
+        self.hash_chain |= output.hash_chain.unwrap_or(false);
+        self.events = self.events.take().or(output.events);
+        self.sqlite = self.sqlite.take().or(output.sqlite);
+        self.publish = self.publish.take().or(output.publish);
+        self.publish_key = self.publish_key.or(output.publish_key);
+        self.webhook_url = self.webhook_url.take().or(output.webhook_url);
+        self.notify_withdrawals_over = self
+            .notify_withdrawals_over
+            .or(output.notify_withdrawals_over);
+        self.postgres_url = self.postgres_url.take().or(output.postgres_url);
+        self.postgres_interval_secs = self
+            .postgres_interval_secs
+            .or(output.postgres_interval_secs);
+        self.recovery_report = self.recovery_report.take().or(output.recovery_report);
+        self.recovery_interval_secs = self
+            .recovery_interval_secs
+            .or(output.recovery_interval_secs);
+        self.recovery_adjustments = self
+            .recovery_adjustments
+            .take()
+            .or(output.recovery_adjustments);
+        self.fraud.config = config.fraud;
+    }
+}
+
+/// Options for the `inspect` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct InspectOptions {
+    /// Snapshot written by `process --save-state`
+    #[arg(long, value_name = "PATH")]
+    pub state: PathBuf,
+    /// Client to report on
+    #[arg(long)]
+    pub client: ClientId,
+    /// Take the key of an encrypted snapshot from what this command prints;
+    /// `ENGINE_STATE_KEY` takes precedence
+    #[arg(long, value_name = "COMMAND")]
+    pub state_key_command: Option<String>,
+}
+
+/// Options for the `diff` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct DiffOptions {
+    /// Accounts CSV from the earlier run
+    pub before: PathBuf,
+    /// Accounts CSV from the later run
+    pub after: PathBuf,
+    /// Output format
+    #[arg(long, value_name = "csv|json", default_value_t)]
+    pub format: OutputFormat,
+}
+
+/// Options for the `merge` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct MergeOptions {
+    /// Accounts CSV files to combine
+    #[arg(required = true, num_args = 2..)]
+    pub inputs: Vec<PathBuf>,
+    /// What to do with a client listed in more than one file
+    #[arg(long, value_name = "reject|sum", default_value_t)]
+    pub duplicates: MergeDuplicates,
+}
+
+/// Options for the `test-fixtures` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct FixturesOptions {
+    /// Directory searched for fixtures, each a directory holding
+    /// `input.csv`, `expected.csv` and optionally `config.toml`
+    pub dir: PathBuf,
+}
+
+/// Options for the `statement` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct StatementOptions {
+    /// Transactions CSV file
+    pub input: PathBuf,
+    #[command(flatten)]
+    pub dialect: DialectOptions,
+    /// Client to report on (required unless --all)
+    #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+    pub client: Option<ClientId>,
+    /// Report on every client
+    #[arg(long)]
+    pub all: bool,
+    /// Statement format
+    #[arg(long, value_name = "csv|json", default_value_t)]
+    pub format: OutputFormat,
+    #[command(flatten)]
+    pub parsing: InputOptions,
+    #[command(flatten)]
+    pub rules: RuleOptions,
+    #[command(flatten)]
+    pub fraud: FraudOptions,
+    /// Format of log events on stderr
+    #[arg(long, value_name = "text|json", default_value_t)]
+    pub log_format: LogFormat,
+}
+
+/// Options for the `report` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct ReportOptions {
+    /// Transactions CSV file
+    pub input: PathBuf,
+    #[command(flatten)]
+    pub dialect: DialectOptions,
+    /// How many clients and disputes to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    #[command(flatten)]
+    pub parsing: InputOptions,
+    #[command(flatten)]
+    pub rules: RuleOptions,
+    #[command(flatten)]
+    pub fraud: FraudOptions,
+    /// Format of log events on stderr
+    #[arg(long, value_name = "text|json", default_value_t)]
+    pub log_format: LogFormat,
+}
+
+/// Options for the `validate` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct ValidateOptions {
+    /// Transactions CSV file
+    pub input: PathBuf,
+    #[command(flatten)]
+    pub dialect: DialectOptions,
+    #[command(flatten)]
+    pub parsing: InputOptions,
+    #[command(flatten)]
+    pub rules: RuleOptions,
+    #[command(flatten)]
+    pub fraud: FraudOptions,
+    /// Print the report as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+    /// Format of log events on stderr
+    #[arg(long, value_name = "text|json", default_value_t)]
+    pub log_format: LogFormat,
+}
+
+/// Options for the `replay` subcommand
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+#[command(group = clap::ArgGroup::new("breakpoint").required(true))]
+pub struct ReplayOptions {
+    /// Transactions CSV file
+    pub input: PathBuf,
+    #[command(flatten)]
+    pub dialect: DialectOptions,
+    /// Stop after the first row with this transaction ID
+    #[arg(long, value_name = "TX", group = "breakpoint")]
+    pub until_tx: Option<TxId>,
+    /// Stop after this input line (the header is line 1)
+    #[arg(long, value_name = "N", group = "breakpoint")]
+    pub until_line: Option<u64>,
+    /// Also save a binary snapshot of the state at the breakpoint
+    #[arg(long, value_name = "PATH")]
+    pub save_state: Option<PathBuf>,
+    /// Encrypt the snapshot with the key this command prints;
+    /// `ENGINE_STATE_KEY` takes precedence
+    #[arg(long, value_name = "COMMAND")]
+    pub state_key_command: Option<String>,
+    #[command(flatten)]
+    pub parsing: InputOptions,
+    #[command(flatten)]
+    pub rules: RuleOptions,
+    #[command(flatten)]
+    pub fraud: FraudOptions,
+    /// Format of log events on stderr
+    #[arg(long, value_name = "text|json", default_value_t)]
+    pub log_format: LogFormat,
+}
+
+/// Options for the `generate` subcommand
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct GenerateOptions {
+    /// Number of clients, with IDs from 1
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub clients: ClientId,
+    /// Number of rows to write
+    #[arg(long, value_name = "N", default_value_t = 100_000)]
+    pub transactions: u64,
+    /// Share of rows disputing one of the client's recent deposits
+    #[arg(long, value_name = "RATE", default_value_t = 0.01, value_parser = parse_rate)]
+    pub dispute_rate: f64,
+    /// Share of non-dispute rows that are withdrawals
+    #[arg(long, value_name = "RATE", default_value_t = 0.3, value_parser = parse_rate)]
+    pub withdrawal_rate: f64,
+    /// Share of disputes settled by a chargeback rather than a resolve
+    #[arg(long, value_name = "RATE", default_value_t = 0.1, value_parser = parse_rate)]
+    pub chargeback_rate: f64,
+    /// Zipf exponent of the client distribution (0 for uniform)
+    #[arg(long, value_name = "S", default_value_t = 1.0, value_parser = parse_skew)]
+    pub zipf: f64,
+    /// Seed for the generator; the same options and seed give the same file
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl GenerateOptions {
+    pub fn spec(&self) -> WorkloadSpec {
+        WorkloadSpec {
+            clients: self.clients,
+            transactions: self.transactions,
+            dispute_rate: self.dispute_rate,
+            withdrawal_rate: self.withdrawal_rate,
+            chargeback_rate: self.chargeback_rate,
+            zipf: self.zipf,
+            seed: self.seed,
+        }
+    }
+}
+
+/// Input file layout shared by `process`, `statement`, `validate` and
+/// `replay`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct DialectOptions {
+    /// Field separator, such as `;` or `\t` for tab-separated files [default: ,]
+    #[arg(long, value_name = "CHAR")]
+    pub delimiter: Option<Delimiter>,
+    /// The file has no header row; columns are read in the order
+    /// `type,client,tx,amount,timestamp,currency,to_currency,idempotency_key`
+    #[arg(long)]
+    pub no_headers: bool,
+    /// Read a column from a differently named header, such as
+    /// `client=customer_id`; repeatable or comma-separated
+    #[arg(long = "column", value_name = "COLUMN=HEADER", value_delimiter = ',')]
+    pub columns: Vec<ColumnAlias>,
+}
+
+impl DialectOptions {
+    /// The layout these options describe
+    pub fn dialect(&self) -> CsvDialect {
+        CsvDialect {
+            delimiter: self.delimiter.unwrap_or_default(),
+            has_headers: !self.no_headers,
+            columns: self.columns.iter().cloned().collect(),
+        }
+    }
+}
+
+/// How fields are read, shared by `process`, `statement`, `report`,
+/// `validate` and `replay`.
+///
+/// Settings a `process` run can also take from its config file are
+/// optional; unset ones fall back to the file and then to their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct InputOptions {
+    /// How amounts with more than 4 decimal places are handled [default: truncate]
+    #[arg(long, value_name = "reject|truncate|round")]
+    pub amount_precision: Option<AmountPrecision>,
+    /// Whether amounts may carry thousands separators, quotes or a currency
+    /// symbol [default: strict]
+    #[arg(long, value_name = "strict|lenient")]
+    pub amount_format: Option<AmountFormat>,
+    /// Whether amounts are decimal numbers or whole numbers of
+    /// ten-thousandths [default: decimal]
+    #[arg(long, value_name = "decimal|minor-units")]
+    pub amount_mode: Option<AmountMode>,
+    /// Accept another name for a transaction type, such as `withdraw=withdrawal`;
+    /// repeatable or comma-separated
+    #[arg(long = "type-alias", value_name = "ALIAS=TYPE", value_delimiter = ',')]
+    pub type_aliases: Vec<TypeAlias>,
+}
+
+impl InputOptions {
+    /// How rows are parsed under these options
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            amount_precision: self.amount_precision.unwrap_or_default(),
+            amount_format: self.amount_format.unwrap_or_default(),
+            amount_mode: self.amount_mode.unwrap_or_default(),
+            type_aliases: Arc::new(self.type_aliases.iter().cloned().collect()),
+        }
+    }
+}
+
+/// Business rules shared by `process`, `statement`, `report`, `validate` and
+/// `replay`.
+///
+/// Settings a `process` run can also take from its config file are
+/// optional; unset ones fall back to the file and then to their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct RuleOptions {
+    /// Check that each client's timestamps never go backwards [default: off]
+    #[arg(long, value_name = "off|flag|reject")]
+    pub require_monotonic: Option<MonotonicPolicy>,
+    /// Reject disputes filed more than N days after the deposit
+    #[arg(long = "dispute-window-days", value_name = "N", value_parser = parse_days)]
+    pub dispute_window: Option<Duration>,
+    /// Whether transaction IDs are unique across all clients or per client
+    /// [default: global]
+    #[arg(long, value_name = "global|per-client")]
+    pub duplicate_tx: Option<DuplicateTxPolicy>,
+    /// Exchange rates (`from,to,rate` CSV) for `convert` transactions
+    #[arg(long, value_name = "PATH")]
+    pub fx_rates: Option<PathBuf>,
+    /// What `close` does with a remaining balance [default: require-empty]
+    #[arg(long, value_name = "require-empty|payout")]
+    pub close_policy: Option<ClosePolicy>,
+    /// Whether a dispute, resolve or chargeback of another client's
+    /// transaction also puts the disputing account on review hold
+    /// [default: reject]
+    #[arg(long, value_name = "reject|review")]
+    pub client_mismatch: Option<ClientMismatchPolicy>,
+    /// Open an account for the client of a rejected dispute, resolve or
+    /// chargeback, as earlier versions did
+    #[arg(long)]
+    pub open_on_reference: bool,
+    /// What disputing more than the account has available does
+    /// [default: allow-negative]
+    #[arg(long, value_name = "allow-negative|cap|review")]
+    pub dispute_shortfall: Option<DisputeShortfallPolicy>,
+    /// Dispute-flow rows frozen and chargeback-locked accounts still take,
+    /// as a comma-separated list of dispute, resolve and chargeback, or none
+    /// [default: dispute,resolve,chargeback]
+    #[arg(long, value_name = "TYPES")]
+    pub locked_allows: Option<LockedTypes>,
+    /// How balances are rounded after every change [default: to-zero]
+    #[arg(long, value_name = "to-zero|half-even|half-up")]
+    pub rounding: Option<RoundingMode>,
+    /// Decimal places kept in balances, at most 4 [default: 4]
+    #[arg(long, value_name = "N")]
+    pub precision: Option<Precision>,
+    /// How long an idempotency key stays claimed; forever if unset
+    #[arg(long = "idempotency-window-hours", value_name = "N", value_parser = parse_hours)]
+    pub idempotency_window: Option<Duration>,
+    #[command(flatten)]
+    pub limits: LimitOptions,
+}
+
+impl RuleOptions {
+    /// How balances are rounded after every change
+    pub fn rounding(&self) -> Rounding {
+        Rounding {
+            mode: self.rounding.unwrap_or_default(),
+            precision: self.precision.unwrap_or_default(),
+        }
+    }
+
+    /// The rules these options set, converting with `fx_rates`. Spilling,
+    /// compaction and the other run-wide stores are left for the caller to
+    /// add.
+    pub fn rules(&self, fx_rates: Option<Arc<FxRates>>) -> Rules {
+        Rules {
+            dispute_window: self.dispute_window,
+            duplicate_tx: self.duplicate_tx.unwrap_or_default(),
+            fx_rates,
+            close_policy: self.close_policy.unwrap_or_default(),
+            client_mismatch: self.client_mismatch.unwrap_or_default(),
+            open_on_reference: self.open_on_reference,
+            dispute_shortfall: self.dispute_shortfall.unwrap_or_default(),
+            locked_allows: self.locked_allows.unwrap_or_default(),
+            rounding: self.rounding(),
+            ..Rules::default()
+        }
+    }
+}
+
+/// Risk limits shared by `process`, `statement`, `report`, `validate` and `replay`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct LimitOptions {
+    /// Reject deposits, withdrawals and conversions larger than this
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_cap)]
+    pub max_tx_amount: Option<Decimal>,
+    /// Reject deposits once a client's total for the day would exceed this
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_cap)]
+    pub daily_deposit_limit: Option<Decimal>,
+    /// Reject withdrawals once a client's total for the day would exceed this
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_cap)]
+    pub daily_withdrawal_limit: Option<Decimal>,
+    /// Reject deposits once a client's total for the run would exceed this
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_cap)]
+    pub total_deposit_limit: Option<Decimal>,
+    /// Reject a client's rows beyond this many per interval of their
+    /// timestamps, such as `100/1m`
+    #[arg(long, value_name = "ROWS/INTERVAL")]
+    pub rate_limit: Option<RateLimit>,
+    /// Per-client overrides of the limits above, as a CSV file
+    #[arg(long, value_name = "PATH")]
+    pub client_limits: Option<PathBuf>,
+    /// Overrides of the limits above for account segments such as
+    /// `unverified` or `tier=gold`, as a CSV file
+    #[arg(long, value_name = "PATH")]
+    pub segment_limits: Option<PathBuf>,
+    /// Tier, KYC status, country and opening time of each account, as a CSV
+    /// file
+    #[arg(long, value_name = "PATH")]
+    pub account_meta: Option<PathBuf>,
+}
+
+/// Fraud rules shared by `process`, `statement`, `report`, `validate` and `replay`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct FraudOptions {
+    /// Run the fraud rules in the `[fraud]` table of this TOML file, such
+    /// as a config file, instead of those of `--config`
+    #[arg(long, value_name = "PATH")]
+    pub fraud_rules: Option<PathBuf>,
+    /// Fraud rules from the config file's `[fraud]` table
+    #[arg(skip)]
+    pub config: FraudRules,
+}
+
+impl FraudOptions {
+    /// The rules to run, reading `--fraud-rules` if it was given
+    pub fn load(&self) -> Result<FraudRules, EngineError> {
+        match &self.fraud_rules {
+            Some(path) => FraudRules::load(path),
+            None => Ok(self.config.clone()),
+        }
+    }
+}
+
+/// Parse a count that must be at least one
+fn parse_positive(raw: &str) -> Result<usize, String> {
+    match raw.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("invalid number '{raw}'")),
+    }
+}
+
+/// Parse a share between 0 and 1
+fn parse_rate(raw: &str) -> Result<f64, String> {
+    match raw.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        Ok(_) => Err("must be between 0 and 1".to_string()),
+        Err(_) => Err(format!("invalid number '{raw}'")),
+    }
+}
+
+/// Parse a Zipf exponent, which must not be negative
+fn parse_skew(raw: &str) -> Result<f64, String> {
+    match raw.parse::<f64>() {
+        Ok(skew) if skew >= 0.0 => Ok(skew),
+        Ok(_) => Err("must not be negative".to_string()),
+        Err(_) => Err(format!("invalid number '{raw}'")),
+    }
+}
+
+/// Parse a limit, which must be a positive amount
+fn parse_cap(raw: &str) -> Result<Decimal, String> {
+    match raw.parse::<Decimal>() {
+        Ok(cap) if cap > Decimal::ZERO => Ok(cap),
+        Ok(_) => Err("must be positive".to_string()),
+        Err(_) => Err(format!("invalid amount '{raw}'")),
+    }
+}
+
+/// Parse a whole number of days into a duration
+fn parse_days(raw: &str) -> Result<Duration, String> {
+    raw.parse::<u32>()
+        .map(|days| Duration::days(days.into()))
+        .map_err(|_| format!("invalid number of days '{raw}'"))
+}
+
+/// Parse a whole number of hours into a duration
+fn parse_hours(raw: &str) -> Result<Duration, String> {
+    raw.parse::<u32>()
+        .map(|hours| Duration::hours(hours.into()))
+        .map_err(|_| format!("invalid number of hours '{raw}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TypeAliases;
+    use crate::models::TransactionType;
+
+    fn command(args: &[&str]) -> Result<Command, clap::Error> {
+        Command::try_parse_from(std::iter::once("engine").chain(args.iter().copied()))
+    }
+
+    fn parse(args: &[&str]) -> Result<CliOptions, clap::Error> {
+        match command(args)? {
+            Command::Process(options) => Ok(*options),
+            other => panic!("expected a processing run, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_input_and_rejects() {
+        let options = parse(&["transactions.csv", "--rejects", "rejected.csv"]).unwrap();
+        assert_eq!(options.input, Some(PathBuf::from("transactions.csv")));
+        assert_eq!(options.rejects, Some(PathBuf::from("rejected.csv")));
+        assert_eq!(options.error_policy, None);
+        assert_eq!(
+            parse(&["process", "transactions.csv"]).unwrap().input,
+            options.input
+        );
+    }
+
+    #[test]
+    fn test_parse_tenants() {
+        let options = parse(&[
+            "--tenant",
+            "acme=acme.csv",
+            "--tenant",
+            "beta=beta.csv",
+            "--tenant-dir",
+            "out",
+        ])
+        .unwrap();
+        assert_eq!(options.input, None);
+        assert_eq!(
+            options
+                .tenants
+                .iter()
+                .map(|t| t.name.as_str())
+                .collect::<Vec<_>>(),
+            ["acme", "beta"]
+        );
+        assert_eq!(options.tenant_dir, Some(PathBuf::from("out")));
+        assert!(parse(&["transactions.csv", "--tenant", "acme=acme.csv"]).is_err());
+        assert!(parse(&["--tenant", "acme"]).is_err());
+        assert!(parse(&["--rejects", "rejected.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_policy() {
+        let options = parse(&["--error-policy", "strict", "transactions.csv"]).unwrap();
+        assert_eq!(options.error_policy, Some(ErrorPolicy::Strict));
+        assert!(parse(&["transactions.csv", "--error-policy", "loose"]).is_err());
+    }
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let mut options = parse(&[
+            "transactions.csv",
+            "--error-policy",
+            "strict",
+            "--type-alias",
+            "withdraw=withdrawal",
+            "--column",
+            "tx=txn_id",
+            "--shard",
+            "3/8",
+        ])
+        .unwrap();
+        options.apply_config(EngineConfig {
+            channel_capacity: Some(500),
+            max_client_tasks: Some(64),
+            coalesce_rows: Some(32),
+            error_policy: Some(ErrorPolicy::Collect),
+            verify: Some(true),
+            amount_format: Some(AmountFormat::Lenient),
+            type_aliases: Some("withdraw=deposit,charge_back=chargeback".parse().unwrap()),
+            delimiter: Some(Delimiter(b'\t')),
+            columns: Some("client=customer_id,tx=id".parse().unwrap()),
+            clients: Some("0-99".parse().unwrap()),
+            schedule: Some(PathBuf::from("recurring.csv")),
+            total_deposit_limit: Some(Decimal::from(1000)),
+            account_meta: Some(PathBuf::from("accounts_meta.csv")),
+            ..Default::default()
+        });
+        assert_eq!(options.error_policy, Some(ErrorPolicy::Strict));
+        assert_eq!(options.channel_capacity, Some(500));
+        assert_eq!(options.max_client_tasks, Some(64));
+        assert_eq!(options.coalesce_rows, Some(32));
+        assert!(options.verify);
+        assert_eq!(options.schedule, Some(PathBuf::from("recurring.csv")));
+        assert_eq!(
+            options.rules.limits.total_deposit_limit,
+            Some(Decimal::from(1000))
+        );
+        assert_eq!(
+            options.rules.limits.account_meta,
+            Some(PathBuf::from("accounts_meta.csv"))
+        );
+        assert_eq!(
+            (options.clients, options.shard),
+            (None, Some(ClientSlice::Shard { index: 3, count: 8 }))
+        );
+        assert!(parse(&["transactions.csv", "--clients", "0-9", "--shard", "1/2"]).is_err());
+        assert!(parse(&["transactions.csv", "--clients", "1/2"]).is_err());
+        assert_eq!(options.parsing.amount_format, Some(AmountFormat::Lenient));
+        let aliases: TypeAliases = options.parsing.type_aliases.into_iter().collect();
+        assert_eq!(aliases.get("withdraw"), Some(&TransactionType::Withdrawal));
+        assert_eq!(
+            aliases.get("charge_back"),
+            Some(&TransactionType::Chargeback)
+        );
+        assert!(parse(&["transactions.csv", "--type-alias", "withdraw"]).is_err());
+        let dialect = options.dialect.dialect();
+        assert_eq!(dialect.delimiter, Delimiter(b'\t'));
+        assert!(dialect.has_headers);
+        assert_eq!(
+            (
+                dialect.columns.header("client"),
+                dialect.columns.header("tx")
+            ),
+            ("customer_id", "txn_id")
+        );
+        assert!(parse(&["transactions.csv", "--delimiter", ";;"]).is_err());
+        assert!(parse(&["transactions.csv", "--amount-format", "loose"]).is_err());
+        assert!(parse(&["transactions.csv", "--channel-capacity", "0"]).is_err());
+        assert!(parse(&["transactions.csv", "--max-client-tasks", "0"]).is_err());
+        assert!(parse(&["transactions.csv", "--postgres-interval-secs", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        let options = parse(&["transactions.csv", "--log-format", "json"]).unwrap();
+        assert_eq!(options.log_format, Some(LogFormat::Json));
+        assert!(parse(&["transactions.csv", "--log-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_metrics_addr() {
+        let options = parse(&["transactions.csv", "--metrics-addr", "127.0.0.1:9000"]).unwrap();
+        assert_eq!(
+            options.metrics_addr,
+            Some("127.0.0.1:9000".parse().unwrap())
+        );
+        assert!(parse(&["transactions.csv", "--metrics-addr", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_dispute_window() {
+        let options = parse(&["transactions.csv", "--dispute-window-days", "90"]).unwrap();
+        assert_eq!(options.rules.dispute_window, Some(Duration::days(90)));
+        assert!(parse(&["transactions.csv", "--dispute-window-days", "-1"]).is_err());
+        let options = parse(&["transactions.csv", "--idempotency-window-hours", "24"]).unwrap();
+        assert_eq!(options.rules.idempotency_window, Some(Duration::hours(24)));
+    }
+
+    #[test]
+    fn test_parse_duplicate_tx_policy() {
+        let options = parse(&["transactions.csv", "--duplicate-tx", "per-client"]).unwrap();
+        assert_eq!(
+            options.rules.duplicate_tx,
+            Some(DuplicateTxPolicy::PerClient)
+        );
+        assert!(parse(&["transactions.csv", "--duplicate-tx", "never"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_risk_limits() {
+        let options = parse(&["transactions.csv", "--daily-deposit-limit", "1000.50"]).unwrap();
+        assert_eq!(
+            options.rules.limits.daily_deposit_limit,
+            Some(Decimal::new(100050, 2))
+        );
+        assert_eq!(options.rules.limits.max_tx_amount, None);
+        assert!(parse(&["transactions.csv", "--max-tx-amount", "0"]).is_err());
+        assert!(command(&["validate", "partner.csv", "--max-tx-amount", "ten"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_inspect_command() {
+        assert_eq!(
+            command(&["inspect", "--state", "state.bin", "--client", "42"]).unwrap(),
+            Command::Inspect(InspectOptions {
+                state: PathBuf::from("state.bin"),
+                client: 42,
+                state_key_command: None,
+            })
+        );
+        assert!(command(&["inspect", "--state", "state.bin"]).is_err());
+
+        let options = parse(&["transactions.csv", "--save-state", "state.bin"]).unwrap();
+        assert_eq!(options.save_state, Some(PathBuf::from("state.bin")));
+        let options = parse(&["transactions.csv", "--resume", "state.bin"]).unwrap();
+        assert_eq!(options.resume, Some(PathBuf::from("state.bin")));
+        let options = parse(&["transactions.csv", "--reconcile", "expected.csv"]).unwrap();
+        assert_eq!(options.reconcile, Some(PathBuf::from("expected.csv")));
+    }
+
+    #[test]
+    fn test_parse_statement_command() {
+        assert_eq!(
+            command(&["statement", "transactions.csv", "--all", "--format", "json"]).unwrap(),
+            Command::Statement(StatementOptions {
+                input: PathBuf::from("transactions.csv"),
+                dialect: DialectOptions::default(),
+                client: None,
+                all: true,
+                format: OutputFormat::Json,
+                parsing: InputOptions::default(),
+                rules: RuleOptions::default(),
+                fraud: FraudOptions::default(),
+                log_format: LogFormat::Text,
+            })
+        );
+        assert!(command(&["statement", "transactions.csv"]).is_err());
+        assert!(command(&["statement", "transactions.csv", "--all", "--client", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_report_command() {
+        assert_eq!(
+            command(&["report", "transactions.csv", "--top", "20", "--json"]).unwrap(),
+            Command::Report(ReportOptions {
+                input: PathBuf::from("transactions.csv"),
+                dialect: DialectOptions::default(),
+                top: 20,
+                json: true,
+                parsing: InputOptions::default(),
+                rules: RuleOptions::default(),
+                fraud: FraudOptions::default(),
+                log_format: LogFormat::Text,
+            })
+        );
+        match command(&["report", "transactions.csv"]).unwrap() {
+            Command::Report(options) => assert_eq!(options.top, 10),
+            other => panic!("unexpected command {other:?}"),
+        }
+        match command(&["report", "transactions.csv", "--amount-mode", "minor-units"]).unwrap() {
+            Command::Report(options) => {
+                let parse_options = options.parsing.parse_options();
+                assert_eq!(parse_options.amount_mode, AmountMode::MinorUnits);
+                assert_eq!(parse_options.amount_precision, AmountPrecision::Truncate);
+            }
+            other => panic!("unexpected command {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_diff_command() {
+        assert_eq!(
+            command(&["diff", "monday.csv", "tuesday.csv"]).unwrap(),
+            Command::Diff(DiffOptions {
+                before: PathBuf::from("monday.csv"),
+                after: PathBuf::from("tuesday.csv"),
+                format: OutputFormat::Csv,
+            })
+        );
+        assert!(command(&["diff", "monday.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_merge_command() {
+        assert_eq!(
+            command(&["merge", "a.csv", "b.csv", "c.csv", "--duplicates", "sum"]).unwrap(),
+            Command::Merge(MergeOptions {
+                inputs: ["a.csv", "b.csv", "c.csv"].map(PathBuf::from).to_vec(),
+                duplicates: MergeDuplicates::Sum,
+            })
+        );
+        assert!(command(&["merge", "a.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_test_fixtures_command() {
+        assert_eq!(
+            command(&["test-fixtures", "tests/fixtures/"]).unwrap(),
+            Command::TestFixtures(FixturesOptions {
+                dir: PathBuf::from("tests/fixtures/"),
+            })
+        );
+        assert!(command(&["test-fixtures"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_validate_command() {
+        match command(&["validate", "partner.csv", "--dispute-window-days", "30"]).unwrap() {
+            Command::Validate(options) => {
+                assert_eq!(options.input, PathBuf::from("partner.csv"));
+                assert_eq!(options.rules.dispute_window, Some(Duration::days(30)));
+                assert!(!options.json);
+            }
+            other => panic!("expected validate, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_rule_options_are_shared_by_subcommands() {
+        let args = ["--close-policy", "payout", "--rounding", "half-even"];
+        let process = parse(&[&["transactions.csv"][..], &args].concat()).unwrap();
+        let rules = match command(
+            &[
+                &["replay", "transactions.csv", "--until-tx", "9"][..],
+                &args,
+            ]
+            .concat(),
+        )
+        .unwrap()
+        {
+            Command::Replay(options) => options.rules,
+            other => panic!("expected replay, got {other:?}"),
+        };
+        assert_eq!(rules, process.rules);
+        let built = rules.rules(None);
+        assert_eq!(built.close_policy, ClosePolicy::Payout);
+        assert_eq!(built.rounding.mode, RoundingMode::HalfEven);
+        assert_eq!(built.duplicate_tx, DuplicateTxPolicy::Global);
+    }
+
+    #[test]
+    fn test_parse_replay_breakpoint() {
+        match command(&["replay", "transactions.csv", "--until-tx", "12345"]).unwrap() {
+            Command::Replay(options) => {
+                assert_eq!(options.until_tx, Some(12345));
+                assert_eq!(options.until_line, None);
+            }
+            other => panic!("expected replay, got {other:?}"),
+        }
+        assert!(command(&["replay", "transactions.csv"]).is_err());
+        assert!(
+            command(&[
+                "replay",
+                "transactions.csv",
+                "--until-tx",
+                "1",
+                "--until-line",
+                "2"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_requires_input() {
+        assert!(command(&[]).is_err());
+        assert!(parse(&["transactions.csv", "--rejects"]).is_err());
+    }
+}
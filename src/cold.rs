@@ -0,0 +1,247 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::{Mutex, RwLock};
+
+use crate::error::EngineError;
+use crate::models::{Account, AccountsMap, ClientId};
+
+/// Where one paged-out account sits in the page file
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: usize,
+    len: usize,
+}
+
+/// Accounts of inactive clients moved out of memory into a temporary file.
+///
+/// Activity is counted in rounds: an account whose client had no row since
+/// the last `start_round` can be paged out, and `touch` brings it back
+/// before the client's next row is applied. Like the spill file, the page
+/// file is only appended to and read through a memory map.
+#[derive(Debug)]
+pub struct ColdAccounts {
+    /// Deleted as soon as it is created, so it disappears with the process
+    file: Mutex<File>,
+    /// Map of everything written so far, replaced after every page-out
+    mapped: RwLock<Option<Mmap>>,
+    index: DashMap<ClientId, Slot>,
+    /// Clients with a row this round, and how many of their rows are being
+    /// applied right now
+    active: DashMap<ClientId, usize>,
+}
+
+/// Keeps a client's account in memory while a row of it is applied
+#[derive(Debug)]
+pub struct Touched<'a> {
+    cold: &'a ColdAccounts,
+    client: ClientId,
+}
+
+impl Drop for Touched<'_> {
+    fn drop(&mut self) {
+        if let Some(mut rows) = self.cold.active.get_mut(&self.client) {
+            *rows -= 1;
+        }
+    }
+}
+
+impl ColdAccounts {
+    /// Create an empty store backed by a file in the system temp directory
+    pub fn create() -> Result<Self, EngineError> {
+        Ok(ColdAccounts {
+            file: Mutex::new(tempfile::tempfile()?),
+            mapped: RwLock::new(None),
+            index: DashMap::new(),
+            active: DashMap::new(),
+        })
+    }
+
+    /// Accounts currently paged out
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Whether the client's account is on disk
+    pub fn contains(&self, client: ClientId) -> bool {
+        self.index.contains_key(&client)
+    }
+
+    /// Mark the client active and move its account back into `accounts` if
+    /// it was paged out. The account is not paged out again while the
+    /// returned guard lives.
+    pub fn touch<'a>(&'a self, client: ClientId, accounts: &AccountsMap) -> Touched<'a> {
+        // Held while the account comes back, so a page-out cannot pass it
+        let mut rows = self.active.entry(client).or_insert(0);
+        *rows += 1;
+        if let Some((_, slot)) = self.index.remove(&client) {
+            accounts.insert(client, self.read(slot));
+        }
+        drop(rows);
+        Touched { cold: self, client }
+    }
+
+    /// Move the account of every client without a row this round out of
+    /// `maps`. An account that changes while it is being written stays in
+    /// memory. Returns how many accounts were moved.
+    pub fn page_out(&self, maps: &[&AccountsMap]) -> Result<usize, EngineError> {
+        // Keys are gathered before `active` is looked at: `touch` locks the
+        // two maps the other way round
+        let mut inactive: Vec<(usize, ClientId)> = maps
+            .iter()
+            .enumerate()
+            .flat_map(|(map, accounts)| accounts.iter().map(move |entry| (map, *entry.key())))
+            .collect();
+        inactive.retain(|(_, client)| !self.active.contains_key(client));
+        if inactive.is_empty() {
+            return Ok(0);
+        }
+
+        let mut file = self.file.lock().expect("page file lock poisoned");
+        let mut offset = file.seek(SeekFrom::End(0))? as usize;
+        let mut writer = BufWriter::new(&mut *file);
+        let mut written = Vec::with_capacity(inactive.len());
+        for (map, client) in inactive {
+            let Some(account) = maps[map].get(&client).map(|account| account.clone()) else {
+                continue;
+            };
+            let bytes = bincode::serialize(&account)
+                .map_err(|e| EngineError::Spill(format!("cannot encode account: {e}")))?;
+            writer.write_all(&bytes)?;
+            let slot = Slot {
+                offset,
+                len: bytes.len(),
+            };
+            offset += bytes.len();
+            written.push((map, account, slot));
+        }
+        writer.flush()?;
+        drop(writer);
+        // SAFETY: the file is unlinked and private to this store, and nothing
+        // already written to it is ever modified
+        let mapped = unsafe { Mmap::map(&*file)? };
+        *self.mapped.write().expect("page map lock poisoned") = Some(mapped);
+        drop(file);
+
+        let mut paged = 0;
+        for (map, account, slot) in written {
+            let client = account.client;
+            // A client touched since it was picked keeps its account
+            let Entry::Vacant(_inactive) = self.active.entry(client) else {
+                continue;
+            };
+            self.index.insert(client, slot);
+            if maps[map]
+                .remove_if(&client, |_, current| *current == account)
+                .is_some()
+            {
+                paged += 1;
+            } else {
+                self.index.remove(&client);
+            }
+        }
+        Ok(paged)
+    }
+
+    /// Start counting activity afresh, forgetting every client whose rows
+    /// have all been applied
+    pub fn start_round(&self) {
+        self.active.retain(|_, rows| *rows > 0);
+    }
+
+    /// Move every paged-out account back into `accounts`
+    pub fn restore_all(&self, accounts: &AccountsMap) {
+        for entry in self.index.iter() {
+            accounts.insert(*entry.key(), self.read(*entry.value()));
+        }
+        self.index.clear();
+    }
+
+    fn read(&self, slot: Slot) -> Account {
+        let mapped = self.mapped.read().expect("page map lock poisoned");
+        let bytes = &mapped
+            .as_ref()
+            .expect("paged-out account without a page file")[slot.offset..slot.offset + slot.len];
+        bincode::deserialize(bytes).expect("paged-out account is corrupt")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    /// Clients 1 to 4, each with its client ID as balance
+    fn accounts() -> AccountsMap {
+        let accounts = AccountsMap::new();
+        for client in 1..=4 {
+            let mut account = Account::new(client);
+            account.balance_mut(None).available = Decimal::from(client);
+            account.balance_mut(None).total = Decimal::from(client);
+            accounts.insert(client, account);
+        }
+        accounts
+    }
+
+    #[test]
+    fn test_page_out_skips_accounts_touched_this_round() {
+        let accounts = accounts();
+        let cold = ColdAccounts::create().unwrap();
+        drop(cold.touch(1, &accounts));
+        assert_eq!(cold.page_out(&[&accounts]).unwrap(), 3);
+        assert!(!cold.contains(1));
+        assert!(cold.contains(2) && cold.contains(3) && cold.contains(4));
+        assert_eq!(accounts.len(), 1);
+    }
+
+    #[test]
+    fn test_page_out_keeps_accounts_in_hand_across_rounds() {
+        let accounts = accounts();
+        let cold = ColdAccounts::create().unwrap();
+        let busy = cold.touch(2, &accounts);
+        cold.start_round();
+        assert_eq!(cold.page_out(&[&accounts]).unwrap(), 3);
+        assert!(accounts.contains_key(&2));
+        drop(busy);
+
+        cold.start_round();
+        assert_eq!(cold.page_out(&[&accounts]).unwrap(), 1);
+        assert!(accounts.is_empty());
+    }
+
+    #[test]
+    fn test_touch_brings_a_paged_out_account_back() {
+        let accounts = accounts();
+        let cold = ColdAccounts::create().unwrap();
+        cold.page_out(&[&accounts]).unwrap();
+
+        drop(cold.touch(3, &accounts));
+        assert_eq!(
+            accounts.get(&3).unwrap().balance(None).total,
+            Decimal::from(3)
+        );
+        assert!(!cold.contains(3));
+        assert_eq!(cold.len(), 3);
+    }
+
+    #[test]
+    fn test_restore_all_empties_the_page_file() {
+        let accounts = accounts();
+        let cold = ColdAccounts::create().unwrap();
+        cold.page_out(&[&accounts]).unwrap();
+
+        cold.restore_all(&accounts);
+        assert!(cold.is_empty());
+        assert_eq!(accounts.len(), 4);
+        assert_eq!(
+            accounts.get(&1).unwrap().balance(None).available,
+            Decimal::ONE
+        );
+    }
+}
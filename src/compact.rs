@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::collections::VecDeque;
+
+use crate::config::{CompactPolicy, Rules};
+use crate::models::{ClientId, Transaction, TransactionType, TransactionsMap, TxKey};
+
+/// Transactions whose records were compacted away, with the client each
+/// belonged to.
+///
+/// A tombstoned ID is still taken: a new row reusing it is a duplicate, and
+/// a dispute naming it is turned away as compacted, or as another client's
+/// transaction.
+#[derive(Debug, Default)]
+pub struct Tombstones {
+    owners: DashMap<TxKey, ClientId>,
+}
+
+impl Tombstones {
+    /// Tombstones saved in a snapshot
+    pub fn restore(entries: Vec<(TxKey, ClientId)>) -> Self {
+        Tombstones {
+            owners: entries.into_iter().collect(),
+        }
+    }
+
+    pub fn insert(&self, key: TxKey, client: ClientId) {
+        self.owners.insert(key, client);
+    }
+
+    pub fn contains(&self, key: &TxKey) -> bool {
+        self.owners.contains_key(key)
+    }
+
+    /// The client the compacted transaction belonged to
+    pub fn owner(&self, key: &TxKey) -> Option<ClientId> {
+        self.owners.get(key).map(|owner| *owner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+
+    /// Every tombstone, sorted by ID as snapshots keep them
+    pub fn entries(&self) -> Vec<(TxKey, ClientId)> {
+        let mut entries: Vec<_> = self.owners.iter().map(|e| (*e.key(), *e.value())).collect();
+        entries.sort_by_key(|(key, _)| (key.tx, key.client));
+        entries
+    }
+}
+
+/// Compact the record stored under `key` as `rules.compaction` says,
+/// returning whether there was one to compact.
+///
+/// Must not be called while the record's map entry is held.
+pub fn compact(key: TxKey, transactions: &TransactionsMap, rules: &Rules) -> bool {
+    if rules.compaction == CompactPolicy::Keep {
+        return false;
+    }
+    let Some(client) = transactions.get(&key).map(|record| record.client) else {
+        return false;
+    };
+    // Tombstoned before the record goes, so an owner lookup between the two
+    // still finds one of them
+    if rules.compaction == CompactPolicy::Tombstone
+        && let Some(tombstones) = &rules.tombstones
+    {
+        tombstones.insert(key, client);
+    }
+    transactions.remove(&key);
+    true
+}
+
+/// One client's records waiting to fall out of the dispute window, oldest
+/// first.
+///
+/// Only the client a record belongs to may dispute it, so the timestamps of
+/// that client's own rows tell when it no longer can. Kept by whichever task
+/// applies the client's rows, the queue never compacts a record while a
+/// dispute of it is still on its way.
+#[derive(Debug, Default)]
+pub struct ExpiryQueue {
+    pending: VecDeque<(DateTime<Utc>, TxKey)>,
+    /// Expired records that were under dispute, compacted once settled
+    disputed: Vec<TxKey>,
+}
+
+impl ExpiryQueue {
+    /// Note the record an applied row stored, then compact the client's
+    /// records its timestamp has carried past the dispute window. Returns
+    /// how many were compacted.
+    ///
+    /// Does nothing without a dispute window, or for rows without a
+    /// timestamp.
+    pub fn record(
+        &mut self,
+        transaction: &Transaction,
+        transactions: &TransactionsMap,
+        rules: &Rules,
+    ) -> usize {
+        let (Some(window), Some(now), false) = (
+            rules.dispute_window,
+            transaction.timestamp,
+            rules.compaction == CompactPolicy::Keep,
+        ) else {
+            return 0;
+        };
+        let key = rules.duplicate_tx.key(transaction.client, transaction.tx);
+        let references = matches!(
+            transaction.tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if !references && transactions.contains_key(&key) {
+            self.pending.push_back((now, key));
+        }
+        self.expire(now - window, transactions, rules)
+    }
+
+    /// Compact every queued record stored before `cutoff` that is not under
+    /// dispute
+    fn expire(
+        &mut self,
+        cutoff: DateTime<Utc>,
+        transactions: &TransactionsMap,
+        rules: &Rules,
+    ) -> usize {
+        let mut compacted = 0;
+        let mut settled = |key: TxKey, disputed: &mut Vec<TxKey>| {
+            let open = transactions.get(&key).map(|record| record.disputed);
+            match open {
+                Some(true) => disputed.push(key),
+                Some(false) => compacted += usize::from(compact(key, transactions, rules)),
+                None => {}
+            }
+        };
+        for key in std::mem::take(&mut self.disputed) {
+            settled(key, &mut self.disputed);
+        }
+        while let Some(&(stored, key)) = self.pending.front()
+            && stored < cutoff
+        {
+            self.pending.pop_front();
+            settled(key, &mut self.disputed);
+        }
+        compacted
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.disputed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.disputed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{AccountsMap, TxId};
+    use crate::transaction::handle_transaction_with;
+    use chrono::Duration;
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+
+    /// One of client 1's rows on a day of January 2024
+    fn on_day(tx_type: TransactionType, tx: TxId, day: u32) -> Transaction {
+        let amount = matches!(tx_type, TransactionType::Deposit).then_some(Decimal::ONE);
+        Transaction {
+            timestamp: Some(format!("2024-01-{day:02}T00:00:00Z").parse().unwrap()),
+            ..Transaction::new(tx_type, 1, tx, amount)
+        }
+    }
+
+    fn tombstoning() -> Rules {
+        Rules {
+            dispute_window: Some(Duration::days(5)),
+            compaction: CompactPolicy::Tombstone,
+            tombstones: Some(Arc::new(Tombstones::default())),
+            ..Default::default()
+        }
+    }
+
+    /// Apply the rows in order, returning the stored records, the queue and
+    /// how many records each row compacted
+    fn apply(rows: &[Transaction], rules: &Rules) -> (TransactionsMap, ExpiryQueue, Vec<usize>) {
+        let accounts = AccountsMap::new();
+        let transactions = TransactionsMap::new();
+        let mut queue = ExpiryQueue::default();
+        let mut compacted = Vec::new();
+        for transaction in rows {
+            handle_transaction_with(transaction.clone(), &accounts, &transactions, rules).unwrap();
+            compacted.push(queue.record(transaction, &transactions, rules));
+        }
+        (transactions, queue, compacted)
+    }
+
+    #[test]
+    fn test_record_past_the_window_is_compacted() {
+        let rules = tombstoning();
+        let (transactions, queue, compacted) = apply(
+            &[
+                on_day(TransactionType::Deposit, 1, 1),
+                on_day(TransactionType::Deposit, 2, 4),
+                on_day(TransactionType::Deposit, 3, 8),
+            ],
+            &rules,
+        );
+        assert_eq!(compacted, [0, 0, 1]);
+        assert!(!transactions.contains_key(&TxKey::global(1)));
+        assert!(transactions.contains_key(&TxKey::global(2)));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn test_disputed_record_waits_for_its_resolve() {
+        use TransactionType::{Deposit, Dispute, Resolve};
+        let rules = tombstoning();
+        let (transactions, queue, compacted) = apply(
+            &[
+                on_day(Deposit, 2, 2),
+                on_day(Dispute, 2, 3),
+                on_day(Deposit, 3, 8),
+                on_day(Resolve, 2, 9),
+            ],
+            &rules,
+        );
+        assert_eq!(compacted, [0, 0, 0, 1]);
+        assert!(!transactions.contains_key(&TxKey::global(2)));
+        assert!(transactions.contains_key(&TxKey::global(3)));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_tombstone_keeps_the_owner() {
+        let rules = tombstoning();
+        apply(
+            &[
+                on_day(TransactionType::Deposit, 1, 1),
+                on_day(TransactionType::Deposit, 2, 20),
+            ],
+            &rules,
+        );
+        let tombstones = rules.tombstones.as_ref().unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones.owner(&TxKey::global(1)), Some(1));
+        assert_eq!(tombstones.owner(&TxKey::global(2)), None);
+    }
+
+    #[test]
+    fn test_drop_leaves_no_tombstone() {
+        let rules = Rules {
+            compaction: CompactPolicy::Drop,
+            ..tombstoning()
+        };
+        let (transactions, _, compacted) = apply(
+            &[
+                on_day(TransactionType::Deposit, 1, 1),
+                on_day(TransactionType::Deposit, 2, 20),
+            ],
+            &rules,
+        );
+        assert_eq!(compacted, [0, 1]);
+        assert_eq!(transactions.len(), 1);
+        assert!(rules.tombstones.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_nothing_is_compacted_without_a_window() {
+        let rules = Rules {
+            dispute_window: None,
+            ..tombstoning()
+        };
+        let (transactions, queue, compacted) = apply(
+            &[
+                on_day(TransactionType::Deposit, 1, 1),
+                on_day(TransactionType::Deposit, 2, 20),
+            ],
+            &rules,
+        );
+        assert_eq!(compacted, [0, 0]);
+        assert_eq!(transactions.len(), 2);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,2067 @@
+use chrono::Duration;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::bloom::TxKeyFilter;
+use crate::compact::Tombstones;
+use crate::encryption::{AdminKey, StateKey};
+use crate::error::EngineError;
+use crate::fraud::FraudRules;
+use crate::fx::FxRates;
+use crate::handlers::HandlerRegistry;
+use crate::limits::RateLimit;
+use crate::memory::MemorySize;
+use crate::middleware::MiddlewareChain;
+use crate::models::{ClientId, TransactionType, TxId, TxKey};
+use crate::publish::{PublishKey, PublishTarget};
+use crate::retain::RetainedIds;
+use crate::shard::ClientSlice;
+use crate::spill::SpillStore;
+use crate::validate::{COLUMNS, MAX_DECIMAL_PLACES};
+
+/// How malformed rows and rejected transactions affect a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Stop at the first error with a non-zero exit
+    Strict,
+    /// Log the error and continue
+    #[default]
+    Skip,
+    /// Continue, then report every error at the end and exit non-zero
+    Collect,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ErrorPolicy::Strict),
+            "skip" => Ok(ErrorPolicy::Skip),
+            "collect" => Ok(ErrorPolicy::Collect),
+            other => Err(EngineError::Usage(format!(
+                "invalid error policy '{other}' (expected strict, skip or collect)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorPolicy::Strict => "strict",
+            ErrorPolicy::Skip => "skip",
+            ErrorPolicy::Collect => "collect",
+        })
+    }
+}
+
+/// How input amounts with more than 4 decimal places are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountPrecision {
+    /// Report the row as malformed
+    Reject,
+    /// Drop the extra digits (rounding toward zero)
+    #[default]
+    Truncate,
+    /// Round half to even
+    Round,
+}
+
+impl FromStr for AmountPrecision {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(AmountPrecision::Reject),
+            "truncate" => Ok(AmountPrecision::Truncate),
+            "round" => Ok(AmountPrecision::Round),
+            other => Err(EngineError::Usage(format!(
+                "invalid amount precision '{other}' (expected reject, truncate or round)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AmountPrecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AmountPrecision::Reject => "reject",
+            AmountPrecision::Truncate => "truncate",
+            AmountPrecision::Round => "round",
+        })
+    }
+}
+
+/// How strictly input amounts must be written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountFormat {
+    /// Plain decimal numbers only, such as `1234.5`
+    #[default]
+    Strict,
+    /// Also accept spreadsheet-style amounts such as `1,234.50`, `$20` or
+    /// `'-€3.10'`
+    Lenient,
+}
+
+impl FromStr for AmountFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(AmountFormat::Strict),
+            "lenient" => Ok(AmountFormat::Lenient),
+            other => Err(EngineError::Usage(format!(
+                "invalid amount format '{other}' (expected strict or lenient)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AmountFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AmountFormat::Strict => "strict",
+            AmountFormat::Lenient => "lenient",
+        })
+    }
+}
+
+/// How input amounts are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AmountMode {
+    /// Decimal numbers, such as `12.5`
+    #[default]
+    Decimal,
+    /// Whole numbers of the smallest unit the engine keeps, a ten-thousandth,
+    /// such as `125000` for 12.5
+    MinorUnits,
+}
+
+impl FromStr for AmountMode {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decimal" => Ok(AmountMode::Decimal),
+            "minor-units" => Ok(AmountMode::MinorUnits),
+            other => Err(EngineError::Usage(format!(
+                "invalid amount mode '{other}' (expected decimal or minor-units)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AmountMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AmountMode::Decimal => "decimal",
+            AmountMode::MinorUnits => "minor-units",
+        })
+    }
+}
+
+/// Which way amounts and balances with more decimal places than the engine
+/// keeps are rounded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Drop the extra digits
+    #[default]
+    ToZero,
+    /// Round half to even, as banks usually settle
+    HalfEven,
+    /// Round half away from zero
+    HalfUp,
+}
+
+impl RoundingMode {
+    pub fn strategy(self) -> RoundingStrategy {
+        match self {
+            RoundingMode::ToZero => RoundingStrategy::ToZero,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "to-zero" => Ok(RoundingMode::ToZero),
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "half-up" => Ok(RoundingMode::HalfUp),
+            other => Err(EngineError::Usage(format!(
+                "invalid rounding '{other}' (expected to-zero, half-even or half-up)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RoundingMode::ToZero => "to-zero",
+            RoundingMode::HalfEven => "half-even",
+            RoundingMode::HalfUp => "half-up",
+        })
+    }
+}
+
+/// Decimal places kept in balances, from 0 up to the 4 input amounts may
+/// have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u32")]
+pub struct Precision(u32);
+
+impl Precision {
+    pub fn places(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision(MAX_DECIMAL_PLACES)
+    }
+}
+
+impl TryFrom<u32> for Precision {
+    type Error = EngineError;
+
+    fn try_from(places: u32) -> Result<Self, Self::Error> {
+        if places > MAX_DECIMAL_PLACES {
+            return Err(EngineError::Usage(format!(
+                "invalid precision {places} (expected 0 to {MAX_DECIMAL_PLACES})"
+            )));
+        }
+        Ok(Precision(places))
+    }
+}
+
+impl FromStr for Precision {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let places = s
+            .parse::<u32>()
+            .map_err(|_| EngineError::Usage(format!("invalid precision '{s}'")))?;
+        Precision::try_from(places)
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How amounts and balances are rounded, applied to every balance change
+/// and to the accounts output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rounding {
+    pub mode: RoundingMode,
+    pub precision: Precision,
+}
+
+/// Decimal and thousands separators of the numbers in the accounts output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberLocale {
+    /// `1234567.5`, readable back as input
+    #[default]
+    Plain,
+    /// `1,234,567.5`
+    En,
+    /// `1.234.567,5`
+    De,
+    /// `1 234 567,5`
+    Fr,
+    /// `1'234'567.5`
+    Ch,
+}
+
+impl NumberLocale {
+    /// The thousands separator, if any, and the decimal separator
+    fn separators(self) -> (Option<char>, char) {
+        match self {
+            NumberLocale::Plain => (None, '.'),
+            NumberLocale::En => (Some(','), '.'),
+            NumberLocale::De => (Some('.'), ','),
+            NumberLocale::Fr => (Some(' '), ','),
+            NumberLocale::Ch => (Some('\''), '.'),
+        }
+    }
+
+    /// Rewrite a plain decimal number, such as `-1234.50`, with this
+    /// locale's separators
+    pub fn localize(self, plain: &str) -> String {
+        let (group, point) = self.separators();
+        let (sign, digits) = match plain.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", plain),
+        };
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (digits, None),
+        };
+        let mut localized = String::from(sign);
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0
+                && (integer.len() - i) % 3 == 0
+                && let Some(group) = group
+            {
+                localized.push(group);
+            }
+            localized.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            localized.push(point);
+            localized.push_str(fraction);
+        }
+        localized
+    }
+}
+
+impl FromStr for NumberLocale {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(NumberLocale::Plain),
+            "en" => Ok(NumberLocale::En),
+            "de" => Ok(NumberLocale::De),
+            "fr" => Ok(NumberLocale::Fr),
+            "ch" => Ok(NumberLocale::Ch),
+            other => Err(EngineError::Usage(format!(
+                "invalid number locale '{other}' (expected plain, en, de, fr or ch)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for NumberLocale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NumberLocale::Plain => "plain",
+            NumberLocale::En => "en",
+            NumberLocale::De => "de",
+            NumberLocale::Fr => "fr",
+            NumberLocale::Ch => "ch",
+        })
+    }
+}
+
+/// Client IDs given as a comma-separated list of IDs and `first-last`
+/// ranges, such as `1,2,7-10`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ClientList(Vec<(ClientId, ClientId)>);
+
+impl ClientList {
+    pub fn contains(&self, client: ClientId) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| (first..=last).contains(&client))
+    }
+}
+
+impl FromStr for ClientList {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |part: &str| {
+            EngineError::Usage(format!(
+                "invalid client '{part}' (expected an ID or first-last range)"
+            ))
+        };
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let first: ClientId = first.trim().parse().map_err(|_| invalid(part))?;
+            let last: ClientId = last.trim().parse().map_err(|_| invalid(part))?;
+            if first > last {
+                return Err(invalid(part));
+            }
+            ranges.push((first, last));
+        }
+        Ok(ClientList(ranges))
+    }
+}
+
+impl TryFrom<String> for ClientList {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for ClientList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &(first, last)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match first == last {
+                true => write!(f, "{first}")?,
+                false => write!(f, "{first}-{last}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A column of the accounts output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountColumn {
+    Client,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Closed,
+    Deposits,
+    Withdrawals,
+    Disputes,
+    Chargebacks,
+}
+
+impl AccountColumn {
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountColumn::Client => "client",
+            AccountColumn::Currency => "currency",
+            AccountColumn::Available => "available",
+            AccountColumn::Held => "held",
+            AccountColumn::Total => "total",
+            AccountColumn::Locked => "locked",
+            AccountColumn::Closed => "closed",
+            AccountColumn::Deposits => "deposits",
+            AccountColumn::Withdrawals => "withdrawals",
+            AccountColumn::Disputes => "disputes",
+            AccountColumn::Chargebacks => "chargebacks",
+        }
+    }
+}
+
+impl FromStr for AccountColumn {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(AccountColumn::Client),
+            "currency" => Ok(AccountColumn::Currency),
+            "available" => Ok(AccountColumn::Available),
+            "held" => Ok(AccountColumn::Held),
+            "total" => Ok(AccountColumn::Total),
+            "locked" => Ok(AccountColumn::Locked),
+            "closed" => Ok(AccountColumn::Closed),
+            "deposits" => Ok(AccountColumn::Deposits),
+            "withdrawals" => Ok(AccountColumn::Withdrawals),
+            "disputes" => Ok(AccountColumn::Disputes),
+            "chargebacks" => Ok(AccountColumn::Chargebacks),
+            other => Err(EngineError::Usage(format!(
+                "invalid account column '{other}' (expected client, currency, available, held, total, locked, closed, deposits, withdrawals, disputes or chargebacks)"
+            ))),
+        }
+    }
+}
+
+/// Columns to keep in the accounts output, in the order given, such as
+/// `client,total,locked`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AccountColumns(pub Vec<AccountColumn>);
+
+impl FromStr for AccountColumns {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns = Vec::new();
+        for name in s.split(',') {
+            let column: AccountColumn = name.trim().parse()?;
+            if columns.contains(&column) {
+                return Err(EngineError::Usage(format!(
+                    "account column '{}' given twice",
+                    column.name()
+                )));
+            }
+            columns.push(column);
+        }
+        Ok(AccountColumns(columns))
+    }
+}
+
+impl TryFrom<String> for AccountColumns {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for AccountColumns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.0.iter().map(|column| column.name()).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// One extra name for a transaction type, written `alias=type`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeAlias {
+    pub alias: String,
+    pub tx_type: TransactionType,
+}
+
+impl FromStr for TypeAlias {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || EngineError::Usage(format!("invalid type alias '{s}' (expected alias=type)"));
+        let (alias, tx_type) = s.split_once('=').ok_or_else(invalid)?;
+        let (alias, tx_type) = (alias.trim(), tx_type.trim());
+        if alias.is_empty() {
+            return Err(invalid());
+        }
+        Ok(TypeAlias {
+            alias: alias.to_ascii_lowercase(),
+            tx_type: tx_type.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Extra names accepted in the `type` column, such as `withdraw` for
+/// `withdrawal`, matched in any case.
+///
+/// Read from a `[type-aliases]` config table, or as a comma-separated
+/// `alias=type` list; a later entry for the same alias replaces an earlier
+/// one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct TypeAliases(BTreeMap<String, TransactionType>);
+
+impl TypeAliases {
+    /// The type `name` stands for; `name` must already be lower-case
+    pub fn get(&self, name: &str) -> Option<&TransactionType> {
+        self.0.get(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromIterator<TypeAlias> for TypeAliases {
+    fn from_iter<I: IntoIterator<Item = TypeAlias>>(aliases: I) -> Self {
+        TypeAliases(
+            aliases
+                .into_iter()
+                .map(|alias| (alias.alias, alias.tx_type))
+                .collect(),
+        )
+    }
+}
+
+impl IntoIterator for TypeAliases {
+    type Item = TypeAlias;
+    type IntoIter = std::iter::Map<
+        std::collections::btree_map::IntoIter<String, TransactionType>,
+        fn((String, TransactionType)) -> TypeAlias,
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0
+            .into_iter()
+            .map(|(alias, tx_type)| TypeAlias { alias, tx_type })
+    }
+}
+
+impl FromStr for TypeAliases {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl TryFrom<BTreeMap<String, String>> for TypeAliases {
+    type Error = EngineError;
+
+    fn try_from(table: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        table
+            .into_iter()
+            .map(|(alias, tx_type)| format!("{alias}={tx_type}").parse())
+            .collect()
+    }
+}
+
+/// Field separator of the input file, given as a single ASCII character or
+/// `\t` / `tab` for tab-separated files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Delimiter(pub u8);
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Delimiter(b',')
+    }
+}
+
+impl FromStr for Delimiter {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "\\t" | "\t" | "tab" => Ok(Delimiter(b'\t')),
+            other if other.len() == 1 && other.is_ascii() => Ok(Delimiter(other.as_bytes()[0])),
+            other => Err(EngineError::Usage(format!(
+                "invalid delimiter '{other}' (expected one ASCII character or tab)"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for Delimiter {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+/// The header an upstream file uses for one of the engine's columns,
+/// written `column=header`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnAlias {
+    pub column: String,
+    pub header: String,
+}
+
+impl FromStr for ColumnAlias {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, header) = s
+            .split_once('=')
+            .map(|(column, header)| (column.trim(), header.trim()))
+            .filter(|(_, header)| !header.is_empty())
+            .ok_or_else(|| {
+                EngineError::Usage(format!(
+                    "invalid column mapping '{s}' (expected column=header)"
+                ))
+            })?;
+        if !COLUMNS.contains(&column) {
+            return Err(EngineError::Usage(format!(
+                "unknown column '{column}' (expected one of {})",
+                COLUMNS.join(", ")
+            )));
+        }
+        Ok(ColumnAlias {
+            column: column.to_string(),
+            header: header.to_string(),
+        })
+    }
+}
+
+/// Headers to look for in place of the engine's own column names, such as
+/// `customer_id` for `client`.
+///
+/// Read from a `[columns]` config table, or as a comma-separated
+/// `column=header` list; a later entry for the same column replaces an
+/// earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct ColumnMap(BTreeMap<String, String>);
+
+impl ColumnMap {
+    /// The header holding `column`, which is the column's own name unless
+    /// mapped
+    pub fn header<'a>(&'a self, column: &'a str) -> &'a str {
+        self.0.get(column).map_or(column, String::as_str)
+    }
+}
+
+impl FromIterator<ColumnAlias> for ColumnMap {
+    fn from_iter<I: IntoIterator<Item = ColumnAlias>>(aliases: I) -> Self {
+        ColumnMap(
+            aliases
+                .into_iter()
+                .map(|alias| (alias.column, alias.header))
+                .collect(),
+        )
+    }
+}
+
+impl IntoIterator for ColumnMap {
+    type Item = ColumnAlias;
+    type IntoIter = std::iter::Map<
+        std::collections::btree_map::IntoIter<String, String>,
+        fn((String, String)) -> ColumnAlias,
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0
+            .into_iter()
+            .map(|(column, header)| ColumnAlias { column, header })
+    }
+}
+
+impl FromStr for ColumnMap {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl TryFrom<BTreeMap<String, String>> for ColumnMap {
+    type Error = EngineError;
+
+    fn try_from(table: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        table
+            .into_iter()
+            .map(|(column, header)| format!("{column}={header}").parse())
+            .collect()
+    }
+}
+
+/// How the input file is laid out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: Delimiter,
+    /// Whether the first row names the columns; without one, columns are
+    /// taken in the order `type,client,tx,amount,timestamp,currency,
+    /// to_currency,idempotency_key`
+    pub has_headers: bool,
+    pub columns: ColumnMap,
+}
+
+/// What to do with rows whose timestamp is earlier than the client's previous row
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MonotonicPolicy {
+    /// Timestamps are not checked
+    #[default]
+    Off,
+    /// Log a warning and apply the row anyway
+    Flag,
+    /// Reject the row
+    Reject,
+}
+
+impl FromStr for MonotonicPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(MonotonicPolicy::Off),
+            "flag" => Ok(MonotonicPolicy::Flag),
+            "reject" => Ok(MonotonicPolicy::Reject),
+            other => Err(EngineError::Usage(format!(
+                "invalid monotonic policy '{other}' (expected off, flag or reject)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for MonotonicPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MonotonicPolicy::Off => "off",
+            MonotonicPolicy::Flag => "flag",
+            MonotonicPolicy::Reject => "reject",
+        })
+    }
+}
+
+/// What `merge` does with a client listed in more than one accounts file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeDuplicates {
+    /// Fail, since shards are meant to cover disjoint clients
+    #[default]
+    Reject,
+    /// Add the balances up; the account is locked if it is locked in any file
+    Sum,
+}
+
+impl FromStr for MergeDuplicates {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(MergeDuplicates::Reject),
+            "sum" => Ok(MergeDuplicates::Sum),
+            other => Err(EngineError::Usage(format!(
+                "invalid duplicates policy '{other}' (expected reject or sum)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for MergeDuplicates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MergeDuplicates::Reject => "reject",
+            MergeDuplicates::Sum => "sum",
+        })
+    }
+}
+
+/// Serialization format for reports written by the engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(EngineError::Usage(format!(
+                "invalid output format '{other}' (expected csv or json)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+/// How log events are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per event, with structured fields at the top level
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(EngineError::Usage(format!(
+                "invalid log format '{other}' (expected text or json)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// Scope within which transaction IDs must be unique
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateTxPolicy {
+    /// IDs are unique across all clients; reusing another client's ID is
+    /// rejected as a duplicate
+    #[default]
+    Global,
+    /// IDs are only unique within a client, so different clients may reuse
+    /// the same ID
+    PerClient,
+}
+
+impl DuplicateTxPolicy {
+    /// Key under which a client's transaction is stored
+    pub fn key(&self, client: ClientId, tx: TxId) -> TxKey {
+        match self {
+            DuplicateTxPolicy::Global => TxKey::global(tx),
+            DuplicateTxPolicy::PerClient => TxKey::per_client(client, tx),
+        }
+    }
+}
+
+impl FromStr for DuplicateTxPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(DuplicateTxPolicy::Global),
+            "per-client" => Ok(DuplicateTxPolicy::PerClient),
+            other => Err(EngineError::Usage(format!(
+                "invalid duplicate transaction policy '{other}' (expected global or per-client)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DuplicateTxPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DuplicateTxPolicy::Global => "global",
+            DuplicateTxPolicy::PerClient => "per-client",
+        })
+    }
+}
+
+/// What closing an account does with funds still available on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClosePolicy {
+    /// Reject the close unless every balance is zero
+    #[default]
+    RequireEmpty,
+    /// Pay out whatever is available and close the account
+    Payout,
+}
+
+impl FromStr for ClosePolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "require-empty" => Ok(ClosePolicy::RequireEmpty),
+            "payout" => Ok(ClosePolicy::Payout),
+            other => Err(EngineError::Usage(format!(
+                "invalid close policy '{other}' (expected require-empty or payout)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ClosePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClosePolicy::RequireEmpty => "require-empty",
+            ClosePolicy::Payout => "payout",
+        })
+    }
+}
+
+/// What becomes of a transaction's record once it is settled: charged back,
+/// or past the dispute window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactPolicy {
+    /// Keep the whole record for the rest of the run
+    #[default]
+    Keep,
+    /// Replace it by a tombstone naming its client, so the ID stays taken
+    Tombstone,
+    /// Drop it outright, letting a later row reuse the ID
+    Drop,
+}
+
+impl FromStr for CompactPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(CompactPolicy::Keep),
+            "tombstone" => Ok(CompactPolicy::Tombstone),
+            "drop" => Ok(CompactPolicy::Drop),
+            other => Err(EngineError::Usage(format!(
+                "invalid compaction '{other}' (expected keep, tombstone or drop)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CompactPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompactPolicy::Keep => "keep",
+            CompactPolicy::Tombstone => "tombstone",
+            CompactPolicy::Drop => "drop",
+        })
+    }
+}
+
+/// What a dispute, resolve or chargeback naming another client's
+/// transaction does besides being rejected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientMismatchPolicy {
+    /// Nothing: the row is rejected with reason `client_mismatch`
+    #[default]
+    Reject,
+    /// Also flag the disputing client for fraud review, putting their
+    /// account on review hold
+    Review,
+}
+
+impl FromStr for ClientMismatchPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ClientMismatchPolicy::Reject),
+            "review" => Ok(ClientMismatchPolicy::Review),
+            other => Err(EngineError::Usage(format!(
+                "invalid client mismatch policy '{other}' (expected reject or review)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ClientMismatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClientMismatchPolicy::Reject => "reject",
+            ClientMismatchPolicy::Review => "review",
+        })
+    }
+}
+
+/// What disputing a deposit worth more than the account has available does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeShortfallPolicy {
+    /// Hold the whole amount, taking `available` below zero
+    #[default]
+    AllowNegative,
+    /// Hold only what is still available
+    Cap,
+    /// Hold nothing yet and put the account on review hold
+    Review,
+}
+
+impl FromStr for DisputeShortfallPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow-negative" => Ok(DisputeShortfallPolicy::AllowNegative),
+            "cap" => Ok(DisputeShortfallPolicy::Cap),
+            "review" => Ok(DisputeShortfallPolicy::Review),
+            other => Err(EngineError::Usage(format!(
+                "invalid dispute shortfall policy '{other}' (expected allow-negative, cap or review)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DisputeShortfallPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisputeShortfallPolicy::AllowNegative => "allow-negative",
+            DisputeShortfallPolicy::Cap => "cap",
+            DisputeShortfallPolicy::Review => "review",
+        })
+    }
+}
+
+/// Which dispute-flow rows frozen and chargeback-locked accounts still take,
+/// given as a comma-separated list of `dispute`, `resolve` and `chargeback`,
+/// or `none`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LockedTypes {
+    pub dispute: bool,
+    pub resolve: bool,
+    pub chargeback: bool,
+}
+
+impl LockedTypes {
+    /// Nothing at all, freezing even dispute processing
+    pub const NONE: LockedTypes = LockedTypes {
+        dispute: false,
+        resolve: false,
+        chargeback: false,
+    };
+
+    /// Whether a locked account takes a row of this type
+    pub fn allows(&self, tx_type: &TransactionType) -> bool {
+        match tx_type {
+            TransactionType::Dispute => self.dispute,
+            TransactionType::Resolve => self.resolve,
+            TransactionType::Chargeback => self.chargeback,
+            _ => false,
+        }
+    }
+}
+
+/// Disputes keep settling on locked accounts, as they always have
+impl Default for LockedTypes {
+    fn default() -> Self {
+        LockedTypes {
+            dispute: true,
+            resolve: true,
+            chargeback: true,
+        }
+    }
+}
+
+impl FromStr for LockedTypes {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut types = LockedTypes::NONE;
+        if s.trim() == "none" {
+            return Ok(types);
+        }
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "dispute" => types.dispute = true,
+                "resolve" => types.resolve = true,
+                "chargeback" => types.chargeback = true,
+                other => {
+                    return Err(EngineError::Usage(format!(
+                        "invalid locked-account type '{other}' (expected dispute, resolve, chargeback or none)"
+                    )));
+                }
+            }
+        }
+        Ok(types)
+    }
+}
+
+impl TryFrom<String> for LockedTypes {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for LockedTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = [
+            (self.dispute, "dispute"),
+            (self.resolve, "resolve"),
+            (self.chargeback, "chargeback"),
+        ]
+        .into_iter()
+        .filter_map(|(allowed, name)| allowed.then_some(name))
+        .collect();
+        match names.as_slice() {
+            [] => f.write_str("none"),
+            names => f.write_str(&names.join(",")),
+        }
+    }
+}
+
+/// Transaction types handed to a client task's priority lane, given as a
+/// comma-separated list such as `chargeback,close`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PriorityTypes(Vec<TransactionType>);
+
+impl PriorityTypes {
+    pub fn contains(&self, tx_type: &TransactionType) -> bool {
+        self.0.contains(tx_type)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for PriorityTypes {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .map(|name| {
+                name.parse().map_err(|_| {
+                    EngineError::Usage(format!("invalid priority transaction type '{name}'"))
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(PriorityTypes)
+    }
+}
+
+impl TryFrom<String> for PriorityTypes {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for PriorityTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.0.iter().map(TransactionType::as_str).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// How closed accounts appear in the accounts output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClosedAccounts {
+    /// Listed like any other (locked) account
+    #[default]
+    Include,
+    /// Left out of the output
+    Exclude,
+    /// Listed with an extra `closed` column
+    Flag,
+}
+
+impl FromStr for ClosedAccounts {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(ClosedAccounts::Include),
+            "exclude" => Ok(ClosedAccounts::Exclude),
+            "flag" => Ok(ClosedAccounts::Flag),
+            other => Err(EngineError::Usage(format!(
+                "invalid closed accounts mode '{other}' (expected include, exclude or flag)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ClosedAccounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClosedAccounts::Include => "include",
+            ClosedAccounts::Exclude => "exclude",
+            ClosedAccounts::Flag => "flag",
+        })
+    }
+}
+
+/// Business rules applied by `handle_transaction_with`
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// How long after a deposit it may still be disputed; unlimited if unset
+    pub dispute_window: Option<Duration>,
+    /// Scope within which transaction IDs must be unique
+    pub duplicate_tx: DuplicateTxPolicy,
+    /// Exchange rates for `convert` rows; every conversion is rejected if unset
+    pub fx_rates: Option<Arc<FxRates>>,
+    /// What closing an account does with a remaining balance
+    pub close_policy: ClosePolicy,
+    /// What referring to another client's transaction does
+    pub client_mismatch: ClientMismatchPolicy,
+    /// Open an account for the client of a rejected dispute, resolve or
+    /// chargeback, as the engine used to
+    pub open_on_reference: bool,
+    /// What a dispute of more than the account has available does
+    pub dispute_shortfall: DisputeShortfallPolicy,
+    /// Dispute-flow rows frozen and chargeback-locked accounts still take
+    pub locked_allows: LockedTypes,
+    /// How balances are rounded after every change
+    pub rounding: Rounding,
+    /// Records moved to disk, checked for anything missing from the map
+    pub spill: Option<Arc<SpillStore>>,
+    /// Every transaction key stored, letting new keys skip the duplicate
+    /// check
+    pub tx_filter: Option<Arc<TxKeyFilter>>,
+    /// The only IDs whose records are kept, when a first pass found them
+    pub retained: Option<Arc<RetainedIds>>,
+    /// What becomes of records once charged back or past the dispute window
+    pub compaction: CompactPolicy,
+    /// Clients of compacted records, whose IDs stay taken
+    pub tombstones: Option<Arc<Tombstones>>,
+    /// Library hooks run around every transaction
+    pub middleware: Option<Arc<MiddlewareChain>>,
+    /// Handlers for transaction types beyond the built-in ones
+    pub handlers: Option<Arc<HandlerRegistry>>,
+}
+
+/// Settings read from a `--config` TOML file.
+///
+/// Every key is optional; command-line flags take precedence over values set
+/// here.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EngineConfig {
+    /// Async runtime worker threads; defaults to one per CPU core
+    pub worker_threads: Option<usize>,
+    /// Transactions buffered per client before ingestion waits
+    pub channel_capacity: Option<usize>,
+    /// Shard workers owning the accounts; one task per client if unset
+    pub shards: Option<usize>,
+    /// Client tasks started before further clients share existing ones
+    pub max_client_tasks: Option<usize>,
+    /// Seconds without a row before a client's task shuts down
+    pub client_idle_secs: Option<usize>,
+    /// Only handle this range of client IDs, such as `"0-16383"`
+    pub clients: Option<ClientSlice>,
+    /// Only handle this modulo slice of clients, such as `"3/8"`
+    pub shard: Option<ClientSlice>,
+    /// Back-to-back deposits and withdrawals of one client applied under a
+    /// single account update
+    pub coalesce_rows: Option<usize>,
+    /// Transaction types that go ahead of a client's other queued rows
+    pub priority_types: Option<PriorityTypes>,
+    /// Abort once the account and transaction state is estimated to need
+    /// more than this, such as `4G`
+    pub max_memory: Option<MemorySize>,
+    /// Move cold transactions to a temporary file once the state is
+    /// estimated to need more than this
+    pub spill_over: Option<MemorySize>,
+    /// Move the accounts of inactive clients to a temporary file once the
+    /// accounts are estimated to need more than this
+    pub page_accounts_over: Option<MemorySize>,
+    /// Transaction IDs the bloom filter in front of the duplicate check is
+    /// sized for
+    pub bloom_filter_ids: Option<usize>,
+    pub error_policy: Option<ErrorPolicy>,
+    pub amount_precision: Option<AmountPrecision>,
+    pub amount_format: Option<AmountFormat>,
+    pub amount_mode: Option<AmountMode>,
+    pub rounding: Option<RoundingMode>,
+    pub precision: Option<Precision>,
+    /// Field separator of the input file
+    pub delimiter: Option<Delimiter>,
+    /// The input file has no header row
+    pub no_headers: Option<bool>,
+    /// Headers to read the engine's columns from, as a `[columns]` table
+    pub columns: Option<ColumnMap>,
+    /// Extra names for transaction types, as a `[type-aliases]` table
+    pub type_aliases: Option<TypeAliases>,
+    pub require_monotonic: Option<MonotonicPolicy>,
+    pub dispute_window_days: Option<u32>,
+    pub duplicate_tx: Option<DuplicateTxPolicy>,
+    pub fx_rates: Option<PathBuf>,
+    pub close_policy: Option<ClosePolicy>,
+    pub client_mismatch: Option<ClientMismatchPolicy>,
+    /// Open accounts for the clients of rejected disputes, resolves and
+    /// chargebacks
+    pub open_on_reference: Option<bool>,
+    pub dispute_shortfall: Option<DisputeShortfallPolicy>,
+    pub locked_allows: Option<LockedTypes>,
+    pub idempotency_window_hours: Option<u32>,
+    pub max_tx_amount: Option<Decimal>,
+    pub daily_deposit_limit: Option<Decimal>,
+    pub daily_withdrawal_limit: Option<Decimal>,
+    pub total_deposit_limit: Option<Decimal>,
+    pub rate_limit: Option<RateLimit>,
+    pub client_limits: Option<PathBuf>,
+    pub segment_limits: Option<PathBuf>,
+    /// Seed file of account tiers, KYC status and countries
+    pub account_meta: Option<PathBuf>,
+    pub verify: Option<bool>,
+    pub deterministic: Option<bool>,
+    /// Read the input twice, keeping only the records disputes need
+    pub two_pass: Option<bool>,
+    /// Compact records once charged back or past the dispute window
+    pub compact_records: Option<CompactPolicy>,
+    /// Read the input file off a memory map on the blocking pool
+    pub mmap_input: Option<bool>,
+    /// Pause ingestion on `SIGUSR1` and resume it on `SIGUSR2`
+    pub pause_on_signal: Option<bool>,
+    /// Reload limits, exchange rates and fraud rules on `SIGHUP`
+    pub reload_on_signal: Option<bool>,
+    pub resume: Option<PathBuf>,
+    /// Key snapshots are encrypted with, as 64 hex digits
+    pub state_key: Option<StateKey>,
+    /// Command printing the state key, such as a KMS client
+    pub state_key_command: Option<String>,
+    /// Resume from a plain snapshot even though a state key is set
+    pub migrate_plain_state: Option<bool>,
+    /// Signed file that hold, release and close rows must arrive in
+    pub admin_input: Option<PathBuf>,
+    /// Secret admin rows are signed with
+    pub admin_key: Option<AdminKey>,
+    /// Command printing the admin key
+    pub admin_key_command: Option<String>,
+    /// Recurring instructions to generate transactions from
+    pub schedule: Option<PathBuf>,
+    /// Accounts CSV the final balances must match
+    pub reconcile: Option<PathBuf>,
+    /// Client that carries what others owe in recovery adjustments
+    pub suspense_account: Option<ClientId>,
+    pub output: OutputConfig,
+    pub fraud: FraudRules,
+}
+
+/// The `[output]` table of an `EngineConfig`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutputConfig {
+    /// Directory for the accounts files of `--tenant` runs
+    pub tenant_dir: Option<PathBuf>,
+    pub rejects: Option<PathBuf>,
+    pub ledger: Option<PathBuf>,
+    pub save_state: Option<PathBuf>,
+    pub stats: Option<PathBuf>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub log_format: Option<LogFormat>,
+    pub progress: Option<bool>,
+    pub fraud_report: Option<PathBuf>,
+    pub closed_accounts: Option<ClosedAccounts>,
+    /// Write every balance with exactly `precision` decimal places
+    pub fixed_decimals: Option<bool>,
+    pub number_locale: Option<NumberLocale>,
+    /// Only write accounts that are locked
+    pub only_locked: Option<bool>,
+    /// Only write balances that are not all zero
+    pub only_nonzero: Option<bool>,
+    /// Only write these clients' accounts
+    pub clients: Option<ClientList>,
+    /// Columns of the accounts output, in order
+    pub columns: Option<AccountColumns>,
+    /// Add each client's transaction counts to the accounts output
+    pub extended: Option<bool>,
+    pub audit_log: Option<PathBuf>,
+    /// Keep a hash chain over applied transactions
+    pub hash_chain: Option<bool>,
+    pub events: Option<PathBuf>,
+    pub sqlite: Option<PathBuf>,
+    pub publish: Option<PublishTarget>,
+    pub publish_key: Option<PublishKey>,
+    pub webhook_url: Option<String>,
+    pub notify_withdrawals_over: Option<Decimal>,
+    pub postgres_url: Option<String>,
+    pub postgres_interval_secs: Option<usize>,
+    /// CSV file of the balances left below zero
+    pub recovery_report: Option<PathBuf>,
+    /// Seconds between rewrites of the recovery report during the run
+    pub recovery_interval_secs: Option<usize>,
+    /// CSV file of `adjustment` rows moving negative balances to the
+    /// suspense account
+    pub recovery_adjustments: Option<PathBuf>,
+}
+
+/// Prefix of the environment variables read by `EngineConfig::from_env`
+pub const ENV_PREFIX: &str = "ENGINE_";
+
+impl EngineConfig {
+    /// Read and parse a TOML config file
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        let text = std::fs::read_to_string(path)?;
+        let config: EngineConfig = toml::from_str(&text).map_err(|e| EngineError::Config {
+            path: path.to_path_buf(),
+            message: e.message().to_string(),
+        })?;
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(EngineError::Config {
+                path: path.to_path_buf(),
+                message: problems.join("; "),
+            });
+        }
+        Ok(config)
+    }
+
+    /// Build a config from `ENGINE_*` environment variables, such as
+    /// `ENGINE_ERROR_POLICY=collect` or `ENGINE_OUTPUT_STATS=stats.json`.
+    ///
+    /// Every variable is checked before returning, so a single error lists
+    /// all unknown names and invalid values at once. Variables without the
+    /// prefix are ignored.
+    pub fn from_env<I: IntoIterator<Item = (String, String)>>(
+        vars: I,
+    ) -> Result<Self, EngineError> {
+        let mut vars: Vec<_> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+
+        let mut config = EngineConfig::default();
+        let mut problems = Vec::new();
+        for (name, raw) in &vars {
+            let p = &mut problems;
+            let output = &mut config.output;
+            match &name[ENV_PREFIX.len()..] {
+                "WORKERS" | "WORKER_THREADS" => config.worker_threads = env_value(name, raw, p),
+                "CHANNEL_CAPACITY" => config.channel_capacity = env_value(name, raw, p),
+                "SHARDS" => config.shards = env_value(name, raw, p),
+                "CLIENTS" => config.clients = env_value(name, raw, p),
+                "SHARD" => config.shard = env_value(name, raw, p),
+                "MAX_CLIENT_TASKS" => config.max_client_tasks = env_value(name, raw, p),
+                "CLIENT_IDLE_SECS" => config.client_idle_secs = env_value(name, raw, p),
+                "COALESCE_ROWS" => config.coalesce_rows = env_value(name, raw, p),
+                "MAX_MEMORY" => config.max_memory = env_value(name, raw, p),
+                "SPILL_OVER" => config.spill_over = env_value(name, raw, p),
+                "PAGE_ACCOUNTS_OVER" => config.page_accounts_over = env_value(name, raw, p),
+                "BLOOM_FILTER_IDS" => config.bloom_filter_ids = env_value(name, raw, p),
+                "ERROR_POLICY" => config.error_policy = env_value(name, raw, p),
+                "AMOUNT_PRECISION" => config.amount_precision = env_value(name, raw, p),
+                "AMOUNT_FORMAT" => config.amount_format = env_value(name, raw, p),
+                "AMOUNT_MODE" => config.amount_mode = env_value(name, raw, p),
+                "ROUNDING" => config.rounding = env_value(name, raw, p),
+                "PRECISION" => config.precision = env_value(name, raw, p),
+                "DELIMITER" => config.delimiter = env_value(name, raw, p),
+                "NO_HEADERS" => config.no_headers = env_flag(name, raw, p),
+                "COLUMNS" => config.columns = env_value(name, raw, p),
+                "TYPE_ALIASES" => config.type_aliases = env_value(name, raw, p),
+                "REQUIRE_MONOTONIC" => config.require_monotonic = env_value(name, raw, p),
+                "DISPUTE_WINDOW_DAYS" => config.dispute_window_days = env_value(name, raw, p),
+                "DUPLICATE_TX" => config.duplicate_tx = env_value(name, raw, p),
+                "FX_RATES" => config.fx_rates = env_value(name, raw, p),
+                "CLOSE_POLICY" => config.close_policy = env_value(name, raw, p),
+                "CLIENT_MISMATCH" => config.client_mismatch = env_value(name, raw, p),
+                "OPEN_ON_REFERENCE" => config.open_on_reference = env_flag(name, raw, p),
+                "DISPUTE_SHORTFALL" => config.dispute_shortfall = env_value(name, raw, p),
+                "LOCKED_ALLOWS" => config.locked_allows = env_value(name, raw, p),
+                "PRIORITY_TYPES" => config.priority_types = env_value(name, raw, p),
+                "IDEMPOTENCY_WINDOW_HOURS" => {
+                    config.idempotency_window_hours = env_value(name, raw, p)
+                }
+                "MAX_TX_AMOUNT" => config.max_tx_amount = env_value(name, raw, p),
+                "DAILY_DEPOSIT_LIMIT" => config.daily_deposit_limit = env_value(name, raw, p),
+                "DAILY_WITHDRAWAL_LIMIT" => config.daily_withdrawal_limit = env_value(name, raw, p),
+                "TOTAL_DEPOSIT_LIMIT" => config.total_deposit_limit = env_value(name, raw, p),
+                "RATE_LIMIT" => config.rate_limit = env_value(name, raw, p),
+                "CLIENT_LIMITS" => config.client_limits = env_value(name, raw, p),
+                "SEGMENT_LIMITS" => config.segment_limits = env_value(name, raw, p),
+                "ACCOUNT_META" => config.account_meta = env_value(name, raw, p),
+                "VERIFY" => config.verify = env_flag(name, raw, p),
+                "DETERMINISTIC" => config.deterministic = env_flag(name, raw, p),
+                "TWO_PASS" => config.two_pass = env_flag(name, raw, p),
+                "COMPACT_RECORDS" => config.compact_records = env_value(name, raw, p),
+                "MMAP_INPUT" => config.mmap_input = env_flag(name, raw, p),
+                "PAUSE_ON_SIGNAL" => config.pause_on_signal = env_flag(name, raw, p),
+                "RELOAD_ON_SIGNAL" => config.reload_on_signal = env_flag(name, raw, p),
+                "RESUME" => config.resume = env_value(name, raw, p),
+                "STATE_KEY" => config.state_key = env_value(name, raw, p),
+                "STATE_KEY_COMMAND" => config.state_key_command = env_value(name, raw, p),
+                "MIGRATE_PLAIN_STATE" => config.migrate_plain_state = env_flag(name, raw, p),
+                "ADMIN_INPUT" => config.admin_input = env_value(name, raw, p),
+                "ADMIN_KEY" => config.admin_key = env_value(name, raw, p),
+                "ADMIN_KEY_COMMAND" => config.admin_key_command = env_value(name, raw, p),
+                "SCHEDULE" => config.schedule = env_value(name, raw, p),
+                "RECONCILE" => config.reconcile = env_value(name, raw, p),
+                "SUSPENSE_ACCOUNT" => config.suspense_account = env_value(name, raw, p),
+                "OUTPUT_TENANT_DIR" => output.tenant_dir = env_value(name, raw, p),
+                "OUTPUT_REJECTS" => output.rejects = env_value(name, raw, p),
+                "OUTPUT_LEDGER" => output.ledger = env_value(name, raw, p),
+                "OUTPUT_SAVE_STATE" => output.save_state = env_value(name, raw, p),
+                "OUTPUT_STATS" => output.stats = env_value(name, raw, p),
+                "OUTPUT_METRICS_ADDR" => output.metrics_addr = env_value(name, raw, p),
+                "OUTPUT_LOG_FORMAT" => output.log_format = env_value(name, raw, p),
+                "OUTPUT_PROGRESS" => output.progress = env_flag(name, raw, p),
+                "OUTPUT_FRAUD_REPORT" => output.fraud_report = env_value(name, raw, p),
+                "OUTPUT_CLOSED_ACCOUNTS" => output.closed_accounts = env_value(name, raw, p),
+                "OUTPUT_FIXED_DECIMALS" => output.fixed_decimals = env_flag(name, raw, p),
+                "OUTPUT_NUMBER_LOCALE" => output.number_locale = env_value(name, raw, p),
+                "OUTPUT_ONLY_LOCKED" => output.only_locked = env_flag(name, raw, p),
+                "OUTPUT_ONLY_NONZERO" => output.only_nonzero = env_flag(name, raw, p),
+                "OUTPUT_CLIENTS" => output.clients = env_value(name, raw, p),
+                "OUTPUT_COLUMNS" => output.columns = env_value(name, raw, p),
+                "OUTPUT_EXTENDED" => output.extended = env_flag(name, raw, p),
+                "OUTPUT_AUDIT_LOG" => output.audit_log = env_value(name, raw, p),
+                "OUTPUT_HASH_CHAIN" => output.hash_chain = env_flag(name, raw, p),
+                "OUTPUT_EVENTS" => output.events = env_value(name, raw, p),
+                "OUTPUT_SQLITE" => output.sqlite = env_value(name, raw, p),
+                "OUTPUT_PUBLISH" => output.publish = env_value(name, raw, p),
+                "OUTPUT_PUBLISH_KEY" => output.publish_key = env_value(name, raw, p),
+                "OUTPUT_WEBHOOK_URL" => output.webhook_url = env_value(name, raw, p),
+                "OUTPUT_NOTIFY_WITHDRAWALS_OVER" => {
+                    output.notify_withdrawals_over = env_value(name, raw, p)
+                }
+                "OUTPUT_POSTGRES_URL" => output.postgres_url = env_value(name, raw, p),
+                "OUTPUT_POSTGRES_INTERVAL_SECS" => {
+                    output.postgres_interval_secs = env_value(name, raw, p)
+                }
+                "OUTPUT_RECOVERY_REPORT" => output.recovery_report = env_value(name, raw, p),
+                "OUTPUT_RECOVERY_INTERVAL_SECS" => {
+                    output.recovery_interval_secs = env_value(name, raw, p)
+                }
+                "OUTPUT_RECOVERY_ADJUSTMENTS" => {
+                    output.recovery_adjustments = env_value(name, raw, p)
+                }
+                _ => problems.push(format!("{name}: unknown setting")),
+            }
+        }
+        problems.extend(config.problems());
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(EngineError::InvalidSettings { problems })
+        }
+    }
+
+    /// Use values from `fallback` for every setting left unset here
+    pub fn or(self, fallback: EngineConfig) -> EngineConfig {
+        let (output, other) = (self.output, fallback.output);
+        // The client slice is replaced as a whole, whichever form it takes
+        let (clients, shard) = match (self.clients, self.shard) {
+            (None, None) => (fallback.clients, fallback.shard),
+            slice => slice,
+        };
+        EngineConfig {
+            worker_threads: self.worker_threads.or(fallback.worker_threads),
+            channel_capacity: self.channel_capacity.or(fallback.channel_capacity),
+            shards: self.shards.or(fallback.shards),
+            clients,
+            shard,
+            max_client_tasks: self.max_client_tasks.or(fallback.max_client_tasks),
+            client_idle_secs: self.client_idle_secs.or(fallback.client_idle_secs),
+            coalesce_rows: self.coalesce_rows.or(fallback.coalesce_rows),
+            max_memory: self.max_memory.or(fallback.max_memory),
+            spill_over: self.spill_over.or(fallback.spill_over),
+            page_accounts_over: self.page_accounts_over.or(fallback.page_accounts_over),
+            bloom_filter_ids: self.bloom_filter_ids.or(fallback.bloom_filter_ids),
+            error_policy: self.error_policy.or(fallback.error_policy),
+            amount_precision: self.amount_precision.or(fallback.amount_precision),
+            amount_format: self.amount_format.or(fallback.amount_format),
+            amount_mode: self.amount_mode.or(fallback.amount_mode),
+            rounding: self.rounding.or(fallback.rounding),
+            precision: self.precision.or(fallback.precision),
+            delimiter: self.delimiter.or(fallback.delimiter),
+            no_headers: self.no_headers.or(fallback.no_headers),
+            columns: match (fallback.columns, self.columns) {
+                (Some(fallback), Some(columns)) => {
+                    Some(fallback.into_iter().chain(columns).collect())
+                }
+                (fallback, columns) => columns.or(fallback),
+            },
+            type_aliases: match (fallback.type_aliases, self.type_aliases) {
+                (Some(fallback), Some(aliases)) => {
+                    Some(fallback.into_iter().chain(aliases).collect())
+                }
+                (fallback, aliases) => aliases.or(fallback),
+            },
+            require_monotonic: self.require_monotonic.or(fallback.require_monotonic),
+            dispute_window_days: self.dispute_window_days.or(fallback.dispute_window_days),
+            duplicate_tx: self.duplicate_tx.or(fallback.duplicate_tx),
+            fx_rates: self.fx_rates.or(fallback.fx_rates),
+            close_policy: self.close_policy.or(fallback.close_policy),
+            client_mismatch: self.client_mismatch.or(fallback.client_mismatch),
+            open_on_reference: self.open_on_reference.or(fallback.open_on_reference),
+            dispute_shortfall: self.dispute_shortfall.or(fallback.dispute_shortfall),
+            locked_allows: self.locked_allows.or(fallback.locked_allows),
+            priority_types: self.priority_types.or(fallback.priority_types),
+            idempotency_window_hours: self
+                .idempotency_window_hours
+                .or(fallback.idempotency_window_hours),
+            max_tx_amount: self.max_tx_amount.or(fallback.max_tx_amount),
+            daily_deposit_limit: self.daily_deposit_limit.or(fallback.daily_deposit_limit),
+            daily_withdrawal_limit: self
+                .daily_withdrawal_limit
+                .or(fallback.daily_withdrawal_limit),
+            total_deposit_limit: self.total_deposit_limit.or(fallback.total_deposit_limit),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            client_limits: self.client_limits.or(fallback.client_limits),
+            segment_limits: self.segment_limits.or(fallback.segment_limits),
+            account_meta: self.account_meta.or(fallback.account_meta),
+            verify: self.verify.or(fallback.verify),
+            deterministic: self.deterministic.or(fallback.deterministic),
+            two_pass: self.two_pass.or(fallback.two_pass),
+            compact_records: self.compact_records.or(fallback.compact_records),
+            mmap_input: self.mmap_input.or(fallback.mmap_input),
+            pause_on_signal: self.pause_on_signal.or(fallback.pause_on_signal),
+            reload_on_signal: self.reload_on_signal.or(fallback.reload_on_signal),
+            resume: self.resume.or(fallback.resume),
+            state_key: self.state_key.or(fallback.state_key),
+            state_key_command: self.state_key_command.or(fallback.state_key_command),
+            migrate_plain_state: self.migrate_plain_state.or(fallback.migrate_plain_state),
+            admin_input: self.admin_input.or(fallback.admin_input),
+            admin_key: self.admin_key.or(fallback.admin_key),
+            admin_key_command: self.admin_key_command.or(fallback.admin_key_command),
+            schedule: self.schedule.or(fallback.schedule),
+            reconcile: self.reconcile.or(fallback.reconcile),
+            suspense_account: self.suspense_account.or(fallback.suspense_account),
+            output: OutputConfig {
+                tenant_dir: output.tenant_dir.or(other.tenant_dir),
+                rejects: output.rejects.or(other.rejects),
+                ledger: output.ledger.or(other.ledger),
+                save_state: output.save_state.or(other.save_state),
+                stats: output.stats.or(other.stats),
+                metrics_addr: output.metrics_addr.or(other.metrics_addr),
+                log_format: output.log_format.or(other.log_format),
+                progress: output.progress.or(other.progress),
+                fraud_report: output.fraud_report.or(other.fraud_report),
+                closed_accounts: output.closed_accounts.or(other.closed_accounts),
+                fixed_decimals: output.fixed_decimals.or(other.fixed_decimals),
+                number_locale: output.number_locale.or(other.number_locale),
+                only_locked: output.only_locked.or(other.only_locked),
+                only_nonzero: output.only_nonzero.or(other.only_nonzero),
+                clients: output.clients.or(other.clients),
+                columns: output.columns.or(other.columns),
+                extended: output.extended.or(other.extended),
+                audit_log: output.audit_log.or(other.audit_log),
+                hash_chain: output.hash_chain.or(other.hash_chain),
+                events: output.events.or(other.events),
+                sqlite: output.sqlite.or(other.sqlite),
+                publish: output.publish.or(other.publish),
+                publish_key: output.publish_key.or(other.publish_key),
+                webhook_url: output.webhook_url.or(other.webhook_url),
+                notify_withdrawals_over: output
+                    .notify_withdrawals_over
+                    .or(other.notify_withdrawals_over),
+                postgres_url: output.postgres_url.or(other.postgres_url),
+                postgres_interval_secs: output
+                    .postgres_interval_secs
+                    .or(other.postgres_interval_secs),
+                recovery_report: output.recovery_report.or(other.recovery_report),
+                recovery_interval_secs: output
+                    .recovery_interval_secs
+                    .or(other.recovery_interval_secs),
+                recovery_adjustments: output.recovery_adjustments.or(other.recovery_adjustments),
+            },
+            fraud: self.fraud.or(fallback.fraud),
+        }
+    }
+
+    /// Values that parse but cannot be used
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.worker_threads == Some(0) {
+            problems.push("worker-threads must be at least 1".to_string());
+        }
+        if self.channel_capacity == Some(0) {
+            problems.push("channel-capacity must be at least 1".to_string());
+        }
+        if self.shards == Some(0) {
+            problems.push("shards must be at least 1".to_string());
+        }
+        if self.max_client_tasks == Some(0) {
+            problems.push("max-client-tasks must be at least 1".to_string());
+        }
+        if self.client_idle_secs == Some(0) {
+            problems.push("client-idle-secs must be at least 1".to_string());
+        }
+        if self.clients.is_some() && self.shard.is_some() {
+            problems.push("clients and shard cannot both be set".to_string());
+        }
+        if self.coalesce_rows == Some(0) {
+            problems.push("coalesce-rows must be at least 1".to_string());
+        }
+        if self.output.postgres_interval_secs == Some(0) {
+            problems.push("postgres-interval-secs must be at least 1".to_string());
+        }
+        if self.output.recovery_interval_secs == Some(0) {
+            problems.push("recovery-interval-secs must be at least 1".to_string());
+        }
+        if self.bloom_filter_ids == Some(0) {
+            problems.push("bloom-filter-ids must be at least 1".to_string());
+        }
+        if let (Some(spill_over), Some(max_memory)) = (self.spill_over, self.max_memory)
+            && spill_over >= max_memory
+        {
+            problems.push("spill-over must be below max-memory".to_string());
+        }
+        if let (Some(page_over), Some(max_memory)) = (self.page_accounts_over, self.max_memory)
+            && page_over >= max_memory
+        {
+            problems.push("page-accounts-over must be below max-memory".to_string());
+        }
+        for (name, cap) in [
+            ("max-tx-amount", self.max_tx_amount),
+            ("daily-deposit-limit", self.daily_deposit_limit),
+            ("daily-withdrawal-limit", self.daily_withdrawal_limit),
+            ("total-deposit-limit", self.total_deposit_limit),
+            (
+                "notify-withdrawals-over",
+                self.output.notify_withdrawals_over,
+            ),
+        ] {
+            if cap.is_some_and(|cap| cap <= Decimal::ZERO) {
+                problems.push(format!("{name} must be positive"));
+            }
+        }
+        problems
+    }
+}
+
+/// Parse one environment variable, recording a problem if it is invalid
+fn env_value<T>(name: &str, raw: &str, problems: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    raw.parse()
+        .map_err(|e| problems.push(format!("{name}: invalid value '{raw}': {e}")))
+        .ok()
+}
+
+/// Parse a boolean environment variable (`true`/`false`, `1`/`0`, `yes`/`no`)
+fn env_flag(name: &str, raw: &str, problems: &mut Vec<String>) -> Option<bool> {
+    match raw.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => {
+            problems.push(format!(
+                "{name}: invalid value '{raw}': expected true or false"
+            ));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_policy_round_trip() {
+        for policy in [ErrorPolicy::Strict, ErrorPolicy::Skip, ErrorPolicy::Collect] {
+            assert_eq!(policy.to_string().parse::<ErrorPolicy>().unwrap(), policy);
+        }
+        assert!("lenient".parse::<ErrorPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_amount_precision_round_trip() {
+        for precision in [
+            AmountPrecision::Reject,
+            AmountPrecision::Truncate,
+            AmountPrecision::Round,
+        ] {
+            assert_eq!(
+                precision.to_string().parse::<AmountPrecision>().unwrap(),
+                precision
+            );
+        }
+        assert!("ceil".parse::<AmountPrecision>().is_err());
+    }
+
+    #[test]
+    fn test_amount_mode_round_trip() {
+        for mode in [AmountMode::Decimal, AmountMode::MinorUnits] {
+            assert_eq!(mode.to_string().parse::<AmountMode>().unwrap(), mode);
+        }
+        assert!("cents".parse::<AmountMode>().is_err());
+    }
+
+    #[test]
+    fn test_number_locale_round_trip() {
+        for locale in [
+            NumberLocale::Plain,
+            NumberLocale::En,
+            NumberLocale::De,
+            NumberLocale::Fr,
+            NumberLocale::Ch,
+        ] {
+            assert_eq!(locale.to_string().parse::<NumberLocale>().unwrap(), locale);
+        }
+        assert!("en-US".parse::<NumberLocale>().is_err());
+    }
+
+    #[test]
+    fn test_localize_numbers() {
+        assert_eq!(NumberLocale::Plain.localize("-1234567.50"), "-1234567.50");
+        assert_eq!(NumberLocale::En.localize("-1234567.50"), "-1,234,567.50");
+        assert_eq!(NumberLocale::De.localize("1234.5"), "1.234,5");
+        assert_eq!(NumberLocale::Fr.localize("123456"), "123 456");
+        assert_eq!(NumberLocale::Ch.localize("-123.4567"), "-123.4567");
+    }
+
+    #[test]
+    fn test_client_list_and_columns_round_trip() {
+        let clients: ClientList = "1, 2,7-10".parse().unwrap();
+        assert_eq!(clients.to_string(), "1,2,7-10");
+        assert!(clients.contains(8) && clients.contains(2) && !clients.contains(5));
+        assert!("10-7".parse::<ClientList>().is_err());
+        assert!("".parse::<ClientList>().is_err());
+
+        let columns: AccountColumns = "client, total,locked".parse().unwrap();
+        assert_eq!(
+            columns.0,
+            [
+                AccountColumn::Client,
+                AccountColumn::Total,
+                AccountColumn::Locked
+            ]
+        );
+        assert_eq!(columns.to_string(), "client,total,locked");
+        assert!("client,client".parse::<AccountColumns>().is_err());
+        assert!("client,balance".parse::<AccountColumns>().is_err());
+    }
+
+    #[test]
+    fn test_rounding_round_trip() {
+        for mode in [
+            RoundingMode::ToZero,
+            RoundingMode::HalfEven,
+            RoundingMode::HalfUp,
+        ] {
+            assert_eq!(mode.to_string().parse::<RoundingMode>().unwrap(), mode);
+        }
+        assert!("ceil".parse::<RoundingMode>().is_err());
+        assert_eq!("2".parse::<Precision>().unwrap().places(), 2);
+        assert_eq!(Precision::default().to_string(), "4");
+        assert!("5".parse::<Precision>().is_err());
+        assert!("-1".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn test_output_format_round_trip() {
+        for format in [OutputFormat::Csv, OutputFormat::Json] {
+            assert_eq!(format.to_string().parse::<OutputFormat>().unwrap(), format);
+        }
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_log_format_round_trip() {
+        for format in [LogFormat::Text, LogFormat::Json] {
+            assert_eq!(format.to_string().parse::<LogFormat>().unwrap(), format);
+        }
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_dispute_shortfall_policy_round_trip() {
+        for policy in [
+            DisputeShortfallPolicy::AllowNegative,
+            DisputeShortfallPolicy::Cap,
+            DisputeShortfallPolicy::Review,
+        ] {
+            assert_eq!(
+                policy
+                    .to_string()
+                    .parse::<DisputeShortfallPolicy>()
+                    .unwrap(),
+                policy
+            );
+        }
+        assert!("floor".parse::<DisputeShortfallPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_locked_types_round_trip() {
+        for raw in ["dispute,resolve,chargeback", "resolve,chargeback", "none"] {
+            assert_eq!(raw.parse::<LockedTypes>().unwrap().to_string(), raw);
+        }
+        let types: LockedTypes = "chargeback, dispute".parse().unwrap();
+        assert!(types.allows(&TransactionType::Dispute));
+        assert!(!types.allows(&TransactionType::Resolve));
+        assert!(!types.allows(&TransactionType::Deposit));
+        assert!("deposit".parse::<LockedTypes>().is_err());
+        assert!("".parse::<LockedTypes>().is_err());
+    }
+
+    #[test]
+    fn test_duplicate_tx_policy_round_trip() {
+        for policy in [DuplicateTxPolicy::Global, DuplicateTxPolicy::PerClient] {
+            assert_eq!(
+                policy.to_string().parse::<DuplicateTxPolicy>().unwrap(),
+                policy
+            );
+        }
+        assert_eq!(DuplicateTxPolicy::Global.key(7, 1), TxKey::global(1));
+        assert_eq!(
+            DuplicateTxPolicy::PerClient.key(7, 1),
+            TxKey::per_client(7, 1)
+        );
+    }
+
+    #[test]
+    fn test_parse_engine_config() {
+        let config: EngineConfig = toml::from_str(
+            r#"
+            channel-capacity = 500
+            error-policy = "collect"
+            dispute-window-days = 30
+            client-mismatch = "review"
+            max-tx-amount = "100.5"
+            max-memory = "2G"
+            spill-over = "1.5G"
+
+            [output]
+            log-format = "json"
+            stats = "stats.json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.channel_capacity, Some(500));
+        assert_eq!(config.error_policy, Some(ErrorPolicy::Collect));
+        assert_eq!(config.dispute_window_days, Some(30));
+        assert_eq!(config.client_mismatch, Some(ClientMismatchPolicy::Review));
+        assert_eq!(config.max_tx_amount, Some(Decimal::new(1005, 1)));
+        assert_eq!(config.max_memory, Some(MemorySize(2 << 30)));
+        assert_eq!(config.spill_over, Some(MemorySize(3 << 29)));
+        let clash = "max-memory = \"1G\"\nspill-over = \"1G\"";
+        let clash: EngineConfig = toml::from_str(clash).unwrap();
+        assert_eq!(clash.problems().len(), 1);
+        assert_eq!(config.output.log_format, Some(LogFormat::Json));
+        assert_eq!(config.output.stats, Some(PathBuf::from("stats.json")));
+        assert_eq!(config.amount_precision, None);
+
+        assert!(toml::from_str::<EngineConfig>("error-policy = \"loose\"").is_err());
+        assert!(toml::from_str::<EngineConfig>("workers = 4").is_err());
+    }
+
+    #[test]
+    fn test_env_config_reports_every_problem() {
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let config = EngineConfig::from_env(vars(&[
+            ("ENGINE_WORKERS", "4"),
+            ("ENGINE_ERROR_POLICY", "collect"),
+            ("ENGINE_OUTPUT_PROGRESS", "yes"),
+            ("ENGINE_OUTPUT_SQLITE", "run.db"),
+            ("PATH", "/usr/bin"),
+        ]))
+        .unwrap();
+        assert_eq!(config.worker_threads, Some(4));
+        assert_eq!(config.error_policy, Some(ErrorPolicy::Collect));
+        assert_eq!(config.output.progress, Some(true));
+        assert_eq!(config.output.sqlite, Some(PathBuf::from("run.db")));
+
+        match EngineConfig::from_env(vars(&[
+            ("ENGINE_ERROR_POLICY", "loose"),
+            ("ENGINE_CHANNEL_CAPACITY", "0"),
+            ("ENGINE_VERIFY", "maybe"),
+            ("ENGINE_COLOUR", "red"),
+            ("ENGINE_DAILY_DEPOSIT_LIMIT", "-5"),
+            ("ENGINE_MAX_MEMORY", "lots"),
+        ])) {
+            Err(EngineError::InvalidSettings { problems }) => assert_eq!(problems.len(), 6),
+            other => panic!("expected InvalidSettings, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_config_or_prefers_self() {
+        let env = EngineConfig {
+            error_policy: Some(ErrorPolicy::Strict),
+            ..Default::default()
+        };
+        let file = EngineConfig {
+            error_policy: Some(ErrorPolicy::Collect),
+            channel_capacity: Some(10),
+            ..Default::default()
+        };
+        let merged = env.or(file);
+        assert_eq!(merged.error_policy, Some(ErrorPolicy::Strict));
+        assert_eq!(merged.channel_capacity, Some(10));
+    }
+}
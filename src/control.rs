@@ -0,0 +1,162 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Notify, watch};
+
+use crate::error::EngineError;
+
+/// Rows handed to client tasks and not yet handled, so ingestion can wait
+/// for the tasks to catch up before it pauses
+#[derive(Debug, Default)]
+pub struct InFlight {
+    rows: AtomicUsize,
+    drained: Notify,
+}
+
+impl InFlight {
+    /// Count rows about to be sent to a client task
+    pub fn add(&self, rows: usize) {
+        self.rows.fetch_add(rows, Ordering::AcqRel);
+    }
+
+    /// Count rows a client task has handled, or that never reached one
+    pub fn done(&self, rows: usize) {
+        if self.rows.fetch_sub(rows, Ordering::AcqRel) == rows {
+            self.drained.notify_waiters();
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows.load(Ordering::Acquire)
+    }
+
+    /// Wait until every row handed out has been handled
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.drained.notified();
+            tokio::pin!(notified);
+            // Registered before the count is read, so a last `done` in
+            // between still wakes us
+            notified.as_mut().enable();
+            if self.rows() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Whether ingestion should be paused, switched by `SIGUSR1` (pause) and
+/// `SIGUSR2` (resume)
+#[derive(Debug, Clone)]
+pub struct PauseSwitch {
+    paused: watch::Receiver<bool>,
+}
+
+impl PauseSwitch {
+    /// Listen for the pause and resume signals for the rest of the process
+    #[cfg(unix)]
+    pub fn from_signals() -> Result<Self, EngineError> {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut pause = signal(SignalKind::user_defined1())?;
+        let mut resume = signal(SignalKind::user_defined2())?;
+        let (switch, paused) = watch::channel(false);
+        tokio::spawn(async move {
+            loop {
+                let paused = tokio::select! {
+                    Some(()) = pause.recv() => true,
+                    Some(()) = resume.recv() => false,
+                    else => break,
+                };
+                if switch.send(paused).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(PauseSwitch { paused })
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_signals() -> Result<Self, EngineError> {
+        Err(EngineError::Usage(
+            "--pause-on-signal needs SIGUSR1 and SIGUSR2, which only Unix has".to_string(),
+        ))
+    }
+
+    /// A switch flipped by hand rather than by signals
+    pub fn manual() -> (watch::Sender<bool>, Self) {
+        let (switch, paused) = watch::channel(false);
+        (switch, PauseSwitch { paused })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait until ingestion is resumed; returns at once if it is not paused
+    /// or the switch is gone
+    pub async fn resumed(&mut self) {
+        let _ = self.paused.wait_for(|paused| !paused).await;
+    }
+}
+
+/// Call `reload` on every `SIGHUP` for the rest of the process
+#[cfg(unix)]
+pub fn on_hangup(mut reload: impl FnMut() + Send + 'static) -> Result<(), EngineError> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            reload();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_hangup(_reload: impl FnMut() + Send + 'static) -> Result<(), EngineError> {
+    Err(EngineError::Usage(
+        "--reload-on-signal needs SIGHUP, which only Unix has".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Give spawned tasks a chance to run
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drained_waits_for_every_row() {
+        let in_flight = Arc::new(InFlight::default());
+        in_flight.drained().await;
+
+        in_flight.add(3);
+        let waiting = tokio::spawn({
+            let in_flight = Arc::clone(&in_flight);
+            async move { in_flight.drained().await }
+        });
+        in_flight.done(2);
+        settle().await;
+        assert!(!waiting.is_finished());
+        in_flight.done(1);
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pause_switch() {
+        let (switch, mut pause) = PauseSwitch::manual();
+        assert!(!pause.is_paused());
+        switch.send(true).unwrap();
+        assert!(pause.is_paused());
+        let resumed = tokio::spawn(async move { pause.resumed().await });
+        settle().await;
+        assert!(!resumed.is_finished());
+        switch.send(false).unwrap();
+        resumed.await.unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+use chrono::{DateTime, SecondsFormat};
+use csv_async::{Position, StringRecord};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::models::Transaction;
+use crate::outcome::MalformedRow;
+use crate::validate::{COLUMNS, ColumnIndex, ParseOptions, RowParser};
+
+/// Writer schema of the Avro messages `AvroDecoder` reads, to register with
+/// the schema registry for the topic
+pub const AVRO_SCHEMA: &str = r#"{
+  "type": "record",
+  "name": "Transaction",
+  "namespace": "transaction_engine",
+  "fields": [
+    {"name": "type", "type": "string"},
+    {"name": "client", "type": "long"},
+    {"name": "tx", "type": "long"},
+    {"name": "amount", "type": ["null", "string"], "default": null},
+    {"name": "timestamp", "type": ["null", {"type": "long", "logicalType": "timestamp-millis"}], "default": null},
+    {"name": "currency", "type": ["null", "string"], "default": null},
+    {"name": "to_currency", "type": ["null", "string"], "default": null},
+    {"name": "idempotency_key", "type": ["null", "string"], "default": null}
+  ]
+}"#;
+
+/// Leading byte of a message in the schema registry's wire format, followed
+/// by the schema ID as four big-endian bytes
+const CONFLUENT_MAGIC: u8 = 0;
+
+/// Turns one message taken off a stream into a transaction.
+///
+/// Every decoder ends in the same validation as a CSV row, so a message is
+/// malformed for the same reasons a row would be. `offset` stands in for
+/// the line number in errors.
+pub trait Decoder: Send + Sync {
+    fn decode(&self, message: &[u8], offset: u64) -> Result<Transaction, MalformedRow>;
+}
+
+/// Encoding of the messages on a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// One CSV row per message, columns in canonical order
+    Csv,
+    /// One JSON object per message, keyed by column name
+    Json,
+    /// Avro in the schema registry's wire format, written with
+    /// [`AVRO_SCHEMA`]
+    Avro,
+    /// Protobuf in the schema registry's wire format, written with
+    /// `proto/transaction.proto`
+    Protobuf,
+}
+
+impl MessageFormat {
+    /// The decoder for this format. Protobuf needs the `protobuf` feature.
+    pub fn decoder(self, options: ParseOptions) -> Result<Box<dyn Decoder>, EngineError> {
+        Ok(match self {
+            MessageFormat::Csv => Box::new(CsvDecoder::new(options)),
+            MessageFormat::Json => Box::new(JsonDecoder::new(options)),
+            MessageFormat::Avro => Box::new(AvroDecoder::new(options)),
+            #[cfg(feature = "protobuf")]
+            MessageFormat::Protobuf => Box::new(ProtobufDecoder::new(options)),
+            #[cfg(not(feature = "protobuf"))]
+            MessageFormat::Protobuf => {
+                return Err(EngineError::Usage(
+                    "protobuf messages need a build with the protobuf feature".to_string(),
+                ));
+            }
+        })
+    }
+}
+
+impl FromStr for MessageFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(MessageFormat::Csv),
+            "json" => Ok(MessageFormat::Json),
+            "avro" => Ok(MessageFormat::Avro),
+            "protobuf" => Ok(MessageFormat::Protobuf),
+            other => Err(EngineError::Usage(format!(
+                "invalid message format '{other}' (expected csv, json, avro or protobuf)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for MessageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MessageFormat::Csv => "csv",
+            MessageFormat::Json => "json",
+            MessageFormat::Avro => "avro",
+            MessageFormat::Protobuf => "protobuf",
+        })
+    }
+}
+
+/// Parser for fields laid out in canonical column order
+fn canonical_parser(options: ParseOptions) -> RowParser {
+    RowParser::with_columns(ColumnIndex::in_order(), options)
+}
+
+/// Validate decoded fields in canonical column order
+fn validate(
+    parser: &RowParser,
+    fields: Vec<String>,
+    offset: u64,
+) -> Result<Transaction, MalformedRow> {
+    let mut record: StringRecord = fields.into();
+    let mut position = Position::new();
+    position.set_line(offset);
+    position.set_record(offset);
+    record.set_position(Some(position));
+    parser.validate_row(Ok(record))
+}
+
+/// A message that could not be decoded into fields at all
+fn undecodable(offset: u64, message: impl fmt::Display) -> MalformedRow {
+    MalformedRow {
+        line: Some(offset),
+        fields: Vec::new(),
+        error: EngineError::Decode(format!("message {offset}: {message}")),
+    }
+}
+
+/// A timestamp in milliseconds as the RFC 3339 text a CSV file would hold;
+/// one out of range is left as digits for the parser to reject
+fn timestamp_millis(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis).map_or_else(
+        || millis.to_string(),
+        |time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    )
+}
+
+/// The schema ID and payload of a message in the schema registry's wire
+/// format
+fn confluent_payload(message: &[u8]) -> Result<(u32, &[u8]), String> {
+    match message {
+        [CONFLUENT_MAGIC, a, b, c, d, payload @ ..] => {
+            Ok((u32::from_be_bytes([*a, *b, *c, *d]), payload))
+        }
+        [CONFLUENT_MAGIC, ..] => Err("truncated schema registry header".to_string()),
+        _ => Err("missing schema registry magic byte".to_string()),
+    }
+}
+
+/// Messages holding one CSV row without a header
+#[derive(Debug, Clone)]
+pub struct CsvDecoder {
+    parser: RowParser,
+}
+
+impl CsvDecoder {
+    pub fn new(options: ParseOptions) -> Self {
+        CsvDecoder {
+            parser: canonical_parser(options),
+        }
+    }
+
+    /// Read the columns in the order of `headers` instead
+    pub fn with_headers(
+        headers: &StringRecord,
+        options: ParseOptions,
+    ) -> Result<Self, EngineError> {
+        Ok(CsvDecoder {
+            parser: RowParser::new(headers, options)?,
+        })
+    }
+}
+
+impl Decoder for CsvDecoder {
+    fn decode(&self, message: &[u8], offset: u64) -> Result<Transaction, MalformedRow> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(message);
+        let fields: Vec<String> = match reader.records().next() {
+            Some(Ok(record)) => record.iter().map(str::to_string).collect(),
+            Some(Err(e)) => return Err(undecodable(offset, e)),
+            None => return Err(undecodable(offset, "empty message")),
+        };
+        validate(&self.parser, fields, offset)
+    }
+}
+
+/// Messages holding one JSON object with the columns as keys; numbers may
+/// be given as numbers or strings, and other keys are ignored
+#[derive(Debug, Clone)]
+pub struct JsonDecoder {
+    parser: RowParser,
+}
+
+impl JsonDecoder {
+    pub fn new(options: ParseOptions) -> Self {
+        JsonDecoder {
+            parser: canonical_parser(options),
+        }
+    }
+}
+
+impl Decoder for JsonDecoder {
+    fn decode(&self, message: &[u8], offset: u64) -> Result<Transaction, MalformedRow> {
+        let object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(message).map_err(|e| undecodable(offset, e))?;
+        let fields = COLUMNS
+            .iter()
+            .map(|column| match object.get(*column) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(text)) => text.clone(),
+                Some(other) => other.to_string(),
+            })
+            .collect();
+        validate(&self.parser, fields, offset)
+    }
+}
+
+/// Avro messages in the schema registry's wire format.
+///
+/// The schema is not fetched from the registry: every message must have
+/// been written with [`AVRO_SCHEMA`], under whatever ID the registry gave
+/// it, or under `schema_id` once that is set.
+#[derive(Debug, Clone)]
+pub struct AvroDecoder {
+    parser: RowParser,
+    schema_id: Option<u32>,
+}
+
+impl AvroDecoder {
+    pub fn new(options: ParseOptions) -> Self {
+        AvroDecoder {
+            parser: canonical_parser(options),
+            schema_id: None,
+        }
+    }
+
+    /// Reject messages written under any other schema ID
+    pub fn with_schema_id(mut self, schema_id: u32) -> Self {
+        self.schema_id = Some(schema_id);
+        self
+    }
+
+    /// The fields of an Avro-encoded transaction record, in canonical order
+    fn fields(payload: &[u8]) -> Result<Vec<String>, String> {
+        let mut reader = AvroReader { bytes: payload };
+        let fields = vec![
+            reader.string()?.to_string(),
+            reader.long()?.to_string(),
+            reader.long()?.to_string(),
+            reader
+                .optional(AvroReader::string)?
+                .unwrap_or_default()
+                .to_string(),
+            reader
+                .optional(AvroReader::long)?
+                .map(timestamp_millis)
+                .unwrap_or_default(),
+            reader
+                .optional(AvroReader::string)?
+                .unwrap_or_default()
+                .to_string(),
+            reader
+                .optional(AvroReader::string)?
+                .unwrap_or_default()
+                .to_string(),
+            reader
+                .optional(AvroReader::string)?
+                .unwrap_or_default()
+                .to_string(),
+        ];
+        match reader.bytes.is_empty() {
+            true => Ok(fields),
+            false => Err(format!("{} bytes after the record", reader.bytes.len())),
+        }
+    }
+}
+
+impl Decoder for AvroDecoder {
+    fn decode(&self, message: &[u8], offset: u64) -> Result<Transaction, MalformedRow> {
+        let (schema_id, payload) =
+            confluent_payload(message).map_err(|e| undecodable(offset, e))?;
+        if let Some(expected) = self.schema_id
+            && schema_id != expected
+        {
+            return Err(undecodable(
+                offset,
+                format!("schema ID {schema_id}, expected {expected}"),
+            ));
+        }
+        let fields = Self::fields(payload).map_err(|e| undecodable(offset, e))?;
+        validate(&self.parser, fields, offset)
+    }
+}
+
+/// Reads Avro's binary encoding from the front of a buffer
+struct AvroReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> AvroReader<'a> {
+    /// A zigzag-encoded variable-length `long`, which `int` shares
+    fn long(&mut self) -> Result<i64, String> {
+        let mut value: u64 = 0;
+        for (i, byte) in self.bytes.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.bytes = &self.bytes[i + 1..];
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err("truncated or overlong integer".to_string())
+    }
+
+    fn string(&mut self) -> Result<&'a str, String> {
+        let len = usize::try_from(self.long()?).map_err(|_| "negative string length")?;
+        if len > self.bytes.len() {
+            return Err("truncated string".to_string());
+        }
+        let (text, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        std::str::from_utf8(text).map_err(|e| e.to_string())
+    }
+
+    /// A `["null", T]` union
+    fn optional<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.long()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            branch => Err(format!("union branch {branch} out of range")),
+        }
+    }
+}
+
+/// The message of `proto/transaction.proto`
+#[cfg(feature = "protobuf")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TransactionMessage {
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(uint64, tag = "3")]
+    pub tx: u64,
+    /// Decimal text, so no precision is lost on the way
+    #[prost(string, optional, tag = "4")]
+    pub amount: Option<String>,
+    #[prost(message, optional, tag = "5")]
+    pub timestamp: Option<prost_types::Timestamp>,
+    #[prost(string, optional, tag = "6")]
+    pub currency: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub to_currency: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    pub idempotency_key: Option<String>,
+}
+
+/// Protobuf messages in the schema registry's wire format, holding the
+/// first message type of their schema, `TransactionMessage`
+#[cfg(feature = "protobuf")]
+#[derive(Debug, Clone)]
+pub struct ProtobufDecoder {
+    parser: RowParser,
+}
+
+#[cfg(feature = "protobuf")]
+impl ProtobufDecoder {
+    pub fn new(options: ParseOptions) -> Self {
+        ProtobufDecoder {
+            parser: canonical_parser(options),
+        }
+    }
+
+    /// The payload after the message indexes that follow the schema ID,
+    /// which must point at the schema's first message
+    fn message_payload(payload: &[u8]) -> Result<&[u8], String> {
+        let mut reader = AvroReader { bytes: payload };
+        // The indexes are zigzag varints, as Avro's are; a count of zero is
+        // short for the single index 0
+        let count = reader.long()?;
+        for _ in 0..count {
+            if reader.long()? != 0 {
+                return Err("message type other than the first".to_string());
+            }
+        }
+        Ok(reader.bytes)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl Decoder for ProtobufDecoder {
+    fn decode(&self, message: &[u8], offset: u64) -> Result<Transaction, MalformedRow> {
+        use prost::Message;
+
+        let message = confluent_payload(message)
+            .and_then(|(_, payload)| Self::message_payload(payload))
+            .map_err(|e| undecodable(offset, e))?;
+        let decoded = TransactionMessage::decode(message).map_err(|e| undecodable(offset, e))?;
+        let timestamp = decoded.timestamp.map(|t| {
+            u32::try_from(t.nanos)
+                .ok()
+                .and_then(|nanos| DateTime::from_timestamp(t.seconds, nanos))
+                .map_or_else(
+                    || t.seconds.to_string(),
+                    |time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                )
+        });
+        let fields = vec![
+            decoded.r#type,
+            decoded.client.to_string(),
+            decoded.tx.to_string(),
+            decoded.amount.unwrap_or_default(),
+            timestamp.unwrap_or_default(),
+            decoded.currency.unwrap_or_default(),
+            decoded.to_currency.unwrap_or_default(),
+            decoded.idempotency_key.unwrap_or_default(),
+        ];
+        validate(&self.parser, fields, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RowErrorKind;
+    use crate::models::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn long(value: i64) -> Vec<u8> {
+        let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (zigzag & 0x7f) as u8;
+            zigzag >>= 7;
+            if zigzag == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn string(text: &str) -> Vec<u8> {
+        [long(text.len() as i64), text.as_bytes().to_vec()].concat()
+    }
+
+    fn avro_message(schema_id: u32, client: i64, amount: Option<&str>) -> Vec<u8> {
+        let mut message = vec![CONFLUENT_MAGIC];
+        message.extend(schema_id.to_be_bytes());
+        message.extend(string("deposit"));
+        message.extend(long(client));
+        message.extend(long(7));
+        match amount {
+            Some(amount) => message.extend([long(1), string(amount)].concat()),
+            None => message.extend(long(0)),
+        }
+        message.extend([long(1), long(60_000)].concat());
+        message.extend([long(0), long(0), long(0)].concat());
+        message
+    }
+
+    #[test]
+    fn test_avro_decoder() {
+        let decoder = AvroDecoder::new(ParseOptions::default()).with_schema_id(3);
+        let transaction = decoder.decode(&avro_message(3, 1, Some("2.5")), 1).unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Deposit);
+        assert_eq!((transaction.client, transaction.tx), (1, 7));
+        assert_eq!(transaction.amount, Some(dec!(2.5)));
+        assert_eq!(
+            transaction.timestamp,
+            Some("1970-01-01T00:01:00Z".parse().unwrap())
+        );
+
+        // Fields that decode but fail validation are malformed as in CSV
+        let malformed = decoder
+            .decode(&avro_message(3, -1, Some("2.5")), 2)
+            .unwrap_err();
+        assert_eq!(malformed.line, Some(2));
+        assert_eq!(malformed.fields[..3], ["deposit", "-1", "7"]);
+        assert!(matches!(
+            malformed.error,
+            EngineError::InvalidRow {
+                kind: RowErrorKind::InvalidInteger | RowErrorKind::OutOfRange,
+                ..
+            }
+        ));
+
+        let wrong_schema = decoder.decode(&avro_message(4, 1, None), 3).unwrap_err();
+        assert_eq!(wrong_schema.error.reason_code(), "decode");
+        let truncated = avro_message(3, 1, Some("2.5"));
+        let truncated = decoder
+            .decode(&truncated[..truncated.len() - 2], 4)
+            .unwrap_err();
+        assert_eq!(truncated.error.reason_code(), "decode");
+    }
+
+    #[test]
+    fn test_csv_and_json_decoders() {
+        let csv = MessageFormat::Csv.decoder(ParseOptions::default()).unwrap();
+        let transaction = csv.decode(b"withdrawal, 2, 9, 1.25", 1).unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Withdrawal);
+        assert_eq!(transaction.amount, Some(dec!(1.25)));
+
+        let json = "json".parse::<MessageFormat>().unwrap();
+        let json = json.decoder(ParseOptions::default()).unwrap();
+        let transaction = json
+            .decode(
+                br#"{"type": "deposit", "client": 2, "tx": 10, "amount": "3", "note": "x"}"#,
+                2,
+            )
+            .unwrap();
+        assert_eq!((transaction.client, transaction.tx), (2, 10));
+        assert_eq!(transaction.amount, Some(dec!(3)));
+        let missing = json
+            .decode(br#"{"type": "deposit", "tx": 11}"#, 3)
+            .unwrap_err();
+        assert_eq!(missing.error.reason_code(), "missing_field");
+        assert_eq!(
+            json.decode(b"not json", 4).unwrap_err().error.reason_code(),
+            "decode"
+        );
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn test_protobuf_decoder() {
+        use prost::Message;
+
+        let encoded = TransactionMessage {
+            r#type: "deposit".to_string(),
+            client: 4,
+            tx: 12,
+            amount: Some("0.0001".to_string()),
+            timestamp: Some(prost_types::Timestamp {
+                seconds: 60,
+                nanos: 0,
+            }),
+            currency: Some("EUR".to_string()),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let mut message = vec![CONFLUENT_MAGIC, 0, 0, 0, 9, 0];
+        message.extend(encoded);
+
+        let decoder = ProtobufDecoder::new(ParseOptions::default());
+        let transaction = decoder.decode(&message, 1).unwrap();
+        assert_eq!((transaction.client, transaction.tx), (4, 12));
+        assert_eq!(transaction.amount, Some(dec!(0.0001)));
+        assert_eq!(transaction.currency.unwrap().as_str(), "EUR");
+
+        // Indexes pointing at a second message type
+        message[5] = 2;
+        message.insert(6, 2);
+        assert!(decoder.decode(&message, 2).is_err());
+    }
+}
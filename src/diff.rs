@@ -0,0 +1,151 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::config::OutputFormat;
+use crate::error::EngineError;
+use crate::models::{ClientId, Currency};
+use crate::reconcile::{BalanceSheet, SheetEntry};
+
+/// How an account's balance in one currency changed between two files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Only in the later file
+    Added,
+    /// Only in the earlier file
+    Removed,
+    /// In both, with at least one different value
+    Changed,
+}
+
+/// What changed for one client and currency; amounts are the later value
+/// minus the earlier one, counting a missing balance as zero
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceDelta {
+    pub client: ClientId,
+    /// Left out of the CSV unless either file has currencies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Option<Currency>>,
+    pub change: Change,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// The later `locked` value, set only when it differs from the earlier one
+    pub locked: Option<bool>,
+}
+
+/// Every balance that differs between `before` and `after`, ordered by
+/// client and currency; unchanged balances are left out
+pub fn diff(before: &BalanceSheet, after: &BalanceSheet) -> Vec<BalanceDelta> {
+    let keys: BTreeSet<_> = before
+        .iter()
+        .chain(after.iter())
+        .map(|(client, currency, _)| (client, currency))
+        .collect();
+    let with_currency = keys.iter().any(|(_, currency)| currency.is_some());
+
+    keys.into_iter()
+        .filter_map(|(client, currency)| {
+            let (old, new) = (before.get(client, currency), after.get(client, currency));
+            let change = match (old, new) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+                (old, new) if old == new => return None,
+                _ => Change::Changed,
+            };
+            let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+            let locked = match (old.locked, new.locked) {
+                (Some(old), Some(new)) if old != new => Some(new),
+                (None, Some(true)) if change == Change::Added => Some(true),
+                _ => None,
+            };
+            Some(BalanceDelta {
+                client,
+                currency: with_currency.then_some(currency),
+                change,
+                available: delta(old, new, |entry| entry.balance.available),
+                held: delta(old, new, |entry| entry.balance.held),
+                total: delta(old, new, |entry| entry.balance.total),
+                locked,
+            })
+        })
+        .collect()
+}
+
+fn delta(old: SheetEntry, new: SheetEntry, field: impl Fn(&SheetEntry) -> Decimal) -> Decimal {
+    (field(&new) - field(&old)).normalize()
+}
+
+/// Write deltas as CSV rows or a JSON array
+pub fn write_deltas<W: io::Write>(
+    deltas: &[BalanceDelta],
+    format: OutputFormat,
+    writer: W,
+) -> Result<(), EngineError> {
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for delta in deltas {
+                wtr.serialize(delta)?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(writer, deltas).map_err(|e| EngineError::Io(e.into()))?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(csv: &str) -> BalanceSheet {
+        BalanceSheet::read(csv.as_bytes()).unwrap()
+    }
+
+    fn deltas(before: &str, after: &str) -> String {
+        let mut csv = Vec::new();
+        write_deltas(
+            &diff(&sheet(before), &sheet(after)),
+            OutputFormat::Csv,
+            &mut csv,
+        )
+        .unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    #[test]
+    fn test_diff_lists_changed_clients() {
+        assert_eq!(
+            deltas(
+                "client,available,held,total,locked\n2,5,0,5,false\n",
+                "client,available,held,total,locked\n2,0,2.5,2.5,true\n"
+            ),
+            "client,change,available,held,total,locked\n2,changed,-5,2.5,-2.5,true\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_ignores_rescaled_balances() {
+        let before = sheet("client,available,held,total,locked\n1,10,0,10,false\n");
+        let after = sheet("client,available,held,total,locked\n1,10.00,0,10,false\n");
+        assert!(diff(&before, &after).is_empty());
+    }
+
+    #[test]
+    fn test_diff_lists_added_and_removed_clients() {
+        assert_eq!(
+            deltas(
+                "client,available,held,total,locked\n3,1,0,1,false\n",
+                "client,available,held,total,locked\n4,3,0,3,false\n"
+            ),
+            "client,change,available,held,total,locked\n\
+             3,removed,-1,0,-1,\n\
+             4,added,3,0,3,\n"
+        );
+    }
+}
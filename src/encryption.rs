@@ -0,0 +1,285 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt::{self, Write as _};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+
+/// First bytes of every encrypted state file, followed by the nonce and the
+/// AES-256-GCM ciphertext
+pub const MAGIC: &[u8; 8] = b"RTESEAL1";
+
+const NONCE_LEN: usize = 12;
+
+/// 256-bit AES-GCM key for state files, written as 64 hex digits.
+///
+/// Never printed: its `Debug` output leaves the key out.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct StateKey([u8; 32]);
+
+impl StateKey {
+    /// Take the key a command prints on stdout, such as a KMS or secret
+    /// store client.
+    ///
+    /// The command is run by `sh -c`; surrounding whitespace in its output
+    /// is ignored.
+    pub fn from_command(command: &str) -> Result<Self, EngineError> {
+        run_key_command(command, EngineError::Encryption)?
+            .parse()
+            .map_err(|_| {
+                EngineError::Encryption("key command did not print 64 hex digits".to_string())
+            })
+    }
+
+    /// The key given directly, or else the one `command` prints
+    pub fn resolve(
+        key: Option<StateKey>,
+        command: Option<&str>,
+    ) -> Result<Option<Self>, EngineError> {
+        match (key, command) {
+            (Some(key), _) => Ok(Some(key)),
+            (None, Some(command)) => Self::from_command(command).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+}
+
+impl fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StateKey(..)")
+    }
+}
+
+impl FromStr for StateKey {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EngineError::Usage("a state key must be 64 hex digits".to_string());
+        let key = decode_hex(s).ok_or_else(invalid)?;
+        let key = key.try_into().map_err(|_| invalid())?;
+        Ok(StateKey(key))
+    }
+}
+
+impl TryFrom<String> for StateKey {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+/// Secret shared with whoever signs the rows of an admin input.
+///
+/// Never printed: its `Debug` output leaves the key out.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AdminKey(Vec<u8>);
+
+impl AdminKey {
+    /// Take the key a command prints on stdout, such as a secret store
+    /// client
+    pub fn from_command(command: &str) -> Result<Self, EngineError> {
+        run_key_command(command, EngineError::AdminChannel)?
+            .parse()
+            .map_err(|_| EngineError::AdminChannel("key command printed nothing".to_string()))
+    }
+
+    /// The key given directly, or else the one `command` prints
+    pub fn resolve(
+        key: Option<AdminKey>,
+        command: Option<&str>,
+    ) -> Result<Option<Self>, EngineError> {
+        match (key, command) {
+            (Some(key), _) => Ok(Some(key)),
+            (None, Some(command)) => Self::from_command(command).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn mac(&self, fields: &[String]) -> Hmac<Sha256> {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(fields.join("\x1f").as_bytes());
+        mac
+    }
+
+    /// HMAC-SHA256, in hex, of a row's fields in canonical column order
+    /// joined by `0x1F`
+    pub fn sign(&self, fields: &[String]) -> String {
+        let mut hex = String::with_capacity(64);
+        for byte in self.mac(fields).finalize().into_bytes() {
+            let _ = write!(hex, "{byte:02x}");
+        }
+        hex
+    }
+
+    /// Whether `signature` is the one `sign` gives for these fields,
+    /// compared in constant time
+    pub fn verify(&self, fields: &[String], signature: &str) -> bool {
+        decode_hex(signature).is_some_and(|bytes| self.mac(fields).verify_slice(&bytes).is_ok())
+    }
+}
+
+impl fmt::Debug for AdminKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminKey(..)")
+    }
+}
+
+impl FromStr for AdminKey {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(EngineError::Usage(
+                "an admin key cannot be empty".to_string(),
+            ));
+        }
+        Ok(AdminKey(s.as_bytes().to_vec()))
+    }
+}
+
+impl TryFrom<String> for AdminKey {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+/// What a key command prints on stdout, trimmed, with its failures reported
+/// through `error`.
+///
+/// The command is run by `sh -c`.
+pub fn run_key_command(
+    command: &str,
+    error: fn(String) -> EngineError,
+) -> Result<String, EngineError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| error(format!("cannot run key command: {e}")))?;
+    if !output.status.success() {
+        return Err(error(format!(
+            "key command failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Bytes written as pairs of hex digits, or `None` for anything else
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Whether `bytes` start like a file `seal` wrote
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encrypt `plaintext` under a fresh random nonce.
+///
+/// The header is authenticated along with the data, so a file cut short or
+/// altered anywhere fails to open.
+pub fn seal(key: &StateKey, plaintext: &[u8]) -> Result<Vec<u8>, EngineError> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce)
+        .map_err(|e| EngineError::Encryption(format!("no random nonce: {e}")))?;
+    let ciphertext = key
+        .cipher()
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: MAGIC,
+            },
+        )
+        .map_err(|_| EngineError::Encryption("encryption failed".to_string()))?;
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt what `seal` wrote
+pub fn open(key: &StateKey, sealed: &[u8]) -> Result<Vec<u8>, EngineError> {
+    let body = sealed
+        .strip_prefix(MAGIC.as_slice())
+        .filter(|body| body.len() >= NONCE_LEN)
+        .ok_or_else(|| EngineError::Encryption("not an encrypted state file".to_string()))?;
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    key.cipher()
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: MAGIC,
+            },
+        )
+        .map_err(|_| EngineError::Encryption("wrong key, or the file was altered".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_seal_and_open() {
+        let key: StateKey = KEY.parse().unwrap();
+        let sealed = seal(&key, b"balances").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(8).any(|w| w == b"balances"));
+        assert_eq!(open(&key, &sealed).unwrap(), b"balances");
+        // A fresh nonce every time
+        assert_ne!(seal(&key, b"balances").unwrap(), sealed);
+
+        let other: StateKey = KEY.replace("1f", "ff").parse().unwrap();
+        assert!(matches!(
+            open(&other, &sealed),
+            Err(EngineError::Encryption(_))
+        ));
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(open(&key, &altered).is_err());
+        assert!(open(&key, b"balances").is_err());
+    }
+
+    #[test]
+    fn test_state_key() {
+        assert!("00".parse::<StateKey>().is_err());
+        assert!(KEY.replace('0', "g").parse::<StateKey>().is_err());
+        assert_eq!(
+            format!("{:?}", KEY.parse::<StateKey>().unwrap()),
+            "StateKey(..)"
+        );
+
+        let key = StateKey::from_command(&format!("echo ' {KEY} '")).unwrap();
+        assert_eq!(key, KEY.parse().unwrap());
+        assert!(StateKey::from_command("exit 3").is_err());
+        assert!(StateKey::from_command("echo nope").is_err());
+        assert!(StateKey::resolve(None, Some("exit 1")).is_err());
+        assert!(StateKey::resolve(None, None).unwrap().is_none());
+    }
+}
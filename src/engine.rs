@@ -0,0 +1,179 @@
+use csv_async::{AsyncReaderBuilder, Trim};
+use futures::StreamExt;
+use std::io;
+use std::sync::Arc;
+
+use crate::account::{AccountView, NumberFormat, write_accounts};
+use crate::compact::Tombstones;
+use crate::config::{ClosedAccounts, Rules};
+use crate::error::EngineError;
+use crate::models::{Account, AccountsMap, ClientId, Transaction, TransactionsMap};
+use crate::outcome::{OutcomeTally, Rejected, TransactionOutcome};
+use crate::ownership::{TxOwners, stored_owner};
+use crate::snapshot::Snapshot;
+use crate::transaction::handle_transaction_with;
+use crate::validate::{ParseOptions, RowParser};
+
+/// The engine's state and settlement rules behind a synchronous API, for
+/// programs that embed it rather than run the command-line tool.
+///
+/// Transactions are applied one at a time in submission order, through the
+/// same checks a run makes before fanning rows out to client tasks. Risk
+/// limits, fraud rules and the other per-client guards are left to the
+/// command-line tool.
+#[derive(Debug, Default)]
+pub struct Engine {
+    accounts: AccountsMap,
+    transactions: TransactionsMap,
+    owners: TxOwners,
+    rules: Rules,
+    parse: ParseOptions,
+}
+
+impl Engine {
+    pub fn new(rules: Rules) -> Self {
+        Engine {
+            owners: TxOwners::new(rules.duplicate_tx),
+            rules,
+            ..Default::default()
+        }
+    }
+
+    /// Carry on from the state saved in a snapshot, keeping its tombstones
+    /// unless `rules` bring their own
+    pub fn restore(mut snapshot: Snapshot, mut rules: Rules) -> Self {
+        if rules.tombstones.is_none() && !snapshot.tombstones.is_empty() {
+            let saved = std::mem::take(&mut snapshot.tombstones);
+            rules.tombstones = Some(Arc::new(Tombstones::restore(saved)));
+        }
+        let mut engine = Engine::new(rules);
+        (engine.accounts, engine.transactions) = snapshot.restore();
+        engine
+    }
+
+    /// Interpret the amounts and types of `process_csv` input this way
+    pub fn with_parse_options(mut self, parse: ParseOptions) -> Self {
+        self.parse = parse;
+        self
+    }
+
+    /// Apply one transaction
+    pub fn submit(&mut self, transaction: Transaction) -> TransactionOutcome {
+        let owner_of = |tx| stored_owner(tx, [&self.transactions], &self.rules);
+        match self.owners.check(&transaction, owner_of) {
+            Ok(()) => handle_transaction_with(
+                transaction,
+                &self.accounts,
+                &self.transactions,
+                &self.rules,
+            ),
+            Err(error) => Err(Rejected { transaction, error }),
+        }
+    }
+
+    /// Apply every row of a transactions CSV file with a header row, counting
+    /// what happened to each. Malformed rows are counted and skipped.
+    pub fn process_csv(&mut self, input: &[u8]) -> Result<OutcomeTally, EngineError> {
+        // Reading from memory never waits, so the reader needs no runtime
+        futures::executor::block_on(async {
+            let mut reader = AsyncReaderBuilder::new()
+                .trim(Trim::All)
+                .flexible(true)
+                .create_reader(input);
+            let parser = RowParser::new(reader.headers().await?, self.parse.clone())?;
+            let mut records = reader.records();
+            let mut tally = OutcomeTally::default();
+            while let Some(row) = records.next().await {
+                match parser.validate_row(row) {
+                    Ok(transaction) => tally.record(&self.submit(transaction)),
+                    Err(malformed) => tally.record_malformed(&malformed),
+                }
+            }
+            Ok(tally)
+        })
+    }
+
+    /// Apply every row of an Arrow record batch, counting what happened to
+    /// each as `process_csv` does
+    #[cfg(feature = "arrow")]
+    pub fn process_batch(
+        &mut self,
+        batch: &arrow_array::RecordBatch,
+    ) -> Result<OutcomeTally, EngineError> {
+        let mut tally = OutcomeTally::default();
+        for row in crate::arrow::read_batch(batch, self.parse.clone(), 1)? {
+            match row {
+                Ok(transaction) => tally.record(&self.submit(transaction)),
+                Err(malformed) => tally.record_malformed(&malformed),
+            }
+        }
+        Ok(tally)
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&client).map(|account| account.clone())
+    }
+
+    /// Every account, in client ID order
+    pub fn accounts(&self) -> Vec<Account> {
+        let mut accounts: Vec<_> = self.accounts.iter().map(|e| e.value().clone()).collect();
+        accounts.sort_by_key(|account| account.client);
+        accounts
+    }
+
+    /// Write the accounts CSV a run would print
+    pub fn write_accounts<W: io::Write>(
+        &self,
+        closed: ClosedAccounts,
+        writer: W,
+    ) -> Result<(), EngineError> {
+        write_accounts(
+            &self.accounts,
+            closed,
+            NumberFormat::rounded(self.rules.rounding),
+            &AccountView::default(),
+            writer,
+        )
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let snapshot = Snapshot::capture(&self.accounts, &self.transactions);
+        match &self.rules.tombstones {
+            Some(tombstones) => snapshot.with_tombstones(tombstones),
+            None => snapshot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_process_csv_then_submit() {
+        let mut engine = Engine::new(Rules::default());
+        let tally = engine
+            .process_csv(
+                b"type, client, tx, amount\n\
+                  deposit, 1, 1, 10.0\n\
+                  deposit, 2, 2, 5.0\n\
+                  withdrawal, 1, 3, 20.0\n\
+                  deposit, 1, x, 1.0\n",
+            )
+            .unwrap();
+        assert_eq!(tally.applied, 2);
+        assert_eq!(tally.rejected_total(), 1);
+        assert_eq!(tally.malformed_total(), 1);
+
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None);
+        assert!(engine.submit(dispute).is_ok());
+        let balance = engine.account(1).unwrap().balance(None);
+        assert_eq!((balance.available, balance.held), (dec!(0), dec!(10)));
+
+        let restored = Engine::restore(engine.snapshot(), Rules::default());
+        assert_eq!(restored.accounts(), engine.accounts());
+        assert!(restored.account(3).is_none());
+    }
+}
@@ -0,0 +1,398 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::fraud::FraudAction;
+use crate::limits::{LimitKind, RateLimit};
+use crate::memory::MemorySize;
+use crate::models::{ClientId, Currency, TxId};
+
+/// Why a single CSV field failed validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowErrorKind {
+    MissingField,
+    UnknownType,
+    InvalidInteger,
+    OutOfRange,
+    InvalidDecimal,
+    TooManyDecimals,
+    InvalidTimestamp,
+    InvalidCurrency,
+    InvalidUtf8,
+}
+
+impl RowErrorKind {
+    /// Stable machine-readable code for the validation failure
+    pub fn code(&self) -> &'static str {
+        match self {
+            RowErrorKind::MissingField => "missing_field",
+            RowErrorKind::UnknownType => "unknown_type",
+            RowErrorKind::InvalidInteger => "invalid_integer",
+            RowErrorKind::OutOfRange => "out_of_range",
+            RowErrorKind::InvalidDecimal => "invalid_decimal",
+            RowErrorKind::TooManyDecimals => "too_many_decimals",
+            RowErrorKind::InvalidTimestamp => "invalid_timestamp",
+            RowErrorKind::InvalidCurrency => "invalid_currency",
+            RowErrorKind::InvalidUtf8 => "invalid_utf8",
+        }
+    }
+}
+
+impl fmt::Display for RowErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RowErrorKind::MissingField => "missing value",
+            RowErrorKind::UnknownType => "unknown transaction type",
+            RowErrorKind::InvalidInteger => "not an integer",
+            RowErrorKind::OutOfRange => "value out of range",
+            RowErrorKind::InvalidDecimal => "not a decimal number",
+            RowErrorKind::TooManyDecimals => "more than 4 decimal places",
+            RowErrorKind::InvalidTimestamp => "not an RFC 3339 timestamp or Unix seconds",
+            RowErrorKind::InvalidCurrency => "not a currency code of up to 8 letters or digits",
+            RowErrorKind::InvalidUtf8 => "not valid UTF-8",
+        })
+    }
+}
+
+/// All failure categories produced by the engine
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("{0}")]
+    Usage(String),
+
+    #[error("Invalid config file {}: {message}", path.display())]
+    Config { path: PathBuf, message: String },
+
+    #[error("Invalid settings: {}", problems.join("; "))]
+    InvalidSettings { problems: Vec<String> },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("CSV parse error: {0}")]
+    CsvParse(#[from] csv_async::Error),
+
+    #[error("CSV write error: {0}")]
+    CsvWrite(#[from] csv::Error),
+
+    #[error("Line {line}, column '{column}': {kind} (value: '{value}')")]
+    InvalidRow {
+        line: u64,
+        column: &'static str,
+        value: String,
+        kind: RowErrorKind,
+    },
+
+    #[error("Snapshot error: {0}")]
+    Snapshot(String),
+
+    #[error("Spill file error: {0}")]
+    Spill(String),
+
+    #[error("State encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Admin channel error: {0}")]
+    AdminChannel(String),
+
+    #[error("FX rates error: {0}")]
+    FxRates(String),
+
+    #[error("Expected balances error: {0}")]
+    Reconcile(String),
+
+    #[error("Client {client} is listed in both {first} and {second}")]
+    MergeConflict {
+        client: ClientId,
+        first: String,
+        second: String,
+    },
+
+    #[error("Risk limits error: {0}")]
+    Limits(String),
+
+    #[error("Account metadata error: {0}")]
+    AccountMeta(String),
+
+    #[error("Schedule error: {0}")]
+    Schedule(String),
+
+    #[error("Arrow input error: {0}")]
+    Arrow(String),
+
+    #[error("Message decoding error: {0}")]
+    Decode(String),
+
+    #[error("SQLite export error: {0}")]
+    Sqlite(String),
+
+    #[error("Postgres sync error: {0}")]
+    Postgres(String),
+
+    #[error("Redis state store error: {0}")]
+    Redis(String),
+
+    #[error("Injected fault: {0}")]
+    InjectedFault(String),
+
+    #[error("Event publishing failed: {0}")]
+    Publish(String),
+
+    #[error("Client {client} not found")]
+    UnknownClient { client: ClientId },
+
+    #[error("Invalid or missing amount (Client: {client}, Tx: {tx})")]
+    InvalidAmount { client: ClientId, tx: TxId },
+
+    #[error("Account {client} is locked (Tx: {tx})")]
+    AccountLocked { client: ClientId, tx: TxId },
+
+    #[error("Account {client} is closed (Tx: {tx})")]
+    AccountClosed { client: ClientId, tx: TxId },
+
+    #[error("Account {client} is on review hold (Tx: {tx})")]
+    AccountOnHold { client: ClientId, tx: TxId },
+
+    #[error("Account {client} is not on review hold (Tx: {tx})")]
+    NotOnHold { client: ClientId, tx: TxId },
+
+    #[error("Account {client} cannot be closed while funds are held (Tx: {tx})")]
+    FundsHeld { client: ClientId, tx: TxId },
+
+    #[error(
+        "Account {client} cannot be closed with {amount} {} remaining (Tx: {tx})",
+        currency_name(currency)
+    )]
+    BalanceRemaining {
+        client: ClientId,
+        tx: TxId,
+        currency: Option<Currency>,
+        amount: Decimal,
+    },
+
+    #[error(
+        "Insufficient funds (Client: {client}, Tx: {tx}, Amount: {amount}, Available: {available})"
+    )]
+    InsufficientFunds {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        available: Decimal,
+    },
+
+    #[error("Duplicate transaction ID {tx} (Client: {client})")]
+    DuplicateTx { client: ClientId, tx: TxId },
+
+    #[error("Idempotency key '{key}' was already used (Client: {client}, Tx: {tx})")]
+    DuplicateIdempotencyKey {
+        client: ClientId,
+        tx: TxId,
+        key: String,
+    },
+
+    #[error("Transaction {tx} not found (Client: {client})")]
+    UnknownTx { client: ClientId, tx: TxId },
+
+    #[error("Transaction {tx} was compacted once settled (Client: {client})")]
+    TxCompacted { client: ClientId, tx: TxId },
+
+    #[error("Transaction {tx} belongs to client {owner}, not client {client}")]
+    ClientMismatch {
+        client: ClientId,
+        tx: TxId,
+        owner: ClientId,
+    },
+
+    #[error(
+        "Transaction {tx} is in {}, not {} (Client: {client})",
+        currency_name(expected),
+        currency_name(currency)
+    )]
+    CurrencyMismatch {
+        client: ClientId,
+        tx: TxId,
+        currency: Option<Currency>,
+        expected: Option<Currency>,
+    },
+
+    #[error(
+        "No FX rate from {} to {} (Client: {client}, Tx: {tx})",
+        currency_name(from),
+        currency_name(to)
+    )]
+    NoFxRate {
+        client: ClientId,
+        tx: TxId,
+        from: Option<Currency>,
+        to: Option<Currency>,
+    },
+
+    #[error("{amount} exceeds the {limit} limit of {cap} (Client: {client}, Tx: {tx})")]
+    LimitExceeded {
+        client: ClientId,
+        tx: TxId,
+        limit: LimitKind,
+        amount: Decimal,
+        cap: Decimal,
+    },
+
+    #[error("Client {client} is over their rate limit of {limit} (Tx: {tx})")]
+    RateLimited {
+        client: ClientId,
+        tx: TxId,
+        limit: RateLimit,
+    },
+
+    #[error("Fraud rule {rule} decided to {action} transaction {tx} (Client: {client})")]
+    FraudSuspected {
+        client: ClientId,
+        tx: TxId,
+        rule: &'static str,
+        action: FraudAction,
+    },
+
+    #[error("Middleware rejected transaction {tx}: {reason} (Client: {client})")]
+    MiddlewareRejected {
+        client: ClientId,
+        tx: TxId,
+        reason: String,
+    },
+
+    #[error(
+        "No handler is registered for transaction type '{tx_type}' (Client: {client}, Tx: {tx})"
+    )]
+    UnhandledType {
+        client: ClientId,
+        tx: TxId,
+        tx_type: String,
+    },
+
+    #[error("Transaction {tx} is not a deposit (Client: {client})")]
+    NotADeposit { client: ClientId, tx: TxId },
+
+    #[error("Transaction {tx} is already under dispute (Client: {client})")]
+    AlreadyDisputed { client: ClientId, tx: TxId },
+
+    #[error("Transaction {tx} is not under dispute (Client: {client})")]
+    NotDisputed { client: ClientId, tx: TxId },
+
+    #[error(
+        "Transaction {tx} at {timestamp} is earlier than the client's previous row at {previous} (Client: {client})"
+    )]
+    OutOfOrder {
+        client: ClientId,
+        tx: TxId,
+        timestamp: DateTime<Utc>,
+        previous: DateTime<Utc>,
+    },
+
+    #[error(
+        "Dispute of transaction {tx} at {disputed} is outside the dispute window for the deposit at {deposited} (Client: {client})"
+    )]
+    DisputeWindowExpired {
+        client: ClientId,
+        tx: TxId,
+        deposited: DateTime<Utc>,
+        disputed: DateTime<Utc>,
+    },
+
+    #[error("Balance overflow on account {client}; account frozen")]
+    Overflow { client: ClientId },
+
+    #[error("{count} account invariant violations detected")]
+    InvariantViolations { count: usize },
+
+    #[error("{count} errors collected during the run")]
+    ErrorsCollected { count: usize },
+
+    #[error("{count} differences from the expected balances")]
+    BalancesDiffer { count: usize },
+
+    #[error("{failed} of {total} fixtures failed")]
+    FixturesFailed { failed: usize, total: usize },
+
+    #[error(
+        "Estimated state memory {estimated} exceeds the limit of {limit} \
+         ({accounts} accounts, {transactions} transactions)"
+    )]
+    MemoryLimit {
+        estimated: MemorySize,
+        limit: MemorySize,
+        accounts: usize,
+        transactions: usize,
+    },
+}
+
+impl EngineError {
+    /// Stable machine-readable code for the failure category
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            EngineError::Usage(_) => "usage",
+            EngineError::Config { .. } => "config",
+            EngineError::InvalidSettings { .. } => "invalid_settings",
+            EngineError::Io(_) => "io_error",
+            EngineError::CsvParse(_) => "csv_parse",
+            EngineError::CsvWrite(_) => "csv_write",
+            EngineError::InvalidRow { kind, .. } => kind.code(),
+            EngineError::Snapshot(_) => "snapshot",
+            EngineError::Spill(_) => "spill",
+            EngineError::Encryption(_) => "encryption",
+            EngineError::AdminChannel(_) => "admin_channel",
+            EngineError::FxRates(_) => "fx_rates",
+            EngineError::Reconcile(_) => "reconcile",
+            EngineError::MergeConflict { .. } => "merge_conflict",
+            EngineError::Limits(_) => "limits",
+            EngineError::AccountMeta(_) => "account_meta",
+            EngineError::Schedule(_) => "schedule",
+            EngineError::Arrow(_) => "arrow",
+            EngineError::Decode(_) => "decode",
+            EngineError::Sqlite(_) => "sqlite",
+            EngineError::Postgres(_) => "postgres",
+            EngineError::Redis(_) => "redis",
+            EngineError::InjectedFault(_) => "injected_fault",
+            EngineError::Publish(_) => "publish",
+            EngineError::UnknownClient { .. } => "unknown_client",
+            EngineError::InvalidAmount { .. } => "invalid_amount",
+            EngineError::AccountLocked { .. } => "account_locked",
+            EngineError::AccountClosed { .. } => "account_closed",
+            EngineError::AccountOnHold { .. } => "account_on_hold",
+            EngineError::NotOnHold { .. } => "not_on_hold",
+            EngineError::FundsHeld { .. } => "funds_held",
+            EngineError::BalanceRemaining { .. } => "balance_remaining",
+            EngineError::InsufficientFunds { .. } => "insufficient_funds",
+            EngineError::DuplicateTx { .. } => "duplicate_tx",
+            EngineError::DuplicateIdempotencyKey { .. } => "duplicate_idempotency_key",
+            EngineError::UnknownTx { .. } => "unknown_tx",
+            EngineError::TxCompacted { .. } => "tx_compacted",
+            EngineError::ClientMismatch { .. } => "client_mismatch",
+            EngineError::CurrencyMismatch { .. } => "currency_mismatch",
+            EngineError::NoFxRate { .. } => "no_fx_rate",
+            EngineError::LimitExceeded { limit, .. } => limit.reason_code(),
+            EngineError::RateLimited { .. } => "rate_limited",
+            EngineError::FraudSuspected { action, .. } => match action {
+                FraudAction::Reject => "fraud_rejected",
+                _ => "fraud_hold",
+            },
+            EngineError::MiddlewareRejected { .. } => "middleware_rejected",
+            EngineError::UnhandledType { .. } => "unknown_type",
+            EngineError::NotADeposit { .. } => "not_a_deposit",
+            EngineError::AlreadyDisputed { .. } => "already_disputed",
+            EngineError::NotDisputed { .. } => "not_disputed",
+            EngineError::OutOfOrder { .. } => "out_of_order",
+            EngineError::DisputeWindowExpired { .. } => "dispute_window_expired",
+            EngineError::Overflow { .. } => "overflow",
+            EngineError::InvariantViolations { .. } => "invariant_violations",
+            EngineError::ErrorsCollected { .. } => "errors_collected",
+            EngineError::BalancesDiffer { .. } => "balances_differ",
+            EngineError::FixturesFailed { .. } => "fixtures_failed",
+            EngineError::MemoryLimit { .. } => "memory_limit",
+        }
+    }
+}
+
+/// Currency for messages; rows without one are described as such
+fn currency_name(currency: &Option<Currency>) -> String {
+    currency.map_or_else(|| "no currency".to_string(), |c| c.to_string())
+}
@@ -0,0 +1,214 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::models::{Account, AccountStatus, Balance, ClientId, Currency, TransactionType, TxId};
+use crate::outcome::{Applied, Shortfall};
+
+/// What happened to an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EventKind {
+    DepositApplied,
+    WithdrawalApplied,
+    DisputeOpened,
+    DisputeResolved,
+    ChargebackApplied,
+    ConversionApplied,
+    ReviewHoldPlaced,
+    ReviewHoldReleased,
+    AccountClosed,
+    /// A transaction of a type registered in `Rules::handlers`
+    CustomApplied,
+    /// The account became locked, following the transaction that locked it
+    AccountLocked,
+}
+
+impl EventKind {
+    /// Event for an applied transaction of this type
+    fn for_type(tx_type: &TransactionType) -> Self {
+        match tx_type {
+            TransactionType::Deposit => EventKind::DepositApplied,
+            TransactionType::Withdrawal => EventKind::WithdrawalApplied,
+            TransactionType::Dispute => EventKind::DisputeOpened,
+            TransactionType::Resolve => EventKind::DisputeResolved,
+            TransactionType::Chargeback => EventKind::ChargebackApplied,
+            TransactionType::Convert => EventKind::ConversionApplied,
+            TransactionType::Hold => EventKind::ReviewHoldPlaced,
+            TransactionType::Release => EventKind::ReviewHoldReleased,
+            TransactionType::Close => EventKind::AccountClosed,
+            TransactionType::Custom(_) => EventKind::CustomApplied,
+        }
+    }
+}
+
+/// A balance in one currency before and after a mutation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BalanceChange {
+    pub currency: Option<Currency>,
+    pub before: Balance,
+    pub after: Balance,
+}
+
+/// One line of the `--events` stream
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Decimal>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub balances: Vec<BalanceChange>,
+    /// Account status after the event, when the event changed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<AccountStatus>,
+    /// How a dispute of more than was available was handled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shortfall: Option<Shortfall>,
+}
+
+/// Events for an applied transaction, given the client's account just before
+/// and just after it.
+///
+/// Every applied transaction yields one event carrying the balances it
+/// changed; one that locked the account is followed by an `AccountLocked`
+/// event.
+pub fn events_for(applied: &Applied, before: &Account, after: &Account) -> Vec<Event> {
+    let transaction = &applied.transaction;
+    let balances = applied
+        .currencies()
+        .into_iter()
+        .map(|currency| BalanceChange {
+            currency,
+            before: before.balance(currency),
+            after: after.balance(currency),
+        })
+        .filter(|change| change.before != change.after)
+        .collect();
+    let status_changed = before.status != after.status;
+    let mut events = vec![Event {
+        event: EventKind::for_type(&transaction.tx_type),
+        client: transaction.client,
+        tx: transaction.tx,
+        amount: Some(applied.amount).filter(|amount| !amount.is_zero()),
+        balances,
+        status: status_changed.then_some(after.status),
+        shortfall: applied.shortfall,
+    }];
+    if status_changed && after.is_locked() && !before.is_locked() {
+        events.push(Event {
+            event: EventKind::AccountLocked,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: None,
+            balances: Vec::new(),
+            status: Some(after.status),
+            shortfall: None,
+        });
+    }
+    events
+}
+
+/// Writes events as JSON lines
+pub struct EventWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl EventWriter<File> {
+    /// Create (or truncate) the events file at `path`
+    pub fn create(path: &Path) -> Result<Self, EngineError> {
+        Ok(EventWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(inner: W) -> Self {
+        EventWriter {
+            writer: BufWriter::new(inner),
+        }
+    }
+
+    /// Append one event
+    pub fn write(&mut self, event: &Event) -> Result<(), EngineError> {
+        serde_json::to_writer(&mut self.writer, event).map_err(io::Error::from)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Flush buffered events and return the underlying writer
+    pub fn finish(self) -> Result<W, EngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| EngineError::Io(e.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Transaction;
+
+    fn chargeback() -> (Applied, Account, Account) {
+        let mut before = Account::new(1);
+        *before.balance_mut(None) = Balance {
+            available: Decimal::ZERO,
+            held: Decimal::from(5),
+            total: Decimal::from(5),
+        };
+        let mut after = Account::new(1);
+        after.balance_mut(None);
+        after.status = AccountStatus::ChargebackLocked;
+        let applied = Applied {
+            transaction: Transaction::new(TransactionType::Chargeback, 1, 7, None),
+            amount: Decimal::from(5),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        };
+        (applied, before, after)
+    }
+
+    fn written(events: &[Event]) -> Vec<serde_json::Value> {
+        let mut writer = EventWriter::new(Vec::new());
+        for event in events {
+            writer.write(event).unwrap();
+        }
+        let text = String::from_utf8(writer.finish().unwrap()).unwrap();
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_chargeback_event_carries_changed_balances() {
+        let (applied, before, after) = chargeback();
+        let lines = written(&events_for(&applied, &before, &after));
+        assert_eq!(lines[0]["event"], "ChargebackApplied");
+        assert_eq!(lines[0]["amount"], "5");
+        assert_eq!(lines[0]["balances"][0]["before"]["held"], "5");
+        assert_eq!(lines[0]["balances"][0]["after"]["total"], "0");
+        assert_eq!(lines[0]["status"], "chargeback_locked");
+    }
+
+    #[test]
+    fn test_locking_event_is_followed_by_account_locked() {
+        let (applied, before, after) = chargeback();
+        let lines = written(&events_for(&applied, &before, &after));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["event"], "AccountLocked");
+        assert_eq!(lines[1]["status"], "chargeback_locked");
+        assert!(lines[1].get("balances").is_none());
+    }
+
+    #[test]
+    fn test_unchanged_status_emits_no_lock() {
+        let (applied, mut before, after) = chargeback();
+        before.status = after.status;
+        let events = events_for(&applied, &before, &after);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status, None);
+    }
+}
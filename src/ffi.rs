@@ -0,0 +1,406 @@
+//! C interface for embedding the engine in another process, built with the
+//! `ffi` feature. `build.rs` generates the matching `include/transaction_engine.h`
+//! with cbindgen.
+//!
+//! Amounts cross the boundary as signed 64-bit counts of ten-thousandths,
+//! the engine's precision, and timestamps as Unix seconds. Every function
+//! only borrows the pointers it is given.
+
+use chrono::DateTime;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use std::ffi::{CStr, c_char};
+use std::ptr;
+use std::slice;
+
+use crate::config::Rules;
+use crate::engine::Engine;
+use crate::models::{AccountStatus, ClientId, Currency, Transaction, TransactionType, TxId};
+use crate::snapshot::Snapshot;
+
+/// Ten-thousandths in one unit of an amount
+const AMOUNT_SCALE: u32 = 4;
+
+/// An engine with its own accounts and transactions
+pub struct TeEngine(Engine);
+
+/// What a call did
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeStatus {
+    Ok = 0,
+    /// The transaction was valid but a business rule turned it down
+    Rejected = 1,
+    /// No account for the client, or no balance in the currency
+    NotFound = 2,
+    /// A null pointer, an unreadable string or a value out of range
+    InvalidArgument = 3,
+}
+
+/// Type of a transaction; custom types are not available over this
+/// interface
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeTxType {
+    Deposit = 0,
+    Withdrawal = 1,
+    Dispute = 2,
+    Resolve = 3,
+    Chargeback = 4,
+    Convert = 5,
+    Hold = 6,
+    Release = 7,
+    Close = 8,
+}
+
+impl From<TeTxType> for TransactionType {
+    fn from(tx_type: TeTxType) -> Self {
+        match tx_type {
+            TeTxType::Deposit => TransactionType::Deposit,
+            TeTxType::Withdrawal => TransactionType::Withdrawal,
+            TeTxType::Dispute => TransactionType::Dispute,
+            TeTxType::Resolve => TransactionType::Resolve,
+            TeTxType::Chargeback => TransactionType::Chargeback,
+            TeTxType::Convert => TransactionType::Convert,
+            TeTxType::Hold => TransactionType::Hold,
+            TeTxType::Release => TransactionType::Release,
+            TeTxType::Close => TransactionType::Close,
+        }
+    }
+}
+
+/// One transaction, the fields of an input row
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TeTransaction {
+    pub tx_type: TeTxType,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub has_amount: bool,
+    /// In ten-thousandths
+    pub amount: i64,
+    pub has_timestamp: bool,
+    /// Unix seconds
+    pub timestamp: i64,
+    /// Currency code, or null for none
+    pub currency: *const c_char,
+    /// Currency a `convert` moves funds into, or null
+    pub to_currency: *const c_char,
+    /// Idempotency key, or null
+    pub idempotency_key: *const c_char,
+}
+
+/// Lifecycle state of an account
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeAccountStatus {
+    Active = 0,
+    Frozen = 1,
+    ChargebackLocked = 2,
+    Closed = 3,
+    ReviewHold = 4,
+}
+
+impl From<AccountStatus> for TeAccountStatus {
+    fn from(status: AccountStatus) -> Self {
+        match status {
+            AccountStatus::Active => TeAccountStatus::Active,
+            AccountStatus::Frozen => TeAccountStatus::Frozen,
+            AccountStatus::ChargebackLocked => TeAccountStatus::ChargebackLocked,
+            AccountStatus::Closed => TeAccountStatus::Closed,
+            AccountStatus::ReviewHold => TeAccountStatus::ReviewHold,
+        }
+    }
+}
+
+/// One client's balance in one currency, amounts in ten-thousandths
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TeAccount {
+    pub client: ClientId,
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    pub locked: bool,
+    pub status: TeAccountStatus,
+}
+
+/// Bytes owned by the engine library, released with `te_buffer_free`
+#[repr(C)]
+#[derive(Debug)]
+pub struct TeBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// Read an optional C string
+unsafe fn optional_str<'a>(raw: *const c_char) -> Result<Option<&'a str>, ()> {
+    if raw.is_null() {
+        return Ok(None);
+    }
+    unsafe { CStr::from_ptr(raw) }
+        .to_str()
+        .map(Some)
+        .map_err(|_| ())
+}
+
+unsafe fn optional_currency(raw: *const c_char) -> Result<Option<Currency>, ()> {
+    match unsafe { optional_str(raw) }? {
+        Some(code) => code.parse().map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+fn to_units(amount: Decimal) -> Option<i64> {
+    amount
+        .checked_mul(Decimal::from(10_i64.pow(AMOUNT_SCALE)))?
+        .to_i64()
+}
+
+impl TeTransaction {
+    unsafe fn to_transaction(self) -> Result<Transaction, ()> {
+        let timestamp = match self.has_timestamp {
+            true => Some(DateTime::from_timestamp(self.timestamp, 0).ok_or(())?),
+            false => None,
+        };
+        Ok(Transaction {
+            tx_type: self.tx_type.into(),
+            client: self.client,
+            tx: self.tx,
+            amount: self
+                .has_amount
+                .then(|| Decimal::new(self.amount, AMOUNT_SCALE)),
+            timestamp,
+            currency: unsafe { optional_currency(self.currency) }?,
+            to_currency: unsafe { optional_currency(self.to_currency) }?,
+            idempotency_key: unsafe { optional_str(self.idempotency_key) }?.map(str::to_string),
+        })
+    }
+}
+
+/// Copy `text` into a caller's buffer as a NUL-terminated string, cut short
+/// if it does not fit
+unsafe fn write_str(text: &str, buffer: *mut c_char, capacity: usize) {
+    if buffer.is_null() || capacity == 0 {
+        return;
+    }
+    let len = text.len().min(capacity - 1);
+    unsafe {
+        ptr::copy_nonoverlapping(text.as_ptr().cast(), buffer, len);
+        *buffer.add(len) = 0;
+    }
+}
+
+/// A new engine with no accounts and the default rules; free it with
+/// `te_engine_free`
+#[unsafe(no_mangle)]
+pub extern "C" fn te_engine_new() -> *mut TeEngine {
+    Box::into_raw(Box::new(TeEngine(Engine::new(Rules::default()))))
+}
+
+/// An engine carrying on from a snapshot written by `te_engine_snapshot` or
+/// by a run's `--save-state`, or null if the bytes are not a snapshot
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_engine_restore(data: *const u8, len: usize) -> *mut TeEngine {
+    if data.is_null() {
+        return ptr::null_mut();
+    }
+    let bytes = unsafe { slice::from_raw_parts(data, len) };
+    match Snapshot::read(bytes) {
+        Ok(snapshot) => Box::into_raw(Box::new(TeEngine(Engine::restore(
+            snapshot,
+            Rules::default(),
+        )))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `engine` must be null or come from `te_engine_new` or
+/// `te_engine_restore`, and must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_engine_free(engine: *mut TeEngine) {
+    if !engine.is_null() {
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+/// Apply one transaction. When it is rejected the reason code, such as
+/// `insufficient_funds`, is copied into `reason` if that is not null.
+///
+/// # Safety
+///
+/// `engine` and `transaction` must be valid, the strings `transaction`
+/// points to NUL-terminated, and `reason` null or writable for
+/// `reason_capacity` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_engine_submit(
+    engine: *mut TeEngine,
+    transaction: *const TeTransaction,
+    reason: *mut c_char,
+    reason_capacity: usize,
+) -> TeStatus {
+    let (Some(engine), Some(transaction)) =
+        (unsafe { engine.as_mut() }, unsafe { transaction.as_ref() })
+    else {
+        return TeStatus::InvalidArgument;
+    };
+    let Ok(transaction) = (unsafe { transaction.to_transaction() }) else {
+        return TeStatus::InvalidArgument;
+    };
+    match engine.0.submit(transaction) {
+        Ok(_) => TeStatus::Ok,
+        Err(rejected) => {
+            unsafe { write_str(rejected.error.reason_code(), reason, reason_capacity) };
+            TeStatus::Rejected
+        }
+    }
+}
+
+/// Look up a client's balance in `currency`, or without a currency if it is
+/// null
+///
+/// # Safety
+///
+/// `engine` must be valid, `currency` null or NUL-terminated, and
+/// `account` writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_engine_get_account(
+    engine: *const TeEngine,
+    client: ClientId,
+    currency: *const c_char,
+    account: *mut TeAccount,
+) -> TeStatus {
+    let (Some(engine), false) = (unsafe { engine.as_ref() }, account.is_null()) else {
+        return TeStatus::InvalidArgument;
+    };
+    let Ok(currency) = (unsafe { optional_currency(currency) }) else {
+        return TeStatus::InvalidArgument;
+    };
+    let Some(found) = engine.0.account(client) else {
+        return TeStatus::NotFound;
+    };
+    let Some(balance) = found.balances.get(&currency).copied().or_else(|| {
+        // An account that never moved funds has a zero balance without a
+        // currency
+        (found.balances.is_empty() && currency.is_none()).then(Default::default)
+    }) else {
+        return TeStatus::NotFound;
+    };
+    let (Some(available), Some(held), Some(total)) = (
+        to_units(balance.available),
+        to_units(balance.held),
+        to_units(balance.total),
+    ) else {
+        return TeStatus::InvalidArgument;
+    };
+    unsafe {
+        *account = TeAccount {
+            client,
+            available,
+            held,
+            total,
+            locked: found.is_locked(),
+            status: found.status.into(),
+        };
+    }
+    TeStatus::Ok
+}
+
+/// Serialize the engine's state in the snapshot format `--resume` reads.
+/// Free the result with `te_buffer_free`; it is empty if serializing failed.
+///
+/// # Safety
+///
+/// `engine` must be valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_engine_snapshot(engine: *const TeEngine) -> TeBuffer {
+    let mut bytes = Vec::new();
+    if let Some(engine) = unsafe { engine.as_ref() }
+        && engine.0.snapshot().write(&mut bytes).is_err()
+    {
+        bytes.clear();
+    }
+    let bytes = Box::into_raw(bytes.into_boxed_slice());
+    TeBuffer {
+        len: bytes.len(),
+        data: bytes.cast(),
+    }
+}
+
+/// # Safety
+///
+/// `buffer` must come from `te_engine_snapshot` and not be freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn te_buffer_free(buffer: TeBuffer) {
+    if !buffer.data.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(tx_type: TeTxType, tx: TxId, amount: Option<i64>) -> TeTransaction {
+        TeTransaction {
+            tx_type,
+            client: 1,
+            tx,
+            has_amount: amount.is_some(),
+            amount: amount.unwrap_or_default(),
+            has_timestamp: false,
+            timestamp: 0,
+            currency: ptr::null(),
+            to_currency: ptr::null(),
+            idempotency_key: ptr::null(),
+        }
+    }
+
+    #[test]
+    fn test_submit_snapshot_and_restore() {
+        unsafe {
+            let engine = te_engine_new();
+            let deposit = transaction(TeTxType::Deposit, 1, Some(15_000));
+            assert_eq!(
+                te_engine_submit(engine, &deposit, ptr::null_mut(), 0),
+                TeStatus::Ok
+            );
+            let withdrawal = transaction(TeTxType::Withdrawal, 2, Some(20_000));
+            let mut reason = [0 as c_char; 8];
+            assert_eq!(
+                te_engine_submit(engine, &withdrawal, reason.as_mut_ptr(), reason.len()),
+                TeStatus::Rejected
+            );
+            assert_eq!(CStr::from_ptr(reason.as_ptr()).to_str(), Ok("insuffi"));
+
+            let snapshot = te_engine_snapshot(engine);
+            te_engine_free(engine);
+            let restored = te_engine_restore(snapshot.data, snapshot.len);
+            te_buffer_free(snapshot);
+            assert!(!restored.is_null());
+
+            let mut account = std::mem::zeroed::<TeAccount>();
+            assert_eq!(
+                te_engine_get_account(restored, 1, ptr::null(), &mut account),
+                TeStatus::Ok
+            );
+            assert_eq!((account.available, account.total), (15_000, 15_000));
+            assert_eq!(
+                te_engine_get_account(restored, 1, c"EUR".as_ptr(), &mut account),
+                TeStatus::NotFound
+            );
+            assert_eq!(
+                te_engine_get_account(restored, 2, ptr::null(), &mut account),
+                TeStatus::NotFound
+            );
+            te_engine_free(restored);
+            assert!(te_engine_restore(b"junk".as_ptr(), 4).is_null());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use rust_decimal::Decimal;
+use similar::TextDiff;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::EngineError;
+
+/// Input file of a fixture
+pub const INPUT_FILE: &str = "input.csv";
+/// Accounts the input is expected to produce
+pub const EXPECTED_FILE: &str = "expected.csv";
+/// Optional settings for the fixture's run, in the `--config` format
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// A directory holding an `input.csv` and the `expected.csv` accounts it
+/// should produce
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    /// Path of the directory relative to the fixtures root, or `.` for the
+    /// root itself
+    pub name: String,
+    pub input: PathBuf,
+    pub expected: PathBuf,
+    pub config: Option<PathBuf>,
+}
+
+/// Every fixture at or below `root`, ordered by name.
+///
+/// A directory with only one of the two files is an error rather than a
+/// fixture that silently never runs.
+pub fn discover(root: &Path) -> Result<Vec<Fixture>, EngineError> {
+    let mut fixtures = Vec::new();
+    visit(root, root, &mut fixtures)?;
+    if fixtures.is_empty() {
+        return Err(EngineError::Usage(format!(
+            "no {INPUT_FILE}/{EXPECTED_FILE} pairs found under {}",
+            root.display()
+        )));
+    }
+    fixtures.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(fixtures)
+}
+
+fn visit(root: &Path, dir: &Path, fixtures: &mut Vec<Fixture>) -> Result<(), EngineError> {
+    let (input, expected) = (dir.join(INPUT_FILE), dir.join(EXPECTED_FILE));
+    match (input.is_file(), expected.is_file()) {
+        (true, true) => {
+            let name = match dir.strip_prefix(root) {
+                Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+                Ok(relative) => relative.display().to_string(),
+                Err(_) => dir.display().to_string(),
+            };
+            let config = Some(dir.join(CONFIG_FILE)).filter(|path| path.is_file());
+            fixtures.push(Fixture {
+                name,
+                input,
+                expected,
+                config,
+            });
+        }
+        (false, false) => {}
+        (true, false) | (false, true) => {
+            return Err(EngineError::Usage(format!(
+                "{} needs both {INPUT_FILE} and {EXPECTED_FILE}",
+                dir.display()
+            )));
+        }
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            visit(root, &path, fixtures)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bring an accounts CSV into a canonical form, so that files which only
+/// differ in layout compare equal.
+///
+/// Fields are trimmed, decimals lose their trailing zeros (`1.5000` becomes
+/// `1.5`) and blank lines are dropped. The header stays first and the other
+/// rows are sorted by client, numerically, then by their remaining fields.
+pub fn normalize(csv: &str) -> Result<String, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes());
+    let mut rows = reader
+        .records()
+        .map(|record| {
+            record
+                .map(|record| record.iter().map(normalize_field).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let header = if rows.is_empty() {
+        None
+    } else {
+        Some(rows.remove(0))
+    };
+    rows.sort_by(|a, b| compare_rows(a, b));
+    let mut normalized = String::new();
+    for row in header.iter().chain(&rows) {
+        normalized.push_str(&row.join(","));
+        normalized.push('\n');
+    }
+    Ok(normalized)
+}
+
+fn normalize_field(field: &str) -> String {
+    match field.parse::<Decimal>() {
+        Ok(number) => number.normalize().to_string(),
+        Err(_) => field.to_string(),
+    }
+}
+
+fn compare_rows(a: &[String], b: &[String]) -> Ordering {
+    let client = |row: &[String]| row.first().and_then(|client| client.parse::<u64>().ok());
+    client(a).cmp(&client(b)).then_with(|| a.cmp(b))
+}
+
+/// A unified diff from the normalized `expected` to the normalized `actual`
+/// accounts, or `None` when they match
+pub fn compare(name: &str, expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let diff = TextDiff::from_lines(expected, actual)
+        .unified_diff()
+        .context_radius(3)
+        .header(
+            &format!("{name}/{EXPECTED_FILE}"),
+            &format!("{name}/actual"),
+        )
+        .to_string();
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_ignores_layout() {
+        let expected = "client,available,held,total,locked\n\
+                        2,0,0,0,true\n\
+                        10,1.5,0,1.5,false\n\
+                        1,3,0,3,false\n";
+        let actual = "client, available, held, total, locked\n\
+                      1, 3.0000, 0.0000, 3.0000, false\n\n\
+                      2, 0, 0, 0, true\n\
+                      10, 1.5000, 0, 1.50, false\n";
+        assert_eq!(normalize(expected), normalize(actual));
+        assert_eq!(
+            normalize(actual).unwrap(),
+            "client,available,held,total,locked\n\
+             1,3,0,3,false\n\
+             2,0,0,0,true\n\
+             10,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_compare_prints_unified_diff() {
+        let expected = normalize("client,available\n1,3\n2,4\n").unwrap();
+        let actual = normalize("client,available\n1,3\n2,5\n").unwrap();
+        assert_eq!(compare("dispute", &expected, &expected), None);
+        assert_eq!(
+            compare("dispute", &expected, &actual).unwrap(),
+            "--- dispute/expected.csv\n\
+             +++ dispute/actual\n\
+             @@ -1,3 +1,3 @@\n \
+             client,available\n \
+             1,3\n\
+             -2,4\n\
+             +2,5\n"
+        );
+    }
+
+    #[test]
+    fn test_discover_requires_both_files() {
+        let root = tempfile::tempdir().unwrap();
+        for name in ["b", "a", "a/nested"] {
+            let dir = root.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(INPUT_FILE), "").unwrap();
+            fs::write(dir.join(EXPECTED_FILE), "").unwrap();
+        }
+        fs::write(root.path().join("b").join(CONFIG_FILE), "").unwrap();
+        let fixtures = discover(root.path()).unwrap();
+        assert_eq!(
+            fixtures.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            ["a", "a/nested", "b"]
+        );
+        assert_eq!(fixtures[0].config, None);
+        assert_eq!(
+            fixtures[2].config,
+            Some(root.path().join("b").join(CONFIG_FILE))
+        );
+
+        fs::remove_file(root.path().join("b").join(EXPECTED_FILE)).unwrap();
+        assert!(discover(root.path()).is_err());
+        let empty = tempfile::tempdir().unwrap();
+        assert!(discover(empty.path()).is_err());
+    }
+}
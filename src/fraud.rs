@@ -0,0 +1,711 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::EngineError;
+use crate::meta::{AccountMeta, AccountMetas, Segment};
+use crate::models::{ClientId, Transaction, TransactionType, TxId};
+
+/// What happens to a transaction a fraud rule fires on, from mildest to
+/// strictest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FraudAction {
+    /// Apply the transaction and only report it
+    #[default]
+    Flag,
+    /// Keep the transaction out of the books and put the client's account on
+    /// review hold
+    Hold,
+    /// Reject the transaction outright
+    Reject,
+}
+
+impl fmt::Display for FraudAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FraudAction::Flag => "flag",
+            FraudAction::Hold => "hold",
+            FraudAction::Reject => "reject",
+        })
+    }
+}
+
+impl Serialize for FraudAction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A check run on every row before it is applied.
+///
+/// Each client's rows reach a rule in the order they are applied, and a rule
+/// keeps whatever per-client history it needs between calls.
+pub trait Rule: fmt::Debug + Send {
+    /// Name used in the fraud report
+    fn name(&self) -> &'static str;
+
+    /// Describe what looks suspicious about `transaction`, if anything
+    fn evaluate(&mut self, transaction: &Transaction) -> Option<String>;
+}
+
+/// Fires when a client files more than `max_disputes` disputes within their
+/// last `window` transactions
+#[derive(Debug)]
+pub struct RapidDisputes {
+    max_disputes: usize,
+    window: usize,
+    recent: HashMap<ClientId, VecDeque<bool>>,
+}
+
+impl RapidDisputes {
+    pub fn new(max_disputes: usize, window: usize) -> Self {
+        RapidDisputes {
+            max_disputes,
+            window,
+            recent: HashMap::new(),
+        }
+    }
+}
+
+impl Rule for RapidDisputes {
+    fn name(&self) -> &'static str {
+        "rapid_disputes"
+    }
+
+    fn evaluate(&mut self, transaction: &Transaction) -> Option<String> {
+        let is_dispute = transaction.tx_type == TransactionType::Dispute;
+        let recent = self.recent.entry(transaction.client).or_default();
+        recent.push_back(is_dispute);
+        if recent.len() > self.window {
+            recent.pop_front();
+        }
+        let disputes = recent.iter().filter(|&&dispute| dispute).count();
+        (is_dispute && disputes > self.max_disputes).then(|| {
+            format!(
+                "{disputes} disputes in the client's last {} transactions",
+                recent.len()
+            )
+        })
+    }
+}
+
+/// Fires on a large withdrawal that follows a run of at least `min_deposits`
+/// small deposits, a common structuring pattern
+#[derive(Debug)]
+pub struct SmallDepositsThenWithdrawal {
+    small_deposit: Decimal,
+    min_deposits: usize,
+    large_withdrawal: Decimal,
+    streaks: HashMap<ClientId, usize>,
+}
+
+impl SmallDepositsThenWithdrawal {
+    pub fn new(small_deposit: Decimal, min_deposits: usize, large_withdrawal: Decimal) -> Self {
+        SmallDepositsThenWithdrawal {
+            small_deposit,
+            min_deposits,
+            large_withdrawal,
+            streaks: HashMap::new(),
+        }
+    }
+}
+
+impl Rule for SmallDepositsThenWithdrawal {
+    fn name(&self) -> &'static str {
+        "small_deposits_then_withdrawal"
+    }
+
+    fn evaluate(&mut self, transaction: &Transaction) -> Option<String> {
+        let amount = transaction.amount?;
+        let streak = self.streaks.entry(transaction.client).or_default();
+        match transaction.tx_type {
+            TransactionType::Deposit if amount <= self.small_deposit => *streak += 1,
+            TransactionType::Deposit => *streak = 0,
+            TransactionType::Withdrawal if amount >= self.large_withdrawal => {
+                let deposits = std::mem::take(streak);
+                return (deposits >= self.min_deposits).then(|| {
+                    format!(
+                        "withdrawal of {amount} after {deposits} deposits of at most {}",
+                        self.small_deposit
+                    )
+                });
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Fires when a client makes `repeats` deposits, or `repeats` withdrawals, of
+/// the same amount in a row
+#[derive(Debug)]
+pub struct AmountBursts {
+    repeats: usize,
+    last: HashMap<ClientId, (TransactionType, Decimal, usize)>,
+}
+
+impl AmountBursts {
+    pub fn new(repeats: usize) -> Self {
+        AmountBursts {
+            repeats,
+            last: HashMap::new(),
+        }
+    }
+}
+
+impl Rule for AmountBursts {
+    fn name(&self) -> &'static str {
+        "amount_bursts"
+    }
+
+    fn evaluate(&mut self, transaction: &Transaction) -> Option<String> {
+        let amount = transaction.amount?;
+        if !matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return None;
+        }
+        let count = match self.last.get(&transaction.client) {
+            Some((tx_type, last, count)) if *tx_type == transaction.tx_type && *last == amount => {
+                count + 1
+            }
+            _ => 1,
+        };
+        self.last.insert(
+            transaction.client,
+            (transaction.tx_type.clone(), amount, count),
+        );
+        (count >= self.repeats).then(|| {
+            format!(
+                "{count} {}s of {amount} in a row",
+                transaction.tx_type.as_str()
+            )
+        })
+    }
+}
+
+/// Rules to run, each with the action taken when it fires
+type RuleSet = Vec<(Box<dyn Rule>, FraudAction)>;
+
+/// Settings for the `rapid-disputes` rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RapidDisputesConfig {
+    pub max_disputes: usize,
+    pub window: usize,
+    #[serde(default)]
+    pub action: FraudAction,
+}
+
+/// Settings for the `small-deposits` rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SmallDepositsConfig {
+    pub small_deposit: Decimal,
+    pub min_deposits: usize,
+    pub large_withdrawal: Decimal,
+    #[serde(default)]
+    pub action: FraudAction,
+}
+
+/// Settings for the `amount-bursts` rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AmountBurstsConfig {
+    pub repeats: usize,
+    #[serde(default)]
+    pub action: FraudAction,
+}
+
+/// The `[fraud]` table of a config file; each rule only runs when its table
+/// is present
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FraudRules {
+    pub rapid_disputes: Option<RapidDisputesConfig>,
+    pub small_deposits: Option<SmallDepositsConfig>,
+    pub amount_bursts: Option<AmountBurstsConfig>,
+    /// Rules replacing the ones above for accounts in a segment, as
+    /// `[fraud.segment."tier=gold".amount-bursts]` tables
+    #[serde(rename = "segment")]
+    pub segments: BTreeMap<Segment, FraudRules>,
+}
+
+impl FraudRules {
+    /// Use rules from `fallback` for every rule left unset here
+    pub fn or(self, fallback: FraudRules) -> FraudRules {
+        let mut segments = fallback.segments;
+        segments.extend(self.segments);
+        FraudRules {
+            rapid_disputes: self.rapid_disputes.or(fallback.rapid_disputes),
+            small_deposits: self.small_deposits.or(fallback.small_deposits),
+            amount_bursts: self.amount_bursts.or(fallback.amount_bursts),
+            segments,
+        }
+    }
+
+    /// The rules that apply to one account: each rule as set for the most
+    /// specific segment the account is in, or as set for everyone
+    pub fn for_account(&self, meta: &AccountMeta) -> FraudRules {
+        let mut rules = FraudRules {
+            segments: BTreeMap::new(),
+            ..self.clone()
+        };
+        for (segment, overrides) in &self.segments {
+            if segment.matches(meta) {
+                rules = FraudRules {
+                    segments: BTreeMap::new(),
+                    ..overrides.clone()
+                }
+                .or(rules);
+            }
+        }
+        rules
+    }
+
+    /// Read the `[fraud]` table of a TOML file, such as a config file. The
+    /// file's other keys are ignored, and a file without the table sets no
+    /// rules.
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        #[derive(Deserialize)]
+        struct RulesFile {
+            #[serde(default)]
+            fraud: FraudRules,
+        }
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str::<RulesFile>(&text)
+            .map(|file| file.fraud)
+            .map_err(|e| EngineError::Config {
+                path: path.to_path_buf(),
+                message: e.message().to_string(),
+            })
+    }
+
+    /// Build the rules this configuration enables, each with its action
+    fn build(&self) -> RuleSet {
+        let mut rules: RuleSet = Vec::new();
+        if let Some(rule) = &self.rapid_disputes {
+            rules.push((
+                Box::new(RapidDisputes::new(rule.max_disputes, rule.window)),
+                rule.action,
+            ));
+        }
+        if let Some(rule) = &self.small_deposits {
+            rules.push((
+                Box::new(SmallDepositsThenWithdrawal::new(
+                    rule.small_deposit,
+                    rule.min_deposits,
+                    rule.large_withdrawal,
+                )),
+                rule.action,
+            ));
+        }
+        if let Some(rule) = &self.amount_bursts {
+            rules.push((Box::new(AmountBursts::new(rule.repeats)), rule.action));
+        }
+        rules
+    }
+}
+
+/// One rule firing on one transaction, as written to the fraud report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Verdict {
+    pub rule: &'static str,
+    pub action: FraudAction,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub tx_type: Cow<'static, str>,
+    pub amount: Option<Decimal>,
+    pub detail: String,
+}
+
+/// Runs every configured rule on each row and collects their verdicts
+#[derive(Debug, Default)]
+pub struct FraudEngine {
+    rules: RuleSet,
+    /// Rules of the segments in the config, built once for each distinct
+    /// set of rules the clients seen so far need
+    segmented: Option<SegmentedRules>,
+    verdicts: Vec<Verdict>,
+}
+
+/// Per-account rule sets of a config with `[fraud.segment]` tables
+#[derive(Debug)]
+struct SegmentedRules {
+    config: FraudRules,
+    meta: Arc<AccountMetas>,
+    sets: Vec<(FraudRules, RuleSet)>,
+    clients: HashMap<ClientId, usize>,
+}
+
+impl SegmentedRules {
+    /// Index of the rule set that applies to `client`
+    fn set_for(&mut self, client: ClientId) -> usize {
+        if let Some(&set) = self.clients.get(&client) {
+            return set;
+        }
+        let rules = self.config.for_account(self.meta.get(client));
+        let set = match self.sets.iter().position(|(config, _)| *config == rules) {
+            Some(set) => set,
+            None => {
+                let built = rules.build();
+                self.sets.push((rules, built));
+                self.sets.len() - 1
+            }
+        };
+        self.clients.insert(client, set);
+        set
+    }
+}
+
+impl FraudEngine {
+    /// Engine running the rules configured in `config`
+    pub fn new(config: &FraudRules) -> Self {
+        FraudEngine::with_meta(config, Arc::default())
+    }
+
+    /// Engine running the rules configured in `config`, choosing the rules
+    /// for each client by the segments their account is in
+    pub fn with_meta(config: &FraudRules, meta: Arc<AccountMetas>) -> Self {
+        if config.segments.is_empty() {
+            return FraudEngine {
+                rules: config.build(),
+                ..Default::default()
+            };
+        }
+        FraudEngine {
+            segmented: Some(SegmentedRules {
+                config: config.clone(),
+                meta,
+                sets: Vec::new(),
+                clients: HashMap::new(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Add a rule, applying `action` whenever it fires
+    pub fn with_rule(mut self, rule: Box<dyn Rule>, action: FraudAction) -> Self {
+        self.rules.push((rule, action));
+        self
+    }
+
+    /// Run every rule on a transaction about to be applied.
+    ///
+    /// Every rule that fires leaves a verdict. The strictest action among
+    /// them decides the outcome: held and rejected transactions return an
+    /// error naming the rule responsible.
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let mut strictest: Option<(&'static str, FraudAction)> = None;
+        let segment_rules = match &mut self.segmented {
+            Some(segmented) => {
+                let set = segmented.set_for(transaction.client);
+                &mut segmented.sets[set].1[..]
+            }
+            None => &mut [],
+        };
+        for (rule, action) in segment_rules.iter_mut().chain(&mut self.rules) {
+            let Some(detail) = rule.evaluate(transaction) else {
+                continue;
+            };
+            if strictest.is_none_or(|(_, strictest)| *action > strictest) {
+                strictest = Some((rule.name(), *action));
+            }
+            self.verdicts.push(Verdict {
+                rule: rule.name(),
+                action: *action,
+                client: transaction.client,
+                tx: transaction.tx,
+                tx_type: transaction.tx_type.name(),
+                amount: transaction.amount,
+                detail,
+            });
+        }
+        match strictest {
+            Some((rule, action)) if action > FraudAction::Flag => {
+                Err(EngineError::FraudSuspected {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                    rule,
+                    action,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Verdicts reached since the last call
+    pub fn take_verdicts(&mut self) -> Vec<Verdict> {
+        std::mem::take(&mut self.verdicts)
+    }
+}
+
+/// CSV writer for the fraud report
+pub struct FraudReportWriter<W: std::io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl FraudReportWriter<File> {
+    /// Create (or truncate) the fraud report at `path`
+    pub fn create(path: &Path) -> Result<Self, EngineError> {
+        Ok(FraudReportWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: std::io::Write> FraudReportWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FraudReportWriter {
+            writer: csv::Writer::from_writer(inner),
+        }
+    }
+
+    /// Append one verdict
+    pub fn write(&mut self, verdict: &Verdict) -> Result<(), EngineError> {
+        self.writer.serialize(verdict)?;
+        Ok(())
+    }
+
+    /// Flush buffered rows and return the underlying writer
+    pub fn finish(self) -> Result<W, EngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| EngineError::Io(e.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_fire_on_their_patterns() {
+        let mut disputes = RapidDisputes::new(1, 3);
+        assert!(
+            disputes
+                .evaluate(&Transaction::new(TransactionType::Dispute, 1, 1, None))
+                .is_none()
+        );
+        assert!(
+            disputes
+                .evaluate(&Transaction::new(TransactionType::Dispute, 1, 2, None))
+                .is_some()
+        );
+        disputes.evaluate(&Transaction::new(
+            TransactionType::Deposit,
+            1,
+            3,
+            Some(Decimal::from(1)),
+        ));
+        disputes.evaluate(&Transaction::new(
+            TransactionType::Deposit,
+            1,
+            4,
+            Some(Decimal::from(1)),
+        ));
+        assert!(
+            disputes
+                .evaluate(&Transaction::new(TransactionType::Dispute, 1, 5, None))
+                .is_none()
+        );
+
+        let mut structuring =
+            SmallDepositsThenWithdrawal::new(Decimal::from(10), 2, Decimal::from(100));
+        structuring.evaluate(&Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::from(9)),
+        ));
+        structuring.evaluate(&Transaction::new(
+            TransactionType::Deposit,
+            1,
+            2,
+            Some(Decimal::from(10)),
+        ));
+        assert!(
+            structuring
+                .evaluate(&Transaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    3,
+                    Some(Decimal::from(150))
+                ))
+                .is_some()
+        );
+        assert!(
+            structuring
+                .evaluate(&Transaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    4,
+                    Some(Decimal::from(150))
+                ))
+                .is_none()
+        );
+
+        let mut bursts = AmountBursts::new(3);
+        assert!(
+            bursts
+                .evaluate(&Transaction::new(
+                    TransactionType::Deposit,
+                    1,
+                    1,
+                    Some(Decimal::from(5))
+                ))
+                .is_none()
+        );
+        assert!(
+            bursts
+                .evaluate(&Transaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    2,
+                    Some(Decimal::from(5))
+                ))
+                .is_none()
+        );
+        assert!(
+            bursts
+                .evaluate(&Transaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    3,
+                    Some(Decimal::from(5))
+                ))
+                .is_none()
+        );
+        assert!(
+            bursts
+                .evaluate(&Transaction::new(
+                    TransactionType::Withdrawal,
+                    1,
+                    4,
+                    Some(Decimal::from(5))
+                ))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_strictest_action_decides() {
+        let mut engine = FraudEngine::default()
+            .with_rule(Box::new(AmountBursts::new(1)), FraudAction::Flag)
+            .with_rule(Box::new(AmountBursts::new(2)), FraudAction::Hold);
+
+        engine
+            .check(&Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(Decimal::from(5)),
+            ))
+            .unwrap();
+        assert!(matches!(
+            engine.check(&Transaction::new(
+                TransactionType::Deposit,
+                1,
+                2,
+                Some(Decimal::from(5))
+            )),
+            Err(EngineError::FraudSuspected {
+                action: FraudAction::Hold,
+                ..
+            })
+        ));
+        let verdicts = engine.take_verdicts();
+        assert_eq!(verdicts.len(), 3);
+        assert_eq!(verdicts[2].action, FraudAction::Hold);
+        assert!(engine.take_verdicts().is_empty());
+    }
+
+    #[test]
+    fn test_parse_fraud_rules() {
+        let rules: FraudRules = toml::from_str(
+            r#"
+            [rapid-disputes]
+            max-disputes = 3
+            window = 10
+            action = "hold"
+
+            [amount-bursts]
+            repeats = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules.rapid_disputes.map(|rule| rule.action),
+            Some(FraudAction::Hold)
+        );
+        assert_eq!(
+            rules.amount_bursts.map(|rule| rule.action),
+            Some(FraudAction::Flag)
+        );
+        assert!(rules.small_deposits.is_none());
+    }
+
+    #[test]
+    fn test_segment_rules_follow_account_metadata() {
+        let rules: FraudRules = toml::from_str(
+            r#"
+            [amount-bursts]
+            repeats = 3
+
+            [segment.unverified.amount-bursts]
+            repeats = 2
+            action = "reject"
+            "#,
+        )
+        .unwrap();
+        let meta = AccountMetas::read("client,kyc_verified\n1,true\n".as_bytes()).unwrap();
+        let mut engine = FraudEngine::with_meta(&rules, Arc::new(meta));
+
+        for (client, tx) in [(1, 1), (1, 2), (2, 3)] {
+            let deposit =
+                Transaction::new(TransactionType::Deposit, client, tx, Some(Decimal::from(5)));
+            engine.check(&deposit).unwrap();
+        }
+        let unverified = Transaction::new(TransactionType::Deposit, 2, 4, Some(Decimal::from(5)));
+        assert!(matches!(
+            engine.check(&unverified),
+            Err(EngineError::FraudSuspected {
+                action: FraudAction::Reject,
+                ..
+            })
+        ));
+        assert!(toml::from_str::<FraudRules>("[segment.vip.amount-bursts]\nrepeats = 2").is_err());
+    }
+
+    #[test]
+    fn test_load_reads_the_fraud_table_of_a_config_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            b"channel-capacity = 500\n\n[fraud.amount-bursts]\nrepeats = 3\naction = \"hold\"\n",
+        )
+        .unwrap();
+        let rules = FraudRules::load(file.path()).unwrap();
+        assert_eq!(
+            rules.amount_bursts,
+            Some(AmountBurstsConfig {
+                repeats: 3,
+                action: FraudAction::Hold,
+            })
+        );
+        assert_eq!(rules.rapid_disputes, None);
+
+        let mut bad = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut bad, b"[fraud.amount-bursts]\nrepeats = \"x\"\n").unwrap();
+        assert!(matches!(
+            FraudRules::load(bad.path()),
+            Err(EngineError::Config { .. })
+        ));
+    }
+}
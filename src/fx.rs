@@ -0,0 +1,94 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::models::Currency;
+
+/// One row of an `--fx-rates` file: one unit of `from` buys `rate` of `to`
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    from: Currency,
+    to: Currency,
+    rate: Decimal,
+}
+
+/// Exchange rates used by `convert` transactions, loaded once at startup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FxRates {
+    rates: HashMap<(Currency, Currency), Decimal>,
+}
+
+impl FxRates {
+    /// Read a `from,to,rate` CSV file
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        FxRates::from_reader(file)
+            .map_err(|e| EngineError::FxRates(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse `from,to,rate` CSV rows; every rate must be positive and each
+    /// currency pair may only be listed once
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, String> {
+        let mut rates = HashMap::new();
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let row: RateRow = row.map_err(|e| e.to_string())?;
+            if row.rate <= Decimal::ZERO {
+                return Err(format!("rate {} -> {} must be positive", row.from, row.to));
+            }
+            if rates.insert((row.from, row.to), row.rate).is_some() {
+                return Err(format!("rate {} -> {} is listed twice", row.from, row.to));
+            }
+        }
+        Ok(FxRates { rates })
+    }
+
+    /// Rate for converting `from` into `to`.
+    ///
+    /// Falls back to the inverse of the opposite pair when only that one is
+    /// listed.
+    pub fn rate(&self, from: Currency, to: Currency) -> Option<Decimal> {
+        self.rates.get(&(from, to)).copied().or_else(|| {
+            self.rates
+                .get(&(to, from))
+                .and_then(|inverse| Decimal::ONE.checked_div(*inverse))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency(code: &str) -> Currency {
+        code.parse().unwrap()
+    }
+
+    #[test]
+    fn test_direct_and_inverse_rates() {
+        let rates = FxRates::from_reader("from,to,rate\nEUR,USD,1.25\n".as_bytes()).unwrap();
+        assert_eq!(
+            rates.rate(currency("EUR"), currency("USD")),
+            Some(Decimal::new(125, 2))
+        );
+        assert_eq!(
+            rates.rate(currency("USD"), currency("EUR")),
+            Some(Decimal::new(8, 1))
+        );
+        assert_eq!(rates.rate(currency("EUR"), currency("GBP")), None);
+    }
+
+    #[test]
+    fn test_rejects_bad_rate_files() {
+        for text in [
+            "from,to,rate\nEUR,USD,0\n",
+            "from,to,rate\nEUR,USD,1\nEUR,USD,2\n",
+            "from,to,rate\nEUR,USD,abc\n",
+            "from,to,rate\nE/R,USD,1\n",
+        ] {
+            assert!(FxRates::from_reader(text.as_bytes()).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::EngineError;
+use crate::models::{Account, Transaction};
+
+/// Applies rows of a transaction type the engine has no built-in handling
+/// for, such as `interest`, `bonus` or `fee`.
+///
+/// The handler only decides how much the row moves; the engine checks the
+/// account's status, records the row under its ID and changes the balance
+/// in the row's currency. Handlers are shared by every client task.
+pub trait TransactionHandler: Send + Sync {
+    /// How much `transaction` adds to the account's available and total
+    /// funds, negative to take funds away, or why it is rejected
+    fn apply(&self, transaction: &Transaction, account: &Account) -> Result<Decimal, EngineError>;
+}
+
+impl<F> TransactionHandler for F
+where
+    F: Fn(&Transaction, &Account) -> Result<Decimal, EngineError> + Send + Sync,
+{
+    fn apply(&self, transaction: &Transaction, account: &Account) -> Result<Decimal, EngineError> {
+        self(transaction, account)
+    }
+}
+
+/// Handlers for custom transaction types, keyed by the name in the `type`
+/// column.
+///
+/// Type names are lower-cased when rows are parsed, so handlers must be
+/// registered under lower-case names. A handler registered under a built-in
+/// name is never used.
+#[derive(Default, Clone)]
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Arc<dyn TransactionHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Register `handler` for rows of type `tx_type`, replacing any handler
+    /// registered for it before
+    pub fn with_handler<H: TransactionHandler + 'static>(
+        mut self,
+        tx_type: impl Into<String>,
+        handler: H,
+    ) -> Self {
+        self.handlers.insert(tx_type.into(), Arc::new(handler));
+        self
+    }
+
+    /// The handler for `tx_type`, if one is registered
+    pub fn get(&self, tx_type: &str) -> Option<&dyn TransactionHandler> {
+        self.handlers.get(tx_type).map(|handler| handler.as_ref())
+    }
+}
+
+impl fmt::Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut types: Vec<_> = self.handlers.keys().collect();
+        types.sort();
+        f.debug_struct("HandlerRegistry")
+            .field("types", &types)
+            .finish()
+    }
+}
@@ -0,0 +1,203 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::fmt::{self, Write as _};
+
+use crate::models::{ClientId, Currency};
+use crate::outcome::Applied;
+
+/// SHA-256 digest of a chain link
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Digest(pub [u8; 32]);
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut hex = String::with_capacity(64);
+        for byte in self.0 {
+            let _ = write!(hex, "{byte:02x}");
+        }
+        f.write_str(&hex)
+    }
+}
+
+/// The link after `prev` for one applied transaction.
+///
+/// Covers the client, transaction ID, type, currency and every amount the
+/// transaction moved, so two runs that applied the same rows with the same
+/// effects produce the same link. Amounts are normalized first, so `1.50`
+/// and `1.5` hash alike.
+pub fn link(prev: &Digest, applied: &Applied) -> Digest {
+    let transaction = &applied.transaction;
+    let currency = |c: Option<Currency>| c.map(|c| c.to_string()).unwrap_or_default();
+    let mut entry = format!(
+        "{}\x1f{}\x1f{}\x1f{}\x1f{}",
+        transaction.client,
+        transaction.tx,
+        transaction.tx_type.name(),
+        currency(transaction.currency),
+        applied.amount.normalize()
+    );
+    if let Some(conversion) = applied.conversion {
+        let _ = write!(
+            entry,
+            "\x1e{}\x1f{}",
+            conversion.to,
+            conversion.converted.normalize()
+        );
+    }
+    for payout in &applied.payouts {
+        let _ = write!(
+            entry,
+            "\x1e{}\x1f{}",
+            currency(payout.currency),
+            payout.amount.normalize()
+        );
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(prev.0);
+    hasher.update(entry.as_bytes());
+    Digest(hasher.finalize().into())
+}
+
+/// Running hash chains over applied transactions, one per client.
+///
+/// Each client's rows are applied in file order by a single task, so its
+/// chain is the same in every run over the same input. Rows of different
+/// clients interleave differently from run to run, so the run-wide digest is
+/// not chained in arrival order but folded from the client heads in client
+/// ID order.
+#[derive(Debug, Default)]
+pub struct HashChain {
+    heads: DashMap<ClientId, Digest>,
+}
+
+impl HashChain {
+    /// Carry on from the heads saved in a snapshot
+    pub fn restore(state: &ChainState) -> Self {
+        HashChain {
+            heads: state.heads.iter().copied().collect(),
+        }
+    }
+
+    /// Extend the client's chain with an applied transaction and return its
+    /// new head
+    pub fn record(&self, applied: &Applied) -> Digest {
+        let mut head = self.heads.entry(applied.transaction.client).or_default();
+        *head = link(&head, applied);
+        *head
+    }
+
+    /// Head of one client's chain, if any of their rows was applied
+    pub fn head(&self, client: ClientId) -> Option<Digest> {
+        self.heads.get(&client).map(|head| *head)
+    }
+
+    /// Every client head with the run-wide digest over them
+    pub fn state(&self) -> ChainState {
+        let mut heads: Vec<_> = self.heads.iter().map(|e| (*e.key(), *e.value())).collect();
+        heads.sort_by_key(|(client, _)| *client);
+        let digest = fold(&heads);
+        ChainState { heads, digest }
+    }
+}
+
+/// Run-wide digest over client heads sorted by client ID
+fn fold(heads: &[(ClientId, Digest)]) -> Digest {
+    heads
+        .iter()
+        .fold(Digest::default(), |digest, (client, head)| {
+            let mut hasher = Sha256::new();
+            hasher.update(digest.0);
+            hasher.update(client.to_be_bytes());
+            hasher.update(head.0);
+            Digest(hasher.finalize().into())
+        })
+}
+
+/// Client heads of a hash chain and the digest over them, as kept in a
+/// snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainState {
+    /// Sorted by client ID
+    pub heads: Vec<(ClientId, Digest)>,
+    pub digest: Digest,
+}
+
+impl ChainState {
+    /// Whether `digest` is the one the heads fold into, i.e. neither was
+    /// altered after the state was saved
+    pub fn is_consistent(&self) -> bool {
+        self.heads.is_sorted_by_key(|(client, _)| *client) && fold(&self.heads) == self.digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Transaction, TransactionType, TxId};
+    use rust_decimal::Decimal;
+
+    fn applied(client: ClientId, tx: TxId, amount: Decimal) -> Applied {
+        Applied {
+            transaction: Transaction::new(TransactionType::Deposit, client, tx, Some(amount)),
+            amount,
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_digest_ignores_interleaving_of_clients() {
+        let rows = [
+            applied(1, 1, Decimal::new(150, 2)),
+            applied(2, 2, Decimal::ONE),
+            applied(1, 3, Decimal::TWO),
+        ];
+        let forward = HashChain::default();
+        for row in &rows {
+            forward.record(row);
+        }
+        let interleaved = HashChain::default();
+        for row in [&rows[0], &rows[2], &rows[1]] {
+            interleaved.record(row);
+        }
+        assert_eq!(forward.state(), interleaved.state());
+        assert!(forward.state().is_consistent());
+
+        // Same amount written differently, same chain
+        let rescaled = HashChain::default();
+        rescaled.record(&applied(1, 1, Decimal::new(15, 1)));
+        assert_eq!(rescaled.head(1), Some(link(&Digest::default(), &rows[0])));
+
+        // Order within a client matters
+        let swapped = HashChain::default();
+        for row in [&rows[2], &rows[0], &rows[1]] {
+            swapped.record(row);
+        }
+        assert_ne!(swapped.state().digest, forward.state().digest);
+        assert_eq!(swapped.head(2), forward.head(2));
+    }
+
+    #[test]
+    fn test_restored_chain_continues() {
+        let rows = [applied(1, 1, Decimal::ONE), applied(1, 2, Decimal::TWO)];
+        let whole = HashChain::default();
+        for row in &rows {
+            whole.record(row);
+        }
+
+        let first = HashChain::default();
+        first.record(&rows[0]);
+        let resumed = HashChain::restore(&first.state());
+        resumed.record(&rows[1]);
+        assert_eq!(resumed.state(), whole.state());
+
+        let mut tampered = whole.state();
+        tampered.heads[0].1.0[0] ^= 1;
+        assert!(!tampered.is_consistent());
+        assert_eq!(whole.state().digest.to_string().len(), 64);
+    }
+}
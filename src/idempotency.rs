@@ -0,0 +1,103 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Transaction};
+
+/// Remembers the idempotency keys each client has used so a resubmitted row
+/// is rejected instead of being applied twice
+#[derive(Debug, Default)]
+pub struct IdempotencyGuard {
+    /// Keys only clash within this long of each other; unlimited if unset
+    window: Option<Duration>,
+    /// When each key was first seen, if that row had a timestamp
+    seen: HashMap<(ClientId, String), Option<DateTime<Utc>>>,
+}
+
+impl IdempotencyGuard {
+    pub fn new(window: Option<Duration>) -> Self {
+        IdempotencyGuard {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Check a row's idempotency key against the client's earlier rows.
+    ///
+    /// The first row with a key claims it whether or not it goes on to be
+    /// applied. A later row with the same key is a duplicate unless a window
+    /// is set and both rows carry timestamps further apart than the window,
+    /// in which case the later row claims the key afresh. Rows without a key
+    /// are never checked.
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let Some(key) = &transaction.idempotency_key else {
+            return Ok(());
+        };
+        let slot = (transaction.client, key.clone());
+        if let Some(&first) = self.seen.get(&slot) {
+            let expired = match (self.window, first, transaction.timestamp) {
+                (Some(window), Some(first), Some(now)) => now - first > window,
+                _ => false,
+            };
+            if !expired {
+                return Err(EngineError::DuplicateIdempotencyKey {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                    key: key.clone(),
+                });
+            }
+        }
+        self.seen.insert(slot, transaction.timestamp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{TransactionType, TxId};
+
+    /// A deposit of client 1 with an idempotency key, `hours` hours after
+    /// the epoch
+    fn keyed(tx: TxId, key: Option<&str>, hours: i64) -> Transaction {
+        Transaction {
+            timestamp: DateTime::from_timestamp(hours * 3600, 0),
+            idempotency_key: key.map(str::to_string),
+            ..Transaction::new(TransactionType::Deposit, 1, tx, None)
+        }
+    }
+
+    #[test]
+    fn test_repeated_key_is_rejected_within_the_window() {
+        let mut guard = IdempotencyGuard::new(Some(Duration::hours(24)));
+        guard.check(&keyed(1, Some("a"), 0)).unwrap();
+        assert!(matches!(
+            guard.check(&keyed(2, Some("a"), 24)),
+            Err(EngineError::DuplicateIdempotencyKey { tx: 2, .. })
+        ));
+        guard.check(&keyed(3, Some("b"), 24)).unwrap();
+    }
+
+    #[test]
+    fn test_rows_without_a_key_are_not_checked() {
+        let mut guard = IdempotencyGuard::new(Some(Duration::hours(24)));
+        guard.check(&keyed(1, None, 0)).unwrap();
+        guard.check(&keyed(2, None, 0)).unwrap();
+    }
+
+    #[test]
+    fn test_keys_expire_after_window() {
+        let mut guard = IdempotencyGuard::new(Some(Duration::hours(24)));
+        guard.check(&keyed(1, Some("a"), 0)).unwrap();
+        guard.check(&keyed(2, Some("a"), 25)).unwrap();
+        // Reusing the key claimed it again
+        assert!(guard.check(&keyed(3, Some("a"), 26)).is_err());
+    }
+
+    #[test]
+    fn test_keys_never_expire_without_a_window() {
+        let mut guard = IdempotencyGuard::default();
+        guard.check(&keyed(1, Some("a"), 0)).unwrap();
+        assert!(guard.check(&keyed(2, Some("a"), 1000)).is_err());
+    }
+}
@@ -0,0 +1,644 @@
+use csv_async::{AsyncReader, AsyncReaderBuilder, ByteRecord, Position, StringRecord, Trim};
+#[cfg(feature = "mmap-input")]
+use memmap2::Mmap;
+#[cfg(feature = "mmap-input")]
+use std::io::Cursor;
+use std::path::Path;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "arrow")]
+use crate::arrow::ArrowRows;
+use crate::encryption::AdminKey;
+use crate::error::EngineError;
+use crate::models::{Transaction, TransactionType};
+use crate::outcome::MalformedRow;
+use crate::validate::{ColumnIndex, ParseOptions, RowParser};
+
+/// Rows read before a chunk is handed to the blocking pool for parsing
+pub const PARSE_CHUNK_ROWS: usize = 1024;
+
+/// Parsed chunks the blocking-pool reader may get ahead of the rows handed
+/// out
+pub const QUEUED_CHUNKS: usize = 4;
+
+/// Extensions of input files read as Arrow IPC files or streams rather
+/// than CSV
+pub const ARROW_EXTENSIONS: [&str; 4] = ["arrow", "arrows", "feather", "ipc"];
+
+/// Whether `path` names an Arrow input file
+pub fn is_arrow_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ARROW_EXTENSIONS
+                .iter()
+                .any(|arrow| arrow.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Map a local input file into memory, to be read and parsed with
+/// `ParsedRows::on_blocking_pool`.
+///
+/// Only regular files can be mapped; pipes and other streams cannot.
+#[cfg(feature = "mmap-input")]
+pub fn map_input(path: &Path) -> Result<Cursor<Mmap>, EngineError> {
+    let file = std::fs::File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(EngineError::Usage(format!(
+            "--mmap-input needs a regular file, and {} is not one",
+            path.display()
+        )));
+    }
+    // SAFETY: the map is only read, and the run's input is not expected to
+    // change while it is read; a file truncated under the map ends the
+    // process with SIGBUS
+    let map = unsafe { Mmap::map(&file)? };
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Ok(Cursor::new(map))
+}
+
+/// Transaction types that change an account's standing rather than move
+/// funds. Once an admin key is set they are only taken from the signed admin
+/// input.
+const ADMIN_TYPES: [TransactionType; 3] = [
+    TransactionType::Hold,
+    TransactionType::Release,
+    TransactionType::Close,
+];
+
+/// Column of the admin input carrying each row's signature
+pub const SIGNATURE_COLUMN: &str = "signature";
+
+/// Whether rows of this type need the admin channel
+pub fn is_admin_type(tx_type: &TransactionType) -> bool {
+    ADMIN_TYPES.contains(tx_type)
+}
+
+/// Read an admin input: a CSV file with the usual columns and a `signature`
+/// column, holding only admin transactions.
+///
+/// Every row must be signed with `key`, and is checked before it is even
+/// parsed. A row with a missing or wrong signature, or of a type that does not
+/// need the admin channel, comes back malformed.
+pub async fn read_admin_input(
+    path: &Path,
+    key: &AdminKey,
+    options: ParseOptions,
+) -> Result<Vec<ParsedRow>, EngineError> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .create_reader(file);
+    let headers = reader.headers().await?.clone();
+    let columns = ColumnIndex::from_headers(&headers)?;
+    let Some(signed) = headers.iter().position(|h| h == SIGNATURE_COLUMN) else {
+        return Err(EngineError::AdminChannel(format!(
+            "{} has no {SIGNATURE_COLUMN} column",
+            path.display()
+        )));
+    };
+    let parser = RowParser::with_columns(columns, options);
+    let mut rows = Vec::new();
+    let mut record = StringRecord::new();
+    loop {
+        let row = match reader.read_record(&mut record).await {
+            Ok(true) => Ok(record.clone()),
+            Ok(false) => break,
+            Err(e) => Err(e),
+        };
+        let position = record.position().cloned();
+        let fields = columns.canonical_fields(&record);
+        let refuse = |message: String| MalformedRow {
+            line: position.as_ref().map(|p| p.line()),
+            fields: fields.clone(),
+            error: EngineError::AdminChannel(message),
+        };
+        let transaction = match row {
+            Ok(_) if !key.verify(&fields, record.get(signed).unwrap_or_default()) => {
+                Err(refuse("missing or wrong signature".to_string()))
+            }
+            row => parser.validate_row(row).and_then(|transaction| {
+                if is_admin_type(&transaction.tx_type) {
+                    Ok(transaction)
+                } else {
+                    Err(refuse(format!(
+                        "{} rows belong on the regular input",
+                        transaction.tx_type.name()
+                    )))
+                }
+            }),
+        };
+        rows.push(ParsedRow {
+            position: None,
+            transaction,
+        });
+    }
+    Ok(rows)
+}
+
+/// Turn an admin transaction that arrived on the regular input into a
+/// malformed row, so a forged row in a partner's file cannot change an
+/// account's standing
+fn refuse_admin_row(row: ParsedRow) -> ParsedRow {
+    let transaction = match row.transaction {
+        Ok(transaction) if is_admin_type(&transaction.tx_type) => Err(MalformedRow {
+            line: row.position.as_ref().map(|p| p.line()),
+            fields: canonical_fields(&transaction),
+            error: EngineError::AdminChannel(format!(
+                "{} rows are only accepted on the admin input",
+                transaction.tx_type.name()
+            )),
+        }),
+        transaction => transaction,
+    };
+    ParsedRow {
+        position: row.position,
+        transaction,
+    }
+}
+
+/// A parsed transaction's fields in canonical column order, for reporting
+fn canonical_fields(transaction: &Transaction) -> Vec<String> {
+    let text = |value: Option<String>| value.unwrap_or_default();
+    vec![
+        transaction.tx_type.name().into_owned(),
+        transaction.client.to_string(),
+        transaction.tx.to_string(),
+        text(transaction.amount.map(|a| a.to_string())),
+        text(transaction.timestamp.map(|t| t.to_rfc3339())),
+        text(transaction.currency.map(|c| c.to_string())),
+        text(transaction.to_currency.map(|c| c.to_string())),
+        text(transaction.idempotency_key.clone()),
+    ]
+}
+
+/// One input row after parsing, in input order
+#[derive(Debug)]
+pub struct ParsedRow {
+    pub position: Option<Position>,
+    pub transaction: Result<Transaction, MalformedRow>,
+}
+
+/// Where parsed rows come from
+enum Source<R> {
+    /// Read and parsed on the calling task, one row at a time
+    Inline {
+        reader: Box<AsyncReader<R>>,
+        parser: RowParser,
+        record: ByteRecord,
+        skip: u64,
+        rows_read: u64,
+    },
+    /// Read on a background task and parsed in chunks on the blocking pool
+    Pipeline {
+        /// Chunks queued in input order as soon as they start parsing
+        chunks: mpsc::Receiver<JoinHandle<Vec<ParsedRow>>>,
+        current: std::vec::IntoIter<ParsedRow>,
+        /// Returns how many rows it read once it stops
+        reading: JoinHandle<u64>,
+    },
+    /// Read and parsed on one thread of the blocking pool, in chunks
+    Blocking {
+        chunks: mpsc::Receiver<Vec<ParsedRow>>,
+        current: std::vec::IntoIter<ParsedRow>,
+        /// Returns how many rows it read once it stops
+        reading: JoinHandle<u64>,
+    },
+    /// Record batches of an Arrow file, converted a batch at a time
+    #[cfg(feature = "arrow")]
+    Arrow { rows: Box<ArrowRows>, skip: u64 },
+}
+
+/// The rows of an input file, parsed and handed over one at a time in file
+/// order
+pub struct ParsedRows<R> {
+    source: Source<R>,
+    /// Checked admin rows to hand over last, when the admin channel is on
+    admin: Option<std::vec::IntoIter<ParsedRow>>,
+    /// Input rows handed out so far, not counting the skipped ones
+    handed: u64,
+}
+
+impl<R> ParsedRows<R>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    /// Parse the rows of `reader`, skipping the first `skip` without parsing
+    /// them.
+    ///
+    /// With `parallelism` above 1, a background task reads the input and
+    /// parses up to that many chunks of rows at once on the blocking pool;
+    /// otherwise each row is read and parsed only when asked for.
+    pub fn new(reader: AsyncReader<R>, parser: RowParser, skip: u64, parallelism: usize) -> Self {
+        let source = if parallelism > 1 {
+            let (chunks_tx, chunks) = mpsc::channel(parallelism);
+            Source::Pipeline {
+                chunks,
+                current: Vec::new().into_iter(),
+                reading: tokio::spawn(read_chunks(reader, parser, skip, chunks_tx)),
+            }
+        } else {
+            Source::Inline {
+                reader: Box::new(reader),
+                parser,
+                record: ByteRecord::new(),
+                skip,
+                rows_read: 0,
+            }
+        };
+        ParsedRows {
+            source,
+            admin: None,
+            handed: 0,
+        }
+    }
+
+    /// Read and parse the rows of `reader` on one thread of the blocking
+    /// pool, skipping the first `skip`, and hand them over in chunks.
+    ///
+    /// Meant for a reader that never waits, such as a memory-mapped file:
+    /// the thread then goes through the whole input without a trip back to
+    /// the runtime, whose threads only ever pick up parsed chunks.
+    pub fn on_blocking_pool<S>(reader: AsyncReader<S>, parser: RowParser, skip: u64) -> Self
+    where
+        S: AsyncRead + Unpin + Send + 'static,
+    {
+        let (chunks_tx, chunks) = mpsc::channel(QUEUED_CHUNKS);
+        let reading = tokio::task::spawn_blocking(move || {
+            futures::executor::block_on(read_and_parse(reader, parser, skip, chunks_tx))
+        });
+        ParsedRows {
+            source: Source::Blocking {
+                chunks,
+                current: Vec::new().into_iter(),
+                reading,
+            },
+            admin: None,
+            handed: 0,
+        }
+    }
+
+    /// The rows of an Arrow file, skipping the first `skip`
+    #[cfg(feature = "arrow")]
+    pub fn from_arrow(rows: ArrowRows, skip: u64) -> Self {
+        ParsedRows {
+            source: Source::Arrow {
+                rows: Box::new(rows),
+                skip,
+            },
+            admin: None,
+            handed: 0,
+        }
+    }
+
+    /// Turn away admin transactions found in the input, and hand over the
+    /// rows of the admin input once it is exhausted
+    pub fn with_admin_rows(mut self, rows: Vec<ParsedRow>) -> Self {
+        self.admin = Some(rows.into_iter());
+        self
+    }
+
+    /// The next row, or `None` once the input is exhausted
+    pub async fn next(&mut self) -> Option<ParsedRow> {
+        let row = self.next_row().await;
+        if row.is_some() {
+            self.handed += 1;
+        }
+        match row {
+            Some(row) if self.admin.is_some() => Some(refuse_admin_row(row)),
+            Some(row) => Some(row),
+            None => self.admin.as_mut()?.next(),
+        }
+    }
+
+    /// Input rows handed out by `next` so far, after the skipped ones and
+    /// not counting admin rows
+    pub fn rows_handed(&self) -> u64 {
+        self.handed
+    }
+
+    async fn next_row(&mut self) -> Option<ParsedRow> {
+        match &mut self.source {
+            Source::Inline {
+                reader,
+                parser,
+                record,
+                skip,
+                rows_read,
+            } => loop {
+                let row = match reader.read_byte_record(record).await {
+                    Ok(true) => Ok(&*record),
+                    Ok(false) => return None,
+                    Err(e) => Err(e),
+                };
+                *rows_read += 1;
+                if *rows_read > *skip {
+                    return Some(parse_row(parser, row));
+                }
+            },
+            Source::Pipeline {
+                chunks, current, ..
+            } => loop {
+                if let Some(row) = current.next() {
+                    return Some(row);
+                }
+                let chunk = chunks.recv().await?;
+                *current = chunk.await.expect("row parser panicked").into_iter();
+            },
+            Source::Blocking {
+                chunks, current, ..
+            } => loop {
+                if let Some(row) = current.next() {
+                    return Some(row);
+                }
+                *current = chunks.recv().await?.into_iter();
+            },
+            #[cfg(feature = "arrow")]
+            Source::Arrow { rows, skip } => loop {
+                let transaction = rows.next()?;
+                if rows.rows_read() > *skip {
+                    let mut position = Position::new();
+                    position.set_line(rows.rows_read());
+                    position.set_record(rows.rows_read());
+                    return Some(ParsedRow {
+                        position: Some(position),
+                        transaction,
+                    });
+                }
+            },
+        }
+    }
+
+    /// Stop reading and return how many rows were read, skipped ones
+    /// included
+    pub async fn finish(self) -> u64 {
+        match self.source {
+            Source::Inline { rows_read, .. } => rows_read,
+            Source::Pipeline {
+                chunks, reading, ..
+            } => {
+                drop(chunks);
+                reading.await.expect("input reader panicked")
+            }
+            Source::Blocking {
+                chunks, reading, ..
+            } => {
+                drop(chunks);
+                reading.await.expect("input reader panicked")
+            }
+            #[cfg(feature = "arrow")]
+            Source::Arrow { rows, .. } => rows.rows_read(),
+        }
+    }
+}
+
+/// Read every row into chunks and queue each chunk for parsing, until the
+/// input or the queue's receiver is gone
+async fn read_chunks<R>(
+    mut reader: AsyncReader<R>,
+    parser: RowParser,
+    skip: u64,
+    chunks: mpsc::Sender<JoinHandle<Vec<ParsedRow>>>,
+) -> u64
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut rows_read = 0;
+    let mut chunk = Vec::with_capacity(PARSE_CHUNK_ROWS);
+    loop {
+        let mut record = ByteRecord::new();
+        let row = match reader.read_byte_record(&mut record).await {
+            Ok(true) => Ok(record),
+            Ok(false) => break,
+            Err(e) => Err(e),
+        };
+        rows_read += 1;
+        if rows_read <= skip {
+            continue;
+        }
+        chunk.push(row);
+        if chunk.len() == PARSE_CHUNK_ROWS {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(PARSE_CHUNK_ROWS));
+            if chunks
+                .send(parse_chunk(parser.clone(), full))
+                .await
+                .is_err()
+            {
+                return rows_read;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = chunks.send(parse_chunk(parser, chunk)).await;
+    }
+    rows_read
+}
+
+/// Read and parse every row into chunks and queue each chunk, until the
+/// input or the queue's receiver is gone
+async fn read_and_parse<S>(
+    mut reader: AsyncReader<S>,
+    parser: RowParser,
+    skip: u64,
+    chunks: mpsc::Sender<Vec<ParsedRow>>,
+) -> u64
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut rows_read = 0;
+    let mut record = ByteRecord::new();
+    let mut chunk = Vec::with_capacity(PARSE_CHUNK_ROWS);
+    loop {
+        let row = match reader.read_byte_record(&mut record).await {
+            Ok(true) => Ok(&record),
+            Ok(false) => break,
+            Err(e) => Err(e),
+        };
+        rows_read += 1;
+        if rows_read <= skip {
+            continue;
+        }
+        chunk.push(parse_row(&parser, row));
+        if chunk.len() == PARSE_CHUNK_ROWS {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(PARSE_CHUNK_ROWS));
+            if chunks.send(full).await.is_err() {
+                return rows_read;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = chunks.send(chunk).await;
+    }
+    rows_read
+}
+
+/// Parse one chunk of rows on the blocking pool
+fn parse_chunk(
+    parser: RowParser,
+    rows: Vec<Result<ByteRecord, csv_async::Error>>,
+) -> JoinHandle<Vec<ParsedRow>> {
+    tokio::task::spawn_blocking(move || {
+        rows.into_iter()
+            .map(|row| match row {
+                Ok(record) => parse_row(&parser, Ok(&record)),
+                Err(e) => parse_row(&parser, Err(e)),
+            })
+            .collect()
+    })
+}
+
+fn parse_row(parser: &RowParser, row: Result<&ByteRecord, csv_async::Error>) -> ParsedRow {
+    let position = match &row {
+        Ok(record) => record.position().cloned(),
+        Err(e) => e.position().cloned(),
+    };
+    let line = position.as_ref().map(|p| p.line());
+    let transaction = tracing::debug_span!("row", line).in_scope(|| parser.validate_bytes(row));
+    ParsedRow {
+        position,
+        transaction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::ParseOptions;
+    use csv_async::AsyncReaderBuilder;
+
+    async fn rows_of(input: String, skip: u64, parallelism: usize) -> (Vec<u64>, u64) {
+        let mut reader = AsyncReaderBuilder::new().create_reader(std::io::Cursor::new(input));
+        let parser =
+            RowParser::new(reader.headers().await.unwrap(), ParseOptions::default()).unwrap();
+        drain(ParsedRows::new(reader, parser, skip, parallelism)).await
+    }
+
+    /// The IDs of every row handed out, 0 for malformed ones, and the rows
+    /// read
+    #[allow(clippy::useless_conversion)]
+    async fn drain<R>(mut rows: ParsedRows<R>) -> (Vec<u64>, u64)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let mut txs = Vec::new();
+        while let Some(row) = rows.next().await {
+            txs.push(row.transaction.map_or(0, |t| t.tx.into()));
+        }
+        (txs, rows.finish().await)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_pipeline_keeps_input_order() {
+        let count = PARSE_CHUNK_ROWS as u64 * 3 + 7;
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=count {
+            input.push_str(&format!("deposit,1,{tx},1\n"));
+        }
+        input.push_str("deposit,1,x,1\n");
+
+        let (inline, read) = rows_of(input.clone(), 5, 1).await;
+        assert_eq!(read, count + 1);
+        let expected: Vec<u64> = (6..=count).chain([0]).collect();
+        assert_eq!(inline, expected);
+        assert_eq!(rows_of(input, 5, 4).await, (expected, count + 1));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pool_reads_like_inline() {
+        let count = PARSE_CHUNK_ROWS as u64 * (QUEUED_CHUNKS as u64 + 2) + 3;
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=count {
+            input.push_str(&format!("deposit,1,{tx},1\n"));
+        }
+        input.push_str("deposit,1,x,1\n");
+
+        let mut reader =
+            AsyncReaderBuilder::new().create_reader(std::io::Cursor::new(input.clone()));
+        let parser =
+            RowParser::new(reader.headers().await.unwrap(), ParseOptions::default()).unwrap();
+        let rows = ParsedRows::<&[u8]>::on_blocking_pool(reader, parser, 5);
+        assert_eq!(drain(rows).await, rows_of(input, 5, 1).await);
+    }
+
+    #[cfg(feature = "mmap-input")]
+    #[test]
+    fn test_map_input_refuses_other_than_regular_files() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "type,client,tx,amount\n").unwrap();
+        assert_eq!(
+            map_input(file.path()).unwrap().get_ref().as_ref(),
+            b"type,client,tx,amount\n"
+        );
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(map_input(dir.path()), Err(EngineError::Usage(_))));
+    }
+
+    #[tokio::test]
+    async fn test_admin_channel() {
+        let key: AdminKey = "secret".parse().unwrap();
+        let fields = |row: &str| -> Vec<String> {
+            let mut fields: Vec<_> = row.split(',').map(String::from).collect();
+            fields.resize(8, String::new());
+            fields
+        };
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            format!(
+                "type,client,tx,signature\n\
+                 release,1,10,{}\n\
+                 close,2,11,{}\n\
+                 deposit,1,12,{}\n\
+                 close,3,13,\n",
+                key.sign(&fields("release,1,10")),
+                key.sign(&fields("close,2,99")),
+                key.sign(&fields("deposit,1,12")),
+            ),
+        )
+        .unwrap();
+        let admin = read_admin_input(file.path(), &key, ParseOptions::default())
+            .await
+            .unwrap();
+        let codes: Vec<_> = admin
+            .iter()
+            .map(|row| match &row.transaction {
+                Ok(transaction) => transaction.tx_type.name().into_owned(),
+                Err(malformed) => malformed.error.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "release",
+                "Admin channel error: missing or wrong signature",
+                "Admin channel error: deposit rows belong on the regular input",
+                "Admin channel error: missing or wrong signature",
+            ]
+        );
+        let other: AdminKey = "other".parse().unwrap();
+        assert!(!other.verify(&fields("release,1,10"), &key.sign(&fields("release,1,10"))));
+        assert_eq!(format!("{key:?}"), "AdminKey(..)");
+
+        // Admin rows go last; the same types on the regular input are refused
+        let input = "type,client,tx,amount\ndeposit,1,1,5\nhold,1,2,\n";
+        let mut reader = AsyncReaderBuilder::new().create_reader(std::io::Cursor::new(input));
+        let parser =
+            RowParser::new(reader.headers().await.unwrap(), ParseOptions::default()).unwrap();
+        let mut rows = ParsedRows::new(reader, parser, 0, 1).with_admin_rows(admin);
+        let mut handed = Vec::new();
+        while let Some(row) = rows.next().await {
+            handed.push(row.transaction.map_err(|m| (m.line, m.fields[0].clone())));
+        }
+        assert_eq!(handed.len(), 6);
+        assert_eq!(handed[0].as_ref().unwrap().tx, 1);
+        assert_eq!(
+            handed[1].as_ref().unwrap_err(),
+            &(Some(3), "hold".to_string())
+        );
+        assert_eq!(handed[2].as_ref().unwrap().tx, 10);
+    }
+}
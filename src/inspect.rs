@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::io;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Currency, TxId};
+use crate::snapshot::Snapshot;
+
+/// One line of a client's transaction history
+#[derive(Debug, Serialize)]
+struct HistoryRow<'a> {
+    tx: TxId,
+    #[serde(rename = "type")]
+    tx_type: &'a str,
+    amount: rust_decimal::Decimal,
+    disputed: bool,
+    timestamp: Option<String>,
+    currency: Option<Currency>,
+    /// Rate applied by a conversion
+    rate: Option<rust_decimal::Decimal>,
+}
+
+/// Write a client's balances and applied row counts followed by their
+/// transaction history.
+///
+/// Balances in a named currency are labelled with it, e.g. `available (EUR)`.
+/// History lists every deposit, withdrawal and conversion record kept for the
+/// client together with whether it is currently under dispute.
+pub fn write_client_report<W: io::Write>(
+    snapshot: &Snapshot,
+    client: ClientId,
+    mut writer: W,
+) -> Result<(), EngineError> {
+    let account = snapshot
+        .account(client)
+        .ok_or(EngineError::UnknownClient { client })?;
+
+    writeln!(writer, "client: {}", account.client)?;
+    for (currency, balance) in account.balances() {
+        let label = currency.map(|c| format!(" ({c})")).unwrap_or_default();
+        writeln!(writer, "available{label}: {}", balance.available)?;
+        writeln!(writer, "held{label}: {}", balance.held)?;
+        writeln!(writer, "total{label}: {}", balance.total)?;
+    }
+    writeln!(writer, "status: {}", account.status)?;
+    let counts = account.counts;
+    writeln!(
+        writer,
+        "applied: {} deposits, {} withdrawals, {} disputes, {} chargebacks",
+        counts.deposits, counts.withdrawals, counts.disputes, counts.chargebacks
+    )?;
+    writeln!(writer)?;
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    for (key, record) in snapshot.client_transactions(client) {
+        wtr.serialize(HistoryRow {
+            tx: key.tx,
+            tx_type: if record.conversion.is_some() {
+                "convert"
+            } else if record.amount.is_sign_negative() {
+                "withdrawal"
+            } else {
+                "deposit"
+            },
+            amount: record.amount.abs(),
+            disputed: record.disputed,
+            timestamp: record.timestamp.map(|t| t.to_rfc3339()),
+            currency: record.currency,
+            rate: record.conversion.map(|c| c.rate),
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, Balance, TransactionRecord, TxKey};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_client_report() {
+        let mut account = Account::new(42);
+        *account.balance_mut(None) = Balance {
+            available: Decimal::from(5),
+            held: Decimal::from(10),
+            total: Decimal::from(15),
+        };
+        let eur = "EUR".parse().ok();
+        account.balance_mut(eur).available = Decimal::ONE;
+        account.balance_mut(eur).total = Decimal::ONE;
+        let snapshot = Snapshot {
+            version: crate::snapshot::SNAPSHOT_VERSION,
+            accounts: vec![account],
+            state_root: Default::default(),
+            transactions: vec![
+                (
+                    TxKey::global(1),
+                    TransactionRecord {
+                        client: 42,
+                        amount: Decimal::from(20),
+                        disputed: false,
+                        timestamp: None,
+                        currency: None,
+                        conversion: None,
+                        held: None,
+                    },
+                ),
+                (
+                    TxKey::global(2),
+                    TransactionRecord {
+                        client: 7,
+                        amount: Decimal::from(1),
+                        disputed: false,
+                        timestamp: None,
+                        currency: None,
+                        conversion: None,
+                        held: None,
+                    },
+                ),
+                (
+                    TxKey::global(3),
+                    TransactionRecord {
+                        client: 42,
+                        amount: Decimal::from(-5),
+                        disputed: false,
+                        timestamp: None,
+                        currency: eur,
+                        conversion: None,
+                        held: None,
+                    },
+                ),
+            ],
+            offset: None,
+            schedule: Default::default(),
+            chain: None,
+            tombstones: Vec::new(),
+        };
+
+        let mut output = Vec::new();
+        write_client_report(&snapshot, 42, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client: 42\navailable: 5\nheld: 10\ntotal: 15\n\
+             available (EUR): 1\nheld (EUR): 0\ntotal (EUR): 1\nstatus: active\n\
+             applied: 0 deposits, 0 withdrawals, 0 disputes, 0 chargebacks\n\n\
+             tx,type,amount,disputed,timestamp,currency,rate\n\
+             1,deposit,20,false,,,\n\
+             3,withdrawal,5,false,,EUR,\n"
+        );
+
+        assert!(matches!(
+            write_client_report(&snapshot, 1, Vec::new()),
+            Err(EngineError::UnknownClient { client: 1 })
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::models::{
+    Account, AccountsMap, ClientId, Currency, TransactionType, TransactionsMap, TxId,
+};
+use crate::outcome::Applied;
+
+/// Which account invariant was broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// `total != available + held`
+    BalanceMismatch,
+    /// Held funds below zero
+    NegativeHeld,
+    /// Held funds below what the open disputes hold, so that resolving them
+    /// all would release more than is held
+    HeldBelowDisputes { disputed: Decimal },
+    /// A resolve released more than its dispute had held
+    ResolveExceedsHold { hold: Decimal, released: Decimal },
+}
+
+/// An account found in a state that should be impossible
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    pub client: ClientId,
+    /// Currency of the broken balance, if the input named one
+    pub currency: Option<Currency>,
+    /// Transaction that was just applied, when checked after a mutation
+    pub tx: Option<TxId>,
+    pub kind: ViolationKind,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Account {}", self.client)?;
+        if let Some(currency) = self.currency {
+            write!(f, " ({currency})")?;
+        }
+        match self.kind {
+            ViolationKind::BalanceMismatch => write!(
+                f,
+                ": total {} != available {} + held {}",
+                self.total, self.available, self.held
+            )?,
+            ViolationKind::NegativeHeld => write!(f, ": held {} is negative", self.held)?,
+            ViolationKind::HeldBelowDisputes { disputed } => write!(
+                f,
+                ": held {} is below the {} open disputes hold",
+                self.held, disputed
+            )?,
+            ViolationKind::ResolveExceedsHold { hold, released } => write!(
+                f,
+                ": resolve released {released} but its dispute held {hold}"
+            )?,
+        }
+        if let Some(tx) = self.tx {
+            write!(f, " (after Tx: {tx})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the invariants of each of an account's balances.
+///
+/// Negative `available` or `total` are not flagged: disputing or charging back
+/// a deposit whose funds were already withdrawn legitimately produces them.
+pub fn check_account(account: &Account, tx: Option<TxId>) -> Vec<InvariantViolation> {
+    let mut violations = Vec::new();
+    for (currency, balance) in account.balances() {
+        let violation = |kind| InvariantViolation {
+            client: account.client,
+            currency,
+            tx,
+            kind,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+        };
+        if balance.total != balance.available + balance.held {
+            violations.push(violation(ViolationKind::BalanceMismatch));
+        }
+        if balance.held < Decimal::ZERO {
+            violations.push(violation(ViolationKind::NegativeHeld));
+        }
+    }
+    violations
+}
+
+/// Check every account, sorted by client ID for a stable report
+pub fn check_accounts(accounts: &AccountsMap) -> Vec<InvariantViolation> {
+    let mut violations: Vec<_> = accounts
+        .iter()
+        .flat_map(|entry| check_account(entry.value(), None))
+        .collect();
+    violations.sort_by_key(|v| v.client);
+    violations
+}
+
+/// What each open dispute of one client holds, tracked from the rows as
+/// they are applied rather than read back from the transaction records.
+///
+/// Held funds are only ever moved there by a dispute and released by its
+/// resolve or chargeback, so a balance holding less than its open disputes
+/// means some other row spent held funds.
+#[derive(Debug, Clone, Default)]
+pub struct DisputeHolds {
+    open: HashMap<TxId, (Option<Currency>, Decimal)>,
+}
+
+impl DisputeHolds {
+    /// Track `applied` and check `account`, as it is right after, against
+    /// the disputes still open.
+    ///
+    /// Disputes opened before tracking started, such as ones restored from
+    /// a snapshot, are not counted, which can only hide a shortfall.
+    pub fn check(&mut self, applied: &Applied, account: &Account) -> Vec<InvariantViolation> {
+        let transaction = &applied.transaction;
+        let mut violations = Vec::new();
+        let violation = |currency, kind| {
+            let balance = account.balance(currency);
+            InvariantViolation {
+                client: account.client,
+                currency,
+                tx: Some(transaction.tx),
+                kind,
+                available: balance.available,
+                held: balance.held,
+                total: balance.total,
+            }
+        };
+        match transaction.tx_type {
+            TransactionType::Dispute => {
+                self.open
+                    .insert(transaction.tx, (transaction.currency, applied.amount));
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                if let Some((currency, hold)) = self.open.remove(&transaction.tx)
+                    && transaction.tx_type == TransactionType::Resolve
+                    && applied.amount > hold
+                {
+                    let kind = ViolationKind::ResolveExceedsHold {
+                        hold,
+                        released: applied.amount,
+                    };
+                    violations.push(violation(currency, kind));
+                }
+            }
+            _ => {}
+        }
+        for currency in applied.currencies() {
+            let disputed: Decimal = self
+                .open
+                .values()
+                .filter(|(open, _)| *open == currency)
+                .map(|(_, hold)| *hold)
+                .sum();
+            if account.balance(currency).held < disputed {
+                violations.push(violation(
+                    currency,
+                    ViolationKind::HeldBelowDisputes { disputed },
+                ));
+            }
+        }
+        violations
+    }
+}
+
+/// Check every balance holds at least what the open disputes recorded in
+/// `transactions` hold, sorted by client and currency
+pub fn check_dispute_holds(
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+) -> Vec<InvariantViolation> {
+    let mut disputed: HashMap<(ClientId, Option<Currency>), Decimal> = HashMap::new();
+    for entry in transactions.iter().filter(|entry| entry.disputed) {
+        *disputed.entry((entry.client, entry.currency)).or_default() +=
+            entry.held.unwrap_or(entry.amount);
+    }
+    let mut violations: Vec<_> = disputed
+        .into_iter()
+        .filter_map(|((client, currency), disputed)| {
+            let account = accounts.get(&client)?;
+            let balance = account.balance(currency);
+            (balance.held < disputed).then_some(InvariantViolation {
+                client,
+                currency,
+                tx: None,
+                kind: ViolationKind::HeldBelowDisputes { disputed },
+                available: balance.available,
+                held: balance.held,
+                total: balance.total,
+            })
+        })
+        .collect();
+    violations.sort_by_key(|v| (v.client, v.currency));
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Balance, Transaction, TransactionRecord, TxKey};
+
+    fn account(available: i64, held: i64, total: i64) -> Account {
+        let mut account = Account::new(1);
+        *account.balance_mut(None) = Balance {
+            available: Decimal::from(available),
+            held: Decimal::from(held),
+            total: Decimal::from(total),
+        };
+        account
+    }
+
+    #[test]
+    fn test_consistent_account_has_no_violations() {
+        assert!(check_account(&account(10, 5, 15), None).is_empty());
+        assert!(check_account(&account(-5, 10, 5), None).is_empty());
+    }
+
+    #[test]
+    fn test_detects_violations() {
+        let violations = check_account(&account(10, -5, 10), Some(7));
+        let kinds: Vec<_> = violations.iter().map(|v| v.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![ViolationKind::BalanceMismatch, ViolationKind::NegativeHeld]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "Account 1: total 10 != available 10 + held -5 (after Tx: 7)"
+        );
+    }
+
+    #[test]
+    fn test_violation_names_currency() {
+        let mut account = Account::new(1);
+        account.balance_mut("EUR".parse().ok()).held = Decimal::NEGATIVE_ONE;
+        let violations = check_account(&account, None);
+        assert_eq!(
+            violations.last().unwrap().to_string(),
+            "Account 1 (EUR): held -1 is negative"
+        );
+    }
+
+    #[test]
+    fn test_check_accounts_sorted_by_client() {
+        let accounts = AccountsMap::new();
+        for client in [3, 1, 2] {
+            let mut broken = account(1, 1, 1);
+            broken.client = client;
+            accounts.insert(client, broken);
+        }
+        let clients: Vec<_> = check_accounts(&accounts).iter().map(|v| v.client).collect();
+        assert_eq!(clients, vec![1, 2, 3]);
+    }
+
+    fn applied(tx_type: TransactionType, tx: TxId, amount: i64) -> Applied {
+        Applied {
+            transaction: Transaction::new(tx_type, 1, tx, None),
+            amount: Decimal::from(amount),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_dispute_holds_catch_spent_held_funds() {
+        let mut holds = DisputeHolds::default();
+        let dispute = applied(TransactionType::Dispute, 1, 10);
+        assert!(holds.check(&dispute, &account(0, 10, 10)).is_empty());
+
+        // A withdrawal that took funds out of held
+        let withdrawal = applied(TransactionType::Withdrawal, 2, 4);
+        let violations = holds.check(&withdrawal, &account(0, 6, 6));
+        assert_eq!(
+            violations[0].to_string(),
+            "Account 1: held 6 is below the 10 open disputes hold (after Tx: 2)"
+        );
+
+        let resolve = applied(TransactionType::Resolve, 1, 12);
+        let kinds: Vec<_> = holds
+            .check(&resolve, &account(12, 0, 12))
+            .iter()
+            .map(|v| v.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![ViolationKind::ResolveExceedsHold {
+                hold: Decimal::from(10),
+                released: Decimal::from(12),
+            }]
+        );
+        let chargeback = applied(TransactionType::Chargeback, 1, 10);
+        assert!(holds.check(&chargeback, &account(0, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_check_dispute_holds_against_records() {
+        let accounts = AccountsMap::new();
+        accounts.insert(1, account(0, 5, 5));
+        let transactions = TransactionsMap::default();
+        for (tx, held) in [(1, None), (2, Some(Decimal::from(3)))] {
+            let record = TransactionRecord {
+                client: 1,
+                amount: Decimal::from(4),
+                disputed: true,
+                timestamp: None,
+                currency: None,
+                conversion: None,
+                held,
+            };
+            transactions.insert(TxKey::global(tx), record);
+        }
+        let violations = check_dispute_holds(&accounts, &transactions);
+        assert_eq!(
+            violations[0].to_string(),
+            "Account 1: held 5 is below the 7 open disputes hold"
+        );
+        accounts.insert(1, account(0, 7, 7));
+        assert!(check_dispute_holds(&accounts, &transactions).is_empty());
+    }
+}
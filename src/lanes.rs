@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+
+use crate::config::PriorityTypes;
+use crate::models::Transaction;
+
+/// Rows waiting for a client task, handed out with the priority lane first.
+///
+/// A priority row goes ahead of the rows queued before it, unless one of
+/// them has its transaction ID: a chargeback never overtakes the dispute it
+/// settles, nor a dispute the deposit it disputes. Priority rows keep their
+/// order among themselves, as do all other rows.
+#[derive(Debug)]
+pub struct Lanes {
+    priority: PriorityTypes,
+    queued: VecDeque<Transaction>,
+    capacity: usize,
+}
+
+impl Lanes {
+    /// Lanes holding at most `capacity` rows between them
+    pub fn new(priority: PriorityTypes, capacity: usize) -> Self {
+        Lanes {
+            priority,
+            queued: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queued.len() >= self.capacity
+    }
+
+    pub fn push(&mut self, transaction: Transaction) {
+        self.queued.push_back(transaction);
+    }
+
+    /// The next row to apply: the earliest priority row if nothing ahead of
+    /// it shares its transaction ID, and otherwise the earliest row
+    pub fn pop(&mut self) -> Option<Transaction> {
+        let first = self
+            .queued
+            .iter()
+            .position(|queued| self.priority.contains(&queued.tx_type));
+        if let Some(index) = first
+            && !self
+                .queued
+                .range(..index)
+                .any(|ahead| ahead.tx == self.queued[index].tx)
+        {
+            return self.queued.remove(index);
+        }
+        self.queued.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{TransactionType, TxId};
+
+    /// Push client 1's rows, then pop every row in the order they come out
+    fn drain(lanes: &mut Lanes, rows: &[(TransactionType, TxId)]) -> Vec<(TransactionType, TxId)> {
+        for (tx_type, tx) in rows.iter().cloned() {
+            lanes.push(Transaction::new(tx_type, 1, tx, None));
+        }
+        std::iter::from_fn(|| lanes.pop())
+            .map(|t| (t.tx_type, t.tx))
+            .collect()
+    }
+
+    #[test]
+    fn test_priority_rows_skip_ahead_of_unrelated_rows() {
+        use TransactionType::{Close, Deposit};
+        let mut lanes = Lanes::new("close".parse().unwrap(), 8);
+        let order = drain(&mut lanes, &[(Deposit, 1), (Deposit, 2), (Close, 9)]);
+        assert_eq!(order, [(Close, 9), (Deposit, 1), (Deposit, 2)]);
+    }
+
+    #[test]
+    fn test_priority_row_waits_for_rows_with_its_id() {
+        use TransactionType::{Chargeback, Close, Deposit, Dispute};
+        let mut lanes = Lanes::new("dispute,chargeback,close".parse().unwrap(), 8);
+        let order = drain(
+            &mut lanes,
+            &[
+                (Deposit, 1),
+                (Deposit, 2),
+                (Dispute, 1),
+                (Deposit, 3),
+                (Close, 9),
+                (Chargeback, 1),
+            ],
+        );
+        // The dispute waits for its deposit; the close then goes first, and
+        // the chargeback waits for the dispute
+        assert_eq!(
+            order,
+            [
+                (Deposit, 1),
+                (Dispute, 1),
+                (Close, 9),
+                (Chargeback, 1),
+                (Deposit, 2),
+                (Deposit, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lanes_fill_up_to_capacity() {
+        let mut lanes = Lanes::new("close".parse().unwrap(), 2);
+        lanes.push(Transaction::new(TransactionType::Deposit, 1, 1, None));
+        assert!(!lanes.is_full());
+        lanes.push(Transaction::new(TransactionType::Close, 1, 2, None));
+        assert!(lanes.is_full());
+    }
+}
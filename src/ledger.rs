@@ -0,0 +1,393 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::error::EngineError;
+use crate::models::{AccountsMap, ClientId, Currency, TransactionType, TxId};
+use crate::outcome::Applied;
+
+/// An account in the double-entry books
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// Funds held by the operator on behalf of all clients
+    OperatorCash,
+    /// What the operator owes a client and the client may spend
+    ClientAvailable(ClientId),
+    /// What the operator owes a client but is frozen by a dispute
+    ClientHeld(ClientId),
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::OperatorCash => write!(f, "operator:cash"),
+            LedgerAccount::ClientAvailable(client) => write!(f, "client:{client}:available"),
+            LedgerAccount::ClientHeld(client) => write!(f, "client:{client}:held"),
+        }
+    }
+}
+
+/// One side of a journal entry; positive amounts are debits, negative credits
+#[derive(Debug, Clone, PartialEq)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub currency: Option<Currency>,
+    pub amount: Decimal,
+}
+
+/// Set of postings produced by one applied transaction, balanced within each
+/// currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub tx: TxId,
+    pub postings: Vec<Posting>,
+}
+
+impl JournalEntry {
+    /// Build the entry for an applied transaction.
+    ///
+    /// Client balances are liabilities of the operator, so a deposit debits
+    /// operator cash and credits the client's available funds. A conversion
+    /// is booked as a withdrawal in the source currency and a deposit in the
+    /// target currency. A chargeback whose dispute held less than the deposit
+    /// takes the rest from the client's available funds.
+    pub fn for_applied(applied: &Applied) -> Self {
+        let client = applied.transaction.client;
+        let currency = applied.transaction.currency;
+        // Custom types report a signed change; every other amount is positive
+        let amount = applied.amount.abs();
+        let available = LedgerAccount::ClientAvailable(client);
+        let held = LedgerAccount::ClientHeld(client);
+        let cash = LedgerAccount::OperatorCash;
+
+        // (debited account, credited account)
+        let pair = match applied.transaction.tx_type {
+            TransactionType::Deposit => Some((cash, available)),
+            TransactionType::Withdrawal | TransactionType::Convert => Some((available, cash)),
+            TransactionType::Dispute => Some((available, held)),
+            TransactionType::Resolve => Some((held, available)),
+            TransactionType::Chargeback => Some((held, cash)),
+            // Account status changes move no funds; a close books its
+            // payouts below
+            TransactionType::Hold | TransactionType::Release | TransactionType::Close => None,
+            TransactionType::Custom(_) if applied.amount.is_sign_negative() => {
+                Some((available, cash))
+            }
+            TransactionType::Custom(_) => Some((cash, available)),
+        };
+
+        let mut postings = Vec::new();
+        let mut post = |debit, credit, currency, amount: Decimal| {
+            postings.push(Posting {
+                account: debit,
+                currency,
+                amount,
+            });
+            postings.push(Posting {
+                account: credit,
+                currency,
+                amount: -amount,
+            });
+        };
+        let uncovered = match (&applied.transaction.tx_type, applied.shortfall) {
+            (TransactionType::Chargeback, Some(shortfall)) => shortfall.uncovered,
+            _ => Decimal::ZERO,
+        };
+        // A dispute deferred by the `review` shortfall policy holds nothing
+        if let Some((debit, credit)) = pair
+            && amount != uncovered
+        {
+            post(debit, credit, currency, amount - uncovered);
+        }
+        if !uncovered.is_zero() {
+            post(available, cash, currency, uncovered);
+        }
+        if let Some(conversion) = applied.conversion {
+            post(cash, available, Some(conversion.to), conversion.converted);
+        }
+        for payout in &applied.payouts {
+            post(available, cash, payout.currency, payout.amount);
+        }
+
+        JournalEntry {
+            tx: applied.transaction.tx,
+            postings,
+        }
+    }
+
+    /// Whether debits equal credits in every currency
+    pub fn is_balanced(&self) -> bool {
+        let mut net: BTreeMap<Option<Currency>, Decimal> = BTreeMap::new();
+        for posting in &self.postings {
+            *net.entry(posting.currency).or_default() += posting.amount;
+        }
+        net.values().all(|amount| amount.is_zero())
+    }
+}
+
+/// One row of the trial balance output
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TrialBalanceRow {
+    pub account: String,
+    pub debit: Decimal,
+    pub credit: Decimal,
+}
+
+/// Double-entry books accumulated from applied transactions.
+///
+/// Each currency is kept in separate books that balance on their own; rows
+/// without a currency go to the `None` books.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    books: BTreeMap<Option<Currency>, BTreeMap<LedgerAccount, Decimal>>,
+    entries: u64,
+}
+
+impl Ledger {
+    /// Post a balanced journal entry
+    pub fn post(&mut self, entry: &JournalEntry) {
+        debug_assert!(entry.is_balanced(), "unbalanced journal entry {entry:?}");
+        for posting in &entry.postings {
+            *self
+                .books
+                .entry(posting.currency)
+                .or_default()
+                .entry(posting.account)
+                .or_default() += posting.amount;
+        }
+        self.entries += 1;
+    }
+
+    /// Number of journal entries posted so far
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Net balance of a single ledger account in one currency (debits minus
+    /// credits)
+    pub fn balance(&self, currency: Option<Currency>, account: LedgerAccount) -> Decimal {
+        self.books
+            .get(&currency)
+            .and_then(|book| book.get(&account))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sum of every ledger balance; zero whenever the books are consistent
+    pub fn net(&self) -> Decimal {
+        self.books.values().flat_map(|book| book.values()).sum()
+    }
+
+    /// Per-account debit/credit balances followed by a `total` row, repeated
+    /// for each currency.
+    ///
+    /// Accounts and totals in a named currency are prefixed with it, e.g.
+    /// `EUR:operator:cash` and `EUR:total`.
+    pub fn trial_balance(&self) -> Vec<TrialBalanceRow> {
+        let mut rows = Vec::new();
+        for (currency, book) in &self.books {
+            let name = |account: &dyn fmt::Display| match currency {
+                Some(currency) => format!("{currency}:{account}"),
+                None => account.to_string(),
+            };
+            let first = rows.len();
+            rows.extend(book.iter().map(|(account, &balance)| TrialBalanceRow {
+                account: name(account),
+                debit: if balance > Decimal::ZERO {
+                    balance
+                } else {
+                    Decimal::ZERO
+                },
+                credit: if balance < Decimal::ZERO {
+                    -balance
+                } else {
+                    Decimal::ZERO
+                },
+            }));
+            let total = TrialBalanceRow {
+                account: name(&"total"),
+                debit: rows[first..].iter().map(|r| r.debit).sum(),
+                credit: rows[first..].iter().map(|r| r.credit).sum(),
+            };
+            rows.push(total);
+        }
+        if rows.is_empty() {
+            rows.push(TrialBalanceRow {
+                account: "total".to_string(),
+                debit: Decimal::ZERO,
+                credit: Decimal::ZERO,
+            });
+        }
+        rows
+    }
+
+    /// Clients whose ledger balances disagree with the account snapshot in
+    /// any currency
+    pub fn mismatched_clients(&self, accounts: &AccountsMap) -> Vec<ClientId> {
+        let mut clients: Vec<_> = accounts
+            .iter()
+            .filter(|entry| {
+                let account = entry.value();
+                account.balances().any(|(currency, balance)| {
+                    self.balance(currency, LedgerAccount::ClientAvailable(account.client))
+                        != -balance.available
+                        || self.balance(currency, LedgerAccount::ClientHeld(account.client))
+                            != -balance.held
+                })
+            })
+            .map(|entry| *entry.key())
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Write the trial balance as CSV
+    pub fn write_trial_balance<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for row in self.trial_balance() {
+            wtr.serialize(row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DisputeShortfallPolicy;
+    use crate::models::{Payout, Transaction};
+    use crate::outcome::Shortfall;
+
+    fn applied(tx_type: TransactionType, tx: TxId, amount: i64) -> Applied {
+        let amount = Decimal::from(amount);
+        let payouts = match tx_type {
+            TransactionType::Close => vec![Payout {
+                currency: None,
+                amount,
+            }],
+            _ => Vec::new(),
+        };
+        Applied {
+            transaction: Transaction::new(tx_type, 1, tx, None),
+            amount,
+            conversion: None,
+            payouts,
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_every_entry_is_balanced() {
+        for tx_type in [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Convert,
+            TransactionType::Hold,
+            TransactionType::Release,
+            TransactionType::Close,
+        ] {
+            assert!(JournalEntry::for_applied(&applied(tx_type, 1, 10)).is_balanced());
+        }
+    }
+
+    #[test]
+    fn test_trial_balance_sums_to_zero() {
+        let mut ledger = Ledger::default();
+        for a in [
+            applied(TransactionType::Deposit, 1, 100),
+            applied(TransactionType::Withdrawal, 2, 30),
+            applied(TransactionType::Dispute, 1, 100),
+            applied(TransactionType::Chargeback, 1, 100),
+        ] {
+            ledger.post(&JournalEntry::for_applied(&a));
+        }
+
+        assert_eq!(ledger.entries(), 4);
+        assert_eq!(ledger.net(), Decimal::ZERO);
+        // The client's available balance is -30, a debit: they owe the operator
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::ClientAvailable(1)),
+            Decimal::from(30)
+        );
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::ClientHeld(1)),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::OperatorCash),
+            Decimal::from(-30)
+        );
+
+        let total = ledger.trial_balance().pop().unwrap();
+        assert_eq!(total.account, "total");
+        assert_eq!(total.debit, total.credit);
+    }
+
+    #[test]
+    fn test_capped_chargeback_takes_rest_from_available() {
+        let mut ledger = Ledger::default();
+        let mut chargeback = applied(TransactionType::Chargeback, 1, 100);
+        chargeback.shortfall = Some(Shortfall {
+            policy: DisputeShortfallPolicy::Cap,
+            uncovered: Decimal::from(60),
+        });
+        for a in [
+            applied(TransactionType::Deposit, 1, 100),
+            applied(TransactionType::Withdrawal, 2, 60),
+            applied(TransactionType::Dispute, 1, 40),
+            chargeback,
+        ] {
+            ledger.post(&JournalEntry::for_applied(&a));
+        }
+
+        assert_eq!(ledger.net(), Decimal::ZERO);
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::ClientAvailable(1)),
+            Decimal::from(60)
+        );
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::ClientHeld(1)),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn test_currencies_have_separate_books() {
+        let mut ledger = Ledger::default();
+        let mut eur = applied(TransactionType::Deposit, 2, 7);
+        eur.transaction.currency = "EUR".parse().ok();
+        ledger.post(&JournalEntry::for_applied(&applied(
+            TransactionType::Deposit,
+            1,
+            100,
+        )));
+        ledger.post(&JournalEntry::for_applied(&eur));
+
+        assert_eq!(
+            ledger.balance(None, LedgerAccount::OperatorCash),
+            Decimal::from(100)
+        );
+        let accounts: Vec<_> = ledger
+            .trial_balance()
+            .into_iter()
+            .map(|row| (row.account, row.debit))
+            .collect();
+        assert_eq!(
+            accounts,
+            [
+                ("operator:cash".to_string(), Decimal::from(100)),
+                ("client:1:available".to_string(), Decimal::ZERO),
+                ("total".to_string(), Decimal::from(100)),
+                ("EUR:operator:cash".to_string(), Decimal::from(7)),
+                ("EUR:client:1:available".to_string(), Decimal::ZERO),
+                ("EUR:total".to_string(), Decimal::from(7)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+pub mod account;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod audit;
+pub mod bloom;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod chronology;
+pub mod cli;
+pub mod cold;
+pub mod compact;
+pub mod config;
+#[cfg(feature = "cli")]
+pub mod control;
+pub mod decode;
+pub mod diff;
+pub mod encryption;
+pub mod engine;
+pub mod error;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixtures;
+pub mod fraud;
+pub mod fx;
+pub mod handlers;
+pub mod hashchain;
+pub mod idempotency;
+#[cfg(feature = "cli")]
+pub mod ingest;
+pub mod inspect;
+pub mod invariants;
+pub mod lanes;
+pub mod ledger;
+pub mod limits;
+pub mod memory;
+pub mod merge;
+pub mod merkle;
+pub mod meta;
+pub mod middleware;
+pub mod models;
+pub mod notify;
+pub mod outcome;
+pub mod ownership;
+pub mod postgres;
+pub mod progress;
+pub mod publish;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod reconcile;
+pub mod recovery;
+pub mod rejects;
+pub mod report;
+pub mod retain;
+pub mod schedule;
+pub mod shard;
+pub mod simulation;
+pub mod snapshot;
+pub mod spill;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod statement;
+pub mod stats;
+pub mod store;
+pub mod telemetry;
+pub mod tenant;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod transaction;
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod workload;
@@ -0,0 +1,679 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::EngineError;
+use crate::meta::{AccountMetas, Segment};
+use crate::models::{ClientId, Currency, Transaction, TransactionType};
+use crate::outcome::Applied;
+use crate::schedule::Interval;
+
+/// At most `rows` rows per `per`, written like `100/1m`.
+///
+/// Enforced as a token bucket holding up to `rows` tokens, refilled evenly
+/// over each `per`; every row takes one token, so a client can burst up to
+/// `rows` rows at once and then keep to the rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub rows: u32,
+    pub per: Interval,
+}
+
+impl FromStr for RateLimit {
+    type Err = EngineError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid rate limit '{raw}': expected a positive row count and an interval, such as 100/1m"
+            ))
+        };
+        let (rows, per) = raw.trim().split_once('/').ok_or_else(invalid)?;
+        let rows = rows.trim().parse().map_err(|_| invalid())?;
+        if rows == 0 {
+            return Err(invalid());
+        }
+        let per = per.parse().map_err(|_| invalid())?;
+        Ok(RateLimit { rows, per })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.rows, self.per)
+    }
+}
+
+/// Caps on a client's transactions; unset caps do not apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct RiskLimits {
+    /// Largest single deposit, withdrawal or conversion
+    pub max_tx_amount: Option<Decimal>,
+    /// Most a client may deposit per calendar day (UTC)
+    pub daily_deposit_limit: Option<Decimal>,
+    /// Most a client may withdraw per calendar day (UTC)
+    pub daily_withdrawal_limit: Option<Decimal>,
+    /// Most a client may deposit over the whole run
+    pub total_deposit_limit: Option<Decimal>,
+    /// Most rows of any type a client may send in a stretch of time
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl RiskLimits {
+    /// Use caps from `fallback` for every cap left unset here
+    pub fn or(self, fallback: RiskLimits) -> RiskLimits {
+        RiskLimits {
+            max_tx_amount: self.max_tx_amount.or(fallback.max_tx_amount),
+            daily_deposit_limit: self.daily_deposit_limit.or(fallback.daily_deposit_limit),
+            daily_withdrawal_limit: self
+                .daily_withdrawal_limit
+                .or(fallback.daily_withdrawal_limit),
+            total_deposit_limit: self.total_deposit_limit.or(fallback.total_deposit_limit),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.caps().next().is_none() && self.rate_limit.is_none()
+    }
+
+    /// Every cap that is set
+    pub fn caps(&self) -> impl Iterator<Item = Decimal> {
+        [
+            self.max_tx_amount,
+            self.daily_deposit_limit,
+            self.daily_withdrawal_limit,
+            self.total_deposit_limit,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// One row of a `--client-limits` file; empty cells fall back to the global
+/// caps
+#[derive(Debug, Deserialize)]
+struct ClientLimitsRow {
+    client: ClientId,
+    max_tx_amount: Option<Decimal>,
+    daily_deposit_limit: Option<Decimal>,
+    daily_withdrawal_limit: Option<Decimal>,
+    total_deposit_limit: Option<Decimal>,
+    rate_limit: Option<RateLimit>,
+}
+
+/// One row of a `--segment-limits` file; empty cells fall back to broader
+/// segments and then to the global caps
+#[derive(Debug, Deserialize)]
+struct SegmentLimitsRow {
+    segment: Segment,
+    max_tx_amount: Option<Decimal>,
+    daily_deposit_limit: Option<Decimal>,
+    daily_withdrawal_limit: Option<Decimal>,
+    total_deposit_limit: Option<Decimal>,
+    rate_limit: Option<RateLimit>,
+}
+
+/// Global caps plus overrides per account segment and per client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LimitsTable {
+    global: RiskLimits,
+    /// Sorted from the broadest segment to the most specific
+    segments: Vec<(Segment, RiskLimits)>,
+    clients: HashMap<ClientId, RiskLimits>,
+    meta: Arc<AccountMetas>,
+}
+
+impl LimitsTable {
+    /// Caps that apply to every client alike
+    pub fn new(global: RiskLimits) -> Self {
+        LimitsTable {
+            global,
+            ..Default::default()
+        }
+    }
+
+    /// Account metadata the segment overrides are matched against
+    pub fn with_meta(self, meta: Arc<AccountMetas>) -> Self {
+        LimitsTable { meta, ..self }
+    }
+
+    /// Read per-segment overrides from a
+    /// `segment,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit,total_deposit_limit`
+    /// CSV file
+    pub fn load_segments(self, path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        self.read_segments(file)
+            .map_err(|e| EngineError::Limits(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse per-segment overrides; each segment may only be listed once and
+    /// every cap must be positive
+    pub fn read_segments<R: io::Read>(mut self, reader: R) -> Result<Self, String> {
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let row: SegmentLimitsRow = row.map_err(|e| e.to_string())?;
+            let limits = RiskLimits {
+                max_tx_amount: row.max_tx_amount,
+                daily_deposit_limit: row.daily_deposit_limit,
+                daily_withdrawal_limit: row.daily_withdrawal_limit,
+                total_deposit_limit: row.total_deposit_limit,
+                rate_limit: row.rate_limit,
+            };
+            if limits.caps().any(|cap| cap <= Decimal::ZERO) {
+                return Err(format!("limits for {} must be positive", row.segment));
+            }
+            if self
+                .segments
+                .iter()
+                .any(|(segment, _)| *segment == row.segment)
+            {
+                return Err(format!("{} is listed twice", row.segment));
+            }
+            self.segments.push((row.segment, limits));
+        }
+        self.segments.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(self)
+    }
+
+    /// Read per-client overrides from a
+    /// `client,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit` CSV
+    /// file
+    pub fn load_clients(self, path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        self.read_clients(file)
+            .map_err(|e| EngineError::Limits(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse per-client overrides; each client may only be listed once and
+    /// every cap must be positive
+    pub fn read_clients<R: io::Read>(mut self, reader: R) -> Result<Self, String> {
+        for row in csv::Reader::from_reader(reader).deserialize() {
+            let row: ClientLimitsRow = row.map_err(|e| e.to_string())?;
+            let limits = RiskLimits {
+                max_tx_amount: row.max_tx_amount,
+                daily_deposit_limit: row.daily_deposit_limit,
+                daily_withdrawal_limit: row.daily_withdrawal_limit,
+                total_deposit_limit: row.total_deposit_limit,
+                rate_limit: row.rate_limit,
+            };
+            if limits.caps().any(|cap| cap <= Decimal::ZERO) {
+                return Err(format!("limits for client {} must be positive", row.client));
+            }
+            if self.clients.insert(row.client, limits).is_some() {
+                return Err(format!("client {} is listed twice", row.client));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Caps in force for one client: their own overrides, then those of
+    /// the segments their account is in, most specific first, then the
+    /// global caps
+    pub fn for_client(&self, client: ClientId) -> RiskLimits {
+        let mut fallback = self.global;
+        if !self.segments.is_empty() {
+            let meta = self.meta.get(client);
+            for (segment, limits) in &self.segments {
+                if segment.matches(meta) {
+                    fallback = limits.or(fallback);
+                }
+            }
+        }
+        self.clients
+            .get(&client)
+            .map_or(fallback, |limits| limits.or(fallback))
+    }
+}
+
+/// Which cap a transaction ran into; for the daily caps the amount reported
+/// is the day's total including the rejected transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    MaxTxAmount,
+    DailyDeposit,
+    DailyWithdrawal,
+    TotalDeposit,
+}
+
+impl LimitKind {
+    /// Stable machine-readable code for the rejection
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            LimitKind::MaxTxAmount => "max_tx_amount_exceeded",
+            LimitKind::DailyDeposit => "daily_deposit_limit_exceeded",
+            LimitKind::DailyWithdrawal => "daily_withdrawal_limit_exceeded",
+            LimitKind::TotalDeposit => "total_deposit_limit_exceeded",
+        }
+    }
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LimitKind::MaxTxAmount => "per-transaction",
+            LimitKind::DailyDeposit => "daily deposit",
+            LimitKind::DailyWithdrawal => "daily withdrawal",
+            LimitKind::TotalDeposit => "total deposit",
+        })
+    }
+}
+
+/// What a client has deposited and withdrawn in one currency on one day,
+/// and deposited over the whole run
+#[derive(Debug, Default)]
+struct DailyTotals {
+    day: Option<NaiveDate>,
+    deposited: Decimal,
+    withdrawn: Decimal,
+    deposited_in_run: Decimal,
+}
+
+/// A client's rate limit tokens as of the latest timestamp seen.
+///
+/// Counted in row-milliseconds so refills stay exact: a full bucket holds
+/// `rows` times the interval's length, every millisecond adds `rows` and
+/// every row takes the interval's length.
+#[derive(Debug)]
+struct TokenBucket {
+    level: i128,
+    at: DateTime<Utc>,
+}
+
+/// Enforces risk limits and keeps the daily totals they are checked against
+#[derive(Debug, Default)]
+pub struct LimitTracker {
+    limits: Arc<LimitsTable>,
+    totals: HashMap<(ClientId, Option<Currency>), DailyTotals>,
+    buckets: HashMap<ClientId, TokenBucket>,
+}
+
+impl LimitTracker {
+    pub fn new(limits: Arc<LimitsTable>) -> Self {
+        LimitTracker {
+            limits,
+            totals: HashMap::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Check later rows against `limits`, keeping the totals and rate-limit
+    /// buckets so far
+    pub fn set_limits(&mut self, limits: Arc<LimitsTable>) {
+        self.limits = limits;
+    }
+
+    /// Take a token from the client's bucket after refilling it for the
+    /// time their rows' timestamps moved on.
+    ///
+    /// The input is the clock, as for recurring transactions, so a file
+    /// gets the same verdicts however fast it is read. Rows without a
+    /// timestamp are not limited, and one earlier than the client's latest
+    /// refills nothing.
+    fn take_token(
+        &mut self,
+        transaction: &Transaction,
+        limit: RateLimit,
+    ) -> Result<(), EngineError> {
+        let Some(now) = transaction.timestamp else {
+            return Ok(());
+        };
+        let rows = i128::from(limit.rows);
+        let period = i128::from(limit.per.duration().num_milliseconds());
+        let full = rows * period;
+        let bucket = self
+            .buckets
+            .entry(transaction.client)
+            .or_insert(TokenBucket {
+                level: full,
+                at: now,
+            });
+        let elapsed = i128::from((now - bucket.at).num_milliseconds().max(0));
+        bucket.level = (bucket.level + elapsed * rows).min(full);
+        bucket.at = bucket.at.max(now);
+        if bucket.level < period {
+            return Err(EngineError::RateLimited {
+                client: transaction.client,
+                tx: transaction.tx,
+                limit,
+            });
+        }
+        bucket.level -= period;
+        Ok(())
+    }
+
+    /// Check a transaction against the client's caps before it is applied.
+    ///
+    /// Every row counts towards the rate limit, including those a later
+    /// check turns down.
+    ///
+    /// Daily totals are kept per currency and start over when a row's
+    /// timestamp falls on a later UTC day than the client's previous rows;
+    /// rows without a timestamp, or with an earlier one, count towards the
+    /// current day.
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        let limits = self.limits.for_client(transaction.client);
+        if limits.is_empty() {
+            return Ok(());
+        }
+        if let Some(limit) = limits.rate_limit {
+            self.take_token(transaction, limit)?;
+        }
+        let Some(amount) = transaction.amount else {
+            return Ok(());
+        };
+        let exceeded = |limit, amount, cap| EngineError::LimitExceeded {
+            client: transaction.client,
+            tx: transaction.tx,
+            limit,
+            amount,
+            cap,
+        };
+        let daily = match transaction.tx_type {
+            TransactionType::Deposit => Some((LimitKind::DailyDeposit, limits.daily_deposit_limit)),
+            TransactionType::Withdrawal => {
+                Some((LimitKind::DailyWithdrawal, limits.daily_withdrawal_limit))
+            }
+            TransactionType::Convert => None,
+            _ => return Ok(()),
+        };
+        if let Some(cap) = limits.max_tx_amount
+            && amount > cap
+        {
+            return Err(exceeded(LimitKind::MaxTxAmount, amount, cap));
+        }
+        let Some((kind, cap)) = daily else {
+            return Ok(());
+        };
+        let run_cap = limits
+            .total_deposit_limit
+            .filter(|_| kind == LimitKind::DailyDeposit);
+        if cap.is_none() && run_cap.is_none() {
+            return Ok(());
+        }
+
+        let totals = self
+            .totals
+            .entry((transaction.client, transaction.currency))
+            .or_default();
+        let day = transaction.timestamp.map(|t| t.date_naive());
+        if day > totals.day {
+            *totals = DailyTotals {
+                day,
+                deposited_in_run: totals.deposited_in_run,
+                ..Default::default()
+            };
+        }
+        let so_far = match kind {
+            LimitKind::DailyDeposit => totals.deposited,
+            _ => totals.withdrawn,
+        };
+        let checks = [
+            (kind, so_far, cap),
+            (LimitKind::TotalDeposit, totals.deposited_in_run, run_cap),
+        ];
+        for (kind, so_far, cap) in checks {
+            let Some(cap) = cap else {
+                continue;
+            };
+            match so_far.checked_add(amount) {
+                Some(total) if total <= cap => {}
+                total => return Err(exceeded(kind, total.unwrap_or(Decimal::MAX), cap)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an applied deposit or withdrawal to the client's daily totals
+    pub fn record(&mut self, applied: &Applied) {
+        let transaction = &applied.transaction;
+        let Some(totals) = self
+            .totals
+            .get_mut(&(transaction.client, transaction.currency))
+        else {
+            return;
+        };
+        match transaction.tx_type {
+            TransactionType::Deposit => {
+                totals.deposited += applied.amount;
+                totals.deposited_in_run += applied.amount;
+            }
+            TransactionType::Withdrawal => totals.withdrawn += applied.amount,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TxId;
+    use TransactionType::{Deposit, Withdrawal};
+    use chrono::DateTime;
+
+    /// One of client 1's rows, `day` days after the epoch
+    fn on_day(tx_type: TransactionType, tx: TxId, amount: i64, day: i64) -> Transaction {
+        Transaction {
+            timestamp: DateTime::from_timestamp(day * 86_400, 0),
+            ..Transaction::new(tx_type, 1, tx, Some(Decimal::from(amount)))
+        }
+    }
+
+    /// Check a row and record it when it passes
+    fn apply(tracker: &mut LimitTracker, transaction: Transaction) -> Result<(), EngineError> {
+        tracker.check(&transaction)?;
+        tracker.record(&Applied {
+            amount: transaction.amount.unwrap(),
+            transaction,
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_daily_limits_reset_on_new_day() {
+        let limits = LimitsTable::new(RiskLimits {
+            max_tx_amount: Some(Decimal::from(80)),
+            daily_deposit_limit: Some(Decimal::from(100)),
+            daily_withdrawal_limit: None,
+            total_deposit_limit: None,
+            rate_limit: None,
+        });
+        let mut tracker = LimitTracker::new(Arc::new(limits));
+
+        apply(&mut tracker, on_day(Deposit, 1, 60, 0)).unwrap();
+        assert!(matches!(
+            apply(&mut tracker, on_day(Deposit, 2, 90, 0)),
+            Err(EngineError::LimitExceeded {
+                limit: LimitKind::MaxTxAmount,
+                ..
+            })
+        ));
+        assert!(matches!(
+            apply(&mut tracker, on_day(Deposit, 3, 50, 0)),
+            Err(EngineError::LimitExceeded {
+                limit: LimitKind::DailyDeposit,
+                ..
+            })
+        ));
+        apply(&mut tracker, on_day(Deposit, 4, 40, 0)).unwrap();
+        apply(&mut tracker, on_day(Withdrawal, 5, 80, 0)).unwrap();
+        apply(&mut tracker, on_day(Deposit, 6, 50, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_reloaded_limits_keep_the_totals() {
+        let daily = |limit| {
+            Arc::new(LimitsTable::new(RiskLimits {
+                daily_deposit_limit: Some(Decimal::from(limit)),
+                ..Default::default()
+            }))
+        };
+        let mut tracker = LimitTracker::new(daily(100));
+        apply(&mut tracker, on_day(Deposit, 1, 60, 0)).unwrap();
+        tracker.set_limits(daily(80));
+        assert!(matches!(
+            apply(&mut tracker, on_day(Deposit, 2, 30, 0)),
+            Err(EngineError::LimitExceeded {
+                limit: LimitKind::DailyDeposit,
+                ..
+            })
+        ));
+        apply(&mut tracker, on_day(Deposit, 3, 20, 0)).unwrap();
+    }
+
+    #[test]
+    fn test_client_overrides_fall_back_to_global() {
+        let global = RiskLimits {
+            max_tx_amount: Some(Decimal::from(100)),
+            ..Default::default()
+        };
+        let table = LimitsTable::new(global)
+            .read_clients(
+                "client,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit\n\
+                 7,,,50\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            table.for_client(7),
+            RiskLimits {
+                daily_withdrawal_limit: Some(Decimal::from(50)),
+                ..global
+            }
+        );
+        assert_eq!(table.for_client(8), global);
+
+        for text in [
+            "client,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit\n7,0,,\n",
+            "client,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit\n7,1,,\n7,2,,\n",
+        ] {
+            assert!(
+                LimitsTable::default()
+                    .read_clients(text.as_bytes())
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_segment_limits_key_on_account_metadata() {
+        let meta = AccountMetas::read(
+            "client,tier,kyc_verified
+1,gold,false
+2,,true
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let table = LimitsTable::new(RiskLimits {
+            max_tx_amount: Some(Decimal::from(500)),
+            ..Default::default()
+        })
+        .with_meta(Arc::new(meta))
+        .read_segments(
+            "segment,max_tx_amount,daily_deposit_limit,daily_withdrawal_limit,total_deposit_limit\n\
+             tier=gold,5000,,,\n\
+             unverified,,,,1000\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(table.for_client(1).max_tx_amount, Some(Decimal::from(5000)));
+        assert_eq!(table.for_client(2).total_deposit_limit, None);
+        assert_eq!(
+            table.for_client(3).total_deposit_limit,
+            Some(Decimal::from(1000))
+        );
+
+        // The run total carries over from one day to the next
+        let mut tracker = LimitTracker::new(Arc::new(table));
+        apply(&mut tracker, on_day(Deposit, 1, 400, 0)).unwrap();
+        apply(&mut tracker, on_day(Deposit, 2, 400, 1)).unwrap();
+        assert!(matches!(
+            apply(&mut tracker, on_day(Deposit, 3, 400, 2)),
+            Err(EngineError::LimitExceeded {
+                limit: LimitKind::TotalDeposit,
+                ..
+            })
+        ));
+        apply(&mut tracker, on_day(Withdrawal, 4, 400, 2)).unwrap();
+
+        let duplicate = "segment,max_tx_amount\nunverified,1\nUnverified,2\n";
+        assert!(
+            LimitsTable::default()
+                .read_segments(duplicate.as_bytes())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_refills_with_timestamps() {
+        let meta =
+            AccountMetas::read("client,tier,kyc_verified\n2,gold,true\n".as_bytes()).unwrap();
+        let table = LimitsTable::new(RiskLimits {
+            rate_limit: Some("2/1m".parse().unwrap()),
+            ..Default::default()
+        })
+        .with_meta(Arc::new(meta))
+        .read_segments("segment,rate_limit\ntier=gold,100/1s\n".as_bytes())
+        .unwrap();
+        let mut tracker = LimitTracker::new(Arc::new(table));
+        let at = |client, tx, seconds| Transaction {
+            client,
+            timestamp: DateTime::from_timestamp(seconds, 0),
+            ..on_day(Deposit, tx, 1, 0)
+        };
+
+        let verdicts: Vec<_> = [0, 0, 0, 30, 30, 40, 90, 91, 91]
+            .into_iter()
+            .enumerate()
+            .map(|(tx, seconds)| tracker.check(&at(1, tx as TxId, seconds)).is_ok())
+            .collect();
+        assert_eq!(
+            verdicts,
+            [true, true, false, true, false, false, true, true, false]
+        );
+        assert!(matches!(
+            tracker.check(&at(1, 10, 91)),
+            Err(EngineError::RateLimited {
+                client: 1,
+                tx: 10,
+                ..
+            })
+        ));
+        // No timestamp, no limit; and the gold tier has its own rate
+        assert!(
+            tracker
+                .check(&Transaction {
+                    timestamp: None,
+                    ..at(1, 11, 91)
+                })
+                .is_ok()
+        );
+        for tx in 0..100 {
+            tracker.check(&at(2, tx, 0)).unwrap();
+        }
+        assert!(tracker.check(&at(2, 100, 0)).is_err());
+
+        assert_eq!("100/1m".parse::<RateLimit>().unwrap().to_string(), "100/1m");
+        for raw in ["0/1m", "100", "100/0s", "x/1m"] {
+            assert!(raw.parse::<RateLimit>().is_err());
+        }
+    }
+}
@@ -1,139 +1,2572 @@
-use csv_async::{AsyncReaderBuilder, Trim};
-use env_logger::Env;
-use futures::{StreamExt, TryStreamExt};
-use log::{self, error};
+use chrono::Duration;
+use csv_async::{AsyncReader, AsyncReaderBuilder, Trim};
+use futures::StreamExt;
+use rust_decimal::Decimal;
 use std::collections::HashMap;
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::fs::File;
-use tokio::io::BufReader;
-use tokio::sync::mpsc;
-
-use crate::account::output_accounts;
-use crate::models::{AccountsMap, Transaction, TransactionsMap};
-use crate::transaction::handle_transaction;
-
-mod account;
-mod models;
-mod transaction;
-
-#[tokio::main]
-async fn main() {
-    let env = Env::default().filter_or("RUST_LOG", "info");
-    env_logger::Builder::from_env(env).init();
-    if let Err(e) = run().await {
+use tokio::io::{AsyncRead, BufReader};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, warn};
+
+use rust_transaction_engine::account::{
+    AccountView, NumberFormat, output_accounts, write_accounts,
+};
+#[cfg(feature = "arrow")]
+use rust_transaction_engine::arrow::ArrowRows;
+use rust_transaction_engine::audit::{AuditLogWriter, AuditSequencer};
+use rust_transaction_engine::bloom::TxKeyFilter;
+#[cfg(feature = "chaos")]
+use rust_transaction_engine::chaos::{Chaos, ChaosConfig};
+use rust_transaction_engine::chronology::ChronologyGuard;
+use rust_transaction_engine::cli::{
+    CliOptions, Command, DiffOptions, FixturesOptions, InspectOptions, LimitOptions, MergeOptions,
+    ReplayOptions, ReportOptions, RuleOptions, StatementOptions, ValidateOptions,
+};
+use rust_transaction_engine::cold::{ColdAccounts, Touched};
+use rust_transaction_engine::compact::{ExpiryQueue, Tombstones};
+use rust_transaction_engine::config::{
+    ClientMismatchPolicy, ClosedAccounts, CompactPolicy, CsvDialect, ENV_PREFIX, EngineConfig,
+    ErrorPolicy, MonotonicPolicy, PriorityTypes, Rules,
+};
+use rust_transaction_engine::control::{self, InFlight, PauseSwitch};
+use rust_transaction_engine::diff::{diff, write_deltas};
+use rust_transaction_engine::encryption::{AdminKey, StateKey};
+use rust_transaction_engine::error::EngineError;
+use rust_transaction_engine::events::{EventWriter, events_for};
+use rust_transaction_engine::fixtures::{self, Fixture};
+use rust_transaction_engine::fraud::{
+    FraudAction, FraudEngine, FraudReportWriter, FraudRules, Verdict,
+};
+use rust_transaction_engine::fx::FxRates;
+use rust_transaction_engine::hashchain::HashChain;
+use rust_transaction_engine::idempotency::IdempotencyGuard;
+use rust_transaction_engine::ingest::{self, ParsedRows};
+use rust_transaction_engine::inspect::write_client_report;
+use rust_transaction_engine::invariants::{
+    DisputeHolds, InvariantViolation, check_account, check_accounts, check_dispute_holds,
+};
+use rust_transaction_engine::lanes::Lanes;
+use rust_transaction_engine::ledger::{JournalEntry, Ledger};
+use rust_transaction_engine::limits::{LimitTracker, LimitsTable, RiskLimits};
+use rust_transaction_engine::memory::{MEMORY_CHECK_ROWS, MemorySize, StateSize};
+use rust_transaction_engine::merge::merge;
+use rust_transaction_engine::meta::AccountMetas;
+use rust_transaction_engine::models::{
+    Account, AccountsMap, ClientId, Transaction, TransactionType, TransactionsMap,
+};
+use rust_transaction_engine::notify::{DEFAULT_NOTIFY_QUEUE, Notification, Notifier};
+use rust_transaction_engine::outcome::{
+    MalformedRow, OutcomeTally, Rejected, RowReport, TransactionOutcome,
+};
+use rust_transaction_engine::ownership::{TxOwners, stored_owner};
+use rust_transaction_engine::postgres::{self, DEFAULT_SYNC_INTERVAL_SECS};
+use rust_transaction_engine::progress::{PROGRESS_INTERVAL, ProgressTracker};
+use rust_transaction_engine::publish::EventPublisher;
+use rust_transaction_engine::reconcile::BalanceSheet;
+use rust_transaction_engine::recovery::{self, NegativeBalance};
+use rust_transaction_engine::rejects::RejectsWriter;
+use rust_transaction_engine::report::Report;
+use rust_transaction_engine::retain::{RetainScan, RetainedIds};
+use rust_transaction_engine::schedule::Scheduler;
+use rust_transaction_engine::shard::{self, ShardState};
+use rust_transaction_engine::snapshot::Snapshot;
+use rust_transaction_engine::spill::SpillStore;
+#[cfg(feature = "sqlite")]
+use rust_transaction_engine::sqlite::SqliteExport;
+use rust_transaction_engine::statement::Statement;
+use rust_transaction_engine::stats::RunStats;
+use rust_transaction_engine::telemetry;
+use rust_transaction_engine::tenant::{self, Tenant};
+use rust_transaction_engine::transaction::{
+    handle_run, handle_transaction_with, place_on_review_hold,
+};
+use rust_transaction_engine::validate::{ColumnIndex, ParseOptions, RowParser};
+use rust_transaction_engine::workload::write_workload;
+
+/// Transactions buffered per client channel unless configured otherwise
+const DEFAULT_CHANNEL_CAPACITY: usize = 50;
+
+fn main() {
+    let mut command = Command::try_parse_from(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Command::Process(options) = &mut command {
+        let flags = options.clone();
+        match read_config(options) {
+            Ok(config) => {
+                options.apply_config(config);
+                options.flags = Some(flags);
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(threads) = options.worker_threads {
+            runtime.worker_threads(threads);
+        }
+    }
+
+    // Tracing is set up outside the runtime so the OTLP exporter can block
+    // while flushing when the guard is dropped
+    let guard = telemetry::init_tracing(command.log_format());
+    let runtime = runtime
+        .enable_all()
+        .build()
+        .expect("failed to start async runtime");
+    let result = runtime.block_on(run(command));
+    if let Err(e) = &result {
         error!("Application error: {}", e);
+    }
+    drop(runtime);
+    drop(guard);
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
 }
 
-async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        return Err("Usage: cargo run -- transactions.csv > accounts.csv".into());
+/// The settings of the `ENGINE_*` variables and the config file.
+///
+/// Flags take precedence over ENGINE_* variables, which take precedence over
+/// the config file.
+fn read_config(options: &CliOptions) -> Result<EngineConfig, EngineError> {
+    let env = EngineConfig::from_env(std::env::vars())?;
+    match &options.config {
+        Some(path) => EngineConfig::load(path).map(|file| env.or(file)),
+        None => Ok(env),
+    }
+}
+
+async fn run(command: Command) -> Result<(), EngineError> {
+    match command {
+        Command::Process(options) if !options.tenants.is_empty() => process_tenants(*options).await,
+        Command::Process(options) => process(*options, None).await,
+        Command::Inspect(options) => inspect(&options),
+        Command::Statement(options) => statement(&options).await,
+        Command::Report(options) => report(&options).await,
+        Command::Validate(options) => validate(&options).await,
+        Command::Replay(options) => replay(&options).await,
+        Command::Generate(options) => write_workload(options.spec(), std::io::stdout().lock()),
+        Command::Diff(options) => diff_accounts(&options),
+        Command::Merge(options) => merge_accounts(&options),
+        Command::TestFixtures(options) => test_fixtures(&options).await,
     }
-    let input_path = &args[1];
-    let file = File::open(input_path).await?;
-    let reader = BufReader::new(file);
+}
 
-    // Shared thread-safe maps for accounts and transactions
-    let accounts: Arc<AccountsMap> = Arc::new(models::AccountsMap::new());
-    let transactions: Arc<TransactionsMap> = Arc::new(models::TransactionsMap::new());
+/// Check a row's timestamp against the client's earlier rows, its amount
+/// against the client's risk limits and the row itself against the fraud
+/// rules, then apply it.
+///
+/// A fraud rule with the `hold` action also puts the account on review hold.
+fn handle_in_order(
+    transaction: Transaction,
+    guards: &mut ClientGuards,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> TransactionOutcome {
+    if let Err(error) = guards.check(&transaction) {
+        hold_if_asked(&error, accounts, transaction.client);
+        return Err(Rejected { transaction, error });
+    }
+    let outcome = handle_transaction_with(transaction, accounts, transactions, rules);
+    if let Ok(applied) = &outcome {
+        guards.limits.record(applied);
+        guards
+            .expiry
+            .record(&applied.transaction, transactions, rules);
+    }
+    outcome
+}
 
-    const CONCURRENCY_LIMIT: usize = 50;
+/// Guards of every client a task has applied rows for
+type ClientGuardsMap = HashMap<ClientId, ClientGuards>;
 
-    // Each client has a dedicated channel to process transactions sequentially
-    let senders: Arc<Mutex<HashMap<u16, mpsc::Sender<Transaction>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+/// Per-client state kept by whichever task applies a client's rows
+#[derive(Debug)]
+struct ClientGuards {
+    chronology: ChronologyGuard,
+    limits: LimitTracker,
+    fraud: FraudEngine,
+    idempotency: IdempotencyGuard,
+    audit: AuditSequencer,
+    /// What the client's open disputes hold, in verify mode
+    holds: DisputeHolds,
+    /// Records waiting to fall out of the dispute window
+    expiry: ExpiryQueue,
+}
+
+impl ClientGuards {
+    fn new(
+        require_monotonic: MonotonicPolicy,
+        limits: &Arc<LimitsTable>,
+        fraud: &FraudRules,
+        meta: &Arc<AccountMetas>,
+        idempotency_window: Option<Duration>,
+    ) -> Self {
+        ClientGuards {
+            chronology: ChronologyGuard::new(require_monotonic),
+            limits: LimitTracker::new(Arc::clone(limits)),
+            fraud: FraudEngine::with_meta(fraud, Arc::clone(meta)),
+            idempotency: IdempotencyGuard::new(idempotency_window),
+            audit: AuditSequencer::default(),
+            holds: DisputeHolds::default(),
+            expiry: ExpiryQueue::default(),
+        }
+    }
+
+    /// Check later rows against reloaded limits and fraud rules. Limit
+    /// totals carry over, as does fraud-rule history unless the rules are
+    /// rebuilt
+    fn reload(&mut self, options: &ClientTaskOptions, rebuild_fraud: bool) {
+        self.limits.set_limits(Arc::clone(&options.limits));
+        if rebuild_fraud {
+            self.fraud = FraudEngine::with_meta(&options.fraud, Arc::clone(&options.meta));
+        }
+    }
 
-    let accounts_clone = Arc::clone(&accounts);
-    let transactions_clone = Arc::clone(&transactions);
-    let senders_clone = Arc::clone(&senders);
+    /// Run a row past the idempotency, chronology, limit and fraud checks
+    fn check(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.idempotency
+            .check(transaction)
+            .and_then(|()| self.chronology.check(transaction))
+            .and_then(|()| self.limits.check(transaction))
+            .and_then(|()| self.fraud.check(transaction))
+    }
+}
+
+/// A file applied one row at a time in input order, through the same rules
+/// and guards as a `process --deterministic` run, for the subcommands that
+/// replay a file on throwaway state: `statement`, `report`, `validate` and
+/// `replay`
+struct InOrderRun {
+    accounts: AccountsMap,
+    transactions: TransactionsMap,
+    rules: Rules,
+    guards: ClientGuards,
+    owners: TxOwners,
+}
+
+impl InOrderRun {
+    /// Load the files the rule options name and start from empty state
+    fn new(options: &RuleOptions, fraud: &FraudRules) -> Result<Self, EngineError> {
+        let meta = load_account_meta(&options.limits)?;
+        Ok(InOrderRun {
+            accounts: AccountsMap::new(),
+            transactions: TransactionsMap::new(),
+            rules: options.rules(load_fx_rates(options.fx_rates.as_deref())?),
+            guards: ClientGuards::new(
+                options.require_monotonic.unwrap_or_default(),
+                &load_limits(&options.limits, &meta)?,
+                fraud,
+                &meta,
+                options.idempotency_window,
+            ),
+            owners: TxOwners::new(options.duplicate_tx.unwrap_or_default()),
+        })
+    }
+
+    /// Apply one row. Returns its outcome, followed by that of the review
+    /// hold a reference to another client's transaction puts the client on
+    /// under the `review` policy. Fraud verdicts are logged as they come.
+    fn apply(&mut self, transaction: Transaction) -> Vec<TransactionOutcome> {
+        let mut outcomes = Vec::with_capacity(1);
+        let owner_of = |tx| stored_owner(tx, [&self.transactions], &self.rules);
+        match self.owners.check(&transaction, owner_of) {
+            Ok(()) => outcomes.push(handle_in_order(
+                transaction,
+                &mut self.guards,
+                &self.accounts,
+                &self.transactions,
+                &self.rules,
+            )),
+            Err(error) => {
+                let review = mismatch_review(&transaction, &error, self.rules.client_mismatch);
+                outcomes.push(Err(Rejected { transaction, error }));
+                if let Some((verdict, hold)) = review {
+                    log_verdict(&verdict);
+                    outcomes.push(handle_in_order(
+                        hold,
+                        &mut self.guards,
+                        &self.accounts,
+                        &self.transactions,
+                        &self.rules,
+                    ));
+                }
+            }
+        }
+        for verdict in self.guards.fraud.take_verdicts() {
+            log_verdict(&verdict);
+        }
+        outcomes
+    }
+}
+
+/// Put the account on review hold if a fraud rule with the `hold` action
+/// rejected one of its rows
+fn hold_if_asked(error: &EngineError, accounts: &AccountsMap, client: ClientId) {
+    if let EngineError::FraudSuspected {
+        action: FraudAction::Hold,
+        ..
+    } = error
+    {
+        place_on_review_hold(accounts, client);
+    }
+}
+
+/// The fraud verdict and the `hold` row for the disputing client when the
+/// owner registry turned down a reference to another client's transaction
+/// under the `review` client-mismatch policy
+fn mismatch_review(
+    transaction: &Transaction,
+    error: &EngineError,
+    policy: ClientMismatchPolicy,
+) -> Option<(Verdict, Transaction)> {
+    if policy != ClientMismatchPolicy::Review
+        || !matches!(error, EngineError::ClientMismatch { .. })
+    {
+        return None;
+    }
+    let verdict = Verdict {
+        rule: "client_mismatch",
+        action: FraudAction::Hold,
+        client: transaction.client,
+        tx: transaction.tx,
+        tx_type: transaction.tx_type.name(),
+        amount: transaction.amount,
+        detail: error.to_string(),
+    };
+    let hold = Transaction {
+        tx_type: TransactionType::Hold,
+        amount: None,
+        currency: None,
+        to_currency: None,
+        idempotency_key: None,
+        ..transaction.clone()
+    };
+    Some((verdict, hold))
+}
+
+/// Risk limits, exchange rates and fraud rules, the settings a run can
+/// reload while it runs
+#[derive(Debug)]
+struct Policies {
+    limits: Arc<LimitsTable>,
+    meta: Arc<AccountMetas>,
+    fraud: FraudRules,
+    fx_rates: Option<Arc<FxRates>>,
+}
+
+impl Policies {
+    /// Load every file the options name, failing on the first bad one
+    fn load(options: &CliOptions) -> Result<Self, EngineError> {
+        let meta = load_account_meta(&options.rules.limits)?;
+        Ok(Policies {
+            limits: load_limits(&options.rules.limits, &meta)?,
+            meta,
+            fraud: options.fraud.load()?,
+            fx_rates: load_fx_rates(options.rules.fx_rates.as_deref())?,
+        })
+    }
 
-    // Stream CSV transactions line-by-line
-    let csv_reader = AsyncReaderBuilder::new()
+    /// Read the config again, apply it to the command-line `flags` as at
+    /// startup and load the files the result names
+    fn reload(flags: &CliOptions) -> Result<Self, EngineError> {
+        let mut options = flags.clone();
+        options.apply_config(read_config(flags)?);
+        Policies::load(&options)
+    }
+}
+
+/// Load the `--fx-rates` file, if one was given
+fn load_fx_rates(path: Option<&Path>) -> Result<Option<Arc<FxRates>>, EngineError> {
+    path.map(|path| FxRates::load(path).map(Arc::new))
+        .transpose()
+}
+
+/// Load the `--account-meta` seed file, if one was given
+fn load_account_meta(options: &LimitOptions) -> Result<Arc<AccountMetas>, EngineError> {
+    let Some(path) = &options.account_meta else {
+        return Ok(Arc::default());
+    };
+    let meta = AccountMetas::load(path)?;
+    info!("Loaded metadata for {} accounts", meta.len());
+    Ok(Arc::new(meta))
+}
+
+/// Build the risk limits table from the limit flags and any per-segment and
+/// per-client files
+fn load_limits(
+    options: &LimitOptions,
+    meta: &Arc<AccountMetas>,
+) -> Result<Arc<LimitsTable>, EngineError> {
+    let mut table = LimitsTable::new(RiskLimits {
+        max_tx_amount: options.max_tx_amount,
+        daily_deposit_limit: options.daily_deposit_limit,
+        daily_withdrawal_limit: options.daily_withdrawal_limit,
+        total_deposit_limit: options.total_deposit_limit,
+        rate_limit: options.rate_limit,
+    })
+    .with_meta(Arc::clone(meta));
+    if let Some(path) = &options.segment_limits {
+        table = table.load_segments(path)?;
+    }
+    match &options.client_limits {
+        Some(path) => table.load_clients(path).map(Arc::new),
+        None => Ok(Arc::new(table)),
+    }
+}
+
+/// Replay a file in order up to a breakpoint and print the state at that point.
+///
+/// The row matching `--until-tx` or `--until-line` is the last one handled.
+async fn replay(options: &ReplayOptions) -> Result<(), EngineError> {
+    let (parser, reader) = open_input(
+        &options.input,
+        &options.dialect.dialect(),
+        options.parsing.parse_options(),
+    )
+    .await?;
+    let mut records = reader.into_records();
+
+    let mut run = InOrderRun::new(&options.rules, &options.fraud.load()?)?;
+    let mut stopped_at = None;
+    let mut rows_handled: u64 = 0;
+    while let Some(row) = records.next().await {
+        let line = match &row {
+            Ok(record) => record.position(),
+            Err(e) => e.position(),
+        }
+        .map(|p| p.line());
+        let past_line =
+            matches!((options.until_line, line), (Some(until), Some(line)) if line > until);
+        if past_line {
+            break;
+        }
+
+        let tx = match parser.validate_row(row) {
+            Ok(transaction) => {
+                let tx = transaction.tx;
+                for outcome in run.apply(transaction) {
+                    if let Err(rejected) = &outcome {
+                        log_rejected(rejected);
+                    }
+                }
+                Some(tx)
+            }
+            Err(malformed) => {
+                log_malformed(&malformed);
+                None
+            }
+        };
+        rows_handled += 1;
+        if options.until_line.is_some() && options.until_line == line
+            || options.until_tx.is_some() && options.until_tx == tx
+        {
+            stopped_at = line;
+            break;
+        }
+    }
+
+    match stopped_at {
+        Some(line) => info!("Replay stopped after line {}", line),
+        None => warn!("Breakpoint not reached; replayed the whole file"),
+    }
+    output_accounts(
+        &run.accounts,
+        ClosedAccounts::Include,
+        NumberFormat::rounded(run.rules.rounding),
+        &AccountView::default(),
+    )?;
+    if let Some(path) = &options.save_state {
+        let key = subcommand_state_key(options.state_key_command.as_deref())?;
+        let snapshot = Snapshot::capture(&run.accounts, &run.transactions).at_offset(rows_handled);
+        snapshot.save_with(path, key.as_ref())?;
+        info!(
+            "State snapshot saved to {} (state root {})",
+            path.display(),
+            snapshot.state_root
+        );
+    }
+    Ok(())
+}
+
+/// A run's transactions file, opened but not yet read
+enum InputFile {
+    Csv(RowParser, Box<AsyncReader<BufReader<File>>>),
+    /// A CSV file to read and parse off a memory map on the blocking pool
+    #[cfg(feature = "mmap-input")]
+    Mapped(RowParser, Box<AsyncReader<std::io::Cursor<memmap2::Mmap>>>),
+    #[cfg(feature = "arrow")]
+    Arrow(ArrowRows),
+}
+
+impl InputFile {
+    /// Open a CSV file, through a memory map if `mmap` is set, or an Arrow
+    /// file if its extension says so
+    async fn open(
+        path: &Path,
+        dialect: &CsvDialect,
+        options: ParseOptions,
+        mmap: bool,
+    ) -> Result<Self, EngineError> {
+        if ingest::is_arrow_file(path) {
+            #[cfg(feature = "arrow")]
+            return Ok(InputFile::Arrow(ArrowRows::open(path, options)?));
+            #[cfg(not(feature = "arrow"))]
+            return Err(EngineError::Usage(format!(
+                "{} is an Arrow file, which needs a build with the arrow feature",
+                path.display()
+            )));
+        }
+        if mmap {
+            #[cfg(feature = "mmap-input")]
+            {
+                let map = ingest::map_input(path)?;
+                let (parser, reader) = csv_input(map, dialect, options).await?;
+                return Ok(InputFile::Mapped(parser, Box::new(reader)));
+            }
+            #[cfg(not(feature = "mmap-input"))]
+            return Err(EngineError::Usage(
+                "--mmap-input needs a build with the mmap-input feature".to_string(),
+            ));
+        }
+        let (parser, reader) = open_input(path, dialect, options).await?;
+        Ok(InputFile::Csv(parser, Box::new(reader)))
+    }
+
+    /// Its rows after the first `skip`
+    fn rows(self, skip: u64, parallelism: usize) -> ParsedRows<BufReader<File>> {
+        match self {
+            InputFile::Csv(parser, reader) => ParsedRows::new(*reader, parser, skip, parallelism),
+            #[cfg(feature = "mmap-input")]
+            InputFile::Mapped(parser, reader) => {
+                ParsedRows::on_blocking_pool(*reader, parser, skip)
+            }
+            #[cfg(feature = "arrow")]
+            InputFile::Arrow(rows) => ParsedRows::from_arrow(rows, skip),
+        }
+    }
+}
+
+/// Read the input once, past the first `skip` rows, for the transaction IDs
+/// whose records a two-pass run keeps
+async fn scan_retained(
+    path: &Path,
+    dialect: &CsvDialect,
+    options: ParseOptions,
+    skip: u64,
+    mmap: bool,
+) -> Result<RetainedIds, EngineError> {
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut rows = InputFile::open(path, dialect, options, mmap)
+        .await?
+        .rows(skip, parallelism);
+    let mut scan = RetainScan::default();
+    while let Some(row) = rows.next().await {
+        if let Ok(transaction) = &row.transaction {
+            scan.observe(transaction);
+        }
+    }
+    rows.finish().await;
+    Ok(scan.finish())
+}
+
+/// Open a transactions file, returning a parser for its header layout and a
+/// reader positioned at the first record
+async fn open_input(
+    path: &Path,
+    dialect: &CsvDialect,
+    options: ParseOptions,
+) -> Result<(RowParser, AsyncReader<BufReader<File>>), EngineError> {
+    let file = File::open(path).await?;
+    csv_input(BufReader::new(file), dialect, options).await
+}
+
+/// Read the header of CSV input in `dialect` from any source, for
+/// `open_input` and the memory-mapped reader alike
+async fn csv_input<R>(
+    reader: R,
+    dialect: &CsvDialect,
+    options: ParseOptions,
+) -> Result<(RowParser, AsyncReader<R>), EngineError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut csv_reader = AsyncReaderBuilder::new()
+        .delimiter(dialect.delimiter.0)
+        .has_headers(dialect.has_headers)
         .trim(Trim::All)
         .flexible(true)
-        .create_deserializer(reader)
-        .into_deserialize::<Transaction>();
-
-    csv_reader
-        .map(|tx| tx.map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>))
-        .try_for_each(move |transaction| {
-            let senders = Arc::clone(&senders_clone);
-            let accounts = Arc::clone(&accounts_clone);
-            let transactions = Arc::clone(&transactions_clone);
-            async move {
-                match transaction.tx_type {
-                    models::TransactionType::Deposit | models::TransactionType::Withdrawal => {
-                        if transaction
-                            .amount
-                            .is_none_or(|a| a <= rust_decimal::Decimal::ZERO)
-                        {
-                            log::warn!(
-                                "Invalid or missing amount in deposit/withdrawal: {:?}",
-                                transaction
-                            );
-                            return Ok(());
+        .create_reader(reader);
+    let columns = match dialect.has_headers {
+        true => ColumnIndex::mapped(csv_reader.headers().await?, &dialect.columns)?,
+        false => ColumnIndex::in_order(),
+    };
+    Ok((RowParser::with_columns(columns, options), csv_reader))
+}
+
+/// Run a file through parsing and every business rule on throwaway state and
+/// report what a real run would do, without writing accounts or any other
+/// output files
+async fn validate(options: &ValidateOptions) -> Result<(), EngineError> {
+    let started = Instant::now();
+    let (parser, reader) = open_input(
+        &options.input,
+        &options.dialect.dialect(),
+        options.parsing.parse_options(),
+    )
+    .await?;
+    let mut records = reader.into_records();
+
+    let mut run = InOrderRun::new(&options.rules, &options.fraud.load()?)?;
+    let mut tally = OutcomeTally::default();
+    while let Some(row) = records.next().await {
+        let transaction = match parser.validate_row(row) {
+            Ok(transaction) => transaction,
+            Err(malformed) => {
+                log_malformed(&malformed);
+                tally.record_malformed(&malformed);
+                continue;
+            }
+        };
+        for outcome in run.apply(transaction) {
+            if let Err(rejected) = &outcome {
+                log_rejected(rejected);
+            }
+            tally.record(&outcome);
+        }
+    }
+
+    let stats = RunStats::new(&tally, &run.accounts, started.elapsed());
+    let stdout = std::io::stdout().lock();
+    if options.json {
+        stats.write_json(stdout)
+    } else {
+        stats.write_text(stdout)
+    }
+}
+
+/// Replay a transactions file in order and print running-balance statements.
+///
+/// Rows are applied one at a time, under the same rules and guards as
+/// `process`, so each statement line reflects the balances immediately after
+/// that transaction; malformed and rejected rows are logged and left out of
+/// the statement.
+async fn statement(options: &StatementOptions) -> Result<(), EngineError> {
+    let (parser, reader) = open_input(
+        &options.input,
+        &options.dialect.dialect(),
+        options.parsing.parse_options(),
+    )
+    .await?;
+    let mut records = reader.into_records();
+
+    let mut run = InOrderRun::new(&options.rules, &options.fraud.load()?)?;
+    let mut statement = Statement::new(options.client);
+    while let Some(row) = records.next().await {
+        let transaction = match parser.validate_row(row) {
+            Ok(transaction) => transaction,
+            Err(malformed) => {
+                log_malformed(&malformed);
+                continue;
+            }
+        };
+        for outcome in run.apply(transaction) {
+            match outcome {
+                Ok(applied) => statement.record(&applied, &run.accounts),
+                Err(rejected) => log_rejected(&rejected),
+            }
+        }
+    }
+
+    statement.write(options.format, std::io::stdout().lock())
+}
+
+/// Print analytics over a transactions file, gathered while applying it under
+/// the same rules and guards as `process`
+async fn report(options: &ReportOptions) -> Result<(), EngineError> {
+    let (parser, reader) = open_input(
+        &options.input,
+        &options.dialect.dialect(),
+        options.parsing.parse_options(),
+    )
+    .await?;
+    let mut records = reader.into_records();
+
+    let mut run = InOrderRun::new(&options.rules, &options.fraud.load()?)?;
+    let mut report = Report::new(options.top);
+    while let Some(row) = records.next().await {
+        let transaction = match parser.validate_row(row) {
+            Ok(transaction) => transaction,
+            Err(malformed) => {
+                log_malformed(&malformed);
+                continue;
+            }
+        };
+        for outcome in run.apply(transaction) {
+            match outcome {
+                Ok(applied) => report.record(&applied),
+                Err(rejected) => log_rejected(&rejected),
+            }
+        }
+    }
+
+    let summary = report.summary();
+    if options.json {
+        summary.write_json(std::io::stdout().lock())
+    } else {
+        summary.write_text(std::io::stdout().lock())
+    }
+}
+
+/// Print the per-client changes between two accounts files
+fn diff_accounts(options: &DiffOptions) -> Result<(), EngineError> {
+    let before = BalanceSheet::load(&options.before)?;
+    let after = BalanceSheet::load(&options.after)?;
+    write_deltas(
+        &diff(&before, &after),
+        options.format,
+        std::io::stdout().lock(),
+    )
+}
+
+/// Print the accounts files of sharded runs as one
+fn merge_accounts(options: &MergeOptions) -> Result<(), EngineError> {
+    let sheets = options
+        .inputs
+        .iter()
+        .map(|path| Ok((path.display().to_string(), BalanceSheet::load(path)?)))
+        .collect::<Result<Vec<_>, EngineError>>()?;
+    merge(sheets, options.duplicates)?.write(std::io::stdout().lock())
+}
+
+/// Run every fixture under the directory and print a unified diff for each
+/// one whose accounts differ from the expected ones
+async fn test_fixtures(options: &FixturesOptions) -> Result<(), EngineError> {
+    let fixtures = fixtures::discover(&options.dir)?;
+    let scratch = tempfile::tempdir()?;
+    let mut failed = 0;
+    for fixture in &fixtures {
+        let output = scratch.path().join("accounts.csv");
+        let result = run_fixture(fixture, &output)
+            .instrument(info_span!("fixture", name = %fixture.name))
+            .await;
+        match result {
+            Ok(None) => println!("ok     {}", fixture.name),
+            Ok(Some(diff)) => {
+                failed += 1;
+                println!("FAILED {}", fixture.name);
+                print!("{diff}");
+            }
+            Err(e) => {
+                failed += 1;
+                println!("FAILED {}: {}", fixture.name, e);
+            }
+        }
+    }
+    println!(
+        "{} fixtures: {} passed, {} failed",
+        fixtures.len(),
+        fixtures.len() - failed,
+        failed
+    );
+    match failed {
+        0 => Ok(()),
+        failed => Err(EngineError::FixturesFailed {
+            failed,
+            total: fixtures.len(),
+        }),
+    }
+}
+
+/// Process a fixture's input in file order with the settings of its
+/// `config.toml`, if any, and compare the accounts written to `output`
+/// with the expected ones.
+///
+/// `ENGINE_*` variables are ignored so that fixtures give the same result
+/// in every environment.
+async fn run_fixture(fixture: &Fixture, output: &Path) -> Result<Option<String>, EngineError> {
+    let mut options = CliOptions {
+        input: Some(fixture.input.clone()),
+        deterministic: true,
+        ..Default::default()
+    };
+    if let Some(path) = &fixture.config {
+        options.apply_config(EngineConfig::load(path)?);
+    }
+    process(options, Some(output.to_path_buf())).await?;
+    let normalize = |path: &Path| {
+        fixtures::normalize(&fs::read_to_string(path)?)
+            .map_err(|e| EngineError::Reconcile(format!("{}: {e}", path.display())))
+    };
+    let expected = normalize(&fixture.expected)?;
+    let actual = normalize(output)?;
+    Ok(fixtures::compare(&fixture.name, &expected, &actual))
+}
+
+/// Print a client's balances and transaction history from a saved snapshot
+fn inspect(options: &InspectOptions) -> Result<(), EngineError> {
+    let key = subcommand_state_key(options.state_key_command.as_deref())?;
+    let snapshot = Snapshot::load_with(&options.state, key.as_ref())?;
+    write_client_report(&snapshot, options.client, std::io::stdout().lock())
+}
+
+/// The state key of a subcommand that reads no config file:
+/// `ENGINE_STATE_KEY`, or else what `command` prints
+fn subcommand_state_key(command: Option<&str>) -> Result<Option<StateKey>, EngineError> {
+    let key = std::env::var(format!("{ENV_PREFIX}STATE_KEY"))
+        .ok()
+        .map(|raw| raw.parse())
+        .transpose()?;
+    StateKey::resolve(key, command)
+}
+
+/// Process every `--tenant` file at once, each with its own state.
+///
+/// Each tenant's accounts go to their own file, and every other per-run file,
+/// as well as every input file keyed by client ID, gets the tenant's name
+/// added to its path.
+async fn process_tenants(mut options: CliOptions) -> Result<(), EngineError> {
+    let tenants = std::mem::take(&mut options.tenants);
+    tenant::check_unique(&tenants)?;
+    // One metrics endpoint serves every tenant
+    if let Some(addr) = options.metrics_addr.take() {
+        telemetry::install_prometheus(addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    let dir = options
+        .tenant_dir
+        .take()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let runs = tenants.iter().map(|tenant| {
+        process(
+            tenant_options(&options, tenant),
+            Some(tenant.accounts_path(&dir)),
+        )
+        .instrument(info_span!("tenant", name = %tenant.name))
+    });
+    let results = futures::future::join_all(runs).await;
+
+    let mut first_error = None;
+    for (tenant, result) in tenants.iter().zip(results) {
+        match result {
+            Ok(()) => info!(
+                "Tenant {}: accounts written to {}",
+                tenant.name,
+                tenant.accounts_path(&dir).display()
+            ),
+            Err(e) => {
+                error!("Tenant {} failed: {}", tenant.name, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// The options of one tenant's run within a `--tenant` process
+fn tenant_options(options: &CliOptions, tenant: &Tenant) -> CliOptions {
+    let path = |path: &Option<PathBuf>| path.as_deref().map(|path| tenant.path_for(path));
+    let mut rules = options.rules.clone();
+    rules.limits.client_limits = path(&rules.limits.client_limits);
+    rules.limits.account_meta = path(&rules.limits.account_meta);
+    CliOptions {
+        input: Some(tenant.input.clone()),
+        resume: path(&options.resume),
+        schedule: path(&options.schedule),
+        reconcile: path(&options.reconcile),
+        rejects: path(&options.rejects),
+        ledger: path(&options.ledger),
+        save_state: path(&options.save_state),
+        stats: path(&options.stats),
+        fraud_report: path(&options.fraud_report),
+        audit_log: path(&options.audit_log),
+        events: path(&options.events),
+        sqlite: path(&options.sqlite),
+        recovery_report: path(&options.recovery_report),
+        recovery_adjustments: path(&options.recovery_adjustments),
+        rules,
+        flags: options
+            .flags
+            .as_ref()
+            .map(|flags| Box::new(tenant_options(flags, tenant))),
+        ..options.clone()
+    }
+}
+
+/// Process one transactions file, writing the accounts to `output` or, by
+/// default, to stdout
+async fn process(options: CliOptions, output: Option<PathBuf>) -> Result<(), EngineError> {
+    let started = Instant::now();
+    let Some(input) = options.input.clone() else {
+        return Err(EngineError::Usage("no transactions file given".to_string()));
+    };
+    #[cfg(not(feature = "sqlite"))]
+    if options.sqlite.is_some() {
+        return Err(EngineError::Usage(
+            "--sqlite needs a build with the sqlite feature".to_string(),
+        ));
+    }
+    if options.recovery_adjustments.is_some() && options.suspense_account.is_none() {
+        return Err(EngineError::Usage(
+            "--recovery-adjustments needs a --suspense-account".to_string(),
+        ));
+    }
+    // A two-pass run drops records its own input never reads back, which a
+    // later run or the adjustment numbering would need
+    for (set, flag) in [
+        (options.save_state.is_some(), "--save-state"),
+        (
+            options.recovery_adjustments.is_some(),
+            "--recovery-adjustments",
+        ),
+        (options.chaos.is_some(), "--chaos"),
+    ] {
+        if options.two_pass && set {
+            return Err(EngineError::Usage(format!(
+                "--two-pass cannot be combined with {flag}"
+            )));
+        }
+    }
+    let compaction = options.compact_records.unwrap_or_default();
+    if compaction != CompactPolicy::Keep && options.recovery_adjustments.is_some() {
+        return Err(EngineError::Usage(
+            "--compact-records cannot be combined with --recovery-adjustments".to_string(),
+        ));
+    }
+    #[cfg(not(feature = "chaos"))]
+    if options.chaos.is_some() {
+        return Err(EngineError::Usage(
+            "--chaos needs a build with the chaos feature".to_string(),
+        ));
+    }
+    #[cfg(feature = "chaos")]
+    let chaos = match &options.chaos {
+        Some(spec) => {
+            let config: ChaosConfig = spec.parse()?;
+            warn!("Injecting faults: {}", spec);
+            Some(Chaos::new(config))
+        }
+        None => None,
+    };
+    if let Some(addr) = options.metrics_addr {
+        telemetry::install_prometheus(addr)?;
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    // Stream CSV records line-by-line, validating each into a transaction
+    let parse_options = options.parsing.parse_options();
+    let input_file = InputFile::open(
+        &input,
+        &options.dialect.dialect(),
+        parse_options.clone(),
+        options.mmap_input,
+    )
+    .await?;
+    // With an admin key, hold, release and close rows only come from the
+    // signed admin input, which is checked in full before the run starts and
+    // applied after the last input row
+    let admin_key = AdminKey::resolve(
+        options.admin_key.clone(),
+        options.admin_key_command.as_deref(),
+    )?;
+    let admin_rows = match (&admin_key, &options.admin_input) {
+        (Some(key), Some(path)) => {
+            let rows = ingest::read_admin_input(path, key, parse_options.clone()).await?;
+            info!("Read {} admin rows from {}", rows.len(), path.display());
+            Some(rows)
+        }
+        (Some(_), None) => Some(Vec::new()),
+        (None, Some(_)) => {
+            return Err(EngineError::Usage(
+                "--admin-input needs an admin key, from ENGINE_ADMIN_KEY or --admin-key-command"
+                    .to_string(),
+            ));
+        }
+        (None, None) => None,
+    };
+    let mut progress = if options.progress {
+        let size = tokio::fs::metadata(&input).await.ok().map(|m| m.len());
+        Some(ProgressTracker::new(size, PROGRESS_INTERVAL))
+    } else {
+        None
+    };
+    let writers = ReportWriters {
+        rejects: options
+            .rejects
+            .as_deref()
+            .map(RejectsWriter::create)
+            .transpose()?,
+        fraud_report: options
+            .fraud_report
+            .as_deref()
+            .map(FraudReportWriter::create)
+            .transpose()?,
+        audit_log: options
+            .audit_log
+            .as_deref()
+            .map(AuditLogWriter::open)
+            .transpose()?,
+        events: options
+            .events
+            .as_deref()
+            .map(EventWriter::create)
+            .transpose()?,
+        publisher: match &options.publish {
+            Some(target) => {
+                let publisher =
+                    EventPublisher::connect(target, options.publish_key.unwrap_or_default())
+                        .await?;
+                info!("Publishing events to {}", target);
+                Some(publisher)
+            }
+            None => None,
+        },
+        #[cfg(feature = "sqlite")]
+        sqlite: options
+            .sqlite
+            .as_deref()
+            .map(SqliteExport::create)
+            .transpose()?,
+    };
+    // Read before processing so a bad file fails the run up front
+    let expected = options
+        .reconcile
+        .as_deref()
+        .map(BalanceSheet::load)
+        .transpose()?;
+
+    // Resolved once, before anything is read, so a failing key command stops
+    // the run up front
+    let state_key = StateKey::resolve(
+        options.state_key.clone(),
+        options.state_key_command.as_deref(),
+    )?;
+
+    // Shared thread-safe maps for accounts and transactions, picking up the
+    // state of an earlier run when resuming
+    let (accounts, transactions, resume_offset, saved_schedule, saved_chain, saved_tombstones) =
+        match &options.resume {
+            Some(path) => {
+                let mut snapshot = if options.migrate_plain_state {
+                    Snapshot::load_migrating(path, state_key.as_ref())?
+                } else {
+                    Snapshot::load_with(path, state_key.as_ref())?
+                };
+                let Some(offset) = snapshot.offset else {
+                    return Err(EngineError::Snapshot(format!(
+                        "{} has no input offset to resume from",
+                        path.display()
+                    )));
+                };
+                info!(
+                    "Resuming from {} after {} input rows (state root {})",
+                    path.display(),
+                    offset,
+                    snapshot.state_root
+                );
+                let schedule = std::mem::take(&mut snapshot.schedule);
+                let chain = snapshot.chain.take();
+                if options.hash_chain && chain.is_none() && offset > 0 {
+                    warn!(
+                        "{} has no hash chain; the chain only covers rows from here on",
+                        path.display()
+                    );
+                }
+                let tombstones = std::mem::take(&mut snapshot.tombstones);
+                let (accounts, transactions) = snapshot.restore();
+                (accounts, transactions, offset, schedule, chain, tombstones)
+            }
+            None => (
+                AccountsMap::new(),
+                TransactionsMap::new(),
+                0,
+                Scheduler::default(),
+                None,
+                Vec::new(),
+            ),
+        };
+    // Tombstones of a resumed run keep their IDs taken whatever this run
+    // compacts
+    let tombstones = (compaction == CompactPolicy::Tombstone || !saved_tombstones.is_empty())
+        .then(|| Arc::new(Tombstones::restore(saved_tombstones)));
+    // A resumed run carries on with the saved chains
+    let hash_chain = options.hash_chain.then(|| {
+        Arc::new(
+            saved_chain
+                .as_ref()
+                .map(HashChain::restore)
+                .unwrap_or_default(),
+        )
+    });
+    // A schedule file replaces the instructions of a resumed run
+    let mut scheduler = match &options.schedule {
+        Some(path) => Scheduler::load(path)?.replacing(&saved_schedule),
+        None => saved_schedule,
+    };
+    if !scheduler.is_empty() {
+        info!("Scheduling {} recurring transactions", scheduler.len());
+    }
+    // Sharded runs hand the state to shard workers that own it outright and
+    // merge it back once they finish
+    let shard_count = options.shards.filter(|_| !options.deterministic);
+    let (accounts, transactions, shard_states) = match shard_count {
+        Some(count) => (
+            AccountsMap::new(),
+            TransactionsMap::new(),
+            shard::split(accounts, transactions, count),
+        ),
+        None => (accounts, transactions, Vec::new()),
+    };
+    let accounts = Arc::new(accounts);
+    let transactions = Arc::new(transactions);
+
+    let channel_capacity = options.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY);
+
+    // Past --spill-over, cold transactions move to a temporary file and come
+    // back when they are disputed
+    let spill = options
+        .spill_over
+        .map(|_| SpillStore::create().map(Arc::new))
+        .transpose()?;
+
+    // Past --page-accounts-over, accounts of clients without recent rows
+    // move to a temporary file and come back with their client's next row
+    let cold_accounts = options
+        .page_accounts_over
+        .map(|_| ColdAccounts::create().map(Arc::new))
+        .transpose()?;
+
+    // Restored transactions go into the filter before any row is applied
+    let tx_filter = options.bloom_filter_ids.map(|expected| {
+        let restored: Vec<&TransactionsMap> = std::iter::once(&*transactions)
+            .chain(shard_states.iter().map(|shard| &shard.transactions))
+            .collect();
+        let count = restored.iter().map(|map| map.len()).sum::<usize>();
+        let filter = TxKeyFilter::with_capacity(expected.max(count));
+        for entry in restored.iter().flat_map(|map| map.iter()) {
+            filter.insert(entry.key());
+        }
+        for (key, _) in tombstones.iter().flat_map(|t| t.entries()) {
+            filter.insert(&key);
+        }
+        Arc::new(filter)
+    });
+
+    // A first pass over the input finds the only records the second will
+    // ever read back
+    let retained = match options.two_pass {
+        true => {
+            let ids = scan_retained(
+                &input,
+                &options.dialect.dialect(),
+                parse_options.clone(),
+                resume_offset,
+                options.mmap_input,
+            )
+            .await?;
+            info!("First pass: keeping the records of {} IDs", ids.len());
+            Some(Arc::new(ids))
+        }
+        false => None,
+    };
+
+    let policies = Arc::new(Policies::load(&options)?);
+    let rounding = options.rules.rounding();
+    let rules = Rules {
+        spill: spill.clone(),
+        tx_filter,
+        retained,
+        compaction,
+        tombstones: tombstones.clone(),
+        ..options.rules.rules(policies.fx_rates.clone())
+    };
+    let mut owners = TxOwners::new(rules.duplicate_tx);
+    if let Some(retained) = &rules.retained {
+        owners = owners.with_retained(Arc::clone(retained));
+    }
+    let owners = Arc::new(owners);
+
+    let (notifier, webhook) = match &options.webhook_url {
+        Some(url) => {
+            let (notifier, task) = Notifier::spawn(url, DEFAULT_NOTIFY_QUEUE)?;
+            (Some(notifier), Some(task))
+        }
+        None => (None, None),
+    };
+    let shard_states: Vec<_> = shard_states.into_iter().map(Arc::new).collect();
+    // Balances go to Postgres on a timer, and once more after the last row
+    let sync_stop = CancellationToken::new();
+    let balance_sync = match &options.postgres_url {
+        Some(url) => {
+            let interval = options
+                .postgres_interval_secs
+                .unwrap_or(DEFAULT_SYNC_INTERVAL_SECS);
+            let task = postgres::spawn_sync(
+                url,
+                std::time::Duration::from_secs(interval as u64),
+                Arc::clone(&accounts),
+                shard_states.clone(),
+                sync_stop.clone(),
+            )
+            .await?;
+            info!("Syncing balances to Postgres every {}s", interval);
+            Some(task)
+        }
+        None => None,
+    };
+    // A paused run waits for the rows it handed out to be applied, which
+    // the client tasks count down
+    let mut pause = options
+        .pause_on_signal
+        .then(PauseSwitch::from_signals)
+        .transpose()?;
+    let in_flight = pause.is_some().then(|| Arc::new(InFlight::default()));
+    // Policies reloaded on SIGHUP reach every client task through a watch
+    // channel; a failed reload keeps the ones in force
+    let reloads = match options.reload_on_signal {
+        true => {
+            let flags = options
+                .flags
+                .clone()
+                .unwrap_or_else(|| Box::new(options.clone()));
+            let (reloaded, reloads) = watch::channel(Arc::clone(&policies));
+            control::on_hangup(move || match Policies::reload(&flags) {
+                Ok(policies) => {
+                    info!("Reloaded limits, exchange rates and fraud rules");
+                    reloaded.send_replace(Arc::new(policies));
+                }
+                Err(error) => error!("Reload failed; keeping the current settings: {}", error),
+            })?;
+            Some(reloads)
+        }
+        false => None,
+    };
+    let mut task_options = ClientTaskOptions {
+        verify: options.verify,
+        audit: options.audit_log.is_some(),
+        hash_chain: hash_chain.clone(),
+        events: options.events.is_some() || options.publish.is_some(),
+        notifier,
+        notify_withdrawals_over: options.notify_withdrawals_over,
+        require_monotonic: options.rules.require_monotonic.unwrap_or_default(),
+        limits: Arc::clone(&policies.limits),
+        fraud: policies.fraud.clone(),
+        meta: Arc::clone(&policies.meta),
+        idempotency_window: options.rules.idempotency_window,
+        coalesce_rows: options.coalesce_rows.unwrap_or(1),
+        priority_types: options.priority_types.clone().unwrap_or_default(),
+        in_flight: in_flight.clone(),
+        owners: Arc::clone(&owners),
+        reloads,
+        // Shard workers and shared client tasks are bounded in number, so
+        // only tasks of their own shut down while idle
+        idle_timeout: options
+            .client_idle_secs
+            .filter(|_| shard_states.is_empty() && options.max_client_tasks.is_none())
+            .map(|secs| std::time::Duration::from_secs(secs as u64)),
+        cold_accounts: cold_accounts.clone(),
+        rules,
+    };
+    let mut inline_guards = options.deterministic.then(|| {
+        ClientGuards::new(
+            task_options.require_monotonic,
+            &task_options.limits,
+            &task_options.fraud,
+            &task_options.meta,
+            task_options.idempotency_window,
+        )
+    });
+
+    // Each client has a dedicated channel to process transactions
+    // sequentially. Only the ingestion loop routes rows, so it owns the map
+    // outright and never locks it, even while waiting on a full channel.
+    // Once --max-client-tasks tasks are running, further clients share the
+    // channel of the task their ID hashes to.
+    let mut senders: HashMap<ClientId, mpsc::Sender<Transaction>> = HashMap::new();
+    let mut client_tasks: Vec<mpsc::Sender<Transaction>> = Vec::new();
+    // With an idle timeout, each client's latest task, whose guards the
+    // next task for the client takes over once it has shut down
+    let mut idle_tasks: HashMap<ClientId, JoinHandle<ClientGuardsMap>> = HashMap::new();
+
+    // Every client task reports its outcomes back to a single collector, as
+    // does the ingestion loop for malformed rows. Under the strict policy the
+    // collector cancels ingestion at the first error.
+    let cancel = CancellationToken::new();
+    let (report_tx, report_rx) = mpsc::unbounded_channel();
+    let ledger = options.ledger.as_ref().map(|_| Ledger::default());
+    let collector = tokio::spawn(
+        collect_reports(
+            report_rx,
+            writers,
+            ledger,
+            options.error_policy.unwrap_or_default(),
+            cancel.clone(),
+        )
+        .in_current_span(),
+    );
+    let (mut shard_senders, shard_tasks): (Vec<_>, Vec<_>) = shard_states
+        .iter()
+        .map(|state| {
+            let (tx_chan, rx_chan) = mpsc::channel(channel_capacity);
+            let task = tokio::spawn(process_shard(
+                rx_chan,
+                Arc::clone(state),
+                report_tx.clone(),
+                task_options.clone(),
+            ));
+            (tx_chan, task)
+        })
+        .unzip();
+
+    // With more than one core, rows are read on a background task and parsed
+    // in chunks on the blocking pool, then handed over here in input order
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut rows = input_file.rows(resume_offset, parallelism);
+    let admin_channel = admin_rows.is_some();
+    if let Some(admin_rows) = admin_rows {
+        rows = rows.with_admin_rows(admin_rows);
+    }
+    let mut rows_since_check = 0;
+    let slice = options.clients.or(options.shard);
+    if let Some(slice) = slice {
+        info!("Handling only {}", slice);
+    }
+    let mut rows_skipped: u64 = 0;
+    let mut memory_error = None;
+    // The recovery report is rewritten on the clock, checked as rows arrive
+    let mut recovery_scan = options
+        .recovery_report
+        .as_ref()
+        .zip(options.recovery_interval_secs)
+        .map(|(path, secs)| {
+            (
+                path,
+                std::time::Duration::from_secs(secs as u64),
+                Instant::now(),
+            )
+        });
+    let mut negative_count = 0;
+    'rows: loop {
+        if let Some(pause) = pause.as_mut()
+            && pause.is_paused()
+        {
+            info!(
+                "Pausing after {} rows; waiting for {} in flight",
+                resume_offset + rows.rows_handed(),
+                in_flight.as_ref().map_or(0, |rows| rows.rows())
+            );
+            // A strict run aborting stops its client tasks short of that
+            if let Some(in_flight) = &in_flight {
+                tokio::select! {
+                    () = in_flight.drained() => {}
+                    () = cancel.cancelled() => break,
+                }
+            }
+            if let Some(path) = &options.save_state {
+                if !shard_states.is_empty() {
+                    warn!("Sharded runs cannot checkpoint while paused");
+                } else {
+                    // Every row handed out so far is applied, so the
+                    // checkpoint can be resumed from where ingestion stopped
+                    if let Some(spill) = &spill {
+                        spill.restore_all(&transactions);
+                    }
+                    if let Some(cold) = &cold_accounts {
+                        cold.restore_all(&accounts);
+                    }
+                    let mut snapshot = Snapshot::capture(&accounts, &transactions)
+                        .with_schedule(scheduler.clone());
+                    if let Some(chain) = &hash_chain {
+                        snapshot = snapshot.with_chain(chain.state());
+                    }
+                    if let Some(tombstones) = &tombstones {
+                        snapshot = snapshot.with_tombstones(tombstones);
+                    }
+                    // Admin rows only come once the input is exhausted, and
+                    // a resumed run would apply them all again
+                    if !admin_channel {
+                        snapshot = snapshot.at_offset(resume_offset + rows.rows_handed());
+                    }
+                    match snapshot.save_with(path, state_key.as_ref()) {
+                        Ok(()) => info!(
+                            "Checkpoint saved to {} (state root {})",
+                            path.display(),
+                            snapshot.state_root
+                        ),
+                        Err(error) => error!("Checkpoint failed: {}", error),
+                    }
+                }
+            }
+            info!("Paused; send SIGUSR2 to resume");
+            tokio::select! {
+                () = pause.resumed() => info!("Resumed"),
+                () = cancel.cancelled() => break,
+            }
+        }
+        let Some(row) = rows.next().await else {
+            break;
+        };
+        if cancel.is_cancelled() {
+            break;
+        }
+        telemetry::record_row_ingested();
+
+        if let Some(update) = progress
+            .as_mut()
+            .and_then(|p| p.record(row.position.as_ref().map(|p| p.byte())))
+        {
+            let size = state_size(&accounts, &transactions, &shard_states);
+            info!("Progress: {}; state {}", update, size);
+        }
+        if let Some((path, interval, last)) = recovery_scan.as_mut()
+            && last.elapsed() >= *interval
+        {
+            *last = Instant::now();
+            let negative = negative_balances(&accounts, &shard_states, options.suspense_account);
+            if negative.len() != negative_count {
+                info!("Recovery scan: {} balances below zero", negative.len());
+                negative_count = negative.len();
+            }
+            recovery::write_report(&negative, fs::File::create(path)?)?;
+        }
+
+        // Estimating the state's size takes a pass over the map shards, so
+        // it is only done every few thousand rows
+        rows_since_check += 1;
+        if rows_since_check == MEMORY_CHECK_ROWS {
+            rows_since_check = 0;
+            // Channels of tasks that shut down while idle are let go
+            if task_options.idle_timeout.is_some() {
+                senders.retain(|_, sender| !sender.is_closed());
+            }
+            let mut size = state_size(&accounts, &transactions, &shard_states);
+            if let (Some(spill), Some(spill_over)) = (&spill, options.spill_over)
+                && size.estimate() > spill_over
+            {
+                // Spilling down to half the threshold keeps it from being
+                // crossed again a few rows later
+                let count = size.transactions_over(MemorySize(spill_over.0 / 2));
+                match spill_transactions(spill, count, &transactions, &shard_states) {
+                    Ok(spilled) => info!(
+                        "Spilled {} transactions to disk, {} in all",
+                        spilled,
+                        spill.len()
+                    ),
+                    Err(error) => {
+                        memory_error = Some(error);
+                        break;
+                    }
+                }
+                size = state_size(&accounts, &transactions, &shard_states);
+            }
+            if let Some(cold) = &cold_accounts {
+                if let Some(page_over) = options.page_accounts_over
+                    && size.accounts_estimate() > page_over
+                {
+                    match page_out_accounts(cold, &accounts, &shard_states) {
+                        Ok(paged) => info!(
+                            "Paged {} inactive accounts out to disk, {} in all",
+                            paged,
+                            cold.len()
+                        ),
+                        Err(error) => {
+                            memory_error = Some(error);
+                            break;
                         }
                     }
-                    _ => {}
-                }
-
-                let client_id = transaction.client;
-
-                let sender = {
-                    let mut senders_lock = senders.lock().unwrap();
-
-                    // Create a new channel per client if not already present
-                    senders_lock
-                        .entry(client_id)
-                        .or_insert_with(|| {
-                            let (tx_chan, rx_chan) = mpsc::channel(CONCURRENCY_LIMIT);
-                            let accounts_clone = Arc::clone(&accounts);
-                            let transactions_clone = Arc::clone(&transactions);
-                            tokio::spawn(async move {
-                                process_client_transactions(
-                                    rx_chan,
-                                    accounts_clone,
-                                    transactions_clone,
-                                )
-                                .await;
-                            });
-                            tx_chan
-                        })
-                        .clone()
-                };
+                    size = state_size(&accounts, &transactions, &shard_states);
+                }
+                cold.start_round();
+            }
+            telemetry::record_state_memory(size.estimate().0);
+            if let Err(error) = size.check(options.max_memory) {
+                memory_error = Some(error);
+                break;
+            }
+        }
+
+        let transaction = match row.transaction {
+            Ok(transaction) => transaction,
+            Err(malformed) => {
+                // A malformed row whose client cannot be read is reported by
+                // every instance in a split run
+                let client = malformed.fields.get(1).and_then(|raw| raw.parse().ok());
+                if let (Some(slice), Some(client)) = (slice, client)
+                    && !slice.contains(client)
+                {
+                    rows_skipped += 1;
+                    continue;
+                }
+                // The collector only goes away when a strict run is aborting
+                let _ = report_tx.send(RowReport::Malformed(malformed));
+                continue;
+            }
+        };
+        // Recurring transactions fall due as the input's timestamps pass
+        // them, and go in ahead of the row that moved the clock on
+        let mut due = match transaction.timestamp {
+            Some(now) if !scheduler.is_empty() => scheduler.due(now),
+            _ => Vec::new(),
+        };
+        due.retain(|scheduled| slice.is_none_or(|slice| slice.contains(scheduled.client)));
+        let row = if slice.is_some_and(|slice| !slice.contains(transaction.client)) {
+            rows_skipped += 1;
+            None
+        } else {
+            Some(transaction)
+        };
+        // Under --chaos the source may lose a row or deliver it twice
+        #[cfg(feature = "chaos")]
+        let row = match &chaos {
+            Some(chaos) => row.map_or_else(Vec::new, |transaction| chaos.deliver(transaction)),
+            None => row.into_iter().collect(),
+        };
+
+        for mut transaction in due.into_iter().chain(row) {
+            let client_id = transaction.client;
 
-                // Send transaction to client's channel
+            // Cross-client ID clashes are settled here so file order decides
+            // them, looking the owner up in whichever map holds the record
+            let owner_of = |tx| {
+                let maps = std::iter::once(&*transactions)
+                    .chain(shard_states.iter().map(|state| &state.transactions));
+                stored_owner(tx, maps, &task_options.rules)
+            };
+            let checked = tokio::select! {
+                checked = owners.claim(&transaction, owner_of) => checked,
+                // Rows of an aborting strict run are never settled
+                () = cancel.cancelled() => break 'rows,
+            };
+            if let Err(error) = checked {
+                let (verdicts, hold) =
+                    match mismatch_review(&transaction, &error, task_options.rules.client_mismatch)
+                    {
+                        Some((verdict, hold)) => (vec![verdict], Some(hold)),
+                        None => (Vec::new(), None),
+                    };
+                let _ = report_outcome(Err(Rejected { transaction, error }), verdicts, &report_tx);
+                // A review hold travels like any other row, so it lands
+                // after the client's earlier rows
+                match hold {
+                    Some(hold) => transaction = hold,
+                    None => continue,
+                }
+            }
+
+            let claim = owners.claim_of(&transaction);
+
+            // Deterministic runs apply every row right here, in file order
+            if let Some(guards) = inline_guards.as_mut() {
+                if let Some(rebuild_fraud) = task_options.take_reload() {
+                    guards.reload(&task_options, rebuild_fraud);
+                }
+                if !handle_and_report(
+                    transaction,
+                    guards,
+                    &accounts,
+                    &transactions,
+                    &task_options,
+                    &report_tx,
+                ) {
+                    break 'rows;
+                }
+                owners.settle(claim);
+                continue;
+            }
+
+            // Sharded runs route each client to the worker owning its state
+            if !shard_senders.is_empty() {
+                let sender = &shard_senders[shard::shard_for(client_id, shard_senders.len())];
+                #[cfg(feature = "chaos")]
+                delay_send(chaos.as_ref()).await;
+                task_options.count_in_flight(1);
                 if sender.send(transaction).await.is_err() {
-                    log::warn!(
-                        "Failed to send transaction to client {}'s channel",
-                        client_id
-                    );
+                    warn!("Failed to send transaction to client {}'s shard", client_id);
+                    task_options.count_handled(1);
+                    owners.settle(claim);
                 }
+                continue;
+            }
 
-                Ok(())
+            // Create a new channel per client if not already present
+            let mut spawn = || {
+                let (tx_chan, rx_chan) = mpsc::channel(channel_capacity);
+                let task = tokio::spawn(process_client_transactions(
+                    rx_chan,
+                    Arc::clone(&accounts),
+                    Arc::clone(&transactions),
+                    report_tx.clone(),
+                    task_options.clone(),
+                    idle_tasks.remove(&client_id),
+                ));
+                if task_options.idle_timeout.is_some() {
+                    idle_tasks.insert(client_id, task);
+                }
+                tx_chan
+            };
+            let sender = senders.entry(client_id).or_insert_with(|| {
+                if let Some(max) = options.max_client_tasks {
+                    if client_tasks.len() >= max {
+                        return client_tasks[shard::shard_for(client_id, max)].clone();
+                    }
+                    let tx_chan = spawn();
+                    client_tasks.push(tx_chan.clone());
+                    return tx_chan;
+                }
+                spawn()
+            });
+
+            // Send transaction to client's channel
+            #[cfg(feature = "chaos")]
+            delay_send(chaos.as_ref()).await;
+            task_options.count_in_flight(1);
+            let mut sent = sender.send(transaction).await;
+            // A task that shut down while idle closed its channel; a new
+            // one carries on where it left off
+            if task_options.idle_timeout.is_some()
+                && let Err(SendError(transaction)) = sent
+            {
+                *sender = spawn();
+                sent = sender.send(transaction).await;
             }
-        })
-        .await?;
+            if sent.is_err() {
+                warn!(
+                    "Failed to send transaction to client {}'s channel",
+                    client_id
+                );
+                task_options.count_handled(1);
+                owners.settle(claim);
+            }
+            telemetry::record_channel_depth(client_id, sender.max_capacity() - sender.capacity());
+        }
+    }
 
-    output_accounts(&accounts)?;
+    // Closing every client channel lets the client tasks drain and exit, which
+    // in turn closes the report channel once all transactions are handled.
+    let rows_read = rows.finish().await;
+    senders.clear();
+    client_tasks.clear();
+    shard_senders.clear();
+    drop(report_tx);
+    let summary = collector.await.expect("outcome collector panicked")?;
+    if let Some(error) = memory_error {
+        return Err(error);
+    }
+    for task in shard_tasks {
+        task.await.expect("shard worker panicked");
+    }
+    if let Some(cold) = &cold_accounts {
+        cold.restore_all(&accounts);
+    }
+    // The last sync reads the shards' accounts, so it is done before they
+    // are merged
+    if let Some(sync) = balance_sync {
+        sync_stop.cancel();
+        let report = sync.await.expect("balance sync task panicked");
+        info!(
+            "Postgres: {} balance rows upserted over {} syncs, {} batches failed",
+            report.rows, report.syncs, report.failed_batches
+        );
+        if report.unsynced > 0 {
+            warn!(
+                "Postgres: {} changed balances could not be written",
+                report.unsynced
+            );
+        }
+    }
+    let shard_states = shard_states
+        .into_iter()
+        .map(|state| Arc::into_inner(state).expect("shard worker still holds its state"));
+    shard::merge(shard_states, &accounts, &transactions);
+    if let Some(slice) = slice {
+        info!("Skipped {} rows outside {}", rows_skipped, slice);
+    }
+    if rows_read < resume_offset {
+        warn!(
+            "Input has only {} rows, fewer than the {} already covered by the resumed state",
+            rows_read, resume_offset
+        );
+    }
+    if let Some(webhook) = webhook {
+        // The task finishes once the last notifier is gone and its queue is
+        // drained
+        drop(task_options);
+        let report = webhook.await.expect("webhook task panicked");
+        info!(
+            "Webhook: {} notifications delivered, {} failed, {} dropped",
+            report.delivered, report.failed, report.dropped
+        );
+    }
+    if let Some(progress) = &progress {
+        info!("Progress: {}", progress.finish());
+    }
+    let size = StateSize::of(&accounts, &transactions);
+    telemetry::record_state_memory(size.estimate().0);
+    info!("State memory: {}", size);
+    let tally = &summary.tally;
+    info!(
+        "Processed transactions: {} applied, {} rejected, {} malformed rows",
+        tally.applied,
+        tally.rejected_total(),
+        tally.malformed_total()
+    );
+
+    let closed = options.closed_accounts.unwrap_or_default();
+    let format = NumberFormat {
+        rounding,
+        fixed: options.fixed_decimals,
+        locale: options.number_locale.unwrap_or_default(),
+    };
+    let view = AccountView {
+        only_locked: options.only_locked,
+        only_nonzero: options.only_nonzero,
+        clients: options.output_clients.clone(),
+        columns: options.output_columns.clone(),
+        extended: options.extended,
+    };
+    match &output {
+        Some(path) => write_accounts(&accounts, closed, format, &view, fs::File::create(path)?)?,
+        None => output_accounts(&accounts, closed, format, &view)?,
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let (Some(path), Some(export)) = (&options.sqlite, summary.sqlite) {
+        let (transactions, rejections) = export.finish(&accounts)?;
+        info!(
+            "Wrote {} accounts, {} transactions and {} rejections to {}",
+            accounts.len(),
+            transactions,
+            rejections,
+            path.display()
+        );
+    }
+
+    let chain = hash_chain.map(|chain| chain.state());
+    let mut stats = RunStats::new(tally, &accounts, started.elapsed());
+    if let Some(chain) = &chain {
+        info!("Hash chain digest: {}", chain.digest);
+        stats = stats.with_hash_chain(chain.digest);
+    }
+    info!("Run stats: {}", stats);
+    if let Some(path) = &options.stats {
+        stats.write_json(fs::File::create(path)?)?;
+    }
+
+    if options.recovery_report.is_some() || options.recovery_adjustments.is_some() {
+        let negative = recovery::scan(&accounts, options.suspense_account);
+        if negative.is_empty() {
+            info!("Recovery: no balances below zero");
+        } else {
+            warn!("Recovery: {} balances below zero", negative.len());
+        }
+        if let Some(path) = &options.recovery_report {
+            recovery::write_report(&negative, fs::File::create(path)?)?;
+        }
+        if let (Some(path), Some(suspense)) =
+            (&options.recovery_adjustments, options.suspense_account)
+        {
+            // Adjustments are numbered past every stored ID, spilled or not
+            if let Some(spill) = &spill {
+                spill.restore_all(&transactions);
+            }
+            let rows = recovery::adjustments(&negative, suspense, &transactions)?;
+            recovery::write_adjustments(&rows, fs::File::create(path)?)?;
+            info!(
+                "Recovery: {} adjustments to suspense account {} written to {}",
+                rows.len(),
+                suspense,
+                path.display()
+            );
+        }
+    }
+
+    if let Some(path) = &options.save_state {
+        // Snapshots hold every stored transaction, spilled or not
+        if let Some(spill) = &spill {
+            spill.restore_all(&transactions);
+        }
+        let mut snapshot = Snapshot::capture(&accounts, &transactions).with_schedule(scheduler);
+        if let Some(chain) = chain {
+            snapshot = snapshot.with_chain(chain);
+        }
+        if let Some(tombstones) = &tombstones {
+            snapshot = snapshot.with_tombstones(tombstones);
+        }
+        // An aborted run may have left rows it read unapplied, so its state
+        // cannot be resumed from
+        if !cancel.is_cancelled() {
+            snapshot = snapshot.at_offset(rows_read.max(resume_offset));
+        }
+        snapshot.save_with(path, state_key.as_ref())?;
+        info!(
+            "State snapshot saved to {} (state root {})",
+            path.display(),
+            snapshot.state_root
+        );
+    }
+
+    if let (Some(path), Some(ledger)) = (&options.ledger, &summary.ledger) {
+        ledger.write_trial_balance(fs::File::create(path)?)?;
+        info!(
+            "Ledger: {} journal entries, net balance {}",
+            ledger.entries(),
+            ledger.net()
+        );
+        for client in ledger.mismatched_clients(&accounts) {
+            warn!(
+                "Ledger balances for client {} disagree with account",
+                client
+            );
+        }
+    }
+
+    if let Some(expected) = &expected {
+        let closed = options.closed_accounts.unwrap_or_default();
+        let discrepancies = expected.compare(&BalanceSheet::of(&accounts, closed));
+        for discrepancy in &discrepancies {
+            error!("Reconcile: {}", discrepancy);
+        }
+        if !discrepancies.is_empty() {
+            return Err(EngineError::BalancesDiffer {
+                count: discrepancies.len(),
+            });
+        }
+        info!("Reconcile: all balances match the expected file");
+    }
+
+    if options.verify {
+        // Reconciliation report: accounts still inconsistent at output time
+        let mut final_violations = check_accounts(&accounts);
+        final_violations.extend(check_dispute_holds(&accounts, &transactions));
+        for violation in &final_violations {
+            error!("Invariant violation at output: {}", violation);
+        }
+        let count = summary.violations.len() + final_violations.len();
+        if count > 0 {
+            return Err(EngineError::InvariantViolations { count });
+        }
+        info!("Verify: all account invariants hold");
+    }
+
+    if !summary.errors.is_empty() {
+        for e in &summary.errors {
+            error!("{}", e);
+        }
+        return Err(EngineError::ErrorsCollected {
+            count: summary.errors.len(),
+        });
+    }
     Ok(())
 }
 
-/// Process all transactions for one client sequentially.
+/// Per-client checks and business rules enabled for a run
+#[derive(Debug, Clone)]
+struct ClientTaskOptions {
+    verify: bool,
+    /// Number applied transactions for the audit log
+    audit: bool,
+    /// Per-client hash chains to extend with every applied transaction
+    hash_chain: Option<Arc<HashChain>>,
+    /// Turn applied transactions into events
+    events: bool,
+    /// Webhook queue for chargeback and large-withdrawal notifications
+    notifier: Option<Notifier>,
+    /// Smallest withdrawal the webhook is told about
+    notify_withdrawals_over: Option<Decimal>,
+    require_monotonic: MonotonicPolicy,
+    limits: Arc<LimitsTable>,
+    fraud: FraudRules,
+    /// Account metadata the fraud rules for each client are chosen by
+    meta: Arc<AccountMetas>,
+    /// How long an idempotency key stays claimed
+    idempotency_window: Option<Duration>,
+    /// Most back-to-back deposits and withdrawals of one client applied
+    /// under a single account update
+    coalesce_rows: usize,
+    /// Types that go ahead of other queued rows; none when empty
+    priority_types: PriorityTypes,
+    /// Rows handed to client tasks and not yet handled, counted when the
+    /// run can be paused
+    in_flight: Option<Arc<InFlight>>,
+    /// Transaction IDs held by rows handed to client tasks, let go once
+    /// each row is handled
+    owners: Arc<TxOwners>,
+    /// Policies reloaded on SIGHUP, taken up between two rows
+    reloads: Option<watch::Receiver<Arc<Policies>>>,
+    /// How long a client task waits for a row before shutting down
+    idle_timeout: Option<std::time::Duration>,
+    /// Accounts of inactive clients paged out past --page-accounts-over
+    cold_accounts: Option<Arc<ColdAccounts>>,
+    rules: Rules,
+}
+
+impl ClientTaskOptions {
+    /// Bring the client's account back into memory if it was paged out, and
+    /// keep it there until the guard is dropped
+    fn touch<'a>(&'a self, client: ClientId, accounts: &AccountsMap) -> Option<Touched<'a>> {
+        let cold = self.cold_accounts.as_ref()?;
+        Some(cold.touch(client, accounts))
+    }
+
+    /// Whether a client's rows may be applied in runs: nothing may need to
+    /// see the account between two rows, and no daily limit may depend on
+    /// the row before
+    fn coalesces(&self, client: ClientId) -> bool {
+        self.coalesce_rows > 1
+            && self.priority_types.is_empty()
+            && !(self.verify || self.audit || self.events || self.hash_chain.is_some())
+            && self.limits.for_client(client).is_empty()
+    }
+
+    /// Take up the policies reloaded since the last call. Returns `None`
+    /// if there were none, and otherwise whether fraud rules must be rebuilt
+    fn take_reload(&mut self) -> Option<bool> {
+        let reloads = self.reloads.as_mut()?;
+        if !reloads.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let policies = Arc::clone(&reloads.borrow_and_update());
+        // Segment rules are chosen by the metadata, which was reloaded too
+        let rebuild_fraud = policies.fraud != self.fraud || !policies.fraud.segments.is_empty();
+        self.limits = Arc::clone(&policies.limits);
+        self.meta = Arc::clone(&policies.meta);
+        self.fraud = policies.fraud.clone();
+        self.rules.fx_rates = policies.fx_rates.clone();
+        Some(rebuild_fraud)
+    }
+
+    fn count_in_flight(&self, rows: usize) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.add(rows);
+        }
+    }
+
+    fn count_handled(&self, rows: usize) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.done(rows);
+        }
+    }
+}
+
+/// Hold back a channel send if the injected faults call for it
+#[cfg(feature = "chaos")]
+async fn delay_send(chaos: Option<&Chaos>) {
+    if let Some(delay) = chaos.and_then(Chaos::send_delay) {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Process all transactions for one client sequentially, or for several once
+/// the client-task limit is reached.
 ///
-/// Ensures that all operations for a given client are handled in order.
+/// Ensures that all operations for a given client are handled in order, each
+/// client with its own guards. Rows whose timestamp goes backwards are flagged
+/// or rejected per the monotonic policy, rows over the client's risk limits
+/// are rejected, and in verify mode the client's account invariants are
+/// checked after every applied transaction.
 async fn process_client_transactions(
     mut rx: mpsc::Receiver<Transaction>,
     accounts: Arc<AccountsMap>,
     transactions: Arc<TransactionsMap>,
+    reports: mpsc::UnboundedSender<RowReport>,
+    mut options: ClientTaskOptions,
+    previous: Option<JoinHandle<ClientGuardsMap>>,
+) -> ClientGuardsMap {
+    // The rows a task left queued when it went idle come before these
+    let mut guards = match previous {
+        Some(task) => task.await.expect("client task panicked"),
+        None => HashMap::new(),
+    };
+    telemetry::record_client_task(1.0);
+    apply_queued(
+        &mut rx,
+        &mut guards,
+        &accounts,
+        &transactions,
+        &reports,
+        &mut options,
+    )
+    .await;
+    telemetry::record_client_task(-1.0);
+    guards
+}
+
+/// Apply every row queued on `rx` in order, until the channel closes or the
+/// collector goes away.
+///
+/// With priority types set, rows of those types go ahead of the rows queued
+/// before them, as the client's `Lanes` allow.
+///
+/// Where the options allow it, a deposit or withdrawal is applied together
+/// with the deposits and withdrawals of the same client already queued behind
+/// it, up to `coalesce_rows` at a time, so a hot account is locked once per
+/// run instead of once per row.
+///
+/// With an idle timeout, a task that waits that long for a row closes its
+/// channel and returns once it has applied what was already queued.
+async fn apply_queued(
+    rx: &mut mpsc::Receiver<Transaction>,
+    guards: &mut ClientGuardsMap,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    reports: &mpsc::UnboundedSender<RowReport>,
+    options: &mut ClientTaskOptions,
+) {
+    let mut next = None;
+    let mut lanes = (!options.priority_types.is_empty())
+        .then(|| Lanes::new(options.priority_types.clone(), rx.max_capacity()));
+    loop {
+        // With priority lanes, everything already queued is drawn in so a
+        // priority row can be picked out from behind the rest
+        if let Some(lanes) = lanes.as_mut() {
+            while !lanes.is_full()
+                && let Ok(tx) = rx.try_recv()
+            {
+                lanes.push(tx);
+            }
+        }
+        let tx = match next.take().or_else(|| lanes.as_mut()?.pop()) {
+            Some(tx) => tx,
+            None => match options.idle_timeout.filter(|_| !rx.is_closed()) {
+                Some(idle) => match tokio::time::timeout(idle, rx.recv()).await {
+                    Ok(Some(tx)) => tx,
+                    Ok(None) => break,
+                    Err(_) => {
+                        rx.close();
+                        continue;
+                    }
+                },
+                None => match rx.recv().await {
+                    Some(tx) => tx,
+                    None => break,
+                },
+            },
+        };
+        if let Some(rebuild_fraud) = options.take_reload() {
+            for guards in guards.values_mut() {
+                guards.reload(options, rebuild_fraud);
+            }
+        }
+        let client = tx.client;
+        let guards = guards.entry(client).or_insert_with(|| {
+            ClientGuards::new(
+                options.require_monotonic,
+                &options.limits,
+                &options.fraud,
+                &options.meta,
+                options.idempotency_window,
+            )
+        });
+        let handled = if is_balance_move(&tx) && options.coalesces(client) {
+            let run = take_run(tx, rx, options.coalesce_rows, &mut next);
+            let rows = run.len();
+            let claims: Vec<_> = run
+                .iter()
+                .filter_map(|row| options.owners.claim_of(row))
+                .collect();
+            let handled =
+                handle_run_and_report(run, guards, accounts, transactions, options, reports);
+            options.count_handled(rows);
+            options.owners.settle(claims);
+            handled
+        } else {
+            let claim = options.owners.claim_of(&tx);
+            let handled = handle_and_report(tx, guards, accounts, transactions, options, reports);
+            options.count_handled(1);
+            options.owners.settle(claim);
+            handled
+        };
+        if !handled {
+            break;
+        }
+    }
+}
+
+/// Deposits and withdrawals, the only rows applied in runs
+fn is_balance_move(transaction: &Transaction) -> bool {
+    matches!(
+        transaction.tx_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    )
+}
+
+/// Take `first` plus the deposits and withdrawals of the same client queued
+/// right behind it, up to `limit` rows. The first queued row that does not
+/// fit is left in `next`.
+fn take_run(
+    first: Transaction,
+    rx: &mut mpsc::Receiver<Transaction>,
+    limit: usize,
+    next: &mut Option<Transaction>,
+) -> Vec<Transaction> {
+    let client = first.client;
+    let mut run = vec![first];
+    while run.len() < limit {
+        let Ok(tx) = rx.try_recv() else {
+            break;
+        };
+        if tx.client != client || !is_balance_move(&tx) {
+            *next = Some(tx);
+            break;
+        }
+        run.push(tx);
+    }
+    run
+}
+
+/// Check each row of a run against the client's guards, apply the rows they
+/// admit as one run and report every row's outcome in order.
+///
+/// A row the guards reject ends the run so far: the rows before it are
+/// applied first, exactly as if each row had been handled on its own.
+///
+/// Returns `false` once the collector has gone away.
+fn handle_run_and_report(
+    run: Vec<Transaction>,
+    guards: &mut ClientGuards,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    options: &ClientTaskOptions,
+    reports: &mpsc::UnboundedSender<RowReport>,
+) -> bool {
+    let _touched = run
+        .first()
+        .and_then(|first| options.touch(first.client, accounts));
+    let mut admitted = Vec::with_capacity(run.len());
+    let mut verdicts = Vec::with_capacity(run.len());
+    for transaction in run {
+        match guards.check(&transaction) {
+            Ok(()) => {
+                admitted.push(transaction);
+                verdicts.push(guards.fraud.take_verdicts());
+            }
+            Err(error) => {
+                let applied = apply_run(
+                    std::mem::take(&mut admitted),
+                    std::mem::take(&mut verdicts),
+                    guards,
+                    accounts,
+                    transactions,
+                    options,
+                    reports,
+                );
+                hold_if_asked(&error, accounts, transaction.client);
+                let rejected = Err(Rejected { transaction, error });
+                if !(applied && report_outcome(rejected, guards.fraud.take_verdicts(), reports)) {
+                    return false;
+                }
+            }
+        }
+    }
+    apply_run(
+        admitted,
+        verdicts,
+        guards,
+        accounts,
+        transactions,
+        options,
+        reports,
+    )
+}
+
+/// Apply rows the guards admitted, each with the fraud verdicts it drew, and
+/// report their outcomes
+fn apply_run(
+    run: Vec<Transaction>,
+    verdicts: Vec<Vec<Verdict>>,
+    guards: &mut ClientGuards,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    options: &ClientTaskOptions,
+    reports: &mpsc::UnboundedSender<RowReport>,
+) -> bool {
+    let outcomes = handle_run(run, accounts, transactions, &options.rules);
+    outcomes
+        .into_iter()
+        .zip(verdicts)
+        .all(|(outcome, verdicts)| {
+            if let Ok(applied) = &outcome {
+                guards.limits.record(applied);
+                guards
+                    .expiry
+                    .record(&applied.transaction, transactions, &options.rules);
+                if let Some(notifier) = &options.notifier
+                    && let Some(notification) =
+                        Notification::for_applied(applied, options.notify_withdrawals_over)
+                {
+                    notifier.notify(notification);
+                }
+            }
+            report_outcome(outcome, verdicts, reports)
+        })
+}
+
+/// Send a row's fraud verdicts and then its outcome to the collector
+fn report_outcome(
+    outcome: TransactionOutcome,
+    verdicts: Vec<Verdict>,
+    reports: &mpsc::UnboundedSender<RowReport>,
+) -> bool {
+    verdicts
+        .into_iter()
+        .all(|verdict| reports.send(RowReport::Fraud(verdict)).is_ok())
+        && reports.send(RowReport::Handled(outcome)).is_ok()
+}
+
+/// Size of the account and transaction state, including every shard's
+fn state_size(
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    shards: &[Arc<ShardState>],
+) -> StateSize {
+    let mut size = StateSize::of(accounts, transactions);
+    for shard in shards {
+        size += StateSize::of(&shard.accounts, &shard.transactions);
+    }
+    size
+}
+
+/// Balances below zero in the main state and every shard's, ordered by
+/// client and currency
+fn negative_balances(
+    accounts: &AccountsMap,
+    shards: &[Arc<ShardState>],
+    suspense: Option<ClientId>,
+) -> Vec<NegativeBalance> {
+    let mut negative = recovery::scan(accounts, suspense);
+    for shard in shards {
+        negative.extend(recovery::scan(&shard.accounts, suspense));
+    }
+    negative.sort_by_key(|n| (n.client, n.currency));
+    negative
+}
+
+/// Spill `count` transactions, taken from the main map and every shard's in
+/// proportion to their size
+fn spill_transactions(
+    spill: &SpillStore,
+    count: usize,
+    transactions: &TransactionsMap,
+    shards: &[Arc<ShardState>],
+) -> Result<usize, EngineError> {
+    let maps: Vec<&TransactionsMap> = std::iter::once(transactions)
+        .chain(shards.iter().map(|shard| &shard.transactions))
+        .collect();
+    let total: usize = maps.iter().map(|map| map.len()).sum();
+    let mut spilled = 0;
+    for map in maps {
+        spilled += spill.spill(map, count * map.len() / total.max(1))?;
+    }
+    Ok(spilled)
+}
+
+/// Page out the accounts of inactive clients from the main map and every
+/// shard's
+fn page_out_accounts(
+    cold: &ColdAccounts,
+    accounts: &AccountsMap,
+    shards: &[Arc<ShardState>],
+) -> Result<usize, EngineError> {
+    let maps: Vec<&AccountsMap> = std::iter::once(accounts)
+        .chain(shards.iter().map(|shard| &shard.accounts))
+        .collect();
+    cold.page_out(&maps)
+}
+
+/// Apply the rows of every client routed to one shard, on state only the
+/// shard writes to, until the shard's channel closes.
+///
+/// Each client keeps its own guards, exactly as with one task per client.
+async fn process_shard(
+    mut rx: mpsc::Receiver<Transaction>,
+    state: Arc<ShardState>,
+    reports: mpsc::UnboundedSender<RowReport>,
+    mut options: ClientTaskOptions,
 ) {
-    while let Some(tx) = rx.recv().await {
-        if let Err(e) = handle_transaction(tx, &accounts, &transactions) {
-            log::warn!("Error handling transaction: {:?}", e);
+    apply_queued(
+        &mut rx,
+        &mut HashMap::new(),
+        &state.accounts,
+        &state.transactions,
+        &reports,
+        &mut options,
+    )
+    .await;
+}
+
+/// Handle one transaction and send its outcome, plus any fraud verdicts,
+/// events, audit records and, in verify mode, invariant violations, to the
+/// collector.
+///
+/// Returns `false` once the collector has gone away, which only happens when
+/// a strict run is aborting.
+fn handle_and_report(
+    transaction: Transaction,
+    guards: &mut ClientGuards,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    options: &ClientTaskOptions,
+    reports: &mpsc::UnboundedSender<RowReport>,
+) -> bool {
+    let client = transaction.client;
+    let _touched = options.touch(client, accounts);
+    let before = options.events.then(|| {
+        accounts
+            .get(&client)
+            .map_or_else(|| Account::new(client), |account| account.clone())
+    });
+    let outcome = handle_in_order(transaction, guards, accounts, transactions, &options.rules);
+    if let (Ok(applied), Some(notifier)) = (&outcome, &options.notifier)
+        && let Some(notification) =
+            Notification::for_applied(applied, options.notify_withdrawals_over)
+    {
+        notifier.notify(notification);
+    }
+    let events = match (&outcome, before) {
+        (Ok(applied), Some(before)) => accounts
+            .get(&client)
+            .map(|after| events_for(applied, &before, &after))
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    if let (Ok(applied), Some(chain)) = (&outcome, &options.hash_chain) {
+        chain.record(applied);
+    }
+    let audit = match (&outcome, options.audit) {
+        (Ok(applied), true) => accounts
+            .get(&applied.transaction.client)
+            .map(|account| guards.audit.record(applied, &account))
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    let violations = match (&outcome, options.verify) {
+        (Ok(applied), true) => accounts
+            .get(&applied.transaction.client)
+            .map(|account| {
+                let mut violations = check_account(&account, Some(applied.transaction.tx));
+                violations.extend(guards.holds.check(applied, &account));
+                violations
+            })
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    if !report_outcome(outcome, guards.fraud.take_verdicts(), reports) {
+        return false;
+    }
+    events
+        .into_iter()
+        .all(|event| reports.send(RowReport::Event(event)).is_ok())
+        && audit
+            .into_iter()
+            .all(|record| reports.send(RowReport::Audit(record)).is_ok())
+        && violations
+            .into_iter()
+            .all(|violation| reports.send(RowReport::Violation(violation)).is_ok())
+}
+
+/// Output files the collector writes as reports come in
+struct ReportWriters {
+    rejects: Option<RejectsWriter<fs::File>>,
+    fraud_report: Option<FraudReportWriter<fs::File>>,
+    audit_log: Option<AuditLogWriter<fs::File>>,
+    events: Option<EventWriter<fs::File>>,
+    publisher: Option<EventPublisher>,
+    /// Committed with the final accounts once the run is over
+    #[cfg(feature = "sqlite")]
+    sqlite: Option<SqliteExport>,
+}
+
+impl ReportWriters {
+    /// Flush every open file
+    fn finish(self) -> Result<(), EngineError> {
+        if let Some(writer) = self.rejects {
+            writer.finish()?;
+        }
+        if let Some(writer) = self.fraud_report {
+            writer.finish()?;
+        }
+        if let Some(writer) = self.audit_log {
+            writer.finish()?;
+        }
+        if let Some(writer) = self.events {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// What the collector hands back once every report has been received
+struct CollectedReports {
+    tally: OutcomeTally,
+    /// Errors kept for the end-of-run report under the collect policy
+    errors: Vec<EngineError>,
+    /// Invariant violations found after individual mutations in verify mode
+    violations: Vec<InvariantViolation>,
+    /// Double-entry books, when requested
+    ledger: Option<Ledger>,
+    #[cfg(feature = "sqlite")]
+    sqlite: Option<SqliteExport>,
+}
+
+/// Log every malformed row and rejection, optionally write them to the
+/// rejects file, and count outcomes until all client tasks finish.
+///
+/// Under the strict policy the first error cancels ingestion and is returned
+/// as the run's error; under the collect policy every error is kept so it can
+/// be reported at the end.
+async fn collect_reports(
+    mut rx: mpsc::UnboundedReceiver<RowReport>,
+    mut writers: ReportWriters,
+    mut ledger: Option<Ledger>,
+    policy: ErrorPolicy,
+    cancel: CancellationToken,
+) -> Result<CollectedReports, EngineError> {
+    let mut tally = OutcomeTally::default();
+    let mut collected = Vec::new();
+    let mut violations = Vec::new();
+    while let Some(report) = rx.recv().await {
+        let error = match report {
+            RowReport::Handled(Ok(applied)) => {
+                if let Some(ledger) = ledger.as_mut() {
+                    ledger.post(&JournalEntry::for_applied(&applied));
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(export) = writers.sqlite.as_mut() {
+                    export.write_applied(&applied)?;
+                }
+                tally.record(&Ok(applied));
+                continue;
+            }
+            RowReport::Handled(Err(rejected)) => {
+                log_rejected(&rejected);
+                if let Some(writer) = writers.rejects.as_mut() {
+                    writer.write(&rejected)?;
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(export) = writers.sqlite.as_mut() {
+                    export.write_rejected(&rejected)?;
+                }
+                tally.record_rejected(&rejected);
+                rejected.error
+            }
+            RowReport::Malformed(row) => {
+                log_malformed(&row);
+                if let Some(writer) = writers.rejects.as_mut() {
+                    writer.write_malformed(&row)?;
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(export) = writers.sqlite.as_mut() {
+                    export.write_malformed(&row)?;
+                }
+                tally.record_malformed(&row);
+                row.error
+            }
+            RowReport::Violation(violation) => {
+                error!(
+                    client = violation.client,
+                    tx = violation.tx,
+                    "Invariant violation: {}",
+                    violation
+                );
+                violations.push(violation);
+                continue;
+            }
+            RowReport::Fraud(verdict) => {
+                log_verdict(&verdict);
+                if let Some(writer) = writers.fraud_report.as_mut() {
+                    writer.write(&verdict)?;
+                }
+                continue;
+            }
+            RowReport::Audit(record) => {
+                if let Some(writer) = writers.audit_log.as_mut() {
+                    writer.write(&record)?;
+                }
+                continue;
+            }
+            RowReport::Event(event) => {
+                if let Some(writer) = writers.events.as_mut() {
+                    writer.write(&event)?;
+                }
+                if let Some(publisher) = writers.publisher.as_mut()
+                    && let Err(error) = publisher.publish(&event).await
+                {
+                    // An event that cannot be delivered would leave a gap
+                    // downstream, so the run stops rather than skipping it
+                    cancel.cancel();
+                    writers.finish()?;
+                    return Err(error);
+                }
+                continue;
+            }
+        };
+
+        match policy {
+            ErrorPolicy::Strict => {
+                cancel.cancel();
+                writers.finish()?;
+                return Err(error);
+            }
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Collect => collected.push(error),
         }
     }
+    #[cfg(feature = "sqlite")]
+    let sqlite = writers.sqlite.take();
+    writers.finish()?;
+    Ok(CollectedReports {
+        tally,
+        errors: collected,
+        violations,
+        ledger,
+        #[cfg(feature = "sqlite")]
+        sqlite,
+    })
+}
+
+/// Log a rejected transaction with its identifiers, amount and reason code as
+/// structured fields
+fn log_rejected(rejected: &Rejected) {
+    let transaction = &rejected.transaction;
+    warn!(
+        tx = transaction.tx,
+        client = transaction.client,
+        r#type = transaction.tx_type.as_str(),
+        amount = transaction.amount.map(tracing::field::display),
+        reason = rejected.error.reason_code(),
+        "Transaction rejected: {}",
+        rejected.error
+    );
+}
+
+/// Log a fraud rule verdict with the rule, action and transaction as
+/// structured fields
+fn log_verdict(verdict: &Verdict) {
+    warn!(
+        tx = verdict.tx,
+        client = verdict.client,
+        rule = verdict.rule,
+        action = %verdict.action,
+        "Fraud rule fired: {}",
+        verdict.detail
+    );
+}
+
+/// Log a malformed input row with its line number and reason code
+fn log_malformed(row: &MalformedRow) {
+    warn!(
+        line = row.line,
+        reason = row.error.reason_code(),
+        "Skipping malformed row: {}",
+        row.error
+    );
 }
@@ -0,0 +1,194 @@
+use serde::Deserialize;
+use std::fmt;
+use std::mem::size_of;
+use std::ops::AddAssign;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::models::{
+    Account, AccountsMap, Balance, ClientId, Currency, TransactionRecord, TransactionsMap, TxKey,
+};
+
+/// Input rows between two checks of the state size
+pub const MEMORY_CHECK_ROWS: u64 = 8192;
+
+/// Entries in one B-tree leaf, which every account's balances take up even
+/// with a single currency
+const BTREE_LEAF_ENTRIES: usize = 11;
+
+/// Bytes held per account: its hash map slot plus a leaf of balances
+const ACCOUNT_BYTES: usize = size_of::<(ClientId, Account)>()
+    + BTREE_LEAF_ENTRIES * size_of::<(Option<Currency>, Balance)>()
+    + 2 * size_of::<usize>();
+
+/// Bytes held per stored transaction
+const TRANSACTION_BYTES: usize = size_of::<(TxKey, TransactionRecord)>();
+
+/// An amount of memory, written as a byte count with an optional `K`, `M`,
+/// `G` or `T` suffix in powers of 1024 (`512M`, `2GiB`, `1.5g`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MemorySize(pub u64);
+
+impl FromStr for MemorySize {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EngineError::Usage(format!("invalid memory size '{s}'"));
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+        let shift = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 0,
+            "K" | "KB" | "KIB" => 10,
+            "M" | "MB" | "MIB" => 20,
+            "G" | "GB" | "GIB" => 30,
+            "T" | "TB" | "TIB" => 40,
+            _ => return Err(invalid()),
+        };
+        let bytes = number * (1u64 << shift) as f64;
+        if bytes < 1.0 || bytes >= u64::MAX as f64 {
+            return Err(invalid());
+        }
+        Ok(MemorySize(bytes as u64))
+    }
+}
+
+impl TryFrom<String> for MemorySize {
+    type Error = EngineError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for MemorySize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0 as f64;
+        for (shift, unit) in [(30, "GiB"), (20, "MiB"), (10, "KiB")] {
+            let scale = (1u64 << shift) as f64;
+            if bytes >= scale {
+                return write!(f, "{:.1} {unit}", bytes / scale);
+            }
+        }
+        write!(f, "{} B", self.0)
+    }
+}
+
+/// How much account and transaction state is held, and roughly how much
+/// memory it takes.
+///
+/// The estimate is entry counts times entry sizes, grown by the slack hash
+/// tables keep free; allocator overhead and the per-client guards are not
+/// counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateSize {
+    pub accounts: usize,
+    pub transactions: usize,
+}
+
+impl StateSize {
+    pub fn of(accounts: &AccountsMap, transactions: &TransactionsMap) -> Self {
+        StateSize {
+            accounts: accounts.len(),
+            transactions: transactions.len(),
+        }
+    }
+
+    /// Estimated bytes, assuming tables are on average two thirds full
+    pub fn estimate(&self) -> MemorySize {
+        let entries = self.accounts * ACCOUNT_BYTES + self.transactions * TRANSACTION_BYTES;
+        MemorySize((entries as u64).saturating_mul(3) / 2)
+    }
+
+    /// Estimated bytes of the accounts alone
+    pub fn accounts_estimate(&self) -> MemorySize {
+        MemorySize((self.accounts * ACCOUNT_BYTES) as u64 * 3 / 2)
+    }
+
+    /// Transactions to take out of memory to bring the estimate down to
+    /// `target`
+    pub fn transactions_over(&self, target: MemorySize) -> usize {
+        let excess = self.estimate().0.saturating_sub(target.0);
+        let per_transaction = TRANSACTION_BYTES as u64 * 3 / 2;
+        usize::try_from(excess.div_ceil(per_transaction))
+            .unwrap_or(usize::MAX)
+            .min(self.transactions)
+    }
+
+    /// Fail if the estimate is above `limit`
+    pub fn check(&self, limit: Option<MemorySize>) -> Result<(), EngineError> {
+        match limit {
+            Some(limit) if self.estimate() > limit => Err(EngineError::MemoryLimit {
+                estimated: self.estimate(),
+                limit,
+                accounts: self.accounts,
+                transactions: self.transactions,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AddAssign for StateSize {
+    fn add_assign(&mut self, other: StateSize) {
+        self.accounts += other.accounts;
+        self.transactions += other.transactions;
+    }
+}
+
+impl fmt::Display for StateSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "~{} ({} accounts, {} transactions)",
+            self.estimate(),
+            self.accounts,
+            self.transactions
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_sizes_parse_with_binary_units() {
+        assert_eq!("1024".parse::<MemorySize>().unwrap(), MemorySize(1024));
+        assert_eq!("512M".parse::<MemorySize>().unwrap(), MemorySize(512 << 20));
+        assert_eq!("2GiB".parse::<MemorySize>().unwrap(), MemorySize(2 << 30));
+        assert_eq!("1.5 kb".parse::<MemorySize>().unwrap(), MemorySize(1536));
+        assert!("".parse::<MemorySize>().is_err());
+        assert!("0".parse::<MemorySize>().is_err());
+        assert!("12 parsecs".parse::<MemorySize>().is_err());
+        assert_eq!(MemorySize(3 << 29).to_string(), "1.5 GiB");
+        assert_eq!(MemorySize(100).to_string(), "100 B");
+    }
+
+    #[test]
+    fn test_state_size_checked_against_limit() {
+        let size = StateSize {
+            accounts: 10,
+            transactions: 1_000,
+        };
+        let estimate = size.estimate();
+        assert!(estimate.0 > 1_000 * TRANSACTION_BYTES as u64);
+        assert_eq!(size.transactions_over(estimate), 0);
+        assert_eq!(size.transactions_over(MemorySize(estimate.0 - 1)), 1);
+        assert_eq!(size.transactions_over(MemorySize(1)), 1_000);
+        assert!(size.accounts_estimate() < estimate);
+        size.check(None).unwrap();
+        size.check(Some(estimate)).unwrap();
+        assert!(matches!(
+            size.check(Some(MemorySize(estimate.0 - 1))),
+            Err(EngineError::MemoryLimit {
+                transactions: 1_000,
+                ..
+            })
+        ));
+    }
+}
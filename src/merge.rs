@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use crate::config::MergeDuplicates;
+use crate::error::EngineError;
+use crate::models::ClientId;
+use crate::reconcile::{BalanceSheet, SheetEntry};
+
+/// Combine the accounts files of runs over separate client slices into one.
+///
+/// Each sheet comes with a name for error messages. A client found in more
+/// than one sheet, in any currency, fails the merge under
+/// `MergeDuplicates::Reject`; under `Sum` its balances are added up.
+pub fn merge<I>(sheets: I, duplicates: MergeDuplicates) -> Result<BalanceSheet, EngineError>
+where
+    I: IntoIterator<Item = (String, BalanceSheet)>,
+{
+    let mut merged = BalanceSheet::default();
+    let mut names = Vec::new();
+    // Which sheet each client was first seen in
+    let mut sources: BTreeMap<ClientId, usize> = BTreeMap::new();
+    for (index, (name, sheet)) in sheets.into_iter().enumerate() {
+        names.push(name);
+        for (client, currency, entry) in sheet.iter() {
+            let first = *sources.entry(client).or_insert(index);
+            if first != index && duplicates == MergeDuplicates::Reject {
+                return Err(EngineError::MergeConflict {
+                    client,
+                    first: names[first].clone(),
+                    second: names[index].clone(),
+                });
+            }
+            let entry = match merged.get(client, currency) {
+                Some(earlier) => sum(earlier, entry),
+                None => entry,
+            };
+            merged.insert(client, currency, entry);
+        }
+    }
+    Ok(merged)
+}
+
+fn sum(a: SheetEntry, b: SheetEntry) -> SheetEntry {
+    let mut entry = a;
+    entry.balance.available += b.balance.available;
+    entry.balance.held += b.balance.held;
+    entry.balance.total += b.balance.total;
+    entry.locked = match (a.locked, b.locked) {
+        (Some(a), Some(b)) => Some(a || b),
+        (a, b) => a.or(b),
+    };
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(csv: &str) -> BalanceSheet {
+        BalanceSheet::read(csv.as_bytes()).unwrap()
+    }
+
+    fn shards() -> Vec<(String, BalanceSheet)> {
+        vec![
+            (
+                "low.csv".to_string(),
+                sheet("client,available,held,total,locked\n1,1,0,1,false\n2,5,1,6,false\n"),
+            ),
+            (
+                "high.csv".to_string(),
+                sheet("client,available,held,total,locked\n9,2,0,2,true\n"),
+            ),
+        ]
+    }
+
+    fn overlapping() -> Vec<(String, BalanceSheet)> {
+        let mut shards = shards();
+        shards.push((
+            "extra.csv".to_string(),
+            sheet("client,available,held,total,locked\n2,-1,0,-1,true\n"),
+        ));
+        shards
+    }
+
+    #[test]
+    fn test_merge_disjoint_shards() {
+        let mut out = Vec::new();
+        merge(shards(), MergeDuplicates::Reject)
+            .unwrap()
+            .write(&mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,1,0,1,false\n2,5,1,6,false\n9,2,0,2,true\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_rejects_a_client_in_two_shards() {
+        assert!(matches!(
+            merge(overlapping(), MergeDuplicates::Reject),
+            Err(EngineError::MergeConflict { client: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_merge_sums_a_client_in_two_shards() {
+        let summed = merge(overlapping(), MergeDuplicates::Sum).unwrap();
+        let entry = summed.get(2, None).unwrap();
+        assert_eq!(
+            (entry.balance.total.to_string(), entry.locked),
+            ("5".to_string(), Some(true))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use sha2::{Digest as _, Sha256};
+use std::fmt::Write as _;
+
+use crate::hashchain::Digest;
+use crate::models::Account;
+
+/// Hash of one account: its client, status and every balance.
+///
+/// The account is written as `client␟status` followed by
+/// `␞currency␟available␟held␟total` per balance in currency order, with
+/// amounts normalized, and hashed after a `0x00` byte so a leaf can never be
+/// taken for an inner node.
+pub fn leaf(account: &Account) -> Digest {
+    let mut entry = format!("{}\x1f{}", account.client, account.status);
+    for (currency, balance) in &account.balances {
+        let _ = write!(
+            entry,
+            "\x1e{}\x1f{}\x1f{}\x1f{}",
+            currency.map(|c| c.to_string()).unwrap_or_default(),
+            balance.available.normalize(),
+            balance.held.normalize(),
+            balance.total.normalize()
+        );
+    }
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(entry.as_bytes());
+    Digest(hasher.finalize().into())
+}
+
+/// Hash of two sibling nodes, after a `0x01` byte
+fn node(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left.0);
+    hasher.update(right.0);
+    Digest(hasher.finalize().into())
+}
+
+/// Merkle root over accounts sorted by client ID.
+///
+/// Leaves are paired left to right at each level and a last unpaired node
+/// moves up as it is. An empty set has the all-zero root.
+pub fn state_root(accounts: &[Account]) -> Digest {
+    let mut level: Vec<_> = accounts.iter().map(leaf).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node(left, right),
+                [single] => *single,
+                _ => unreachable!("chunks of two"),
+            })
+            .collect();
+    }
+    level.pop().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ClientId;
+    use rust_decimal::Decimal;
+
+    fn account(client: ClientId, total: Decimal) -> Account {
+        let mut account = Account::new(client);
+        account.balance_mut(None).available = total;
+        account.balance_mut(None).total = total;
+        account
+    }
+
+    fn accounts() -> Vec<Account> {
+        (1..=3)
+            .map(|client| account(client, Decimal::from(client)))
+            .collect()
+    }
+
+    #[test]
+    fn test_state_root_pairs_leaves_in_order() {
+        let accounts = accounts();
+        assert_eq!(
+            state_root(&accounts),
+            node(
+                &node(&leaf(&accounts[0]), &leaf(&accounts[1])),
+                &leaf(&accounts[2])
+            )
+        );
+    }
+
+    #[test]
+    fn test_state_root_of_one_or_no_accounts() {
+        let accounts = accounts();
+        assert_eq!(state_root(&accounts[..1]), leaf(&accounts[0]));
+        assert_eq!(state_root(&[]), Digest::default());
+    }
+
+    #[test]
+    fn test_rescaled_balances_commit_alike() {
+        let accounts = accounts();
+        let mut rescaled = accounts.clone();
+        rescaled[2] = account(3, Decimal::new(300, 2));
+        assert_eq!(state_root(&rescaled), state_root(&accounts));
+    }
+
+    #[test]
+    fn test_changed_balance_changes_the_root() {
+        let accounts = accounts();
+        let mut changed = accounts.clone();
+        changed[1].balance_mut(None).held = Decimal::ONE;
+        assert_ne!(state_root(&changed), state_root(&accounts));
+    }
+}
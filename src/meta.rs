@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::models::ClientId;
+
+/// What is known about a client beyond their balances
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountMeta {
+    /// Lower-cased service tier, such as `gold`
+    pub tier: Option<String>,
+    pub kyc_verified: bool,
+    /// Upper-cased country code
+    pub country: Option<String>,
+    /// When the account was opened
+    pub created: Option<DateTime<Utc>>,
+}
+
+/// One row of an `--account-meta` file
+#[derive(Debug, Deserialize)]
+struct AccountMetaRow {
+    client: ClientId,
+    #[serde(default)]
+    tier: Option<String>,
+    #[serde(default)]
+    kyc_verified: Option<bool>,
+    #[serde(default)]
+    country: Option<String>,
+    #[serde(default)]
+    created: Option<DateTime<Utc>>,
+}
+
+/// Metadata for every client listed in a seed file. Clients left out have
+/// default metadata: unverified, with no tier or country.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountMetas {
+    clients: HashMap<ClientId, AccountMeta>,
+    unlisted: AccountMeta,
+}
+
+impl AccountMetas {
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        Self::read(file).map_err(|e| EngineError::AccountMeta(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse a `client,tier,kyc_verified,country,created` CSV file; every
+    /// column but `client` is optional and each client may only be listed
+    /// once
+    pub fn read<R: io::Read>(reader: R) -> Result<Self, String> {
+        let mut metas = AccountMetas::default();
+        for row in csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize()
+        {
+            let row: AccountMetaRow = row.map_err(|e| e.to_string())?;
+            let meta = AccountMeta {
+                tier: row.tier.map(|tier| tier.to_ascii_lowercase()),
+                kyc_verified: row.kyc_verified.unwrap_or_default(),
+                country: row.country.map(|country| country.to_ascii_uppercase()),
+                created: row.created,
+            };
+            if metas.clients.insert(row.client, meta).is_some() {
+                return Err(format!("client {} is listed twice", row.client));
+            }
+        }
+        Ok(metas)
+    }
+
+    pub fn get(&self, client: ClientId) -> &AccountMeta {
+        self.clients.get(&client).unwrap_or(&self.unlisted)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// A group of accounts picked out by their metadata, written `verified`,
+/// `unverified`, `tier=NAME` or `country=CODE`.
+///
+/// Segments are ordered from the broadest to the most specific, and where
+/// settings for several segments apply to one account the most specific
+/// wins.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Segment {
+    Verified,
+    Unverified,
+    Tier(String),
+    Country(String),
+}
+
+impl Segment {
+    pub fn matches(&self, meta: &AccountMeta) -> bool {
+        match self {
+            Segment::Verified => meta.kyc_verified,
+            Segment::Unverified => !meta.kyc_verified,
+            Segment::Tier(tier) => meta.tier.as_ref() == Some(tier),
+            Segment::Country(country) => meta.country.as_ref() == Some(country),
+        }
+    }
+}
+
+impl FromStr for Segment {
+    type Err = EngineError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid segment '{raw}': expected verified, unverified, tier=NAME or country=CODE"
+            ))
+        };
+        let raw = raw.trim();
+        match raw.split_once('=') {
+            None if raw.eq_ignore_ascii_case("verified") => Ok(Segment::Verified),
+            None if raw.eq_ignore_ascii_case("unverified") => Ok(Segment::Unverified),
+            Some((key, value)) if !value.trim().is_empty() => match key.trim() {
+                "tier" => Ok(Segment::Tier(value.trim().to_ascii_lowercase())),
+                "country" => Ok(Segment::Country(value.trim().to_ascii_uppercase())),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Segment {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Verified => f.write_str("verified"),
+            Segment::Unverified => f.write_str("unverified"),
+            Segment::Tier(tier) => write!(f, "tier={tier}"),
+            Segment::Country(country) => write!(f, "country={country}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments_match_seeded_metadata() {
+        let metas = AccountMetas::read(
+            "client,tier,kyc_verified,country,created\n\
+             1,Gold,true,de,2024-01-01T00:00:00Z\n\
+             2,,false,,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let gold: Segment = "tier=GOLD".parse().unwrap();
+        let germany: Segment = "country=DE".parse().unwrap();
+        let unverified: Segment = "unverified".parse().unwrap();
+
+        assert!(gold.matches(metas.get(1)) && germany.matches(metas.get(1)));
+        assert!(!unverified.matches(metas.get(1)));
+        assert!(unverified.matches(metas.get(2)) && !gold.matches(metas.get(2)));
+        assert!(unverified.matches(metas.get(3)));
+        assert_eq!(gold.to_string(), "tier=gold");
+        assert!(Segment::Unverified < gold && gold < germany);
+
+        for invalid in ["", "tier=", "region=eu", "kyc"] {
+            assert!(invalid.parse::<Segment>().is_err(), "{invalid}");
+        }
+        assert!(AccountMetas::read("client,tier\n1,gold\n1,silver\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::models::{
+    Account, AccountsMap, ClientId, Transaction, TransactionRecord, TransactionsMap, TxKey,
+};
+use crate::outcome::TransactionOutcome;
+
+/// What a middleware layer wants done with a transaction
+#[derive(Debug)]
+pub enum Decision {
+    /// Hand the transaction on unchanged
+    Continue,
+    /// Hand this transaction on instead, for example with a currency or
+    /// timestamp filled in. It must keep the client and transaction ID.
+    Replace(Transaction),
+    /// Reject the transaction for the given reason
+    Reject(String),
+}
+
+/// Read-only view of the engine state a layer can consult.
+///
+/// Both lookups return copies, so nothing stays locked while a layer runs.
+pub trait Context {
+    /// The client's account, if it has one yet
+    fn account(&self, client: ClientId) -> Option<Account>;
+
+    /// The record stored under `key`, if it is in memory
+    fn transaction(&self, key: &TxKey) -> Option<TransactionRecord>;
+}
+
+/// `Context` backed by the maps a transaction is about to be applied to
+pub struct MapsContext<'a> {
+    pub accounts: &'a AccountsMap,
+    pub transactions: &'a TransactionsMap,
+}
+
+impl Context for MapsContext<'_> {
+    fn account(&self, client: ClientId) -> Option<Account> {
+        self.accounts.get(&client).map(|account| account.clone())
+    }
+
+    fn transaction(&self, key: &TxKey) -> Option<TransactionRecord> {
+        self.transactions.get(key).map(|record| record.clone())
+    }
+}
+
+/// A layer wrapped around transaction handling.
+///
+/// `before` sees every transaction ahead of the built-in checks and may
+/// replace or reject it; `after` sees the outcome of every transaction that
+/// got past all the layers. Layers are shared by every client task, so any
+/// state they keep needs its own synchronisation.
+pub trait Middleware: Send + Sync {
+    /// Decide what happens to `transaction` before it is applied
+    fn before(&self, transaction: &Transaction, context: &dyn Context) -> Decision;
+
+    /// Observe what became of a transaction, for metrics or logging
+    fn after(&self, _outcome: &TransactionOutcome) {}
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Transaction, &dyn Context) -> Decision + Send + Sync,
+{
+    fn before(&self, transaction: &Transaction, context: &dyn Context) -> Decision {
+        self(transaction, context)
+    }
+}
+
+/// Layers run in the order they were added, each on what the one before
+/// handed on
+#[derive(Default, Clone)]
+pub struct MiddlewareChain {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    /// Add a layer to the end of the chain
+    pub fn with_middleware<M: Middleware + 'static>(mut self, layer: M) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Run every layer's `before` on `transaction`, stopping at the first
+    /// rejection.
+    ///
+    /// Returns the transaction to apply, or the reason it was rejected along
+    /// with the transaction as it reached the rejecting layer.
+    pub fn before(
+        &self,
+        mut transaction: Transaction,
+        context: &dyn Context,
+    ) -> Result<Transaction, (Transaction, String)> {
+        for layer in &self.layers {
+            match layer.before(&transaction, context) {
+                Decision::Continue => {}
+                Decision::Replace(replacement)
+                    if replacement.client == transaction.client
+                        && replacement.tx == transaction.tx =>
+                {
+                    transaction = replacement;
+                }
+                Decision::Replace(_) => {
+                    return Err((
+                        transaction,
+                        "middleware may not change the client or transaction ID".to_string(),
+                    ));
+                }
+                Decision::Reject(reason) => return Err((transaction, reason)),
+            }
+        }
+        Ok(transaction)
+    }
+
+    /// Run every layer's `after` on an outcome
+    pub fn after(&self, outcome: &TransactionOutcome) {
+        for layer in &self.layers {
+            layer.after(outcome);
+        }
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MiddlewareChain")
+            .field("layers", &self.layers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_layers_run_in_order_until_rejected() {
+        let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+        let context = MapsContext {
+            accounts: &accounts,
+            transactions: &transactions,
+        };
+        let chain = MiddlewareChain::default()
+            .with_middleware(|transaction: &Transaction, _: &dyn Context| {
+                Decision::Replace(Transaction {
+                    amount: transaction.amount.map(|amount| amount * Decimal::TWO),
+                    ..transaction.clone()
+                })
+            })
+            .with_middleware(
+                |transaction: &Transaction, context: &dyn Context| match transaction.amount {
+                    _ if context.account(transaction.client).is_some() => Decision::Continue,
+                    Some(amount) if amount > Decimal::TWO => Decision::Reject("too much".into()),
+                    _ => Decision::Continue,
+                },
+            );
+
+        let passed = chain
+            .before(
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::ONE)),
+                &context,
+            )
+            .unwrap();
+        assert_eq!(passed.amount, Some(Decimal::TWO));
+        let (rejected, reason) = chain
+            .before(
+                Transaction::new(TransactionType::Deposit, 1, 2, Some(Decimal::TWO)),
+                &context,
+            )
+            .unwrap_err();
+        assert_eq!(
+            (rejected.amount, reason.as_str()),
+            (Some(Decimal::from(4)), "too much")
+        );
+
+        let renumbering = MiddlewareChain::default().with_middleware(
+            |transaction: &Transaction, _: &dyn Context| {
+                Decision::Replace(Transaction {
+                    tx: transaction.tx + 1,
+                    ..transaction.clone()
+                })
+            },
+        );
+        assert!(
+            renumbering
+                .before(
+                    Transaction::new(TransactionType::Deposit, 1, 3, Some(Decimal::ONE)),
+                    &context
+                )
+                .is_err()
+        );
+    }
+}
@@ -1,42 +1,600 @@
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::TryFromIntError;
+use std::str::FromStr;
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+use crate::error::RowErrorKind;
+
+/// Client identifier; 32 bits wide with the `wide-ids` feature, 16 otherwise
+#[cfg(not(feature = "wide-ids"))]
+pub type ClientId = u16;
+/// Client identifier; 32 bits wide with the `wide-ids` feature, 16 otherwise
+#[cfg(feature = "wide-ids")]
+pub type ClientId = u32;
+
+/// Transaction identifier; 64 bits wide with the `wide-ids` feature, 32
+/// otherwise
+#[cfg(not(feature = "wide-ids"))]
+pub type TxId = u32;
+/// Transaction identifier; 64 bits wide with the `wide-ids` feature, 32
+/// otherwise
+#[cfg(feature = "wide-ids")]
+pub type TxId = u64;
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
+    #[default]
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Convert,
+    /// Admin row placing the client's account on review hold
+    Hold,
+    /// Admin row lifting a review hold
+    Release,
+    /// Closes the client's account for good
+    Close,
+    /// Any other type, applied by the handler registered for it in
+    /// `Rules::handlers` and rejected if there is none
+    #[serde(untagged)]
+    Custom(String),
+}
+
+impl TransactionType {
+    /// Name as it appears in the `type` column
+    pub fn as_str(&self) -> &str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Convert => "convert",
+            TransactionType::Hold => "hold",
+            TransactionType::Release => "release",
+            TransactionType::Close => "close",
+            TransactionType::Custom(name) => name,
+        }
+    }
+
+    /// The built-in type called `name`, in any case
+    pub fn builtin(name: &str) -> Option<Self> {
+        const BUILTIN: [(&str, TransactionType); 9] = [
+            ("deposit", TransactionType::Deposit),
+            ("withdrawal", TransactionType::Withdrawal),
+            ("dispute", TransactionType::Dispute),
+            ("resolve", TransactionType::Resolve),
+            ("chargeback", TransactionType::Chargeback),
+            ("convert", TransactionType::Convert),
+            ("hold", TransactionType::Hold),
+            ("release", TransactionType::Release),
+            ("close", TransactionType::Close),
+        ];
+        BUILTIN
+            .into_iter()
+            .find(|(builtin, _)| builtin.eq_ignore_ascii_case(name))
+            .map(|(_, tx_type)| tx_type)
+    }
+
+    /// The name, borrowed for built-in types, for records that outlive the
+    /// row
+    pub fn name(&self) -> Cow<'static, str> {
+        match self {
+            TransactionType::Custom(name) => Cow::Owned(name.clone()),
+            TransactionType::Deposit => Cow::Borrowed("deposit"),
+            TransactionType::Withdrawal => Cow::Borrowed("withdrawal"),
+            TransactionType::Dispute => Cow::Borrowed("dispute"),
+            TransactionType::Resolve => Cow::Borrowed("resolve"),
+            TransactionType::Chargeback => Cow::Borrowed("chargeback"),
+            TransactionType::Convert => Cow::Borrowed("convert"),
+            TransactionType::Hold => Cow::Borrowed("hold"),
+            TransactionType::Release => Cow::Borrowed("release"),
+            TransactionType::Close => Cow::Borrowed("close"),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Clone)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
-    pub client: u16,
-    pub tx: u32,
+    pub client: ClientId,
+    pub tx: TxId,
     #[serde(default)]
     pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Currency a `convert` row moves funds into
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    /// Partner-chosen key identifying a submission independently of `tx`
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
-#[derive(Debug, Serialize, Default, PartialEq, Clone)]
-pub struct Account {
+impl Transaction {
+    /// A row without a timestamp, currency or idempotency key
+    pub fn new(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            ..Default::default()
+        }
+    }
+}
+
+/// A row in the original CSV schema, with 16-bit client and 32-bit
+/// transaction IDs.
+///
+/// Every legacy row converts losslessly into a [`Transaction`]; going the
+/// other way fails when either ID does not fit the narrower type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyTransaction {
+    pub tx_type: TransactionType,
     pub client: u16,
+    pub tx: u32,
+    pub amount: Option<Decimal>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+impl From<LegacyTransaction> for Transaction {
+    // The conversions are identities unless `wide-ids` is enabled
+    #[allow(clippy::useless_conversion)]
+    fn from(legacy: LegacyTransaction) -> Self {
+        Transaction {
+            tx_type: legacy.tx_type,
+            client: legacy.client.into(),
+            tx: legacy.tx.into(),
+            amount: legacy.amount,
+            timestamp: legacy.timestamp,
+            currency: None,
+            to_currency: None,
+            idempotency_key: None,
+        }
+    }
+}
+
+impl TryFrom<Transaction> for LegacyTransaction {
+    type Error = TryFromIntError;
+
+    /// Fails when either ID does not fit; the currencies are dropped since
+    /// the legacy schema has no such columns
+    #[allow(clippy::useless_conversion)]
+    fn try_from(transaction: Transaction) -> Result<Self, Self::Error> {
+        Ok(LegacyTransaction {
+            tx_type: transaction.tx_type,
+            client: transaction.client.try_into()?,
+            tx: transaction.tx.try_into()?,
+            amount: transaction.amount,
+            timestamp: transaction.timestamp,
+        })
+    }
+}
+
+/// Currency code from the optional `currency` column, e.g. `EUR` or `USDT`.
+///
+/// Stored inline as up to `MAX_LEN` upper-case ASCII letters and digits, so it
+/// is `Copy` and cheap to use as a map key.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; Currency::MAX_LEN]);
+
+impl Currency {
+    pub const MAX_LEN: usize = 8;
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(Self::MAX_LEN);
+        std::str::from_utf8(&self.0[..len]).expect("currency codes are ASCII")
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = RowErrorKind;
+
+    /// Accepts the built-in types in any case, and otherwise a custom type
+    /// name: a letter followed by up to 31 letters, digits, `_` or `-`,
+    /// lower-cased
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if raw.is_empty() {
+            return Err(RowErrorKind::MissingField);
+        }
+        if let Some(builtin) = TransactionType::builtin(raw) {
+            return Ok(builtin);
+        }
+        let is_name = raw.len() <= 32
+            && raw.starts_with(|c: char| c.is_ascii_alphabetic())
+            && raw
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+        if !is_name {
+            return Err(RowErrorKind::UnknownType);
+        }
+        Ok(TransactionType::Custom(raw.to_ascii_lowercase()))
+    }
+}
+
+/// Built-in types are matched in any case; anything else becomes a custom
+/// type as written, so deserializing never fails on the type alone
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Ok(TransactionType::builtin(&raw).unwrap_or(TransactionType::Custom(raw)))
+    }
+}
+
+impl FromStr for Currency {
+    type Err = RowErrorKind;
+
+    /// Accepts 1 to `MAX_LEN` ASCII letters or digits in any case
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if raw.is_empty()
+            || raw.len() > Self::MAX_LEN
+            || !raw.bytes().all(|b| b.is_ascii_alphanumeric())
+        {
+            return Err(RowErrorKind::InvalidCurrency);
+        }
+        let mut code = [0; Self::MAX_LEN];
+        code[..raw.len()].copy_from_slice(raw.to_ascii_uppercase().as_bytes());
+        Ok(Currency(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Currency({})", self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse()
+            .map_err(|kind| serde::de::Error::custom(format!("{kind}: {raw}")))
+    }
+}
+
+/// Funds a client holds in one currency
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Balance {
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
-    pub locked: bool,
 }
 
-#[derive(Debug, Clone)]
+/// Lifecycle state of an account, deciding which transactions it accepts
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Frozen after a balance overflow
+    Frozen,
+    /// Locked by a chargeback
+    ChargebackLocked,
+    /// Closed for good; accepts nothing further
+    Closed,
+    /// Suspended pending manual review until an admin `release` row
+    ReviewHold,
+}
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::ChargebackLocked => "chargeback_locked",
+            AccountStatus::Closed => "closed",
+            AccountStatus::ReviewHold => "review_hold",
+        }
+    }
+
+    /// Whether a transaction of this type may be applied in this status.
+    ///
+    /// Frozen and chargeback-locked accounts still settle disputes on earlier
+    /// deposits, unless `Rules::locked_allows` says otherwise; accounts on
+    /// review hold also take hold and release rows but
+    /// move no funds and cannot be closed; closed accounts take nothing.
+    pub fn allows(&self, tx_type: &TransactionType) -> bool {
+        use TransactionType::*;
+        match self {
+            AccountStatus::Active => true,
+            AccountStatus::Frozen | AccountStatus::ChargebackLocked => {
+                matches!(tx_type, Dispute | Resolve | Chargeback)
+            }
+            AccountStatus::Closed => false,
+            AccountStatus::ReviewHold => {
+                matches!(tx_type, Dispute | Resolve | Chargeback | Hold | Release)
+            }
+        }
+    }
+
+    /// Whether the status is permanent, as reported in the `locked` column
+    pub fn is_locked(&self) -> bool {
+        matches!(
+            self,
+            AccountStatus::Frozen | AccountStatus::ChargebackLocked | AccountStatus::Closed
+        )
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How many rows of each kind a client has had applied, kept with the
+/// account so a saved state carries them without replaying the file
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub struct TxCounts {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    /// Disputes opened, whether or not they were later resolved
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+/// A client's balances, one per currency they have transacted in.
+///
+/// Rows without a currency share the `None` balance, so single-currency input
+/// behaves exactly as before currencies were introduced. The status applies
+/// to the client as a whole.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+pub struct Account {
+    pub client: ClientId,
+    #[serde(with = "balance_pairs")]
+    pub balances: BTreeMap<Option<Currency>, Balance>,
+    pub status: AccountStatus,
+    #[serde(default)]
+    pub counts: TxCounts,
+}
+
+impl Account {
+    pub fn new(client: ClientId) -> Self {
+        Account {
+            client,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the account is locked for good
+    pub fn is_locked(&self) -> bool {
+        self.status.is_locked()
+    }
+
+    /// Balance in one currency; zero if the client never used it
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// Mutable balance in one currency, created at zero on first use
+    pub fn balance_mut(&mut self, currency: Option<Currency>) -> &mut Balance {
+        self.balances.entry(currency).or_default()
+    }
+
+    /// Every balance, or a single zero balance without a currency for an
+    /// account that has never moved funds
+    pub fn balances(&self) -> impl Iterator<Item = (Option<Currency>, Balance)> + '_ {
+        let empty = self.balances.is_empty().then(|| (None, Balance::default()));
+        self.balances
+            .iter()
+            .map(|(&currency, &balance)| (currency, balance))
+            .chain(empty)
+    }
+}
+
+/// Balances as `(currency, balance)` pairs, since JSON map keys must be
+/// strings and the balance without a currency has none. Bincode writes a map
+/// and a sequence of pairs alike, so snapshots read the same.
+mod balance_pairs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        balances: &BTreeMap<Option<Currency>, Balance>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(balances)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Option<Currency>, Balance>, D::Error> {
+        let pairs = Vec::<(Option<Currency>, Balance)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// What a `convert` transaction credited, kept with its record for audit
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct Conversion {
+    pub to: Currency,
+    /// Units of `to` per unit of the source currency
+    pub rate: Decimal,
+    /// Amount credited in `to`
+    pub converted: Decimal,
+}
+
+/// Funds still available when an account was closed, paid out to the client
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct Payout {
+    pub currency: Option<Currency>,
+    pub amount: Decimal,
+}
+
+/// A stored deposit, withdrawal or conversion.
+///
+/// Deposits have a positive `amount`; withdrawals and conversions record the
+/// amount debited as a negative one.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct TransactionRecord {
-    pub client: u16,
+    pub client: ClientId,
     pub amount: Decimal,
     pub disputed: bool,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub currency: Option<Currency>,
+    pub conversion: Option<Conversion>,
+    /// What the open dispute moved to held, which is less than `amount`
+    /// when the account was short of funds; `None` when not disputed
+    pub held: Option<Decimal>,
+}
+
+/// Key under which a transaction record is stored.
+///
+/// `client` is only set when transaction IDs are unique per client; with
+/// globally unique IDs it is `None` and the record's own `client` field says
+/// who owns the transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TxKey {
+    pub client: Option<ClientId>,
+    pub tx: TxId,
+}
+
+impl TxKey {
+    /// Key for a globally unique transaction ID
+    pub fn global(tx: TxId) -> Self {
+        TxKey { client: None, tx }
+    }
+
+    /// Key for a transaction ID that is only unique within one client
+    pub fn per_client(client: ClientId, tx: TxId) -> Self {
+        TxKey {
+            client: Some(client),
+            tx,
+        }
+    }
 }
 
-pub type AccountsMap = DashMap<u16, Account>;
-pub type TransactionsMap = DashMap<u32, TransactionRecord>;
+pub type AccountsMap = DashMap<ClientId, Account>;
+pub type TransactionsMap = DashMap<TxKey, TransactionRecord>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_transaction_round_trip() {
+        let legacy = LegacyTransaction {
+            tx_type: TransactionType::Deposit,
+            client: u16::MAX,
+            tx: u32::MAX,
+            amount: Some(Decimal::ONE),
+            timestamp: None,
+        };
+        let transaction = Transaction::from(legacy.clone());
+        assert_eq!(transaction.client, ClientId::from(u16::MAX));
+        assert_eq!(LegacyTransaction::try_from(transaction).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_unknown_types_deserialize_as_custom() {
+        let csv = "type,client,tx,amount\nrefund,1,2,3\nwithdrawal,1,3,1\n";
+        let types: Vec<_> = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize::<Transaction>()
+            .map(|row| row.unwrap().tx_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                TransactionType::Custom("refund".to_string()),
+                TransactionType::Withdrawal
+            ]
+        );
+    }
+
+    #[cfg(feature = "wide-ids")]
+    #[test]
+    fn test_wide_ids_do_not_fit_legacy_schema() {
+        let transaction = Transaction::new(
+            TransactionType::Deposit,
+            1,
+            TxId::from(u32::MAX) + 1,
+            Some(Decimal::ONE),
+        );
+        assert!(LegacyTransaction::try_from(transaction).is_err());
+    }
+
+    #[test]
+    fn test_currency_codes() {
+        let eur: Currency = "eur".parse().unwrap();
+        assert_eq!(eur.to_string(), "EUR");
+        assert_eq!("USDT".parse::<Currency>().unwrap().as_str(), "USDT");
+        // Codes sort like the strings they spell
+        assert!("EU".parse::<Currency>().unwrap() < eur);
+        for bad in ["", "E-R", "TOOLONGCODE", "€"] {
+            assert_eq!(bad.parse::<Currency>(), Err(RowErrorKind::InvalidCurrency));
+        }
+    }
+
+    #[test]
+    fn test_status_rules() {
+        use TransactionType::*;
+        let all = [
+            Deposit, Withdrawal, Dispute, Resolve, Chargeback, Convert, Hold, Release, Close,
+        ];
+        let allowed = |status: AccountStatus| {
+            all.iter()
+                .filter(|tx_type| status.allows(tx_type))
+                .map(TransactionType::as_str)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(allowed(AccountStatus::Active).len(), all.len());
+        assert_eq!(
+            allowed(AccountStatus::ChargebackLocked),
+            ["dispute", "resolve", "chargeback"]
+        );
+        assert_eq!(
+            allowed(AccountStatus::Frozen),
+            allowed(AccountStatus::ChargebackLocked)
+        );
+        assert_eq!(
+            allowed(AccountStatus::ReviewHold),
+            ["dispute", "resolve", "chargeback", "hold", "release"]
+        );
+        assert!(allowed(AccountStatus::Closed).is_empty());
+        assert!(!AccountStatus::ReviewHold.is_locked());
+        assert!(AccountStatus::Closed.is_locked());
+    }
+
+    #[test]
+    fn test_account_balances_are_per_currency() {
+        let eur = "EUR".parse().ok();
+        let mut account = Account::new(1);
+        assert_eq!(
+            account.balances().collect::<Vec<_>>(),
+            [(None, Balance::default())]
+        );
+
+        account.balance_mut(eur).available = Decimal::ONE;
+        assert_eq!(account.balance(eur).available, Decimal::ONE);
+        assert_eq!(account.balance(None), Balance::default());
+        assert_eq!(account.balances().count(), 1);
+    }
+}
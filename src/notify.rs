@@ -0,0 +1,249 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Currency, TransactionType, TxId};
+use crate::outcome::Applied;
+
+/// Notifications buffered for the webhook before new ones are dropped
+pub const DEFAULT_NOTIFY_QUEUE: usize = 1000;
+
+/// Attempts at delivering one notification
+#[cfg(feature = "webhook")]
+const DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry; doubled after every further failure
+#[cfg(feature = "webhook")]
+const FIRST_BACKOFF: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Why a notification was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// A chargeback locked the account
+    AccountLocked,
+    /// A withdrawal at or above the configured threshold was applied
+    LargeWithdrawal,
+}
+
+/// JSON body POSTed to the webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Decimal,
+    pub currency: Option<Currency>,
+}
+
+impl Notification {
+    /// Notification owed for an applied transaction, if any.
+    ///
+    /// Every applied chargeback locks the account; withdrawals only notify
+    /// when a threshold is set and the amount reaches it.
+    pub fn for_applied(applied: &Applied, large_withdrawal: Option<Decimal>) -> Option<Self> {
+        let kind = match applied.transaction.tx_type {
+            TransactionType::Chargeback => NotificationKind::AccountLocked,
+            TransactionType::Withdrawal
+                if large_withdrawal.is_some_and(|threshold| applied.amount >= threshold) =>
+            {
+                NotificationKind::LargeWithdrawal
+            }
+            _ => return None,
+        };
+        Some(Notification {
+            kind,
+            client: applied.transaction.client,
+            tx: applied.transaction.tx,
+            amount: applied.amount,
+            currency: applied.transaction.currency,
+        })
+    }
+}
+
+/// How many notifications the webhook task got through
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub delivered: u64,
+    /// Given up on after every attempt failed
+    pub failed: u64,
+    /// Dropped without an attempt because the queue was full
+    pub dropped: u64,
+}
+
+/// Hands notifications to a background webhook task without ever waiting on
+/// it: when the queue is full the notification is dropped and counted
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    queue: mpsc::Sender<Notification>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Notifier {
+    /// Start the task POSTing notifications to `url`, buffering at most
+    /// `capacity` of them.
+    ///
+    /// The task ends once every `Notifier` clone is dropped and the queue is
+    /// drained; its handle then yields the delivery counts. Fails if the
+    /// engine was built without the `webhook` feature.
+    pub fn spawn(
+        url: &str,
+        capacity: usize,
+    ) -> Result<(Notifier, JoinHandle<DeliveryReport>), EngineError> {
+        let (queue, rx) = mpsc::channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let task = spawn_delivery(url, rx, Arc::clone(&dropped))?;
+        Ok((Notifier { queue, dropped }, task))
+    }
+
+    /// Queue a notification, dropping it if the queue is full
+    pub fn notify(&self, notification: Notification) {
+        if let Err(e) = self.queue.try_send(notification) {
+            let notification = e.into_inner();
+            warn!(
+                client = notification.client,
+                tx = notification.tx,
+                "Webhook queue full; dropping notification"
+            );
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "webhook")]
+fn spawn_delivery(
+    url: &str,
+    mut rx: mpsc::Receiver<Notification>,
+    dropped: Arc<AtomicU64>,
+) -> Result<JoinHandle<DeliveryReport>, EngineError> {
+    let url = reqwest::Url::parse(url)
+        .map_err(|e| EngineError::Usage(format!("invalid webhook URL '{url}': {e}")))?;
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| EngineError::Usage(format!("webhook client: {e}")))?;
+    Ok(tokio::spawn(async move {
+        let mut report = DeliveryReport::default();
+        while let Some(notification) = rx.recv().await {
+            if deliver(&client, &url, &notification).await {
+                report.delivered += 1;
+            } else {
+                report.failed += 1;
+            }
+        }
+        report.dropped = dropped.load(Ordering::Relaxed);
+        report
+    }))
+}
+
+#[cfg(not(feature = "webhook"))]
+fn spawn_delivery(
+    _url: &str,
+    _rx: mpsc::Receiver<Notification>,
+    _dropped: Arc<AtomicU64>,
+) -> Result<JoinHandle<DeliveryReport>, EngineError> {
+    Err(EngineError::Usage(
+        "webhook notifications need a build with the `webhook` feature".to_string(),
+    ))
+}
+
+/// POST one notification, retrying with exponential backoff until the
+/// webhook answers with a success status or the attempts run out
+#[cfg(feature = "webhook")]
+async fn deliver(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    notification: &Notification,
+) -> bool {
+    let body = match serde_json::to_vec(notification) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Cannot encode notification: {}", e);
+            return false;
+        }
+    };
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let sent = client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match sent {
+            Ok(_) => return true,
+            Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                warn!(attempt, "Webhook delivery failed, retrying: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                warn!(
+                    client = notification.client,
+                    tx = notification.tx,
+                    "Giving up on webhook notification: {}",
+                    e
+                );
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Transaction;
+
+    fn applied(tx_type: TransactionType, amount: i64) -> Applied {
+        Applied {
+            transaction: Transaction::new(tx_type, 1, 1, Some(Decimal::from(amount))),
+            amount: Decimal::from(amount),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_notifications_for_chargebacks_and_large_withdrawals() {
+        let threshold = Some(Decimal::from(100));
+        let kind = |tx_type, amount| {
+            Notification::for_applied(&applied(tx_type, amount), threshold).map(|n| n.kind)
+        };
+        assert_eq!(
+            kind(TransactionType::Chargeback, 5),
+            Some(NotificationKind::AccountLocked)
+        );
+        assert_eq!(
+            kind(TransactionType::Withdrawal, 100),
+            Some(NotificationKind::LargeWithdrawal)
+        );
+        assert_eq!(kind(TransactionType::Withdrawal, 99), None);
+        assert_eq!(kind(TransactionType::Deposit, 500), None);
+        assert_eq!(
+            Notification::for_applied(&applied(TransactionType::Withdrawal, 500), None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_full_queue_drops_instead_of_waiting() {
+        let (queue, _rx) = mpsc::channel(1);
+        let notifier = Notifier {
+            queue,
+            dropped: Arc::default(),
+        };
+        let notification =
+            Notification::for_applied(&applied(TransactionType::Chargeback, 5), None).unwrap();
+        notifier.notify(notification.clone());
+        notifier.notify(notification);
+        assert_eq!(notifier.dropped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,164 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::audit::AuditRecord;
+use crate::config::DisputeShortfallPolicy;
+use crate::error::EngineError;
+use crate::events::Event;
+use crate::fraud::Verdict;
+use crate::invariants::InvariantViolation;
+use crate::models::{Conversion, Currency, Payout, Transaction};
+
+/// A transaction that changed account or transaction state
+#[derive(Debug, Clone)]
+pub struct Applied {
+    pub transaction: Transaction,
+    /// Amount moved between balances by this transaction
+    pub amount: Decimal,
+    /// What a `convert` credited in its target currency
+    pub conversion: Option<Conversion>,
+    /// What a `close` paid out, one entry per currency with funds left
+    pub payouts: Vec<Payout>,
+    /// What a dispute of more than the account had available did
+    pub shortfall: Option<Shortfall>,
+}
+
+impl Applied {
+    /// Currencies whose balances the transaction changed: a conversion's
+    /// source and target, each currency a close paid out, and otherwise the
+    /// transaction's own currency
+    pub fn currencies(&self) -> Vec<Option<Currency>> {
+        let mut currencies: Vec<_> = match self.payouts.as_slice() {
+            [] => vec![self.transaction.currency],
+            payouts => payouts.iter().map(|p| p.currency).collect(),
+        };
+        currencies.extend(self.conversion.map(|c| Some(c.to)));
+        currencies
+    }
+}
+
+/// A dispute of a deposit worth more than its account had available
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Shortfall {
+    /// How `Rules::dispute_shortfall` handled it
+    pub policy: DisputeShortfallPolicy,
+    /// Part of the deposit that was not available
+    pub uncovered: Decimal,
+}
+
+/// A transaction that was ignored, together with the reason why
+#[derive(Debug)]
+pub struct Rejected {
+    pub transaction: Transaction,
+    pub error: EngineError,
+}
+
+/// Outcome of handling a single transaction
+pub type TransactionOutcome = Result<Applied, Rejected>;
+
+/// An input row that could not be turned into a transaction
+#[derive(Debug)]
+pub struct MalformedRow {
+    pub line: Option<u64>,
+    pub fields: Vec<String>,
+    pub error: EngineError,
+}
+
+/// Everything the ingestion pipeline reports back to the outcome collector
+#[derive(Debug)]
+pub enum RowReport {
+    Handled(TransactionOutcome),
+    Malformed(MalformedRow),
+    Violation(InvariantViolation),
+    Fraud(Verdict),
+    Audit(AuditRecord),
+    Event(Event),
+}
+
+/// Running counts of applied, rejected and malformed rows, keyed by reason code
+/// and by transaction type
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutcomeTally {
+    pub applied: u64,
+    pub rejected: BTreeMap<&'static str, u64>,
+    pub malformed: BTreeMap<&'static str, u64>,
+    pub applied_by_type: BTreeMap<Cow<'static, str>, u64>,
+    pub rejected_by_type: BTreeMap<Cow<'static, str>, u64>,
+}
+
+impl OutcomeTally {
+    /// Count one outcome
+    pub fn record(&mut self, outcome: &TransactionOutcome) {
+        match outcome {
+            Ok(applied) => {
+                self.applied += 1;
+                *self
+                    .applied_by_type
+                    .entry(applied.transaction.tx_type.name())
+                    .or_insert(0) += 1;
+            }
+            Err(rejected) => self.record_rejected(rejected),
+        }
+    }
+
+    /// Count one rejected transaction
+    pub fn record_rejected(&mut self, rejected: &Rejected) {
+        *self
+            .rejected
+            .entry(rejected.error.reason_code())
+            .or_insert(0) += 1;
+        *self
+            .rejected_by_type
+            .entry(rejected.transaction.tx_type.name())
+            .or_insert(0) += 1;
+    }
+
+    /// Count one malformed input row
+    pub fn record_malformed(&mut self, row: &MalformedRow) {
+        *self.malformed.entry(row.error.reason_code()).or_insert(0) += 1;
+    }
+
+    /// Total number of rejected transactions across all reasons
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+
+    /// Total number of malformed rows across all reasons
+    pub fn malformed_total(&self) -> u64 {
+        self.malformed.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+
+    #[test]
+    fn test_tally_counts_by_reason() {
+        let mut tally = OutcomeTally::default();
+        tally.record(&Ok(Applied {
+            transaction: Transaction::new(TransactionType::Dispute, 1, 1, None),
+            amount: Decimal::ONE,
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }));
+        tally.record(&Err(Rejected {
+            transaction: Transaction::new(TransactionType::Dispute, 1, 2, None),
+            error: EngineError::UnknownTx { client: 1, tx: 2 },
+        }));
+        tally.record(&Err(Rejected {
+            transaction: Transaction::new(TransactionType::Dispute, 1, 3, None),
+            error: EngineError::UnknownTx { client: 1, tx: 3 },
+        }));
+
+        assert_eq!(tally.applied, 1);
+        assert_eq!(tally.rejected.get("unknown_tx"), Some(&2));
+        assert_eq!(tally.rejected_total(), 2);
+        assert_eq!(tally.applied_by_type.get("dispute"), Some(&1));
+        assert_eq!(tally.rejected_by_type.get("dispute"), Some(&2));
+    }
+}
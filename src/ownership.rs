@@ -0,0 +1,364 @@
+use rustc_hash::FxHashMap;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::config::{DuplicateTxPolicy, Rules};
+use crate::error::EngineError;
+use crate::models::{ClientId, Transaction, TransactionType, TransactionsMap, TxId, TxKey};
+use crate::retain::RetainedIds;
+
+/// Settles cross-client clashes over global transaction IDs in input order.
+///
+/// Client tasks run concurrently, so under the global policy a clash between
+/// two clients' rows would otherwise be settled by whichever task got there
+/// first. A deposit, withdrawal, conversion or custom row handed to a task
+/// claims its ID until the task has handled it. A later row of another client
+/// naming the ID waits for that, and is then checked against the client whose
+/// record is stored under the ID, so the earlier row in the file wins every
+/// time and a rejected row leaves the ID free.
+///
+/// IDs already applied are looked up where their records are kept, so only
+/// rows in flight are ever held here. In a two-pass run, IDs outside the
+/// retained set are used by a single row and are not tracked at all.
+#[derive(Debug, Default)]
+pub struct TxOwners {
+    policy: DuplicateTxPolicy,
+    retained: Option<Arc<RetainedIds>>,
+    pending: Mutex<FxHashMap<TxId, Claim>>,
+    settled: Notify,
+}
+
+/// The rows of one client in flight with a given ID
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    client: ClientId,
+    rows: usize,
+}
+
+impl TxOwners {
+    pub fn new(policy: DuplicateTxPolicy) -> Self {
+        TxOwners {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Only track the IDs a two-pass run's first pass found referred to or
+    /// reused; no other row can clash with the one row using any other ID
+    pub fn with_retained(mut self, retained: Arc<RetainedIds>) -> Self {
+        self.retained = Some(retained);
+        self
+    }
+
+    /// Check a row against the client owning its ID, as `owner_of` finds it
+    /// stored, when no row is in flight.
+    ///
+    /// Duplicates within one client are left to the client's task, which
+    /// reports them the same way under either policy.
+    pub fn check(
+        &self,
+        transaction: &Transaction,
+        owner_of: impl Fn(TxId) -> Option<ClientId>,
+    ) -> Result<(), EngineError> {
+        if self.policy == DuplicateTxPolicy::PerClient || is_admin(transaction) {
+            return Ok(());
+        }
+        let client = transaction.client;
+        let tx = transaction.tx;
+        match owner_of(tx) {
+            Some(owner) if owner != client && claims(transaction) => {
+                Err(EngineError::DuplicateTx { client, tx })
+            }
+            Some(owner) if owner != client => {
+                Err(EngineError::ClientMismatch { client, tx, owner })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a row about to be handed to its client's task, first waiting for
+    /// any other client's rows in flight with the same ID to be handled.
+    ///
+    /// A row that passes and stores a record claims its ID until `settle` is
+    /// called for it. Nothing is claimed if the future is dropped early.
+    pub async fn claim(
+        &self,
+        transaction: &Transaction,
+        owner_of: impl Fn(TxId) -> Option<ClientId>,
+    ) -> Result<(), EngineError> {
+        if !self.tracks(transaction) {
+            return Ok(());
+        }
+        let client = transaction.client;
+        loop {
+            // Registered before the claims are looked at, so a settle in
+            // between still wakes this row
+            let mut settled = pin!(self.settled.notified());
+            settled.as_mut().enable();
+            {
+                let mut pending = self.pending.lock().expect("claims lock poisoned");
+                let claim = pending.get(&transaction.tx).copied();
+                if claim.is_none_or(|claim| claim.client == client) {
+                    // The owner's own rows pass through to its task
+                    if claim.is_none() {
+                        self.check(transaction, &owner_of)?;
+                    }
+                    if claims(transaction) {
+                        pending
+                            .entry(transaction.tx)
+                            .or_insert(Claim { client, rows: 0 })
+                            .rows += 1;
+                    }
+                    return Ok(());
+                }
+            }
+            settled.await;
+        }
+    }
+
+    /// The ID a row handed out by `claim` holds, if it holds one
+    pub fn claim_of(&self, transaction: &Transaction) -> Option<TxId> {
+        (self.tracks(transaction) && claims(transaction)).then_some(transaction.tx)
+    }
+
+    /// Let go of the IDs held by rows that have been handled, or will never
+    /// be
+    pub fn settle(&self, ids: impl IntoIterator<Item = TxId>) {
+        let mut released = false;
+        {
+            let mut pending = self.pending.lock().expect("claims lock poisoned");
+            for tx in ids {
+                if let Some(claim) = pending.get_mut(&tx) {
+                    claim.rows -= 1;
+                    if claim.rows == 0 {
+                        pending.remove(&tx);
+                        released = true;
+                    }
+                }
+            }
+        }
+        if released {
+            self.settled.notify_waiters();
+        }
+    }
+
+    /// Rows in flight holding an ID
+    pub fn pending(&self) -> usize {
+        self.pending.lock().expect("claims lock poisoned").len()
+    }
+
+    fn tracks(&self, transaction: &Transaction) -> bool {
+        self.policy == DuplicateTxPolicy::Global
+            && !is_admin(transaction)
+            && self
+                .retained
+                .as_ref()
+                .is_none_or(|ids| ids.contains(transaction.tx))
+    }
+}
+
+/// The client whose record is stored under a global ID, in one of `maps`,
+/// spilled to disk or as a tombstone.
+///
+/// A spilled record is faulted in before it leaves the spill index, and a
+/// compacted one is tombstoned before it leaves its map, so looking in this
+/// order never misses a record on the move.
+pub fn stored_owner<'a>(
+    tx: TxId,
+    maps: impl IntoIterator<Item = &'a TransactionsMap>,
+    rules: &Rules,
+) -> Option<ClientId> {
+    let key = TxKey::global(tx);
+    rules
+        .spill
+        .as_ref()
+        .and_then(|spill| spill.owner(&key))
+        .or_else(|| {
+            maps.into_iter()
+                .find_map(|map| map.get(&key).map(|record| record.client))
+        })
+        .or_else(|| rules.tombstones.as_ref()?.owner(&key))
+}
+
+/// Rows that store a record under their ID
+fn claims(transaction: &Transaction) -> bool {
+    matches!(
+        transaction.tx_type,
+        TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Convert
+            | TransactionType::Custom(_)
+    )
+}
+
+/// Admin rows name a client directly and never refer to a stored transaction
+fn is_admin(transaction: &Transaction) -> bool {
+    matches!(
+        transaction.tx_type,
+        TransactionType::Hold | TransactionType::Release | TransactionType::Close
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionRecord;
+    use futures::FutureExt;
+    use rust_decimal::Decimal;
+
+    fn stored(transactions: &TransactionsMap, client: ClientId, tx: TxId) {
+        transactions.insert(
+            TxKey::global(tx),
+            TransactionRecord {
+                client,
+                amount: Decimal::ONE,
+                disputed: false,
+                timestamp: None,
+                currency: None,
+                conversion: None,
+                held: None,
+            },
+        );
+    }
+
+    #[test]
+    fn test_global_policy_stored_owner_keeps_the_id() {
+        let owners = TxOwners::new(DuplicateTxPolicy::Global);
+        let transactions = TransactionsMap::new();
+        stored(&transactions, 1, 7);
+        let owner_of = |tx| stored_owner(tx, [&transactions], &Rules::default());
+
+        // The owner's own rows pass through to its task
+        owners
+            .check(
+                &Transaction::new(TransactionType::Deposit, 1, 7, None),
+                owner_of,
+            )
+            .unwrap();
+        owners
+            .check(
+                &Transaction::new(TransactionType::Dispute, 1, 7, None),
+                owner_of,
+            )
+            .unwrap();
+
+        assert!(matches!(
+            owners.check(
+                &Transaction::new(TransactionType::Withdrawal, 2, 7, None),
+                owner_of
+            ),
+            Err(EngineError::DuplicateTx { client: 2, tx: 7 })
+        ));
+        assert!(matches!(
+            owners.check(
+                &Transaction::new(TransactionType::Dispute, 2, 7, None),
+                owner_of
+            ),
+            Err(EngineError::ClientMismatch {
+                client: 2,
+                tx: 7,
+                owner: 1
+            })
+        ));
+        // Disputes of unknown IDs are left to the client's task
+        owners
+            .check(
+                &Transaction::new(TransactionType::Dispute, 2, 8, None),
+                owner_of,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_per_client_policy_allows_shared_ids() {
+        let owners = TxOwners::new(DuplicateTxPolicy::PerClient);
+        let owner_of = |_| Some(1);
+        owners
+            .check(
+                &Transaction::new(TransactionType::Deposit, 2, 7, None),
+                owner_of,
+            )
+            .unwrap();
+        owners
+            .check(
+                &Transaction::new(TransactionType::Dispute, 2, 7, None),
+                owner_of,
+            )
+            .unwrap();
+        assert_eq!(
+            owners.claim_of(&Transaction::new(TransactionType::Deposit, 2, 7, None)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claim_waits_for_another_clients_row_in_flight() {
+        let owners = TxOwners::new(DuplicateTxPolicy::Global);
+        let transactions = TransactionsMap::new();
+        let owner_of = |tx| stored_owner(tx, [&transactions], &Rules::default());
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 7, None);
+        owners.claim(&deposit, owner_of).await.unwrap();
+        assert_eq!(owners.pending(), 1);
+
+        let dispute = Transaction::new(TransactionType::Dispute, 2, 7, None);
+        let mut waiting = pin!(owners.claim(&dispute, owner_of));
+        assert!(waiting.as_mut().now_or_never().is_none());
+
+        stored(&transactions, 1, 7);
+        owners.settle(owners.claim_of(&deposit));
+        assert!(matches!(
+            waiting.await,
+            Err(EngineError::ClientMismatch { owner: 1, .. })
+        ));
+        assert_eq!(owners.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_row_leaves_the_id_free() {
+        let owners = TxOwners::new(DuplicateTxPolicy::Global);
+        let owner_of = |_| None;
+        let withdrawal = Transaction::new(TransactionType::Withdrawal, 1, 7, None);
+        owners.claim(&withdrawal, owner_of).await.unwrap();
+        // Handled without storing a record, as a rejected row is
+        owners.settle(owners.claim_of(&withdrawal));
+
+        owners
+            .claim(
+                &Transaction::new(TransactionType::Deposit, 2, 7, None),
+                owner_of,
+            )
+            .await
+            .unwrap();
+        assert_eq!(owners.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_two_pass_tracks_only_retained_ids() {
+        let mut scan = crate::retain::RetainScan::default();
+        for transaction in [
+            Transaction::new(TransactionType::Deposit, 1, 7, None),
+            Transaction::new(TransactionType::Deposit, 1, 8, None),
+            Transaction::new(TransactionType::Dispute, 1, 8, None),
+        ] {
+            scan.observe(&transaction);
+        }
+        let owners =
+            TxOwners::new(DuplicateTxPolicy::Global).with_retained(Arc::new(scan.finish()));
+        let owner_of = |_| None;
+
+        let once = Transaction::new(TransactionType::Deposit, 1, 7, None);
+        owners.claim(&once, owner_of).await.unwrap();
+        assert_eq!(owners.claim_of(&once), None);
+        assert_eq!(owners.pending(), 0);
+
+        owners
+            .claim(
+                &Transaction::new(TransactionType::Deposit, 1, 8, None),
+                owner_of,
+            )
+            .await
+            .unwrap();
+        assert_eq!(owners.pending(), 1);
+    }
+}
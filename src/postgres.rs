@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::EngineError;
+use crate::models::{AccountStatus, AccountsMap, Balance, ClientId, Currency};
+use crate::shard::ShardState;
+
+/// Seconds between balance syncs unless configured otherwise
+pub const DEFAULT_SYNC_INTERVAL_SECS: usize = 5;
+
+/// Balances upserted by one statement
+pub const UPSERT_BATCH_ROWS: usize = 500;
+
+/// Table the balances are upserted into, created if missing
+pub const BALANCES_TABLE: &str = "account_balances";
+
+/// Attempts at upserting one batch before leaving it to the next sync
+#[cfg(feature = "postgres")]
+const UPSERT_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry; doubled after every further failure
+#[cfg(feature = "postgres")]
+const FIRST_BACKOFF: Duration = Duration::from_millis(200);
+
+/// One client's balance in one currency as it is written to the table
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceRow {
+    pub client: ClientId,
+    pub currency: Option<Currency>,
+    pub balance: Balance,
+    pub status: AccountStatus,
+}
+
+/// Remembers the balances last written, so each sync only sends the ones
+/// that changed since
+#[derive(Debug, Default)]
+pub struct BalanceTracker {
+    written: HashMap<(ClientId, Option<Currency>), BalanceRow>,
+}
+
+impl BalanceTracker {
+    /// Every balance in `maps` that differs from what was last written, in
+    /// client order
+    pub fn changed(&self, maps: &[&AccountsMap]) -> Vec<BalanceRow> {
+        let mut rows: Vec<_> = maps
+            .iter()
+            .flat_map(|accounts| accounts.iter())
+            .flat_map(|account| {
+                account
+                    .balances()
+                    .map(|(currency, balance)| BalanceRow {
+                        client: account.client,
+                        currency,
+                        balance,
+                        status: account.status,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|row| self.written.get(&(row.client, row.currency)) != Some(row))
+            .collect();
+        rows.sort_by_key(|row| (row.client, row.currency));
+        rows
+    }
+
+    /// Record rows once the database has them
+    pub fn mark_written(&mut self, rows: &[BalanceRow]) {
+        for row in rows {
+            self.written.insert((row.client, row.currency), row.clone());
+        }
+    }
+}
+
+/// What the sync task did over the run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyncReport {
+    pub syncs: u64,
+    /// Balance rows upserted, counting a balance again each time it changed
+    pub rows: u64,
+    /// Batches given up on after every attempt failed; their balances are
+    /// tried again at the next sync
+    pub failed_batches: u64,
+    /// Changed balances the final sync could not write
+    pub unsynced: u64,
+}
+
+/// Connect to Postgres, create the balances table if it is missing and
+/// start upserting every changed balance each `interval`, reading the
+/// accounts of `accounts` and of every shard in `shards`.
+///
+/// Cancelling `stop` makes the task run one last sync and finish; its
+/// handle then yields what it did. Fails if the database cannot be reached
+/// or the engine was built without the `postgres` feature.
+#[cfg(feature = "postgres")]
+pub async fn spawn_sync(
+    url: &str,
+    interval: Duration,
+    accounts: Arc<AccountsMap>,
+    shards: Vec<Arc<ShardState>>,
+    stop: CancellationToken,
+) -> Result<JoinHandle<SyncReport>, EngineError> {
+    let postgres_error = |e: sqlx::Error| EngineError::Postgres(e.to_string());
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_secs(10))
+        .connect(url)
+        .await
+        .map_err(postgres_error)?;
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {BALANCES_TABLE} (
+            client BIGINT NOT NULL,
+            currency TEXT NOT NULL,
+            available NUMERIC NOT NULL,
+            held NUMERIC NOT NULL,
+            total NUMERIC NOT NULL,
+            locked BOOLEAN NOT NULL,
+            status TEXT NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (client, currency)
+        )"
+    ))
+    .execute(&pool)
+    .await
+    .map_err(postgres_error)?;
+
+    Ok(tokio::spawn(async move {
+        let maps: Vec<&AccountsMap> = std::iter::once(&*accounts)
+            .chain(shards.iter().map(|shard| &shard.accounts))
+            .collect();
+        let mut tracker = BalanceTracker::default();
+        let mut report = SyncReport::default();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let last = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = stop.cancelled() => true,
+            };
+            report.syncs += 1;
+            for batch in tracker.changed(&maps).chunks(UPSERT_BATCH_ROWS) {
+                if upsert(&pool, batch).await {
+                    tracker.mark_written(batch);
+                    report.rows += batch.len() as u64;
+                } else {
+                    report.failed_batches += 1;
+                }
+            }
+            if last {
+                report.unsynced = tracker.changed(&maps).len() as u64;
+                break;
+            }
+        }
+        pool.close().await;
+        report
+    }))
+}
+
+#[cfg(not(feature = "postgres"))]
+pub async fn spawn_sync(
+    _url: &str,
+    _interval: Duration,
+    _accounts: Arc<AccountsMap>,
+    _shards: Vec<Arc<ShardState>>,
+    _stop: CancellationToken,
+) -> Result<JoinHandle<SyncReport>, EngineError> {
+    Err(EngineError::Usage(
+        "the Postgres balance sync needs a build with the `postgres` feature".to_string(),
+    ))
+}
+
+/// Upsert one batch in a single statement, retrying with exponential
+/// backoff until it succeeds or the attempts run out
+#[cfg(feature = "postgres")]
+async fn upsert(pool: &sqlx::PgPool, batch: &[BalanceRow]) -> bool {
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 1..=UPSERT_ATTEMPTS {
+        let mut query = sqlx::QueryBuilder::<sqlx::Postgres>::new(format!(
+            "INSERT INTO {BALANCES_TABLE} (client, currency, available, held, total, locked, status) "
+        ));
+        query.push_values(batch, |mut values, row| {
+            values
+                .push_bind(i64::from(row.client))
+                .push_bind(
+                    row.currency
+                        .as_ref()
+                        .map_or("", Currency::as_str)
+                        .to_string(),
+                )
+                .push_bind(row.balance.available)
+                .push_bind(row.balance.held)
+                .push_bind(row.balance.total)
+                .push_bind(row.status.is_locked())
+                .push_bind(row.status.as_str());
+        });
+        query.push(
+            " ON CONFLICT (client, currency) DO UPDATE SET
+                available = EXCLUDED.available,
+                held = EXCLUDED.held,
+                total = EXCLUDED.total,
+                locked = EXCLUDED.locked,
+                status = EXCLUDED.status,
+                updated_at = now()",
+        );
+        match query.build().execute(pool).await {
+            Ok(_) => return true,
+            Err(e) if attempt < UPSERT_ATTEMPTS => {
+                tracing::warn!(attempt, "Balance upsert failed, retrying: {}", e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    rows = batch.len(),
+                    "Giving up on balance upsert until the next sync: {}",
+                    e
+                );
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Account;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_tracker_sends_only_changed_balances() {
+        let accounts = AccountsMap::new();
+        for client in [2, 1] {
+            let mut account = Account::new(client);
+            account.balance_mut(None).available = dec!(5);
+            accounts.insert(client, account);
+        }
+        let mut tracker = BalanceTracker::default();
+        let rows = tracker.changed(&[&accounts]);
+        assert_eq!(
+            rows.iter().map(|row| row.client).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        tracker.mark_written(&rows);
+        assert!(tracker.changed(&[&accounts]).is_empty());
+
+        accounts.get_mut(&2).unwrap().status = AccountStatus::Frozen;
+        accounts.insert(3, Account::new(3));
+        let rows = tracker.changed(&[&accounts]);
+        assert_eq!(
+            rows.iter().map(|row| row.client).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        // Rows that were never written come back at the next sync
+        assert_eq!(tracker.changed(&[&accounts]), rows);
+    }
+
+    #[test]
+    fn test_tracker_reads_every_shard() {
+        let shards: Vec<AccountsMap> = (0..2).map(|_| AccountsMap::new()).collect();
+        for client in [4, 1, 3] {
+            shards[client as usize % 2].insert(client, Account::new(client));
+        }
+        let rows = BalanceTracker::default().changed(&[&shards[0], &shards[1]]);
+        assert_eq!(
+            rows.iter().map(|row| row.client).collect::<Vec<_>>(),
+            vec![1, 3, 4]
+        );
+    }
+}
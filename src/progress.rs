@@ -0,0 +1,164 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How often progress is reported by default
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A point-in-time view of how far ingestion has got
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgressUpdate {
+    pub rows: u64,
+    pub bytes_read: u64,
+    pub total_bytes: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl ProgressUpdate {
+    /// Rows handled per second so far
+    pub fn rows_per_second(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.rows as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Fraction of the input consumed, when its size is known
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_bytes
+            .filter(|total| *total > 0)
+            .map(|total| (self.bytes_read as f64 / total as f64).min(1.0))
+    }
+
+    /// Estimated time left, extrapolated from the byte rate so far
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction().filter(|f| *f > 0.0)?;
+        Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+    }
+}
+
+impl fmt::Display for ProgressUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows, {:.0} rows/s, {:.1} MB read",
+            self.rows,
+            self.rows_per_second(),
+            megabytes(self.bytes_read)
+        )?;
+        if let (Some(total), Some(fraction)) = (self.total_bytes, self.fraction()) {
+            write!(
+                f,
+                " of {:.1} MB ({:.1}%)",
+                megabytes(total),
+                fraction * 100.0
+            )?;
+        }
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {}s", eta.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / 1_000_000.0
+}
+
+/// Counts ingested rows and produces an update at most once per interval
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    total_bytes: Option<u64>,
+    interval: Duration,
+    started: Instant,
+    last_report: Instant,
+    rows: u64,
+    bytes_read: u64,
+}
+
+impl ProgressTracker {
+    /// Start tracking an input of `total_bytes`, if known
+    pub fn new(total_bytes: Option<u64>, interval: Duration) -> Self {
+        let now = Instant::now();
+        ProgressTracker {
+            total_bytes,
+            interval,
+            started: now,
+            last_report: now,
+            rows: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// Count one row ending at byte offset `byte`, returning an update when
+    /// the reporting interval has elapsed
+    pub fn record(&mut self, byte: Option<u64>) -> Option<ProgressUpdate> {
+        self.record_at(byte, Instant::now())
+    }
+
+    fn record_at(&mut self, byte: Option<u64>, now: Instant) -> Option<ProgressUpdate> {
+        self.rows += 1;
+        if let Some(byte) = byte {
+            self.bytes_read = self.bytes_read.max(byte);
+        }
+        if now.duration_since(self.last_report) < self.interval {
+            return None;
+        }
+        self.last_report = now;
+        Some(self.snapshot_at(now))
+    }
+
+    /// Progress as of now, regardless of the interval
+    pub fn finish(&self) -> ProgressUpdate {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn snapshot_at(&self, now: Instant) -> ProgressUpdate {
+        ProgressUpdate {
+            rows: self.rows,
+            bytes_read: self.bytes_read,
+            total_bytes: self.total_bytes,
+            elapsed: now.duration_since(self.started),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_once_per_interval() {
+        let mut tracker = ProgressTracker::new(Some(1_000), Duration::from_secs(5));
+        let start = tracker.started;
+
+        assert!(
+            tracker
+                .record_at(Some(100), start + Duration::from_secs(1))
+                .is_none()
+        );
+        let update = tracker
+            .record_at(Some(250), start + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(update.rows, 2);
+        assert_eq!(update.fraction(), Some(0.25));
+        assert_eq!(update.eta(), Some(Duration::from_secs(15)));
+        assert_eq!(update.rows_per_second(), 0.4);
+        assert!(
+            tracker
+                .record_at(Some(300), start + Duration::from_secs(6))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_unknown_size_has_no_eta() {
+        let update = ProgressUpdate {
+            rows: 10,
+            bytes_read: 2_000_000,
+            total_bytes: None,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(update.eta(), None);
+        assert_eq!(update.to_string(), "10 rows, 5 rows/s, 2.0 MB read");
+    }
+}
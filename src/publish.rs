@@ -0,0 +1,297 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::events::Event;
+
+/// Attempts at publishing one event before the run is aborted
+#[cfg(feature = "nats")]
+const PUBLISH_ATTEMPTS: u32 = 3;
+
+/// How long Kafka may keep retrying one event before giving up
+#[cfg(feature = "kafka")]
+const KAFKA_DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Broker the event stream is published to, given as
+/// `kafka://BROKERS/TOPIC` or `nats://SERVER/SUBJECT`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PublishTarget {
+    /// Comma-separated bootstrap brokers and the topic to produce to
+    Kafka { brokers: String, topic: String },
+    /// Server address and the JetStream subject to publish to
+    Nats { server: String, subject: String },
+}
+
+impl FromStr for PublishTarget {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid publish target '{s}' (expected kafka://BROKERS/TOPIC or nats://SERVER/SUBJECT)"
+            ))
+        };
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (host, name) = rest.split_once('/').ok_or_else(invalid)?;
+        if host.is_empty() || name.is_empty() {
+            return Err(invalid());
+        }
+        let (host, name) = (host.to_string(), name.to_string());
+        match scheme {
+            "kafka" => Ok(PublishTarget::Kafka {
+                brokers: host,
+                topic: name,
+            }),
+            "nats" => Ok(PublishTarget::Nats {
+                server: host,
+                subject: name,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for PublishTarget {
+    type Error = EngineError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl PublishTarget {
+    /// URL scheme, which is also the name of the cargo feature adding support
+    /// for the broker
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            PublishTarget::Kafka { .. } => "kafka",
+            PublishTarget::Nats { .. } => "nats",
+        }
+    }
+}
+
+impl fmt::Display for PublishTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishTarget::Kafka { brokers, topic } => write!(f, "kafka://{brokers}/{topic}"),
+            PublishTarget::Nats { server, subject } => write!(f, "nats://{server}/{subject}"),
+        }
+    }
+}
+
+/// What each published message is keyed by.
+///
+/// Kafka uses the key to pick a partition, so keying by client keeps each
+/// client's events in order; on NATS the key is appended to the subject as a
+/// final token.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishKey {
+    #[default]
+    Client,
+    Tx,
+    None,
+}
+
+impl PublishKey {
+    /// Key for one event, if messages are keyed at all
+    pub fn for_event(&self, event: &Event) -> Option<String> {
+        match self {
+            PublishKey::Client => Some(event.client.to_string()),
+            PublishKey::Tx => Some(event.tx.to_string()),
+            PublishKey::None => None,
+        }
+    }
+}
+
+impl FromStr for PublishKey {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(PublishKey::Client),
+            "tx" => Ok(PublishKey::Tx),
+            "none" => Ok(PublishKey::None),
+            other => Err(EngineError::Usage(format!(
+                "invalid publish key '{other}' (expected client, tx or none)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PublishKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PublishKey::Client => "client",
+            PublishKey::Tx => "tx",
+            PublishKey::None => "none",
+        })
+    }
+}
+
+/// Connection to whichever broker was configured
+enum Backend {
+    #[cfg(feature = "kafka")]
+    Kafka {
+        producer: rdkafka::producer::FutureProducer,
+        topic: String,
+    },
+    #[cfg(feature = "nats")]
+    Nats {
+        jetstream: async_nats::jetstream::Context,
+        subject: String,
+    },
+}
+
+/// Publishes events to Kafka or NATS with at-least-once delivery: `publish`
+/// only returns once the broker has acknowledged the event
+pub struct EventPublisher {
+    backend: Backend,
+    key: PublishKey,
+}
+
+impl EventPublisher {
+    /// Connect to the target's broker.
+    ///
+    /// Fails if the engine was built without the broker's feature (`kafka`
+    /// or `nats`).
+    pub async fn connect(target: &PublishTarget, key: PublishKey) -> Result<Self, EngineError> {
+        let backend = open_backend(target).await?;
+        Ok(EventPublisher { backend, key })
+    }
+
+    /// Publish one event and wait for the broker to acknowledge it
+    pub async fn publish(&mut self, event: &Event) -> Result<(), EngineError> {
+        let key = self.key.for_event(event);
+        let payload = serde_json::to_vec(event).map_err(|e| EngineError::Publish(e.to_string()))?;
+        match &self.backend {
+            #[cfg(feature = "kafka")]
+            Backend::Kafka { producer, topic } => {
+                let mut record = rdkafka::producer::FutureRecord::to(topic).payload(&payload);
+                if let Some(key) = &key {
+                    record = record.key(key);
+                }
+                producer
+                    .send(record, KAFKA_DELIVERY_TIMEOUT)
+                    .await
+                    .map(drop)
+                    .map_err(|(e, _)| EngineError::Publish(format!("kafka topic {topic}: {e}")))
+            }
+            #[cfg(feature = "nats")]
+            Backend::Nats { jetstream, subject } => {
+                let subject = match &key {
+                    Some(key) => format!("{subject}.{key}"),
+                    None => subject.clone(),
+                };
+                let mut attempt = 1;
+                loop {
+                    let acked = match jetstream
+                        .publish(subject.clone(), payload.clone().into())
+                        .await
+                    {
+                        Ok(ack) => ack.await.map(drop).map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match acked {
+                        Ok(()) => return Ok(()),
+                        Err(e) if attempt >= PUBLISH_ATTEMPTS => {
+                            return Err(EngineError::Publish(format!(
+                                "nats subject {subject}: {e}"
+                            )));
+                        }
+                        Err(e) => {
+                            tracing::warn!(attempt, "Retrying publish to {}: {}", subject, e);
+                            attempt += 1;
+                        }
+                    }
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (key, payload);
+                unreachable!("no broker support compiled in")
+            }
+        }
+    }
+}
+
+/// Connect to the broker named by `target`
+async fn open_backend(target: &PublishTarget) -> Result<Backend, EngineError> {
+    match target {
+        #[cfg(feature = "kafka")]
+        PublishTarget::Kafka { brokers, topic } => {
+            let producer = rdkafka::ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("acks", "all")
+                .set("enable.idempotence", "true")
+                .create()
+                .map_err(|e| EngineError::Publish(format!("{target}: {e}")))?;
+            Ok(Backend::Kafka {
+                producer,
+                topic: topic.clone(),
+            })
+        }
+        #[cfg(feature = "nats")]
+        PublishTarget::Nats { server, subject } => {
+            let client = async_nats::connect(server.as_str())
+                .await
+                .map_err(|e| EngineError::Publish(format!("{target}: {e}")))?;
+            Ok(Backend::Nats {
+                jetstream: async_nats::jetstream::new(client),
+                subject: subject.clone(),
+            })
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(EngineError::Publish(format!(
+            "{target}: built without the `{}` feature",
+            target.scheme()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+
+    #[test]
+    fn test_parse_publish_target() {
+        assert_eq!(
+            "kafka://b1:9092,b2:9092/engine.events"
+                .parse::<PublishTarget>()
+                .unwrap(),
+            PublishTarget::Kafka {
+                brokers: "b1:9092,b2:9092".to_string(),
+                topic: "engine.events".to_string(),
+            }
+        );
+        let nats: PublishTarget = "nats://localhost:4222/events".parse().unwrap();
+        assert_eq!(nats.to_string(), "nats://localhost:4222/events");
+        for bad in [
+            "localhost:4222/events",
+            "nats://localhost",
+            "amqp://host/q",
+            "kafka:///t",
+        ] {
+            assert!(bad.parse::<PublishTarget>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_publish_key_for_event() {
+        let event = Event {
+            event: EventKind::DepositApplied,
+            client: 3,
+            tx: 9,
+            amount: None,
+            balances: Vec::new(),
+            status: None,
+            shortfall: None,
+        };
+        assert_eq!(PublishKey::Client.for_event(&event).as_deref(), Some("3"));
+        assert_eq!(PublishKey::Tx.for_event(&event).as_deref(), Some("9"));
+        assert_eq!(PublishKey::None.for_event(&event), None);
+    }
+}
@@ -0,0 +1,268 @@
+//! Python bindings, built into the `transaction_engine` extension module by
+//! `maturin` with the `python` feature.
+//!
+//! Transactions submitted from Python go through exactly the settlement code
+//! a command-line run uses; see [`Engine`](crate::engine::Engine).
+
+use chrono::{DateTime, Utc};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::config::{ClosePolicy, ClosedAccounts, DuplicateTxPolicy, Rules};
+use crate::engine;
+use crate::error;
+use crate::models::{self, ClientId, Currency, TransactionType, TxId};
+use crate::snapshot::Snapshot;
+
+create_exception!(transaction_engine, EngineError, PyException);
+
+fn engine_error(e: error::EngineError) -> PyErr {
+    EngineError::new_err(e.to_string())
+}
+
+fn currency(raw: Option<&str>) -> PyResult<Option<Currency>> {
+    raw.map(|raw| {
+        raw.parse()
+            .map_err(|kind| PyValueError::new_err(format!("{kind}: {raw}")))
+    })
+    .transpose()
+}
+
+/// One input row
+#[pyclass(name = "Transaction", module = "transaction_engine", frozen)]
+#[derive(Clone)]
+pub struct PyTransaction(models::Transaction);
+
+#[pymethods]
+impl PyTransaction {
+    #[new]
+    #[pyo3(signature = (r#type, client, tx, amount=None, timestamp=None, currency=None, to_currency=None, idempotency_key=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        r#type: &str,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<Decimal>,
+        timestamp: Option<DateTime<Utc>>,
+        currency: Option<&str>,
+        to_currency: Option<&str>,
+        idempotency_key: Option<String>,
+    ) -> PyResult<Self> {
+        let tx_type: TransactionType = r#type
+            .parse()
+            .map_err(|kind| PyValueError::new_err(format!("{kind}: {}", r#type)))?;
+        Ok(PyTransaction(models::Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp,
+            currency: self::currency(currency)?,
+            to_currency: self::currency(to_currency)?,
+            idempotency_key,
+        }))
+    }
+
+    #[getter]
+    fn r#type(&self) -> &str {
+        self.0.tx_type.as_str()
+    }
+
+    #[getter]
+    fn client(&self) -> ClientId {
+        self.0.client
+    }
+
+    #[getter]
+    fn tx(&self) -> TxId {
+        self.0.tx
+    }
+
+    #[getter]
+    fn amount(&self) -> Option<Decimal> {
+        self.0.amount
+    }
+
+    #[getter]
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.0.timestamp
+    }
+
+    #[getter]
+    fn currency(&self) -> Option<&str> {
+        self.0.currency.as_ref().map(Currency::as_str)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Transaction(type={:?}, client={}, tx={}, amount={})",
+            self.0.tx_type.as_str(),
+            self.0.client,
+            self.0.tx,
+            self.0
+                .amount
+                .map_or("None".to_string(), |a| format!("'{a}'"))
+        )
+    }
+}
+
+/// A copy of one client's account at the time it was looked up
+#[pyclass(name = "Account", module = "transaction_engine", frozen)]
+pub struct PyAccount(models::Account);
+
+#[pymethods]
+impl PyAccount {
+    #[getter]
+    fn client(&self) -> ClientId {
+        self.0.client
+    }
+
+    /// Available funds without a currency
+    #[getter]
+    fn available(&self) -> Decimal {
+        self.0.balance(None).available
+    }
+
+    /// Held funds without a currency
+    #[getter]
+    fn held(&self) -> Decimal {
+        self.0.balance(None).held
+    }
+
+    /// Total funds without a currency
+    #[getter]
+    fn total(&self) -> Decimal {
+        self.0.balance(None).total
+    }
+
+    #[getter]
+    fn locked(&self) -> bool {
+        self.0.is_locked()
+    }
+
+    #[getter]
+    fn status(&self) -> &'static str {
+        self.0.status.as_str()
+    }
+
+    /// `(available, held, total)` in each currency the client has used,
+    /// keyed by currency code with `None` for rows without one
+    fn balances(&self) -> BTreeMap<Option<String>, (Decimal, Decimal, Decimal)> {
+        self.0
+            .balances()
+            .map(|(currency, b)| {
+                (
+                    currency.map(|c| c.as_str().to_string()),
+                    (b.available, b.held, b.total),
+                )
+            })
+            .collect()
+    }
+
+    fn __repr__(&self) -> String {
+        let balance = self.0.balance(None);
+        format!(
+            "Account(client={}, available='{}', held='{}', total='{}', status={:?})",
+            self.0.client,
+            balance.available,
+            balance.held,
+            balance.total,
+            self.0.status.as_str()
+        )
+    }
+}
+
+/// Accounts and stored transactions, settled by the production rules
+#[pyclass(name = "Engine", module = "transaction_engine", unsendable)]
+pub struct PyEngine(engine::Engine);
+
+#[pymethods]
+impl PyEngine {
+    #[new]
+    #[pyo3(signature = (duplicate_tx="global", close_policy="require-empty"))]
+    fn new(duplicate_tx: &str, close_policy: &str) -> PyResult<Self> {
+        Ok(PyEngine(engine::Engine::new(rules(
+            duplicate_tx,
+            close_policy,
+        )?)))
+    }
+
+    /// Carry on from a state snapshot saved by `save` or by a run's
+    /// `--save-state`
+    #[staticmethod]
+    #[pyo3(signature = (path, duplicate_tx="global", close_policy="require-empty"))]
+    fn load(path: PathBuf, duplicate_tx: &str, close_policy: &str) -> PyResult<Self> {
+        let snapshot = Snapshot::load(&path).map_err(engine_error)?;
+        Ok(PyEngine(engine::Engine::restore(
+            snapshot,
+            rules(duplicate_tx, close_policy)?,
+        )))
+    }
+
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        self.0.snapshot().save(&path).map_err(engine_error)
+    }
+
+    /// Apply one transaction, returning `None` if it was applied and the
+    /// reason code if it was rejected
+    fn submit(&mut self, transaction: &PyTransaction) -> Option<&'static str> {
+        self.0
+            .submit(transaction.0.clone())
+            .err()
+            .map(|rejected| rejected.error.reason_code())
+    }
+
+    /// Apply every row of a transactions CSV file's contents, returning the
+    /// number applied and the rejected and malformed rows by reason code
+    fn process_csv<'py>(&mut self, py: Python<'py>, data: &str) -> PyResult<Bound<'py, PyDict>> {
+        let tally = self.0.process_csv(data.as_bytes()).map_err(engine_error)?;
+        let counts = PyDict::new(py);
+        counts.set_item("applied", tally.applied)?;
+        counts.set_item("rejected", tally.rejected)?;
+        counts.set_item("malformed", tally.malformed)?;
+        Ok(counts)
+    }
+
+    fn account(&self, client: ClientId) -> Option<PyAccount> {
+        self.0.account(client).map(PyAccount)
+    }
+
+    /// Every account, in client ID order
+    fn accounts(&self) -> Vec<PyAccount> {
+        self.0.accounts().into_iter().map(PyAccount).collect()
+    }
+
+    /// The accounts CSV a run would print
+    fn accounts_csv(&self) -> PyResult<String> {
+        let mut output = Vec::new();
+        self.0
+            .write_accounts(ClosedAccounts::Include, &mut output)
+            .map_err(engine_error)?;
+        String::from_utf8(output).map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+}
+
+fn rules(duplicate_tx: &str, close_policy: &str) -> PyResult<Rules> {
+    let parse_error = |e: error::EngineError| PyValueError::new_err(e.to_string());
+    Ok(Rules {
+        duplicate_tx: duplicate_tx
+            .parse::<DuplicateTxPolicy>()
+            .map_err(parse_error)?,
+        close_policy: close_policy.parse::<ClosePolicy>().map_err(parse_error)?,
+        ..Default::default()
+    })
+}
+
+#[pymodule]
+fn transaction_engine(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEngine>()?;
+    module.add_class::<PyTransaction>()?;
+    module.add_class::<PyAccount>()?;
+    module.add("EngineError", module.py().get_type::<EngineError>())?;
+    Ok(())
+}
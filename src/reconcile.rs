@@ -0,0 +1,302 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::config::ClosedAccounts;
+use crate::error::EngineError;
+use crate::models::{AccountStatus, AccountsMap, Balance, ClientId, Currency};
+
+/// One row of an accounts CSV as the engine writes it
+#[derive(Debug, Deserialize)]
+struct SheetRow {
+    client: ClientId,
+    #[serde(default)]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    /// Not compared when the file has no `locked` column
+    #[serde(default)]
+    locked: Option<bool>,
+}
+
+/// The same row as written back out
+#[derive(Debug, Serialize)]
+struct SheetOutputRow {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: Option<bool>,
+}
+
+/// A client's balance in one currency, as listed in an accounts CSV
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SheetEntry {
+    pub balance: Balance,
+    /// `None` when the file has no `locked` column
+    pub locked: Option<bool>,
+}
+
+/// Every balance in an accounts CSV, or in the engine's final state, keyed by
+/// client and currency
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BalanceSheet {
+    entries: BTreeMap<(ClientId, Option<Currency>), SheetEntry>,
+}
+
+/// How a computed balance differs from the expected one
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscrepancyKind {
+    /// Expected, but the run has no such account or currency
+    Missing,
+    /// Produced by the run, but not in the expected file
+    Unexpected,
+    /// One field has a different value
+    Mismatch {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// One difference between the computed and the expected balances
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub client: ClientId,
+    pub currency: Option<Currency>,
+    pub kind: DiscrepancyKind,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Account {}", self.client)?;
+        if let Some(currency) = self.currency {
+            write!(f, " ({currency})")?;
+        }
+        match &self.kind {
+            DiscrepancyKind::Missing => write!(f, ": expected but not produced"),
+            DiscrepancyKind::Unexpected => write!(f, ": produced but not expected"),
+            DiscrepancyKind::Mismatch {
+                field,
+                expected,
+                actual,
+            } => write!(f, ": {field} is {actual}, expected {expected}"),
+        }
+    }
+}
+
+impl BalanceSheet {
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        Self::read(file).map_err(|e| EngineError::Reconcile(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse an accounts CSV; `currency` and `locked` columns are optional,
+    /// and each client and currency may only be listed once
+    pub fn read<R: io::Read>(reader: R) -> Result<Self, String> {
+        let mut sheet = BalanceSheet::default();
+        for row in csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize()
+        {
+            let row: SheetRow = row.map_err(|e| e.to_string())?;
+            let entry = SheetEntry {
+                balance: Balance {
+                    available: row.available,
+                    held: row.held,
+                    total: row.total,
+                },
+                locked: row.locked,
+            };
+            if sheet
+                .entries
+                .insert((row.client, row.currency), entry)
+                .is_some()
+            {
+                return Err(format!("client {} is listed twice", row.client));
+            }
+        }
+        Ok(sheet)
+    }
+
+    /// The balances the accounts output would list; accounts left out by
+    /// `closed` are left out here too
+    pub fn of(accounts: &AccountsMap, closed: ClosedAccounts) -> Self {
+        let mut sheet = BalanceSheet::default();
+        for account in accounts.iter() {
+            if closed == ClosedAccounts::Exclude && account.status == AccountStatus::Closed {
+                continue;
+            }
+            for (currency, balance) in account.balances() {
+                let entry = SheetEntry {
+                    balance,
+                    locked: Some(account.is_locked()),
+                };
+                sheet.entries.insert((account.client, currency), entry);
+            }
+        }
+        sheet
+    }
+
+    /// Every entry, ordered by client and currency
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, Option<Currency>, SheetEntry)> + '_ {
+        self.entries
+            .iter()
+            .map(|(&(client, currency), &entry)| (client, currency, entry))
+    }
+
+    pub fn get(&self, client: ClientId, currency: Option<Currency>) -> Option<SheetEntry> {
+        self.entries.get(&(client, currency)).copied()
+    }
+
+    /// Set a balance, returning the one it replaces
+    pub fn insert(
+        &mut self,
+        client: ClientId,
+        currency: Option<Currency>,
+        entry: SheetEntry,
+    ) -> Option<SheetEntry> {
+        self.entries.insert((client, currency), entry)
+    }
+
+    /// Write the balances in the accounts output layout, with a `currency`
+    /// column only if any balance has one
+    pub fn write<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        let with_currency = self.entries.keys().any(|(_, currency)| currency.is_some());
+        let mut wtr = csv::Writer::from_writer(writer);
+        for (client, currency, entry) in self.iter() {
+            wtr.serialize(SheetOutputRow {
+                client,
+                currency: with_currency.then_some(currency),
+                available: entry.balance.available,
+                held: entry.balance.held,
+                total: entry.balance.total,
+                locked: entry.locked,
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Compare `actual` balances against these expected ones, sorted by
+    /// client and currency
+    pub fn compare(&self, actual: &BalanceSheet) -> Vec<Discrepancy> {
+        let mut unmatched = actual.entries.clone();
+        let mut discrepancies = Vec::new();
+        for (client, currency, expected) in self.iter() {
+            let discrepancy = |kind| Discrepancy {
+                client,
+                currency,
+                kind,
+            };
+            let Some(actual) = unmatched.remove(&(client, currency)) else {
+                discrepancies.push(discrepancy(DiscrepancyKind::Missing));
+                continue;
+            };
+            let fields = [
+                (
+                    "available",
+                    expected.balance.available,
+                    actual.balance.available,
+                ),
+                ("held", expected.balance.held, actual.balance.held),
+                ("total", expected.balance.total, actual.balance.total),
+            ];
+            for (field, expected, actual) in fields {
+                if expected != actual {
+                    discrepancies.push(discrepancy(DiscrepancyKind::Mismatch {
+                        field,
+                        expected: expected.to_string(),
+                        actual: actual.to_string(),
+                    }));
+                }
+            }
+            if let (Some(expected), Some(actual)) = (expected.locked, actual.locked)
+                && expected != actual
+            {
+                discrepancies.push(discrepancy(DiscrepancyKind::Mismatch {
+                    field: "locked",
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                }));
+            }
+        }
+        discrepancies.extend(unmatched.into_keys().map(|(client, currency)| Discrepancy {
+            client,
+            currency,
+            kind: DiscrepancyKind::Unexpected,
+        }));
+        discrepancies.sort_by_key(|d| (d.client, d.currency));
+        discrepancies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Account;
+    use std::str::FromStr;
+
+    fn account(client: ClientId, available: &str, held: &str) -> Account {
+        let mut account = Account::new(client);
+        let balance = account.balance_mut(None);
+        balance.available = Decimal::from_str(available).unwrap();
+        balance.held = Decimal::from_str(held).unwrap();
+        balance.total = balance.available + balance.held;
+        account
+    }
+
+    #[test]
+    fn test_compare_reports_missing_unexpected_and_mismatched() {
+        let expected = BalanceSheet::read(
+            "client,available,held,total,locked\n\
+             1,1.5,0,1.5,false\n\
+             2,2.0,1,3,true\n\
+             4,1,0,1,false\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let accounts = AccountsMap::new();
+        for account in [
+            account(1, "1.50", "0"),
+            account(2, "2", "0.5"),
+            account(3, "1", "0"),
+        ] {
+            accounts.insert(account.client, account);
+        }
+
+        let kinds: Vec<_> = expected
+            .compare(&BalanceSheet::of(&accounts, ClosedAccounts::Include))
+            .into_iter()
+            .map(|d| (d.client, d.kind))
+            .collect();
+        let mismatch = |field, expected: &str, actual: &str| DiscrepancyKind::Mismatch {
+            field,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        };
+        assert_eq!(
+            kinds,
+            vec![
+                (2, mismatch("held", "1", "0.5")),
+                (2, mismatch("total", "3", "2.5")),
+                (2, mismatch("locked", "true", "false")),
+                (3, DiscrepancyKind::Unexpected),
+                (4, DiscrepancyKind::Missing),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_rejects_duplicate_clients() {
+        let csv = "client,available,held,total\n1,1,0,1\n1,2,0,2\n";
+        assert!(BalanceSheet::read(csv.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+
+use crate::error::EngineError;
+use crate::models::{
+    AccountStatus, AccountsMap, Balance, ClientId, Currency, Transaction, TransactionType,
+    TransactionsMap, TxId,
+};
+
+/// Type of the rows `adjustments` generates
+pub const ADJUSTMENT_TYPE: &str = "adjustment";
+
+/// Columns of the recovery report, written even when it has no rows
+const REPORT_HEADER: [&str; 7] = [
+    "client",
+    "currency",
+    "available",
+    "held",
+    "total",
+    "owed",
+    "status",
+];
+
+/// A client's balance in one currency that has gone below zero, such as
+/// after a chargeback of a deposit that was already withdrawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegativeBalance {
+    pub client: ClientId,
+    pub currency: Option<Currency>,
+    pub balance: Balance,
+    pub status: AccountStatus,
+}
+
+impl NegativeBalance {
+    /// What the client owes back: enough to bring both `available` and
+    /// `total` up to zero
+    pub fn owed(&self) -> Decimal {
+        -self.balance.available.min(self.balance.total)
+    }
+}
+
+/// Every balance in `accounts` with negative `available` or `total`, ordered
+/// by client and currency.
+///
+/// The `suspense` account is left out: its negative balance is the sum of
+/// what others owe once their adjustments are applied.
+pub fn scan(accounts: &AccountsMap, suspense: Option<ClientId>) -> Vec<NegativeBalance> {
+    let mut negative: Vec<_> = accounts
+        .iter()
+        .filter(|account| Some(account.client) != suspense)
+        .flat_map(|account| {
+            account
+                .balances()
+                .filter(|(_, balance)| {
+                    balance.available < Decimal::ZERO || balance.total < Decimal::ZERO
+                })
+                .map(|(currency, balance)| NegativeBalance {
+                    client: account.client,
+                    currency,
+                    balance,
+                    status: account.status,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    negative.sort_by_key(|n| (n.client, n.currency));
+    negative
+}
+
+/// One row of the recovery report
+#[derive(Debug, Serialize)]
+struct ReportRow {
+    client: ClientId,
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    owed: Decimal,
+    status: AccountStatus,
+}
+
+/// Write negative balances as a CSV report, one row per client and currency
+pub fn write_report<W: Write>(negative: &[NegativeBalance], writer: W) -> Result<(), EngineError> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(REPORT_HEADER)?;
+    for n in negative {
+        writer.serialize(ReportRow {
+            client: n.client,
+            currency: n.currency,
+            available: n.balance.available,
+            held: n.balance.held,
+            total: n.balance.total,
+            owed: n.owed(),
+            status: n.status,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// `adjustment` rows moving what each client owes to the `suspense` account.
+///
+/// Each negative balance gets a pair: one crediting the client with the owed
+/// amount, bringing the balance back to zero, and one debiting the suspense
+/// account by the same amount, in the same currency. They are numbered
+/// upwards from the largest ID in `transactions`, and fail when the IDs run
+/// out.
+pub fn adjustments(
+    negative: &[NegativeBalance],
+    suspense: ClientId,
+    transactions: &TransactionsMap,
+) -> Result<Vec<Transaction>, EngineError> {
+    let mut next_tx = match transactions.iter().map(|entry| entry.key().tx).max() {
+        Some(last) => last.checked_add(1),
+        None => Some(1),
+    };
+    let mut rows = Vec::with_capacity(negative.len() * 2);
+    for n in negative {
+        for (client, amount) in [(n.client, n.owed()), (suspense, -n.owed())] {
+            let tx = next_tx.ok_or_else(|| {
+                EngineError::Usage("no transaction IDs left for recovery adjustments".to_string())
+            })?;
+            next_tx = tx.checked_add(1);
+            rows.push(Transaction {
+                tx_type: TransactionType::Custom(ADJUSTMENT_TYPE.to_string()),
+                client,
+                tx,
+                amount: Some(amount),
+                timestamp: None,
+                currency: n.currency,
+                to_currency: None,
+                idempotency_key: None,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// An adjustment as written to the adjustments file
+#[derive(Debug, Serialize)]
+struct AdjustmentRow<'a> {
+    #[serde(rename = "type")]
+    tx_type: &'a str,
+    client: ClientId,
+    tx: TxId,
+    amount: Option<Decimal>,
+    currency: Option<Currency>,
+}
+
+/// Write adjustments as a `type,client,tx,amount,currency` CSV file, in the
+/// input format
+pub fn write_adjustments<W: Write>(rows: &[Transaction], writer: W) -> Result<(), EngineError> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for row in rows {
+        writer.serialize(AdjustmentRow {
+            tx_type: row.tx_type.as_str(),
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount,
+            currency: row.currency,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, TransactionRecord, TxKey};
+
+    fn account(client: ClientId, available: i64, held: i64) -> Account {
+        let mut account = Account::new(client);
+        *account.balance_mut(None) = Balance {
+            available: Decimal::from(available),
+            held: Decimal::from(held),
+            total: Decimal::from(available + held),
+        };
+        account
+    }
+
+    #[test]
+    fn test_scan_reports_what_clients_owe() {
+        let accounts = AccountsMap::default();
+        for account in [
+            account(3, -4, 10),
+            account(1, 5, 0),
+            account(2, -6, 0),
+            account(9, -10, 0),
+        ] {
+            accounts.insert(account.client, account);
+        }
+        let negative = scan(&accounts, Some(9));
+        assert_eq!(
+            negative.iter().map(|n| n.client).collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(negative[1].owed(), Decimal::from(4));
+
+        let mut report = Vec::new();
+        write_report(&negative, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client,currency,available,held,total,owed,status\n\
+             2,,-6,0,-6,6,active\n\
+             3,,-4,10,6,4,active\n"
+        );
+        let mut empty = Vec::new();
+        write_report(&[], &mut empty).unwrap();
+        assert_eq!(
+            String::from_utf8(empty).unwrap(),
+            "client,currency,available,held,total,owed,status\n"
+        );
+    }
+
+    #[test]
+    fn test_adjustments_move_debt_to_suspense() {
+        let accounts = AccountsMap::default();
+        accounts.insert(2, account(2, -6, 0));
+        let transactions = TransactionsMap::default();
+        let store = |tx: TxId| {
+            let record = TransactionRecord {
+                client: 2,
+                amount: Decimal::ONE,
+                disputed: false,
+                timestamp: None,
+                currency: None,
+                conversion: None,
+                held: None,
+            };
+            transactions.insert(TxKey::global(tx), record);
+        };
+        store(99);
+        let rows = adjustments(&scan(&accounts, None), 9, &transactions).unwrap();
+        let mut file = Vec::new();
+        write_adjustments(&rows, &mut file).unwrap();
+        assert_eq!(
+            String::from_utf8(file).unwrap(),
+            "type,client,tx,amount,currency\n\
+             adjustment,2,100,6,\n\
+             adjustment,9,101,-6,\n"
+        );
+        store(TxId::MAX);
+        assert!(adjustments(&scan(&accounts, None), 9, &transactions).is_err());
+    }
+}
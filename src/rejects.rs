@@ -0,0 +1,153 @@
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::outcome::{MalformedRow, Rejected};
+
+/// One row of the rejected-transactions file.
+///
+/// Fields are kept as text so malformed rows can be echoed back verbatim.
+#[derive(Debug, Serialize)]
+pub struct RejectRecord {
+    pub line: Option<u64>,
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+    pub reason: &'static str,
+    pub detail: String,
+}
+
+impl From<&Rejected> for RejectRecord {
+    fn from(rejected: &Rejected) -> Self {
+        let transaction = &rejected.transaction;
+        RejectRecord {
+            line: None,
+            tx_type: transaction.tx_type.as_str().to_string(),
+            client: transaction.client.to_string(),
+            tx: transaction.tx.to_string(),
+            amount: transaction
+                .amount
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            reason: rejected.error.reason_code(),
+            detail: rejected.error.to_string(),
+        }
+    }
+}
+
+impl From<&MalformedRow> for RejectRecord {
+    fn from(row: &MalformedRow) -> Self {
+        let field = |index: usize| row.fields.get(index).cloned().unwrap_or_default();
+        RejectRecord {
+            line: row.line,
+            tx_type: field(0),
+            client: field(1),
+            tx: field(2),
+            amount: field(3),
+            reason: row.error.reason_code(),
+            detail: row.error.to_string(),
+        }
+    }
+}
+
+/// CSV writer for rejected transactions
+pub struct RejectsWriter<W: std::io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl RejectsWriter<File> {
+    /// Create (or truncate) the rejects file at `path`
+    pub fn create(path: &Path) -> Result<Self, EngineError> {
+        Ok(RejectsWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: std::io::Write> RejectsWriter<W> {
+    pub fn new(inner: W) -> Self {
+        RejectsWriter {
+            writer: csv::Writer::from_writer(inner),
+        }
+    }
+
+    /// Append one rejected transaction
+    pub fn write(&mut self, rejected: &Rejected) -> Result<(), EngineError> {
+        self.writer.serialize(RejectRecord::from(rejected))?;
+        Ok(())
+    }
+
+    /// Append one malformed input row
+    pub fn write_malformed(&mut self, row: &MalformedRow) -> Result<(), EngineError> {
+        self.writer.serialize(RejectRecord::from(row))?;
+        Ok(())
+    }
+
+    /// Flush buffered rows and return the underlying writer
+    pub fn finish(self) -> Result<W, EngineError> {
+        self.writer
+            .into_inner()
+            .map_err(|e| EngineError::Io(e.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RowErrorKind;
+    use crate::models::{Transaction, TransactionType};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_write_rejected_row() {
+        let rejected = Rejected {
+            transaction: Transaction::new(
+                TransactionType::Withdrawal,
+                2,
+                5,
+                Some(Decimal::from(3)),
+            ),
+            error: EngineError::InsufficientFunds {
+                client: 2,
+                tx: 5,
+                amount: Decimal::from(3),
+                available: Decimal::from(2),
+            },
+        };
+
+        let mut writer = RejectsWriter::new(Vec::new());
+        writer.write(&rejected).unwrap();
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            output,
+            "line,type,client,tx,amount,reason,detail\n\
+             ,withdrawal,2,5,3,insufficient_funds,\"Insufficient funds (Client: 2, Tx: 5, Amount: 3, Available: 2)\"\n"
+        );
+    }
+
+    #[test]
+    fn test_write_malformed_row() {
+        let row = MalformedRow {
+            line: Some(7),
+            fields: vec!["refund".into(), "1".into(), "9".into()],
+            error: EngineError::InvalidRow {
+                line: 7,
+                column: "type",
+                value: "refund".into(),
+                kind: RowErrorKind::UnknownType,
+            },
+        };
+
+        let mut writer = RejectsWriter::new(Vec::new());
+        writer.write_malformed(&row).unwrap();
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            output,
+            "line,type,client,tx,amount,reason,detail\n\
+             7,refund,1,9,,unknown_type,\"Line 7, column 'type': unknown transaction type (value: 'refund')\"\n"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::io;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Currency, TransactionType, TxId};
+use crate::outcome::Applied;
+
+/// Lower bounds of the transaction size buckets; the last one is open-ended
+const SIZE_BUCKETS: [i64; 7] = [0, 1, 10, 100, 1_000, 10_000, 100_000];
+
+/// Disputed amount first, so the heap orders disputes by size
+type DisputeKey = (Decimal, ClientId, TxId, Option<Currency>);
+
+/// Deposits and withdrawals of one client in one currency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Volume {
+    amount: Decimal,
+    rows: u64,
+}
+
+/// Analytics over the applied rows of a file, gathered as they are applied
+/// so that a single pass over the file is enough.
+///
+/// Only the `top` largest disputes are kept; volumes are kept per client and
+/// currency until the end, when the `top` of those are picked.
+#[derive(Debug)]
+pub struct Report {
+    top: usize,
+    volumes: HashMap<(ClientId, Option<Currency>), Volume>,
+    disputes: BinaryHeap<Reverse<DisputeKey>>,
+    charged_back: BTreeMap<Option<Currency>, Decimal>,
+    sizes: [u64; SIZE_BUCKETS.len()],
+}
+
+impl Report {
+    pub fn new(top: usize) -> Self {
+        Report {
+            top,
+            volumes: HashMap::new(),
+            disputes: BinaryHeap::new(),
+            charged_back: BTreeMap::new(),
+            sizes: [0; SIZE_BUCKETS.len()],
+        }
+    }
+
+    pub fn record(&mut self, applied: &Applied) {
+        let transaction = &applied.transaction;
+        let currency = transaction.currency;
+        match transaction.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let volume = self
+                    .volumes
+                    .entry((transaction.client, currency))
+                    .or_default();
+                volume.amount += applied.amount;
+                volume.rows += 1;
+                let bucket = SIZE_BUCKETS
+                    .iter()
+                    .rposition(|&from| applied.amount >= Decimal::from(from))
+                    .unwrap_or(0);
+                self.sizes[bucket] += 1;
+            }
+            TransactionType::Dispute => {
+                // What was disputed, even when the dispute held less
+                let uncovered = applied.shortfall.map_or(Decimal::ZERO, |s| s.uncovered);
+                let amount = applied.amount + uncovered;
+                self.disputes.push(Reverse((
+                    amount,
+                    transaction.client,
+                    transaction.tx,
+                    currency,
+                )));
+                if self.disputes.len() > self.top {
+                    self.disputes.pop();
+                }
+            }
+            TransactionType::Chargeback => {
+                *self.charged_back.entry(currency).or_default() += applied.amount;
+            }
+            _ => {}
+        }
+    }
+
+    /// The figures gathered so far, largest first
+    pub fn summary(&self) -> ReportSummary {
+        let mut top_clients: Vec<_> = self
+            .volumes
+            .iter()
+            .map(|(&(client, currency), volume)| ClientVolume {
+                client,
+                currency,
+                volume: volume.amount,
+                rows: volume.rows,
+            })
+            .collect();
+        top_clients.sort_by(|a, b| {
+            b.volume
+                .cmp(&a.volume)
+                .then_with(|| (a.client, a.currency).cmp(&(b.client, b.currency)))
+        });
+        top_clients.truncate(self.top);
+
+        let mut largest_disputes: Vec<_> = self
+            .disputes
+            .iter()
+            .map(|Reverse((amount, client, tx, currency))| Dispute {
+                client: *client,
+                tx: *tx,
+                currency: *currency,
+                amount: *amount,
+            })
+            .collect();
+        largest_disputes.sort_by(|a, b| b.amount.cmp(&a.amount).then(a.tx.cmp(&b.tx)));
+
+        let charged_back = self
+            .charged_back
+            .iter()
+            .map(|(&currency, &amount)| CurrencyAmount { currency, amount })
+            .collect();
+        let size_distribution = SIZE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, &from)| SizeBucket {
+                from: Decimal::from(from),
+                to: SIZE_BUCKETS.get(i + 1).map(|&to| Decimal::from(to)),
+                count: self.sizes[i],
+            })
+            .collect();
+        ReportSummary {
+            top_clients,
+            largest_disputes,
+            charged_back,
+            size_distribution,
+        }
+    }
+}
+
+/// A client's deposits and withdrawals in one currency, added up
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientVolume {
+    pub client: ClientId,
+    pub currency: Option<Currency>,
+    pub volume: Decimal,
+    pub rows: u64,
+}
+
+/// A dispute and the amount of the deposit it disputed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Dispute {
+    pub client: ClientId,
+    pub tx: TxId,
+    pub currency: Option<Currency>,
+    pub amount: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CurrencyAmount {
+    pub currency: Option<Currency>,
+    pub amount: Decimal,
+}
+
+/// Deposits and withdrawals of at least `from` and below `to`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeBucket {
+    pub from: Decimal,
+    pub to: Option<Decimal>,
+    pub count: u64,
+}
+
+/// What the `report` subcommand prints
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportSummary {
+    pub top_clients: Vec<ClientVolume>,
+    pub largest_disputes: Vec<Dispute>,
+    pub charged_back: Vec<CurrencyAmount>,
+    pub size_distribution: Vec<SizeBucket>,
+}
+
+impl ReportSummary {
+    /// Write a human-readable report, one figure per line
+    pub fn write_text<W: io::Write>(&self, mut writer: W) -> Result<(), EngineError> {
+        let label = |client: ClientId, currency: Option<Currency>| match currency {
+            Some(currency) => format!("{client} ({currency})"),
+            None => client.to_string(),
+        };
+        writeln!(writer, "top clients by volume:")?;
+        for entry in &self.top_clients {
+            writeln!(
+                writer,
+                "  {}: {} ({} rows)",
+                label(entry.client, entry.currency),
+                entry.volume,
+                entry.rows
+            )?;
+        }
+        writeln!(writer, "largest disputes:")?;
+        for dispute in &self.largest_disputes {
+            writeln!(
+                writer,
+                "  tx {}, client {}: {}",
+                dispute.tx,
+                label(dispute.client, dispute.currency),
+                dispute.amount
+            )?;
+        }
+        if self.charged_back.is_empty() {
+            writeln!(writer, "charged back: 0")?;
+        }
+        for entry in &self.charged_back {
+            match entry.currency {
+                Some(currency) => writeln!(writer, "charged back ({currency}): {}", entry.amount)?,
+                None => writeln!(writer, "charged back: {}", entry.amount)?,
+            }
+        }
+        writeln!(writer, "transaction sizes:")?;
+        for bucket in &self.size_distribution {
+            match bucket.to {
+                Some(to) => writeln!(writer, "  {}-{}: {}", bucket.from, to, bucket.count)?,
+                None => writeln!(writer, "  {}+: {}", bucket.from, bucket.count)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the report as a JSON document
+    pub fn write_json<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| EngineError::Io(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DisputeShortfallPolicy;
+    use crate::models::Transaction;
+    use crate::outcome::Shortfall;
+
+    fn applied(tx_type: TransactionType, client: ClientId, tx: TxId, amount: i64) -> Applied {
+        Applied {
+            transaction: Transaction::new(tx_type, client, tx, None),
+            amount: Decimal::from(amount),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_report_keeps_the_top_entries() {
+        let mut report = Report::new(2);
+        for (client, tx, amount) in [(1, 1, 5), (2, 2, 500), (3, 3, 50), (1, 4, 20)] {
+            report.record(&applied(TransactionType::Deposit, client, tx, amount));
+            report.record(&applied(TransactionType::Dispute, client, tx, amount));
+        }
+        let mut capped = applied(TransactionType::Dispute, 3, 5, 10);
+        capped.shortfall = Some(Shortfall {
+            policy: DisputeShortfallPolicy::Cap,
+            uncovered: Decimal::from(90),
+        });
+        report.record(&capped);
+        report.record(&applied(TransactionType::Chargeback, 2, 2, 500));
+
+        let summary = report.summary();
+        let clients: Vec<_> = summary
+            .top_clients
+            .iter()
+            .map(|c| (c.client, c.volume, c.rows))
+            .collect();
+        assert_eq!(
+            clients,
+            [(2, Decimal::from(500), 1), (3, Decimal::from(50), 1)]
+        );
+        let disputes: Vec<_> = summary.largest_disputes.iter().map(|d| d.tx).collect();
+        assert_eq!(disputes, [2, 5]);
+        assert_eq!(summary.largest_disputes[1].amount, Decimal::from(100));
+        let sizes: Vec<_> = summary.size_distribution.iter().map(|b| b.count).collect();
+        assert_eq!(sizes, [0, 1, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_write_text() {
+        let mut report = Report::new(3);
+        report.record(&applied(TransactionType::Deposit, 1, 1, 250_000));
+        report.record(&applied(TransactionType::Withdrawal, 1, 2, 2));
+        let mut text = Vec::new();
+        report.summary().write_text(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "top clients by volume:\n  \
+             1: 250002 (2 rows)\n\
+             largest disputes:\n\
+             charged back: 0\n\
+             transaction sizes:\n  \
+             0-1: 0\n  \
+             1-10: 1\n  \
+             10-100: 0\n  \
+             100-1000: 0\n  \
+             1000-10000: 0\n  \
+             10000-100000: 0\n  \
+             100000+: 1\n"
+        );
+    }
+}
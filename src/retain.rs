@@ -0,0 +1,88 @@
+use rustc_hash::FxHashSet;
+
+use crate::models::{Transaction, TransactionType, TxId};
+
+/// Transaction IDs whose records a two-pass run keeps.
+///
+/// A stored record is only read again by a dispute, resolve or chargeback
+/// naming its ID, or by a later row reusing the ID, which is turned away as a
+/// duplicate. The record of any other ID can be dropped once applied.
+#[derive(Debug, Default)]
+pub struct RetainedIds {
+    ids: FxHashSet<TxId>,
+}
+
+impl RetainedIds {
+    pub fn contains(&self, tx: TxId) -> bool {
+        self.ids.contains(&tx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+/// First pass of a two-pass run, seeing every row of the input once
+#[derive(Debug, Default)]
+pub struct RetainScan {
+    seen: FxHashSet<TxId>,
+    retained: FxHashSet<TxId>,
+}
+
+impl RetainScan {
+    pub fn observe(&mut self, transaction: &Transaction) {
+        let referenced = matches!(
+            transaction.tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if referenced || !self.seen.insert(transaction.tx) {
+            self.retained.insert(transaction.tx);
+        }
+    }
+
+    /// The IDs some row refers to or reuses
+    pub fn finish(self) -> RetainedIds {
+        RetainedIds { ids: self.retained }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ClientId;
+
+    fn retained(rows: &[(TransactionType, ClientId, TxId)]) -> RetainedIds {
+        let mut scan = RetainScan::default();
+        for (tx_type, client, tx) in rows.iter().cloned() {
+            scan.observe(&Transaction::new(tx_type, client, tx, None));
+        }
+        scan.finish()
+    }
+
+    #[test]
+    fn test_scan_keeps_referenced_ids() {
+        use TransactionType::{Chargeback, Deposit, Dispute};
+        let ids = retained(&[
+            (Deposit, 1, 1),
+            (Deposit, 1, 2),
+            (Dispute, 1, 2),
+            (Chargeback, 1, 2),
+            (Dispute, 4, 9),
+        ]);
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(2) && ids.contains(9));
+        assert!(!ids.contains(1));
+    }
+
+    #[test]
+    fn test_scan_keeps_reused_ids() {
+        use TransactionType::{Deposit, Withdrawal};
+        let ids = retained(&[(Deposit, 1, 1), (Withdrawal, 2, 3), (Deposit, 3, 3)]);
+        assert_eq!(ids.len(), 1);
+        assert!(ids.contains(3));
+    }
+}
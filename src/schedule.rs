@@ -0,0 +1,270 @@
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Currency, Transaction, TransactionType, TxId};
+
+/// What a recurring instruction generates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecurringType {
+    Deposit,
+    Withdrawal,
+}
+
+impl From<RecurringType> for TransactionType {
+    fn from(tx_type: RecurringType) -> Self {
+        match tx_type {
+            RecurringType::Deposit => TransactionType::Deposit,
+            RecurringType::Withdrawal => TransactionType::Withdrawal,
+        }
+    }
+}
+
+/// Time between two occurrences of a recurring instruction, written as a
+/// whole number of seconds, minutes, hours, days or weeks such as `7d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Interval {
+    seconds: i64,
+}
+
+impl Interval {
+    const UNITS: [(char, i64); 5] = [
+        ('w', 7 * 86_400),
+        ('d', 86_400),
+        ('h', 3_600),
+        ('m', 60),
+        ('s', 1),
+    ];
+
+    pub fn duration(&self) -> Duration {
+        Duration::seconds(self.seconds)
+    }
+}
+
+impl FromStr for Interval {
+    type Err = EngineError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid interval '{raw}': expected a positive count followed by s, m, h, d or w"
+            ))
+        };
+        let raw = raw.trim();
+        let unit = raw.chars().last().ok_or_else(invalid)?;
+        let (_, scale) = Self::UNITS
+            .into_iter()
+            .find(|(name, _)| *name == unit.to_ascii_lowercase())
+            .ok_or_else(invalid)?;
+        let count: i64 = raw[..raw.len() - unit.len_utf8()]
+            .parse()
+            .map_err(|_| invalid())?;
+        match count.checked_mul(scale) {
+            Some(seconds) if count > 0 && seconds <= Duration::MAX.num_seconds() => {
+                Ok(Interval { seconds })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Interval {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl From<Interval> for String {
+    fn from(interval: Interval) -> Self {
+        interval.to_string()
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (unit, scale) = Self::UNITS
+            .into_iter()
+            .find(|(_, scale)| self.seconds % scale == 0)
+            .expect("every interval is a whole number of seconds");
+        write!(f, "{}{unit}", self.seconds / scale)
+    }
+}
+
+/// A deposit or withdrawal repeated at a fixed interval, such as a weekly
+/// deposit into one client's account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recurring {
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub tx_type: RecurringType,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// When the next occurrence is due; the first one is due at the start
+    /// time given in the schedule file
+    #[serde(rename = "start")]
+    pub next: DateTime<Utc>,
+    pub every: Interval,
+}
+
+/// Recurring instructions and the synthetic transactions they have
+/// generated so far.
+///
+/// The engine reads its input from files, so the clock that makes
+/// instructions fall due is the timestamps of the input rows themselves.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scheduler {
+    instructions: Vec<Recurring>,
+    /// Transactions generated so far. Their IDs count down from the top of
+    /// the ID range, clear of the IDs input files use in practice.
+    issued: TxId,
+}
+
+impl Scheduler {
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        let file = std::fs::File::open(path)?;
+        Self::read(file).map_err(|e| EngineError::Schedule(format!("{}: {e}", path.display())))
+    }
+
+    /// Parse a `client,type,amount,currency,start,every` CSV file; `currency`
+    /// is optional and amounts must be positive
+    pub fn read<R: io::Read>(reader: R) -> Result<Self, String> {
+        let mut scheduler = Scheduler::default();
+        for row in csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader)
+            .deserialize()
+        {
+            let instruction: Recurring = row.map_err(|e| e.to_string())?;
+            if instruction.amount <= Decimal::ZERO {
+                return Err(format!(
+                    "amount for client {} must be positive",
+                    instruction.client
+                ));
+            }
+            scheduler.instructions.push(instruction);
+        }
+        Ok(scheduler)
+    }
+
+    /// These instructions in place of a saved scheduler's, carrying on with
+    /// its transaction IDs so none is handed out twice
+    pub fn replacing(self, saved: &Scheduler) -> Self {
+        Scheduler {
+            issued: saved.issued,
+            ..self
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Every transaction due at or before `now`, oldest first, each stamped
+    /// with the time it fell due
+    pub fn due(&mut self, now: DateTime<Utc>) -> Vec<Transaction> {
+        let mut due = Vec::new();
+        // Ties go to the instruction listed first in the schedule file
+        while let Some(instruction) = self
+            .instructions
+            .iter_mut()
+            .filter(|instruction| instruction.next <= now)
+            .min_by_key(|instruction| instruction.next)
+        {
+            let tx = TxId::MAX - self.issued;
+            due.push(Transaction {
+                tx_type: instruction.tx_type.into(),
+                client: instruction.client,
+                tx,
+                amount: Some(instruction.amount),
+                timestamp: Some(instruction.next),
+                currency: instruction.currency,
+                to_currency: None,
+                idempotency_key: None,
+            });
+            self.issued += 1;
+            match instruction
+                .next
+                .checked_add_signed(instruction.every.duration())
+            {
+                Some(next) => instruction.next = next,
+                None => instruction.next = DateTime::<Utc>::MAX_UTC,
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_intervals() {
+        assert_eq!(
+            "7d".parse::<Interval>().unwrap().duration(),
+            Duration::days(7)
+        );
+        assert_eq!("90m".parse::<Interval>().unwrap().to_string(), "90m");
+        assert_eq!("120m".parse::<Interval>().unwrap().to_string(), "2h");
+        for invalid in ["", "d", "0d", "-1h", "3y", "1.5h"] {
+            assert!(invalid.parse::<Interval>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_due_transactions_in_time_order() {
+        let mut scheduler = Scheduler::read(
+            "client,type,amount,currency,start,every\n\
+             1,deposit,100,,2024-01-01T00:00:00Z,7d\n\
+             2,withdrawal,5,EUR,2024-01-03T00:00:00Z,1w\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let at = |raw: &str| raw.parse::<DateTime<Utc>>().unwrap();
+
+        let due: Vec<_> = scheduler
+            .due(at("2024-01-09T00:00:00Z"))
+            .into_iter()
+            .map(|tx| (tx.client, tx.tx, tx.timestamp.unwrap()))
+            .collect();
+        assert_eq!(
+            due,
+            vec![
+                (1, TxId::MAX, at("2024-01-01T00:00:00Z")),
+                (2, TxId::MAX - 1, at("2024-01-03T00:00:00Z")),
+                (1, TxId::MAX - 2, at("2024-01-08T00:00:00Z")),
+            ]
+        );
+        assert!(scheduler.due(at("2024-01-09T12:00:00Z")).is_empty());
+
+        let mut replaced = Scheduler::read(
+            "client,type,amount,start,every\n3,deposit,1,2024-01-01T00:00:00Z,1d\n".as_bytes(),
+        )
+        .unwrap()
+        .replacing(&scheduler);
+        assert_eq!(
+            replaced.due(at("2024-01-01T12:00:00Z"))[0].tx,
+            TxId::MAX - 3
+        );
+    }
+
+    #[test]
+    fn test_read_rejects_non_positive_amounts() {
+        let csv = "client,type,amount,start,every\n1,deposit,0,2024-01-01T00:00:00Z,1d\n";
+        assert!(Scheduler::read(csv.as_bytes()).is_err());
+    }
+}
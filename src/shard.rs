@@ -0,0 +1,202 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::EngineError;
+use crate::models::{AccountsMap, ClientId, TransactionsMap};
+
+/// Which of `count` shard workers owns `client`.
+///
+/// Every row of a client lands on the same shard, so the shard's private maps
+/// hold everything its clients' disputes can refer to.
+pub fn shard_for(client: ClientId, count: usize) -> usize {
+    (u64::from(client) % count as u64) as usize
+}
+
+/// The clients one engine instance handles when a file is split across
+/// several, given as `first-last` or `index/count`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ClientSlice {
+    /// Client IDs `first` to `last`, inclusive
+    Range { first: ClientId, last: ClientId },
+    /// Clients whose ID is `index` modulo `count`, as with `shard_for`
+    Shard { index: usize, count: usize },
+}
+
+impl ClientSlice {
+    pub fn contains(&self, client: ClientId) -> bool {
+        match *self {
+            ClientSlice::Range { first, last } => (first..=last).contains(&client),
+            ClientSlice::Shard { index, count } => shard_for(client, count) == index,
+        }
+    }
+
+    /// Parse a `first-last` range
+    pub fn range(s: &str) -> Result<Self, EngineError> {
+        let invalid =
+            || EngineError::Usage(format!("invalid client range '{s}' (expected first-last)"));
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let first = first.trim().parse().map_err(|_| invalid())?;
+        let last = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        Ok(ClientSlice::Range { first, last })
+    }
+
+    /// Parse an `index/count` shard, counting from 0
+    pub fn shard(s: &str) -> Result<Self, EngineError> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid shard '{s}' (expected index/count with index below count)"
+            ))
+        };
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index: usize = index.trim().parse().map_err(|_| invalid())?;
+        let count: usize = count.trim().parse().map_err(|_| invalid())?;
+        if index >= count {
+            return Err(invalid());
+        }
+        Ok(ClientSlice::Shard { index, count })
+    }
+}
+
+impl FromStr for ClientSlice {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.contains('/') {
+            true => ClientSlice::shard(s),
+            false => ClientSlice::range(s),
+        }
+    }
+}
+
+impl TryFrom<String> for ClientSlice {
+    type Error = EngineError;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl fmt::Display for ClientSlice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientSlice::Range { first, last } => write!(f, "clients {first}-{last}"),
+            ClientSlice::Shard { index, count } => write!(f, "shard {index}/{count}"),
+        }
+    }
+}
+
+/// Accounts and transactions owned outright by one shard worker
+#[derive(Debug, Default)]
+pub struct ShardState {
+    pub accounts: AccountsMap,
+    pub transactions: TransactionsMap,
+}
+
+/// Hand each of `count` shards the part of the state belonging to its clients
+pub fn split(
+    accounts: AccountsMap,
+    transactions: TransactionsMap,
+    count: usize,
+) -> Vec<ShardState> {
+    let shards: Vec<ShardState> = (0..count).map(|_| ShardState::default()).collect();
+    for (client, account) in accounts {
+        shards[shard_for(client, count)]
+            .accounts
+            .insert(client, account);
+    }
+    for (key, record) in transactions {
+        shards[shard_for(record.client, count)]
+            .transactions
+            .insert(key, record);
+    }
+    shards
+}
+
+/// Merge the shards' state back into one pair of maps for output
+pub fn merge(
+    shards: impl IntoIterator<Item = ShardState>,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+) {
+    for shard in shards {
+        for (client, account) in shard.accounts {
+            accounts.insert(client, account);
+        }
+        for (key, record) in shard.transactions {
+            transactions.insert(key, record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, TransactionRecord, TxKey};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_client_slices() {
+        let range = ClientSlice::range("10-19").unwrap();
+        assert!(range.contains(10) && range.contains(19));
+        assert!(!range.contains(9) && !range.contains(20));
+        let shard: ClientSlice = "3/8".parse().unwrap();
+        assert_eq!(
+            (0..32)
+                .filter(|&client| shard.contains(client))
+                .collect::<Vec<_>>(),
+            vec![3, 11, 19, 27]
+        );
+        for invalid in ["19-10", "8/8", "1-", "a/2", "3"] {
+            assert!(invalid.parse::<ClientSlice>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_split_and_merge_by_client() {
+        let accounts = AccountsMap::new();
+        let transactions = TransactionsMap::new();
+        for client in 1..=5 {
+            accounts.insert(client, Account::new(client));
+            transactions.insert(
+                TxKey::global(client.into()),
+                TransactionRecord {
+                    client,
+                    amount: Decimal::ONE,
+                    disputed: false,
+                    timestamp: None,
+                    currency: None,
+                    conversion: None,
+                    held: None,
+                },
+            );
+        }
+
+        let shards = split(accounts, transactions, 2);
+        assert_eq!(shards[0].accounts.len(), 2);
+        assert_eq!(shards[1].accounts.len(), 3);
+        for (index, shard) in shards.iter().enumerate() {
+            assert!(
+                shard
+                    .accounts
+                    .iter()
+                    .all(|a| shard_for(a.client, 2) == index)
+            );
+            assert!(
+                shard
+                    .transactions
+                    .iter()
+                    .all(|t| shard_for(t.client, 2) == index)
+            );
+        }
+
+        let (accounts, transactions) = (AccountsMap::new(), TransactionsMap::new());
+        merge(shards, &accounts, &transactions);
+        assert_eq!(accounts.len(), 5);
+        assert_eq!(transactions.len(), 5);
+    }
+}
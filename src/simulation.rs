@@ -0,0 +1,414 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use tokio::sync::mpsc;
+
+use crate::config::Rules;
+use crate::models::{
+    Account, AccountsMap, ClientId, Transaction, TransactionType, TransactionsMap,
+};
+use crate::outcome::{OutcomeTally, Rejected};
+use crate::ownership::{TxOwners, stored_owner};
+use crate::transaction::{handle_run, handle_transaction_with};
+use crate::workload::SplitMix64;
+
+/// Rows buffered per client channel unless configured otherwise; small, so
+/// the dispatcher keeps waiting on full channels
+pub const DEFAULT_SIM_CHANNEL_CAPACITY: usize = 4;
+
+/// Runs the fan-out of a run, one channel and task per client, on a
+/// single-threaded executor whose every scheduling decision comes from a
+/// seed.
+///
+/// The dispatcher checks each row against the input-order owner registry
+/// and sends it to its client's bounded channel; each client task applies
+/// its rows in order, in runs of up to `coalesce_rows` deposits and
+/// withdrawals. Every task yields after each row or run, and the executor
+/// then polls a ready task picked with the seed, so different seeds try
+/// different interleavings of the clients and the same seed always repeats
+/// one. The per-client guards of the command-line tool are left out, as in
+/// [`Engine`](crate::engine::Engine).
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    rules: Rules,
+    channel_capacity: usize,
+    coalesce_rows: usize,
+    check_owners: bool,
+}
+
+/// What one simulated run ended with
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    /// Every account, in client ID order
+    pub accounts: Vec<Account>,
+    pub tally: OutcomeTally,
+    /// Times a task was polled
+    pub polls: u64,
+}
+
+/// Two seeds whose runs ended differently
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub seeds: (u64, u64),
+    /// Clients whose accounts differ between the two runs
+    pub clients: Vec<ClientId>,
+    /// Whether the outcome counts differ as well
+    pub tally_differs: bool,
+}
+
+impl Simulation {
+    pub fn new(rules: Rules) -> Self {
+        Simulation {
+            rules,
+            channel_capacity: DEFAULT_SIM_CHANNEL_CAPACITY,
+            coalesce_rows: 1,
+            check_owners: true,
+        }
+    }
+
+    /// Rows each client channel buffers before the dispatcher waits
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Apply up to this many queued deposits and withdrawals of a client as
+    /// one run, as `--coalesce-rows` does
+    pub fn with_coalesce_rows(mut self, rows: usize) -> Self {
+        self.coalesce_rows = rows.max(1);
+        self
+    }
+
+    /// Fan rows out without the input-order owner check, so that clashing
+    /// transaction IDs of different clients go to whichever task gets there
+    /// first, as runs did before the check was added
+    pub fn without_owner_check(mut self) -> Self {
+        self.check_owners = false;
+        self
+    }
+
+    /// Run the transactions with the interleaving `seed` picks
+    pub fn run(&self, transactions: &[Transaction], seed: u64) -> SimulationReport {
+        let accounts = AccountsMap::new();
+        let stored = TransactionsMap::new();
+        let tally = RefCell::new(OutcomeTally::default());
+        let owners = TxOwners::new(self.rules.duplicate_tx);
+
+        let clients: BTreeSet<ClientId> = transactions.iter().map(|t| t.client).collect();
+        let mut senders = BTreeMap::new();
+        let mut tasks: Vec<Task<'_>> = Vec::with_capacity(clients.len() + 1);
+        for client in clients {
+            let (sender, receiver) = mpsc::channel(self.channel_capacity);
+            senders.insert(client, sender);
+            tasks.push(Box::pin(
+                self.client_task(receiver, &accounts, &stored, &owners, &tally),
+            ));
+        }
+        tasks.push(Box::pin(async {
+            let owner_of = |tx| stored_owner(tx, [&stored], &self.rules);
+            for transaction in transactions {
+                if self.check_owners
+                    && let Err(error) = owners.claim(transaction, owner_of).await
+                {
+                    tally.borrow_mut().record(&Err(Rejected {
+                        transaction: transaction.clone(),
+                        error,
+                    }));
+                    continue;
+                }
+                let sender = &senders[&transaction.client];
+                // Every receiver lives until its channel closes
+                let _ = sender.send(transaction.clone()).await;
+                YieldNow::default().await;
+            }
+            // Closing the channels lets the client tasks drain and finish
+            senders.clear();
+        }));
+
+        let polls = run_seeded(tasks, seed);
+        let mut accounts: Vec<_> = accounts.into_iter().map(|(_, account)| account).collect();
+        accounts.sort_by_key(|account| account.client);
+        SimulationReport {
+            accounts,
+            tally: tally.into_inner(),
+            polls,
+        }
+    }
+
+    /// Run the transactions once per seed and check that every run ends
+    /// with the same accounts and outcome counts. Returns the common result,
+    /// or the first pair of seeds that disagree.
+    pub fn check_schedules(
+        &self,
+        transactions: &[Transaction],
+        seeds: Range<u64>,
+    ) -> Result<SimulationReport, Divergence> {
+        let mut seeds = seeds;
+        let first_seed = seeds.next().unwrap_or_default();
+        let first = self.run(transactions, first_seed);
+        for seed in seeds {
+            let report = self.run(transactions, seed);
+            let tally_differs = report.tally != first.tally;
+            if report.accounts != first.accounts || tally_differs {
+                return Err(Divergence {
+                    seeds: (first_seed, seed),
+                    clients: differing_clients(&first.accounts, &report.accounts),
+                    tally_differs,
+                });
+            }
+        }
+        Ok(first)
+    }
+
+    /// A client task: apply queued rows in order until the channel closes
+    async fn client_task(
+        &self,
+        mut receiver: mpsc::Receiver<Transaction>,
+        accounts: &AccountsMap,
+        stored: &TransactionsMap,
+        owners: &TxOwners,
+        tally: &RefCell<OutcomeTally>,
+    ) {
+        let mut next = None;
+        loop {
+            let transaction = match next.take() {
+                Some(transaction) => transaction,
+                None => match receiver.recv().await {
+                    Some(transaction) => transaction,
+                    None => break,
+                },
+            };
+            if is_balance_move(&transaction) && self.coalesce_rows > 1 {
+                let mut run = vec![transaction];
+                while run.len() < self.coalesce_rows {
+                    let Ok(queued) = receiver.try_recv() else {
+                        break;
+                    };
+                    if !is_balance_move(&queued) {
+                        next = Some(queued);
+                        break;
+                    }
+                    run.push(queued);
+                }
+                let claims: Vec<_> = run.iter().filter_map(|row| owners.claim_of(row)).collect();
+                for outcome in handle_run(run, accounts, stored, &self.rules) {
+                    tally.borrow_mut().record(&outcome);
+                }
+                owners.settle(claims);
+            } else {
+                let claim = owners.claim_of(&transaction);
+                let outcome = handle_transaction_with(transaction, accounts, stored, &self.rules);
+                tally.borrow_mut().record(&outcome);
+                owners.settle(claim);
+            }
+            YieldNow::default().await;
+        }
+    }
+}
+
+fn is_balance_move(transaction: &Transaction) -> bool {
+    matches!(
+        transaction.tx_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    )
+}
+
+fn differing_clients(a: &[Account], b: &[Account]) -> Vec<ClientId> {
+    let by_client = |accounts: &[Account]| -> BTreeMap<ClientId, Account> {
+        accounts.iter().map(|a| (a.client, a.clone())).collect()
+    };
+    let (a, b) = (by_client(a), by_client(b));
+    let clients: BTreeSet<_> = a.keys().chain(b.keys()).copied().collect();
+    clients
+        .into_iter()
+        .filter(|client| a.get(client) != b.get(client))
+        .collect()
+}
+
+type Task<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// Tasks woken since they were last polled
+#[derive(Default)]
+struct ReadyQueue(Mutex<BTreeSet<usize>>);
+
+struct TaskWaker {
+    task: usize,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Ok(mut ready) = self.ready.0.lock() {
+            ready.insert(self.task);
+        }
+    }
+}
+
+/// Poll the tasks until none is ready, picking the next one to poll with
+/// `seed` each time. Returns how many polls that took.
+fn run_seeded(mut tasks: Vec<Task<'_>>, seed: u64) -> u64 {
+    let mut rng = SplitMix64(seed);
+    let ready = Arc::new(ReadyQueue((0..tasks.len()).collect::<BTreeSet<_>>().into()));
+    let wakers: Vec<Waker> = (0..tasks.len())
+        .map(|task| {
+            Waker::from(Arc::new(TaskWaker {
+                task,
+                ready: Arc::clone(&ready),
+            }))
+        })
+        .collect();
+    let mut finished = vec![false; tasks.len()];
+    let mut polls = 0;
+    loop {
+        let task = {
+            let Ok(mut ready) = ready.0.lock() else {
+                break;
+            };
+            let Some(&task) = ready
+                .iter()
+                .nth(rng.below(ready.len().max(1) as u64) as usize)
+            else {
+                break;
+            };
+            ready.remove(&task);
+            task
+        };
+        if finished[task] {
+            continue;
+        }
+        polls += 1;
+        let mut context = Context::from_waker(&wakers[task]);
+        if tasks[task].as_mut().poll(&mut context).is_ready() {
+            finished[task] = true;
+        }
+    }
+    polls
+}
+
+/// Gives the executor a chance to switch tasks
+#[derive(Default)]
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateTxPolicy;
+    use crate::workload::{Workload, WorkloadSpec};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_workload_is_independent_of_schedule() {
+        let transactions: Vec<_> = Workload::new(WorkloadSpec {
+            clients: 20,
+            transactions: 2_000,
+            dispute_rate: 0.1,
+            seed: 7,
+            ..Default::default()
+        })
+        .collect();
+        for simulation in [
+            Simulation::new(Rules::default()),
+            Simulation::new(Rules::default()).with_coalesce_rows(8),
+        ] {
+            let report = simulation.check_schedules(&transactions, 0..20).unwrap();
+            assert_eq!(report.tally.applied + report.tally.rejected_total(), 2_000);
+        }
+    }
+
+    #[test]
+    fn test_owner_check_settles_clashing_ids_by_input_order() {
+        // Clients 1 and 2 both deposit under ID 1; client 1's row comes first
+        let mut transactions = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(dec!(10))),
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(dec!(10))),
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(dec!(5))),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+        ];
+        transactions.extend(
+            (10..40).map(|tx| Transaction::new(TransactionType::Deposit, 3, tx, Some(dec!(1)))),
+        );
+
+        let report = Simulation::new(Rules::default())
+            .check_schedules(&transactions, 0..50)
+            .unwrap();
+        // Both of client 2's rows are turned down before they reach its task
+        assert!(report.accounts.iter().all(|account| account.client != 2));
+        assert_eq!(report.tally.rejected["duplicate_tx"], 1);
+
+        let divergence = Simulation::new(Rules::default())
+            .without_owner_check()
+            .check_schedules(&transactions, 0..50)
+            .unwrap_err();
+        assert!(divergence.clients.contains(&1) || divergence.clients.contains(&2));
+
+        // With per-client IDs there is nothing to race over
+        let per_client = Rules {
+            duplicate_tx: DuplicateTxPolicy::PerClient,
+            ..Default::default()
+        };
+        Simulation::new(per_client)
+            .without_owner_check()
+            .check_schedules(&transactions, 0..50)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rejected_row_leaves_its_id_to_the_next_client() {
+        // Client 1's withdrawal under ID 1 bounces, so client 2 may use it
+        let mut transactions = vec![
+            Transaction::new(TransactionType::Withdrawal, 1, 1, Some(dec!(10))),
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(dec!(5))),
+        ];
+        transactions.extend(
+            (10..40).map(|tx| Transaction::new(TransactionType::Deposit, 3, tx, Some(dec!(1)))),
+        );
+
+        let report = Simulation::new(Rules::default())
+            .check_schedules(&transactions, 0..50)
+            .unwrap();
+        let client_2 = report.accounts.iter().find(|account| account.client == 2);
+        assert_eq!(
+            client_2.map(|account| account.balance(None).available),
+            Some(dec!(5))
+        );
+        assert_eq!(report.tally.rejected["insufficient_funds"], 1);
+        assert!(!report.tally.rejected.contains_key("duplicate_tx"));
+    }
+
+    #[test]
+    fn test_same_seed_repeats_its_schedule() {
+        let transactions: Vec<_> = Workload::new(WorkloadSpec {
+            clients: 5,
+            transactions: 200,
+            ..Default::default()
+        })
+        .collect();
+        let simulation = Simulation::new(Rules::default());
+        assert_eq!(
+            simulation.run(&transactions, 3).polls,
+            simulation.run(&transactions, 3).polls
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use crate::compact::Tombstones;
+use crate::encryption::{self, StateKey};
+use crate::error::EngineError;
+use crate::hashchain::{ChainState, Digest};
+use crate::merkle::state_root;
+use crate::models::{Account, AccountsMap, ClientId, TransactionRecord, TransactionsMap, TxKey};
+use crate::schedule::Scheduler;
+
+/// Format version written into every snapshot.
+///
+/// Builds with the `wide-ids` feature write a distinct version so neither
+/// build misreads the other's ID fields.
+#[cfg(not(feature = "wide-ids"))]
+pub const SNAPSHOT_VERSION: u32 = 13;
+/// Format version written into every snapshot.
+///
+/// Builds with the `wide-ids` feature write a distinct version so neither
+/// build misreads the other's ID fields.
+#[cfg(feature = "wide-ids")]
+pub const SNAPSHOT_VERSION: u32 = 0x1_000D;
+
+/// Point-in-time copy of all engine state, sorted by ID
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub accounts: Vec<Account>,
+    /// Merkle root over `accounts` when they were captured
+    pub state_root: Digest,
+    pub transactions: Vec<(TxKey, TransactionRecord)>,
+    /// Input rows fully applied to this state, when every row the saving run
+    /// read was handled; a run resuming from the snapshot skips that many
+    pub offset: Option<u64>,
+    /// Recurring instructions of a `--schedule` run, with how far each has
+    /// got
+    pub schedule: Scheduler,
+    /// Hash chain heads and digest of a `--hash-chain` run
+    pub chain: Option<ChainState>,
+    /// Transactions compacted to tombstones, with their clients
+    pub tombstones: Vec<(TxKey, ClientId)>,
+}
+
+impl Snapshot {
+    /// Copy the current contents of the state maps
+    pub fn capture(accounts: &AccountsMap, transactions: &TransactionsMap) -> Self {
+        let mut accounts: Vec<_> = accounts.iter().map(|e| e.value().clone()).collect();
+        accounts.sort_by_key(|a| a.client);
+        let mut transactions: Vec<_> = transactions
+            .iter()
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect();
+        transactions.sort_by_key(|(key, _)| (key.tx, key.client));
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            state_root: state_root(&accounts),
+            accounts,
+            transactions,
+            offset: None,
+            schedule: Scheduler::default(),
+            chain: None,
+            tombstones: Vec::new(),
+        }
+    }
+
+    /// Record that the state reflects exactly the first `rows` input rows
+    pub fn at_offset(mut self, rows: u64) -> Self {
+        self.offset = Some(rows);
+        self
+    }
+
+    /// Keep a run's recurring instructions with the state
+    pub fn with_schedule(mut self, schedule: Scheduler) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Keep a run's hash chain with the state
+    pub fn with_chain(mut self, chain: ChainState) -> Self {
+        self.chain = Some(chain);
+        self
+    }
+
+    /// Keep the tombstones of compacted transactions with the state
+    pub fn with_tombstones(mut self, tombstones: &Tombstones) -> Self {
+        self.tombstones = tombstones.entries();
+        self
+    }
+
+    /// Rebuild the state maps from this snapshot
+    pub fn restore(self) -> (AccountsMap, TransactionsMap) {
+        let accounts = self.accounts.into_iter().map(|a| (a.client, a)).collect();
+        let transactions = self.transactions.into_iter().collect();
+        (accounts, transactions)
+    }
+
+    /// Look up one client's account
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts
+            .binary_search_by_key(&client, |a| a.client)
+            .ok()
+            .map(|i| &self.accounts[i])
+    }
+
+    /// All transaction records belonging to one client, in tx ID order
+    pub fn client_transactions(
+        &self,
+        client: ClientId,
+    ) -> impl Iterator<Item = &(TxKey, TransactionRecord)> {
+        self.transactions
+            .iter()
+            .filter(move |(_, record)| record.client == client)
+    }
+
+    /// Write the snapshot in its binary format
+    pub fn save(&self, path: &Path) -> Result<(), EngineError> {
+        self.save_with(path, None)
+    }
+
+    /// Read a snapshot written by `save`
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        Self::load_with(path, None)
+    }
+
+    /// Write the snapshot encrypted with `key`, or in plain without one
+    pub fn save_with(&self, path: &Path, key: Option<&StateKey>) -> Result<(), EngineError> {
+        match key {
+            Some(key) => {
+                let mut plain = Vec::new();
+                self.write(&mut plain)?;
+                fs::write(path, encryption::seal(key, &plain)?)?;
+                Ok(())
+            }
+            None => self.write(BufWriter::new(File::create(path)?)),
+        }
+    }
+
+    /// Read a snapshot written by `save_with`, decrypting it with `key`.
+    ///
+    /// With a key, a plain snapshot is rejected: a file swapped for an
+    /// unencrypted one would otherwise be trusted without any check.
+    pub fn load_with(path: &Path, key: Option<&StateKey>) -> Result<Self, EngineError> {
+        Self::load_sealed(path, key, false)
+    }
+
+    /// Read a snapshot like `load_with`, but also take a plain one when a key
+    /// is given, so state saved before encryption was turned on can be
+    /// resumed once and saved again encrypted
+    pub fn load_migrating(path: &Path, key: Option<&StateKey>) -> Result<Self, EngineError> {
+        Self::load_sealed(path, key, true)
+    }
+
+    fn load_sealed(
+        path: &Path,
+        key: Option<&StateKey>,
+        allow_plain: bool,
+    ) -> Result<Self, EngineError> {
+        let bytes = fs::read(path)?;
+        if !encryption::is_sealed(&bytes) {
+            if key.is_some() && !allow_plain {
+                return Err(EngineError::Encryption(format!(
+                    "{} is not encrypted but a state key was given",
+                    path.display()
+                )));
+            }
+            return Self::read(bytes.as_slice());
+        }
+        let Some(key) = key else {
+            return Err(EngineError::Encryption(format!(
+                "{} is encrypted and no state key was given",
+                path.display()
+            )));
+        };
+        Self::read(encryption::open(key, &bytes)?.as_slice())
+    }
+
+    /// Write the binary format to any writer, such as an in-memory buffer
+    pub fn write<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        bincode::serialize_into(writer, self).map_err(|e| EngineError::Snapshot(e.to_string()))
+    }
+
+    /// Read what `write` wrote, checking the format version, that the
+    /// accounts still have the state root they were saved with and that a
+    /// hash chain digest still matches its client heads
+    pub fn read<R: io::Read>(reader: R) -> Result<Self, EngineError> {
+        let snapshot: Snapshot =
+            bincode::deserialize_from(reader).map_err(|e| EngineError::Snapshot(e.to_string()))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(EngineError::Snapshot(format!(
+                "unsupported snapshot version {} (expected {SNAPSHOT_VERSION})",
+                snapshot.version
+            )));
+        }
+        let root = state_root(&snapshot.accounts);
+        if root != snapshot.state_root {
+            return Err(EngineError::Snapshot(format!(
+                "accounts have state root {root}, not the {} they were saved with",
+                snapshot.state_root
+            )));
+        }
+        if snapshot
+            .chain
+            .as_ref()
+            .is_some_and(|chain| !chain.is_consistent())
+        {
+            return Err(EngineError::Snapshot(
+                "hash chain digest does not match its client heads".to_string(),
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TxId;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let accounts = AccountsMap::new();
+        let transactions = TransactionsMap::new();
+        for client in [2, 1] {
+            let mut account = Account::new(client);
+            account.balance_mut(None).available = Decimal::from(client);
+            account.balance_mut(None).total = Decimal::from(client);
+            account.counts.deposits = u64::from(client);
+            accounts.insert(client, account);
+            transactions.insert(
+                TxKey::global(TxId::from(client) * 10),
+                TransactionRecord {
+                    client,
+                    amount: Decimal::from(client),
+                    disputed: false,
+                    timestamp: None,
+                    currency: None,
+                    conversion: None,
+                    held: None,
+                },
+            );
+        }
+
+        let schedule = Scheduler::read(
+            "client,type,amount,start,every\n1,deposit,5,2024-01-01T00:00:00Z,7d\n".as_bytes(),
+        )
+        .unwrap();
+        let snapshot = Snapshot::capture(&accounts, &transactions)
+            .at_offset(4)
+            .with_schedule(schedule.clone())
+            .with_tombstones(&Tombstones::restore(vec![(TxKey::global(5), 2)]));
+        assert_eq!(snapshot.accounts[0].client, 1);
+        assert_eq!(
+            snapshot.account(2).unwrap().balance(None).total,
+            Decimal::from(2)
+        );
+        assert_eq!(snapshot.client_transactions(2).count(), 1);
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.bin", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, snapshot);
+        assert_eq!(loaded.offset, Some(4));
+        assert_eq!(loaded.schedule, schedule);
+        assert_eq!(loaded.tombstones, [(TxKey::global(5), 2)]);
+
+        let mut tampered = snapshot.clone();
+        tampered.accounts[1].balance_mut(None).total = Decimal::ZERO;
+        let mut bytes = Vec::new();
+        tampered.write(&mut bytes).unwrap();
+        assert!(matches!(
+            Snapshot::read(bytes.as_slice()),
+            Err(EngineError::Snapshot(_))
+        ));
+
+        let key: StateKey = "ab".repeat(32).parse().unwrap();
+        snapshot.save_with(&path, Some(&key)).unwrap();
+        assert!(matches!(
+            Snapshot::load(&path),
+            Err(EngineError::Encryption(_))
+        ));
+        assert_eq!(Snapshot::load_with(&path, Some(&key)).unwrap(), snapshot);
+        std::fs::remove_file(&path).unwrap();
+
+        let (accounts, transactions) = loaded.restore();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts.get(&2).unwrap().counts.deposits, 2);
+        assert_eq!(transactions.get(&TxKey::global(10)).unwrap().client, 1);
+    }
+
+    #[test]
+    fn test_keyed_load_rejects_a_plain_snapshot() {
+        let accounts = AccountsMap::new();
+        accounts.insert(1, Account::new(1));
+        let snapshot = Snapshot::capture(&accounts, &TransactionsMap::new());
+        let path = std::env::temp_dir().join(format!("plain-snapshot-{}.bin", std::process::id()));
+        snapshot.save(&path).unwrap();
+
+        let key: StateKey = "cd".repeat(32).parse().unwrap();
+        assert!(matches!(
+            Snapshot::load_with(&path, Some(&key)),
+            Err(EngineError::Encryption(_))
+        ));
+        assert_eq!(
+            Snapshot::load_migrating(&path, Some(&key)).unwrap(),
+            snapshot
+        );
+        assert_eq!(Snapshot::load_with(&path, None).unwrap(), snapshot);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::{Mutex, RwLock};
+
+use crate::error::EngineError;
+use crate::models::{ClientId, TransactionRecord, TransactionsMap, TxKey};
+
+/// Where one spilled record sits in the spill file
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    client: ClientId,
+    offset: usize,
+    len: usize,
+}
+
+/// Transaction records moved out of memory into a temporary file.
+///
+/// The file is only ever appended to and is read through a memory map, so a
+/// record that is looked up again costs a page fault rather than a seek. A
+/// spilled record counts as stored: its ID stays taken, and a dispute,
+/// resolve or chargeback by its owner moves it back into memory first.
+#[derive(Debug)]
+pub struct SpillStore {
+    /// Deleted as soon as it is created, so it disappears with the process
+    file: Mutex<File>,
+    /// Map of everything written so far, replaced after every spill
+    mapped: RwLock<Option<Mmap>>,
+    index: DashMap<TxKey, Slot>,
+}
+
+impl SpillStore {
+    /// Create an empty store backed by a file in the system temp directory
+    pub fn create() -> Result<Self, EngineError> {
+        Ok(SpillStore {
+            file: Mutex::new(tempfile::tempfile()?),
+            mapped: RwLock::new(None),
+            index: DashMap::new(),
+        })
+    }
+
+    /// Records currently spilled
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Whether a record is stored under `key` on disk
+    pub fn contains(&self, key: &TxKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Client owning the record spilled under `key`, if there is one
+    pub fn owner(&self, key: &TxKey) -> Option<ClientId> {
+        self.index.get(key).map(|slot| slot.client)
+    }
+
+    /// Move up to `count` of the coldest records out of `transactions`.
+    ///
+    /// Records under dispute are never spilled; of the rest, the ones with
+    /// the lowest transaction IDs go first. A record that changes while it is
+    /// being written stays in memory. Returns how many records were moved.
+    pub fn spill(
+        &self,
+        transactions: &TransactionsMap,
+        count: usize,
+    ) -> Result<usize, EngineError> {
+        let mut cold: Vec<TxKey> = transactions
+            .iter()
+            .filter(|entry| !entry.disputed)
+            .map(|entry| *entry.key())
+            .collect();
+        if cold.len() > count {
+            cold.select_nth_unstable_by_key(count, |key| (key.tx, key.client));
+            cold.truncate(count);
+        }
+        if cold.is_empty() {
+            return Ok(0);
+        }
+
+        let mut file = self.file.lock().expect("spill file lock poisoned");
+        let mut offset = file.seek(SeekFrom::End(0))? as usize;
+        let mut writer = BufWriter::new(&mut *file);
+        let mut written = Vec::with_capacity(cold.len());
+        for key in cold {
+            let Some(record) = transactions.get(&key).map(|record| record.clone()) else {
+                continue;
+            };
+            let bytes = bincode::serialize(&record)
+                .map_err(|e| EngineError::Spill(format!("cannot encode record: {e}")))?;
+            writer.write_all(&bytes)?;
+            let slot = Slot {
+                client: record.client,
+                offset,
+                len: bytes.len(),
+            };
+            offset += bytes.len();
+            written.push((key, record, slot));
+        }
+        writer.flush()?;
+        drop(writer);
+        // SAFETY: the file is unlinked and private to this store, and nothing
+        // already written to it is ever modified
+        let mapped = unsafe { Mmap::map(&*file)? };
+        *self.mapped.write().expect("spill map lock poisoned") = Some(mapped);
+        drop(file);
+
+        let mut spilled = 0;
+        for (key, record, slot) in written {
+            // Publish the slot before removing the record, so lookups always
+            // find it in one place or the other
+            self.index.insert(key, slot);
+            if transactions
+                .remove_if(&key, |_, current| *current == record)
+                .is_some()
+            {
+                spilled += 1;
+            } else {
+                self.index.remove(&key);
+            }
+        }
+        Ok(spilled)
+    }
+
+    /// Move the record under `key` back into `transactions` if it is spilled
+    /// and belongs to `client`
+    pub fn fault_in(&self, key: TxKey, client: ClientId, transactions: &TransactionsMap) {
+        if self.index.is_empty() {
+            return;
+        }
+        if let Entry::Vacant(entry) = transactions.entry(key) {
+            let Some(slot) = self.index.get(&key).map(|slot| *slot) else {
+                return;
+            };
+            if slot.client != client {
+                return;
+            }
+            entry.insert(self.read(slot));
+            self.index.remove(&key);
+        }
+    }
+
+    /// Move every spilled record back into `transactions`
+    pub fn restore_all(&self, transactions: &TransactionsMap) {
+        for entry in self.index.iter() {
+            transactions.insert(*entry.key(), self.read(*entry.value()));
+        }
+        self.index.clear();
+    }
+
+    fn read(&self, slot: Slot) -> TransactionRecord {
+        let mapped = self.mapped.read().expect("spill map lock poisoned");
+        let bytes = &mapped
+            .as_ref()
+            .expect("spilled record without a spill file")[slot.offset..slot.offset + slot.len];
+        bincode::deserialize(bytes).expect("spilled record is corrupt")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TxId;
+    use rust_decimal::Decimal;
+
+    fn record(client: ClientId, disputed: bool) -> TransactionRecord {
+        TransactionRecord {
+            client,
+            amount: Decimal::new(125, 1),
+            disputed,
+            timestamp: None,
+            currency: None,
+            conversion: None,
+            held: None,
+        }
+    }
+
+    /// Ten records of client 1, the second under dispute
+    fn records() -> TransactionsMap {
+        let transactions = TransactionsMap::new();
+        for tx in 1..=10 {
+            transactions.insert(TxKey::global(tx), record(1, tx == 2));
+        }
+        transactions
+    }
+
+    fn in_memory(transactions: &TransactionsMap, tx: TxId) -> bool {
+        transactions.contains_key(&TxKey::global(tx))
+    }
+
+    #[test]
+    fn test_spills_coldest_records_first() {
+        let transactions = records();
+        let store = SpillStore::create().unwrap();
+        assert_eq!(store.spill(&transactions, 4).unwrap(), 4);
+        assert_eq!(store.spill(&transactions, 2).unwrap(), 2);
+        assert_eq!(store.len(), 6);
+        assert!(!in_memory(&transactions, 1) && !in_memory(&transactions, 7));
+        assert!(in_memory(&transactions, 8));
+        assert_eq!(store.owner(&TxKey::global(3)), Some(1));
+    }
+
+    #[test]
+    fn test_disputed_records_are_never_spilled() {
+        let transactions = records();
+        let store = SpillStore::create().unwrap();
+        assert_eq!(store.spill(&transactions, 10).unwrap(), 9);
+        assert!(in_memory(&transactions, 2));
+    }
+
+    #[test]
+    fn test_fault_in_only_for_the_owner() {
+        let transactions = records();
+        let store = SpillStore::create().unwrap();
+        store.spill(&transactions, 4).unwrap();
+
+        store.fault_in(TxKey::global(3), 2, &transactions);
+        assert!(!in_memory(&transactions, 3));
+        store.fault_in(TxKey::global(3), 1, &transactions);
+        assert_eq!(transactions.get(&TxKey::global(3)).unwrap().client, 1);
+        assert!(!store.contains(&TxKey::global(3)));
+    }
+
+    #[test]
+    fn test_restore_all_brings_every_record_back() {
+        let transactions = records();
+        let store = SpillStore::create().unwrap();
+        store.spill(&transactions, 6).unwrap();
+
+        store.restore_all(&transactions);
+        assert!(store.is_empty());
+        assert_eq!(transactions.len(), 10);
+        assert_eq!(
+            *transactions.get(&TxKey::global(7)).unwrap(),
+            record(1, false)
+        );
+    }
+}
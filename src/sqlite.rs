@@ -0,0 +1,241 @@
+use rusqlite::{Connection, params};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::EngineError;
+use crate::models::{AccountsMap, Currency};
+use crate::outcome::{Applied, MalformedRow, Rejected};
+use crate::rejects::RejectRecord;
+
+/// Tables of the export. Amounts are stored as decimal text so no precision
+/// is lost; cast them to REAL for arithmetic.
+const SCHEMA: &str = "
+CREATE TABLE accounts (
+    client INTEGER NOT NULL,
+    currency TEXT,
+    available TEXT NOT NULL,
+    held TEXT NOT NULL,
+    total TEXT NOT NULL,
+    locked INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE TABLE transactions (
+    seq INTEGER PRIMARY KEY,
+    type TEXT NOT NULL,
+    client INTEGER NOT NULL,
+    tx INTEGER NOT NULL,
+    amount TEXT NOT NULL,
+    currency TEXT,
+    timestamp TEXT,
+    to_currency TEXT,
+    converted TEXT
+);
+CREATE TABLE rejections (
+    id INTEGER PRIMARY KEY,
+    line INTEGER,
+    type TEXT NOT NULL,
+    client TEXT NOT NULL,
+    tx TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
+CREATE INDEX transactions_by_client ON transactions (client, tx);
+CREATE VIEW disputes AS
+    SELECT seq, client, tx, type AS action, amount, currency
+    FROM transactions
+    WHERE type IN ('dispute', 'resolve', 'chargeback');
+";
+
+fn sqlite_error(e: rusqlite::Error) -> EngineError {
+    EngineError::Sqlite(e.to_string())
+}
+
+/// Writes a run's results to a SQLite database: every applied transaction
+/// in the order it was applied, every rejection and malformed row, and the
+/// final accounts.
+///
+/// Everything goes into a single database transaction that `finish`
+/// commits, so an aborted run leaves an empty database rather than half of
+/// one.
+pub struct SqliteExport {
+    connection: Connection,
+    transactions: u64,
+    rejections: u64,
+}
+
+impl SqliteExport {
+    /// Create the database at `path`, replacing any file already there
+    pub fn create(path: &Path) -> Result<Self, EngineError> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let connection = Connection::open(path).map_err(sqlite_error)?;
+        connection
+            .execute_batch(SCHEMA)
+            .and_then(|()| connection.execute_batch("BEGIN"))
+            .map_err(sqlite_error)?;
+        Ok(SqliteExport {
+            connection,
+            transactions: 0,
+            rejections: 0,
+        })
+    }
+
+    /// Record one applied transaction
+    pub fn write_applied(&mut self, applied: &Applied) -> Result<(), EngineError> {
+        let transaction = &applied.transaction;
+        let conversion = applied.conversion.as_ref();
+        self.connection
+            .prepare_cached(
+                "INSERT INTO transactions
+                 (type, client, tx, amount, currency, timestamp, to_currency, converted)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )
+            .and_then(|mut insert| {
+                insert.execute(params![
+                    transaction.tx_type.as_str(),
+                    transaction.client,
+                    transaction.tx,
+                    applied.amount.to_string(),
+                    transaction.currency.as_ref().map(Currency::as_str),
+                    transaction.timestamp.map(|t| t.to_rfc3339()),
+                    conversion.map(|c| c.to.as_str().to_string()),
+                    conversion.map(|c| c.converted.to_string()),
+                ])
+            })
+            .map_err(sqlite_error)?;
+        self.transactions += 1;
+        Ok(())
+    }
+
+    /// Record one rejected transaction
+    pub fn write_rejected(&mut self, rejected: &Rejected) -> Result<(), EngineError> {
+        self.write_rejection(RejectRecord::from(rejected))
+    }
+
+    /// Record one malformed input row
+    pub fn write_malformed(&mut self, row: &MalformedRow) -> Result<(), EngineError> {
+        self.write_rejection(RejectRecord::from(row))
+    }
+
+    fn write_rejection(&mut self, record: RejectRecord) -> Result<(), EngineError> {
+        self.connection
+            .prepare_cached(
+                "INSERT INTO rejections (line, type, client, tx, amount, reason, detail)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .and_then(|mut insert| {
+                insert.execute(params![
+                    record.line,
+                    record.tx_type,
+                    record.client,
+                    record.tx,
+                    record.amount,
+                    record.reason,
+                    record.detail,
+                ])
+            })
+            .map_err(sqlite_error)?;
+        self.rejections += 1;
+        Ok(())
+    }
+
+    /// Write the final accounts, one row per balance, and commit. Returns
+    /// how many transactions and rejections were recorded.
+    pub fn finish(self, accounts: &AccountsMap) -> Result<(u64, u64), EngineError> {
+        {
+            let mut insert = self
+                .connection
+                .prepare(
+                    "INSERT INTO accounts
+                     (client, currency, available, held, total, locked, status)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )
+                .map_err(sqlite_error)?;
+            for account in accounts.iter() {
+                for (currency, balance) in account.balances() {
+                    insert
+                        .execute(params![
+                            account.client,
+                            currency.as_ref().map(Currency::as_str),
+                            balance.available.to_string(),
+                            balance.held.to_string(),
+                            balance.total.to_string(),
+                            account.is_locked(),
+                            account.status.as_str(),
+                        ])
+                        .map_err(sqlite_error)?;
+                }
+            }
+        }
+        self.connection
+            .execute_batch("COMMIT")
+            .map_err(sqlite_error)?;
+        Ok((self.transactions, self.rejections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+
+    fn applied(tx_type: TransactionType, tx: u16) -> Applied {
+        Applied {
+            transaction: Transaction::new(tx_type, 1, tx.into(), None),
+            amount: dec!(2.5),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        }
+    }
+
+    #[test]
+    fn test_export_run_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.db");
+        fs::write(&path, b"stale").unwrap();
+
+        let mut export = SqliteExport::create(&path).unwrap();
+        export
+            .write_applied(&applied(TransactionType::Deposit, 1))
+            .unwrap();
+        export
+            .write_applied(&applied(TransactionType::Dispute, 1))
+            .unwrap();
+        export
+            .write_rejected(&Rejected {
+                transaction: Transaction::new(TransactionType::Resolve, 1, 9, None),
+                error: EngineError::UnknownTx { client: 1, tx: 9 },
+            })
+            .unwrap();
+        let accounts = AccountsMap::new();
+        let mut account = Account::new(1);
+        account.balance_mut(None).held = dec!(2.5);
+        account.balance_mut(None).total = dec!(2.5);
+        accounts.insert(1, account);
+        assert_eq!(export.finish(&accounts).unwrap(), (2, 1));
+
+        let db = Connection::open(&path).unwrap();
+        let held: String = db
+            .query_row("SELECT held FROM accounts WHERE client = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(held, "2.5");
+        let dispute: (i64, String) = db
+            .query_row("SELECT seq, action FROM disputes", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(dispute, (2, "dispute".to_string()));
+        let reason: String = db
+            .query_row("SELECT reason FROM rejections", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(reason, "unknown_tx");
+    }
+}
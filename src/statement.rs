@@ -0,0 +1,187 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::config::OutputFormat;
+use crate::error::EngineError;
+use crate::models::{AccountsMap, ClientId, Currency, TxId};
+use crate::outcome::Applied;
+
+/// One applied transaction and the client's balances in its currency right
+/// after it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub tx_type: Cow<'static, str>,
+    pub amount: Decimal,
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    pub timestamp: Option<String>,
+}
+
+/// A client's statement, in the order its transactions were applied
+#[derive(Debug, Serialize)]
+struct ClientStatement<'a> {
+    client: ClientId,
+    lines: &'a [StatementLine],
+}
+
+/// Running-balance statements for one client or for every client
+#[derive(Debug, Clone, Default)]
+pub struct Statement {
+    client: Option<ClientId>,
+    lines: BTreeMap<ClientId, Vec<StatementLine>>,
+}
+
+impl Statement {
+    /// Start a statement for `client`, or for every client when `None`
+    pub fn new(client: Option<ClientId>) -> Self {
+        Statement {
+            client,
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /// Add an applied transaction, reading the client's balances after it
+    pub fn record(&mut self, applied: &Applied, accounts: &AccountsMap) {
+        let transaction = &applied.transaction;
+        if self
+            .client
+            .is_some_and(|client| client != transaction.client)
+        {
+            return;
+        }
+        let Some(account) = accounts.get(&transaction.client) else {
+            return;
+        };
+        let balance = account.balance(transaction.currency);
+        self.lines
+            .entry(transaction.client)
+            .or_default()
+            .push(StatementLine {
+                client: transaction.client,
+                tx: transaction.tx,
+                tx_type: transaction.tx_type.name(),
+                amount: applied.amount,
+                currency: transaction.currency,
+                available: balance.available,
+                held: balance.held,
+                total: balance.total,
+                locked: account.is_locked(),
+                timestamp: transaction.timestamp.map(|t| t.to_rfc3339()),
+            });
+    }
+
+    /// Statement lines for one client
+    pub fn lines(&self, client: ClientId) -> &[StatementLine] {
+        self.lines.get(&client).map_or(&[], Vec::as_slice)
+    }
+
+    /// Write every client's statement, ordered by client ID
+    pub fn write<W: io::Write>(&self, format: OutputFormat, writer: W) -> Result<(), EngineError> {
+        match format {
+            OutputFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(writer);
+                for line in self.lines.values().flatten() {
+                    wtr.serialize(line)?;
+                }
+                wtr.flush()?;
+            }
+            OutputFormat::Json => {
+                let statements: Vec<_> = self
+                    .lines
+                    .iter()
+                    .map(|(client, lines)| ClientStatement {
+                        client: *client,
+                        lines,
+                    })
+                    .collect();
+                serde_json::to_writer_pretty(writer, &statements)
+                    .map_err(|e| EngineError::Io(e.into()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Transaction, TransactionType, TransactionsMap};
+    use crate::transaction::handle_transaction;
+
+    fn run(statement: &mut Statement, rows: &[(TransactionType, ClientId, TxId, Option<i64>)]) {
+        let accounts = AccountsMap::new();
+        let transactions = TransactionsMap::new();
+        for (tx_type, client, tx, amount) in rows.iter().cloned() {
+            let transaction = Transaction::new(tx_type, client, tx, amount.map(Decimal::from));
+            if let Ok(applied) = handle_transaction(transaction, &accounts, &transactions) {
+                statement.record(&applied, &accounts);
+            }
+        }
+    }
+
+    #[test]
+    fn test_running_balances() {
+        let mut statement = Statement::new(Some(1));
+        run(
+            &mut statement,
+            &[
+                (TransactionType::Deposit, 1, 1, Some(10)),
+                (TransactionType::Deposit, 2, 2, Some(5)),
+                (TransactionType::Withdrawal, 1, 3, Some(50)),
+                (TransactionType::Dispute, 1, 1, None),
+                (TransactionType::Chargeback, 1, 1, None),
+            ],
+        );
+
+        let balances: Vec<_> = statement
+            .lines(1)
+            .iter()
+            .map(|l| (l.tx_type.as_ref(), l.available, l.held, l.locked))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                ("deposit", Decimal::from(10), Decimal::ZERO, false),
+                ("dispute", Decimal::ZERO, Decimal::from(10), false),
+                ("chargeback", Decimal::ZERO, Decimal::ZERO, true),
+            ]
+        );
+        assert!(statement.lines(2).is_empty());
+    }
+
+    #[test]
+    fn test_write_formats() {
+        let mut statement = Statement::new(None);
+        run(
+            &mut statement,
+            &[
+                (TransactionType::Deposit, 2, 1, Some(3)),
+                (TransactionType::Deposit, 1, 2, Some(4)),
+            ],
+        );
+
+        let mut csv = Vec::new();
+        statement.write(OutputFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,tx,type,amount,currency,available,held,total,locked,timestamp\n\
+             1,2,deposit,4,,4,0,4,false,\n\
+             2,1,deposit,3,,3,0,3,false,\n"
+        );
+
+        let mut json = Vec::new();
+        statement.write(OutputFormat::Json, &mut json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value[1]["client"], 2);
+        assert_eq!(value[1]["lines"][0]["available"], "3");
+    }
+}
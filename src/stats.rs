@@ -0,0 +1,193 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::error::EngineError;
+use crate::hashchain::Digest;
+use crate::models::{AccountsMap, Currency};
+use crate::outcome::OutcomeTally;
+
+/// Aggregate figures describing a finished processing run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunStats {
+    pub rows_read: u64,
+    pub parse_failures: u64,
+    pub parse_failures_by_reason: BTreeMap<&'static str, u64>,
+    pub applied: u64,
+    pub applied_by_type: BTreeMap<Cow<'static, str>, u64>,
+    pub rejected: u64,
+    pub rejected_by_type: BTreeMap<Cow<'static, str>, u64>,
+    pub rejected_by_reason: BTreeMap<&'static str, u64>,
+    pub accounts_created: usize,
+    pub accounts_locked: usize,
+    /// Held funds in rows without a currency
+    pub total_held: Decimal,
+    /// Held funds per named currency
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub held_by_currency: BTreeMap<Currency, Decimal>,
+    pub duration_ms: u64,
+    /// Digest over every client's hash chain, in `--hash-chain` runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_chain: Option<String>,
+}
+
+impl RunStats {
+    /// Combine the outcome tally with the final account state
+    pub fn new(tally: &OutcomeTally, accounts: &AccountsMap, duration: Duration) -> Self {
+        let rejected = tally.rejected_total();
+        let parse_failures = tally.malformed_total();
+        let mut total_held = Decimal::ZERO;
+        let mut held_by_currency = BTreeMap::new();
+        for account in accounts.iter() {
+            for (currency, balance) in account.balances() {
+                match currency {
+                    Some(currency) => {
+                        *held_by_currency.entry(currency).or_default() += balance.held
+                    }
+                    None => total_held += balance.held,
+                }
+            }
+        }
+        RunStats {
+            rows_read: tally.applied + rejected + parse_failures,
+            parse_failures,
+            parse_failures_by_reason: tally.malformed.clone(),
+            applied: tally.applied,
+            applied_by_type: tally.applied_by_type.clone(),
+            rejected,
+            rejected_by_type: tally.rejected_by_type.clone(),
+            rejected_by_reason: tally.rejected.clone(),
+            accounts_created: accounts.len(),
+            accounts_locked: accounts.iter().filter(|a| a.is_locked()).count(),
+            total_held,
+            held_by_currency,
+            duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+            hash_chain: None,
+        }
+    }
+
+    /// Report the final digest of a hash-chained run
+    pub fn with_hash_chain(mut self, digest: Digest) -> Self {
+        self.hash_chain = Some(digest.to_string());
+        self
+    }
+
+    /// Write a human-readable report with every breakdown, one figure per line
+    pub fn write_text<W: io::Write>(&self, mut writer: W) -> Result<(), EngineError> {
+        fn lines<K: AsRef<str>>(breakdown: &BTreeMap<K, u64>) -> Vec<(&str, u64)> {
+            breakdown
+                .iter()
+                .map(|(key, count)| (key.as_ref(), *count))
+                .collect()
+        }
+        let sections = [
+            ("rows read", self.rows_read, Vec::new()),
+            (
+                "parse failures",
+                self.parse_failures,
+                lines(&self.parse_failures_by_reason),
+            ),
+            ("applied", self.applied, lines(&self.applied_by_type)),
+            ("rejected", self.rejected, lines(&self.rejected_by_reason)),
+        ];
+        for (label, count, breakdown) in sections {
+            writeln!(writer, "{label}: {count}")?;
+            for (key, count) in breakdown {
+                writeln!(writer, "  {key}: {count}")?;
+            }
+        }
+        writeln!(writer, "accounts: {}", self.accounts_created)?;
+        writeln!(writer, "locked accounts: {}", self.accounts_locked)?;
+        writeln!(writer, "total held: {}", self.total_held)?;
+        for (currency, held) in &self.held_by_currency {
+            writeln!(writer, "total held ({currency}): {held}")?;
+        }
+        if let Some(digest) = &self.hash_chain {
+            writeln!(writer, "hash chain: {digest}")?;
+        }
+        Ok(())
+    }
+
+    /// Write the stats as a JSON document
+    pub fn write_json<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| EngineError::Io(e.into()))
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows read, {} parse failures, {} applied, {} rejected, \
+             {} accounts ({} locked), {} held, {} ms",
+            self.rows_read,
+            self.parse_failures,
+            self.applied,
+            self.rejected,
+            self.accounts_created,
+            self.accounts_locked,
+            self.total_held,
+            self.duration_ms
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Account, AccountStatus, Balance};
+
+    #[test]
+    fn test_stats_from_tally_and_accounts() {
+        let mut tally = OutcomeTally {
+            applied: 3,
+            ..Default::default()
+        };
+        tally.rejected.insert("unknown_tx", 2);
+        tally.malformed.insert("invalid_decimal", 1);
+
+        let accounts = AccountsMap::new();
+        let held = |amount: i64| Balance {
+            held: Decimal::from(amount),
+            total: Decimal::from(amount),
+            ..Default::default()
+        };
+        let mut first = Account::new(1);
+        *first.balance_mut(None) = held(5);
+        *first.balance_mut("EUR".parse().ok()) = held(4);
+        accounts.insert(1, first);
+        let mut second = Account::new(2);
+        *second.balance_mut(None) = held(2);
+        second.status = AccountStatus::ChargebackLocked;
+        accounts.insert(2, second);
+
+        let stats = RunStats::new(&tally, &accounts, Duration::from_millis(1500));
+        assert_eq!(stats.rows_read, 6);
+        assert_eq!(stats.parse_failures, 1);
+        assert_eq!(stats.accounts_created, 2);
+        assert_eq!(stats.accounts_locked, 1);
+        assert_eq!(stats.total_held, Decimal::from(7));
+        assert_eq!(stats.duration_ms, 1500);
+
+        let mut json = Vec::new();
+        stats.write_json(&mut json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["rejected_by_reason"]["unknown_tx"], 2);
+        assert_eq!(value["held_by_currency"]["EUR"], "4");
+
+        let mut text = Vec::new();
+        stats.write_text(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "rows read: 6\n\
+             parse failures: 1\n  invalid_decimal: 1\n\
+             applied: 3\n\
+             rejected: 2\n  unknown_tx: 2\n\
+             accounts: 2\nlocked accounts: 1\ntotal held: 7\ntotal held (EUR): 4\n"
+        );
+    }
+}
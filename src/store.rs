@@ -0,0 +1,413 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::Rules;
+use crate::engine::Engine;
+use crate::error::EngineError;
+use crate::models::{Account, ClientId, Transaction, TxKey};
+use crate::outcome::TransactionOutcome;
+
+/// Where an embedded engine keeps its accounts and stored transactions.
+///
+/// Every store settles transactions with the same rules; they differ in
+/// who can see the state. Errors are the store's own, such as a lost
+/// connection; a rejected transaction is an outcome like any other.
+pub trait StateStore: Send {
+    /// Apply one transaction
+    fn submit(&mut self, transaction: Transaction) -> Result<TransactionOutcome, EngineError>;
+
+    /// One client's account as it stands now
+    fn account(&mut self, client: ClientId) -> Result<Option<Account>, EngineError>;
+}
+
+impl StateStore for Engine {
+    fn submit(&mut self, transaction: Transaction) -> Result<TransactionOutcome, EngineError> {
+        Ok(Engine::submit(self, transaction))
+    }
+
+    fn account(&mut self, client: ClientId) -> Result<Option<Account>, EngineError> {
+        Ok(Engine::account(self, client))
+    }
+}
+
+/// The store behind a `StateStore`, as named in configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StateBackend {
+    /// Maps in this process's memory; see [`Engine`]
+    #[default]
+    Memory,
+    /// A Redis server at this `redis://` or `rediss://` URL, shared by
+    /// every engine instance pointed at it
+    Redis(String),
+}
+
+impl StateBackend {
+    /// Open the store. Redis needs a build with the `redis` feature.
+    pub fn open(&self, rules: Rules) -> Result<Box<dyn StateStore>, EngineError> {
+        match self {
+            StateBackend::Memory => Ok(Box::new(Engine::new(rules))),
+            #[cfg(feature = "redis")]
+            StateBackend::Redis(url) => Ok(Box::new(RedisStore::connect(url, rules)?)),
+            #[cfg(not(feature = "redis"))]
+            StateBackend::Redis(_) => Err(EngineError::Usage(
+                "the Redis state store needs a build with the redis feature".to_string(),
+            )),
+        }
+    }
+}
+
+impl FromStr for StateBackend {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StateBackend::Memory),
+            url if url.starts_with("redis://") || url.starts_with("rediss://") => {
+                Ok(StateBackend::Redis(url.to_string()))
+            }
+            other => Err(EngineError::Usage(format!(
+                "invalid state store '{other}' (expected memory or a redis:// URL)"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for StateBackend {
+    type Error = EngineError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for StateBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateBackend::Memory => f.write_str("memory"),
+            StateBackend::Redis(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Redis key holding a client's account as JSON
+pub fn account_key(prefix: &str, client: ClientId) -> String {
+    format!("{prefix}:account:{client}")
+}
+
+/// Redis key holding a stored transaction as JSON. Per-client IDs include
+/// the client, so two clients' transactions with one ID stay apart.
+pub fn transaction_key(prefix: &str, key: TxKey) -> String {
+    match key.client {
+        Some(client) => format!("{prefix}:tx:{client}:{}", key.tx),
+        None => format!("{prefix}:tx:{}", key.tx),
+    }
+}
+
+/// State kept in Redis, so that several engine instances can serve the same
+/// clients.
+///
+/// A transaction only ever reads and writes its client's account and the
+/// stored transaction its ID names. Both keys are watched while the
+/// transaction is settled on local copies, and the changed values are
+/// written back in one `MULTI`/`EXEC`; if another instance changed either
+/// key in the meantime, nothing is written and the transaction is settled
+/// again on fresh copies.
+#[cfg(feature = "redis")]
+pub struct RedisStore<C = redis::Connection> {
+    connection: C,
+    rules: Rules,
+    prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// Prefix of every key unless set with `with_prefix`
+    pub const DEFAULT_PREFIX: &str = "engine";
+
+    pub fn connect(url: &str, rules: Rules) -> Result<Self, EngineError> {
+        let connection = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .map_err(redis_error)?;
+        Ok(RedisStore {
+            connection,
+            rules,
+            prefix: Self::DEFAULT_PREFIX.to_string(),
+        })
+    }
+
+    /// Keep this store's keys apart from another's on the same server
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_error(e: redis::RedisError) -> EngineError {
+    EngineError::Redis(e.to_string())
+}
+
+/// A value that does not hold the JSON the engine wrote
+#[cfg(feature = "redis")]
+fn corrupt(e: serde_json::Error) -> redis::RedisError {
+    (
+        redis::ErrorKind::TypeError,
+        "stored state is not valid",
+        e.to_string(),
+    )
+        .into()
+}
+
+#[cfg(feature = "redis")]
+impl<C: redis::ConnectionLike + Send> StateStore for RedisStore<C> {
+    fn submit(&mut self, transaction: Transaction) -> Result<TransactionOutcome, EngineError> {
+        use crate::models::{AccountsMap, TransactionRecord, TransactionsMap};
+        use crate::transaction::handle_transaction_with;
+
+        let client = transaction.client;
+        let key = self.rules.duplicate_tx.key(client, transaction.tx);
+        let keys = [
+            account_key(&self.prefix, client),
+            transaction_key(&self.prefix, key),
+        ];
+        let rules = &self.rules;
+        redis::transaction(&mut self.connection, &keys, |connection, pipe| {
+            let (account, record): (Option<String>, Option<String>) =
+                redis::cmd("MGET").arg(&keys).query(connection)?;
+            let account: Option<Account> = account
+                .map(|json| serde_json::from_str(&json))
+                .transpose()
+                .map_err(corrupt)?;
+            let record: Option<TransactionRecord> = record
+                .map(|json| serde_json::from_str(&json))
+                .transpose()
+                .map_err(corrupt)?;
+            let accounts = AccountsMap::new();
+            if let Some(account) = &account {
+                accounts.insert(client, account.clone());
+            }
+            let transactions = TransactionsMap::new();
+            if let Some(record) = &record {
+                transactions.insert(key, record.clone());
+            }
+
+            let outcome =
+                handle_transaction_with(transaction.clone(), &accounts, &transactions, rules);
+            // Rejected rows can change the account too, freezing it on an
+            // overflow for one, so whatever differs from what was read is
+            // written back whatever the outcome
+            let settled = accounts.get(&client).map(|account| account.clone());
+            if settled != account {
+                match settled {
+                    Some(account) => {
+                        let json = serde_json::to_string(&account).map_err(corrupt)?;
+                        pipe.set(&keys[0], json).ignore();
+                    }
+                    None => {
+                        pipe.del(&keys[0]).ignore();
+                    }
+                }
+            }
+            let settled = transactions.get(&key).map(|record| record.clone());
+            if settled != record {
+                match settled {
+                    Some(record) => {
+                        let json = serde_json::to_string(&record).map_err(corrupt)?;
+                        pipe.set(&keys[1], json).ignore();
+                    }
+                    None => {
+                        pipe.del(&keys[1]).ignore();
+                    }
+                }
+            }
+            // EXEC answers nil when a watched key changed, even with nothing
+            // to write, since the outcome was settled on stale copies
+            let committed: Option<()> = pipe.query(connection)?;
+            Ok(committed.map(|()| outcome))
+        })
+        .map_err(redis_error)
+    }
+
+    fn account(&mut self, client: ClientId) -> Result<Option<Account>, EngineError> {
+        let json: Option<String> = redis::cmd("GET")
+            .arg(account_key(&self.prefix, client))
+            .query(&mut self.connection)
+            .map_err(redis_error)?;
+        json.map(|json| serde_json::from_str(&json).map_err(|e| redis_error(corrupt(e))))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_backends_and_keys() {
+        assert_eq!(
+            "memory".parse::<StateBackend>().unwrap(),
+            StateBackend::Memory
+        );
+        let redis: StateBackend = "redis://cache:6379/2".parse().unwrap();
+        assert_eq!(redis.to_string(), "redis://cache:6379/2");
+        assert!("postgres://db".parse::<StateBackend>().is_err());
+
+        assert_eq!(account_key("engine", 7), "engine:account:7");
+        assert_eq!(transaction_key("engine", TxKey::global(9)), "engine:tx:9");
+        assert_eq!(
+            transaction_key("engine", TxKey::per_client(7, 9)),
+            "engine:tx:7:9"
+        );
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = StateBackend::Memory.open(Rules::default()).unwrap();
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(4)));
+        assert!(store.submit(deposit.clone()).unwrap().is_ok());
+        assert!(store.submit(deposit).unwrap().is_err());
+        let account = store.account(1).unwrap().unwrap();
+        assert_eq!(account.balance(None).available, dec!(4));
+        assert!(store.account(2).unwrap().is_none());
+    }
+
+    /// Just enough of a Redis server for `RedisStore`. Nothing else writes
+    /// to it, so a watched key never changes under a transaction.
+    #[cfg(feature = "redis")]
+    #[derive(Default)]
+    struct FakeRedis {
+        values: std::collections::HashMap<String, String>,
+        queued: Option<Vec<Vec<String>>>,
+        writes: usize,
+    }
+
+    #[cfg(feature = "redis")]
+    impl FakeRedis {
+        fn run(&mut self, command: Vec<String>) -> redis::Value {
+            use redis::Value;
+            match command[0].as_str() {
+                "MULTI" => {
+                    self.queued = Some(Vec::new());
+                    Value::Okay
+                }
+                "EXEC" => {
+                    let queued = self.queued.take().unwrap();
+                    Value::Array(queued.into_iter().map(|c| self.apply(c)).collect())
+                }
+                _ if self.queued.is_some() => {
+                    self.queued.as_mut().unwrap().push(command);
+                    Value::SimpleString("QUEUED".to_string())
+                }
+                _ => self.apply(command),
+            }
+        }
+
+        fn apply(&mut self, command: Vec<String>) -> redis::Value {
+            use redis::Value;
+            let get = |values: &std::collections::HashMap<String, String>, key: &String| {
+                values
+                    .get(key)
+                    .map_or(Value::Nil, |value| Value::BulkString(value.clone().into()))
+            };
+            match command[0].as_str() {
+                "WATCH" | "UNWATCH" => Value::Okay,
+                "GET" => get(&self.values, &command[1]),
+                "MGET" => Value::Array(command[1..].iter().map(|k| get(&self.values, k)).collect()),
+                "SET" => {
+                    self.writes += 1;
+                    self.values.insert(command[1].clone(), command[2].clone());
+                    Value::Okay
+                }
+                "DEL" => {
+                    self.writes += 1;
+                    Value::Int(self.values.remove(&command[1]).is_some().into())
+                }
+                other => panic!("unexpected command {other}"),
+            }
+        }
+
+        /// Answer every command packed in `bytes`
+        fn answer(&mut self, bytes: &[u8], commands: usize) -> Vec<redis::Value> {
+            let mut parser = redis::Parser::new();
+            let mut reader = bytes;
+            (0..commands)
+                .map(|_| {
+                    let redis::Value::Array(args) = parser.parse_value(&mut reader).unwrap() else {
+                        panic!("a command is an array");
+                    };
+                    let command = args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            redis::Value::BulkString(arg) => String::from_utf8(arg).unwrap(),
+                            other => panic!("unexpected argument {other:?}"),
+                        })
+                        .collect();
+                    self.run(command)
+                })
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    impl redis::ConnectionLike for FakeRedis {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+            Ok(self.answer(cmd, 1).pop().unwrap())
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            cmd: &[u8],
+            offset: usize,
+            count: usize,
+        ) -> redis::RedisResult<Vec<redis::Value>> {
+            Ok(self.answer(cmd, offset + count).split_off(offset))
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_redis_store_writes_back_rejected_rows_that_change_the_account() {
+        use crate::models::AccountStatus;
+        use rust_decimal::Decimal;
+
+        let mut store = RedisStore {
+            connection: FakeRedis::default(),
+            rules: Rules::default(),
+            prefix: RedisStore::DEFAULT_PREFIX.to_string(),
+        };
+        let deposit = |tx, amount| Transaction::new(TransactionType::Deposit, 1, tx, Some(amount));
+        assert!(store.submit(deposit(1, Decimal::MAX)).unwrap().is_ok());
+        assert_eq!(store.connection.writes, 2);
+
+        // A duplicate changes nothing, so nothing is written
+        assert!(store.submit(deposit(1, dec!(1))).unwrap().is_err());
+        assert_eq!(store.connection.writes, 2);
+
+        // The overflow is rejected but freezes the account
+        assert!(store.submit(deposit(2, dec!(1))).unwrap().is_err());
+        assert_eq!(store.connection.writes, 3);
+        let account = store.account(1).unwrap().unwrap();
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert_eq!(account.balance(None).total, Decimal::MAX);
+        assert!(
+            !store
+                .connection
+                .values
+                .contains_key(&transaction_key("engine", TxKey::global(2)))
+        );
+    }
+}
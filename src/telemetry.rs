@@ -0,0 +1,196 @@
+use metrics::{counter, gauge, histogram};
+#[cfg(feature = "cli")]
+use metrics_exporter_prometheus::PrometheusBuilder;
+#[cfg(feature = "cli")]
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::LogFormat;
+#[cfg(feature = "cli")]
+use crate::error::EngineError;
+use crate::models::ClientId;
+use crate::outcome::TransactionOutcome;
+
+/// Rows read from the input, before validation
+pub const ROWS_INGESTED: &str = "engine_rows_ingested_total";
+/// Handled transactions, labelled by `type` and `outcome`
+pub const TRANSACTIONS: &str = "engine_transactions_total";
+/// Time spent applying a single transaction
+pub const TRANSACTION_LATENCY: &str = "engine_transaction_latency_seconds";
+/// Client tasks currently running
+pub const ACTIVE_CLIENT_TASKS: &str = "engine_active_client_tasks";
+/// Transactions queued on a client's channel, labelled by `client`
+pub const CLIENT_CHANNEL_DEPTH: &str = "engine_client_channel_depth";
+/// Estimated bytes held by account and transaction state
+pub const STATE_MEMORY: &str = "engine_state_memory_bytes";
+
+/// Keeps the trace exporter, if any, alive; pending spans are flushed when
+/// this is dropped
+#[derive(Debug, Default)]
+pub struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush traces: {e}");
+        }
+    }
+}
+
+/// Install the global tracing subscriber.
+///
+/// Events go to stderr in the given format, filtered by `RUST_LOG` (default
+/// `info`). When built
+/// with the `otlp` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are
+/// also exported over OTLP/HTTP. Call this before starting the async runtime.
+pub fn init_tracing(format: LogFormat) -> TracingGuard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let text = (format == LogFormat::Text)
+        .then(|| tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_writer(std::io::stderr)
+    });
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json);
+
+    #[cfg(feature = "otlp")]
+    {
+        let provider = std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").and_then(|_| {
+            otlp_provider()
+                .inspect_err(|e| eprintln!("OTLP exporter disabled: {e}"))
+                .ok()
+        });
+        let layer = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("rust-transaction-engine"))
+        });
+        registry.with(layer).init();
+        TracingGuard { provider }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.init();
+        TracingGuard::default()
+    }
+}
+
+/// Build a tracer provider that batches spans to the OTLP/HTTP endpoint
+/// configured through the standard `OTEL_EXPORTER_OTLP_*` variables
+#[cfg(feature = "otlp")]
+fn otlp_provider() -> Result<opentelemetry_sdk::trace::SdkTracerProvider, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| e.to_string())?;
+    Ok(opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name("rust-transaction-engine")
+                .build(),
+        )
+        .build())
+}
+
+/// Serve Prometheus metrics on `addr` at `/metrics` for the rest of the
+/// process lifetime.
+///
+/// Until this is called every recording function below is a no-op.
+#[cfg(feature = "cli")]
+pub fn install_prometheus(addr: SocketAddr) -> Result<(), EngineError> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .map_err(|e| EngineError::Usage(format!("cannot serve metrics on {addr}: {e}")))
+}
+
+/// Count one input row
+pub fn record_row_ingested() {
+    counter!(ROWS_INGESTED).increment(1);
+}
+
+/// Count one handled transaction and how long it took
+pub fn record_outcome(outcome: &TransactionOutcome, elapsed: Duration) {
+    let (transaction, result) = match outcome {
+        Ok(applied) => (&applied.transaction, "applied"),
+        Err(rejected) => (&rejected.transaction, "rejected"),
+    };
+    let tx_type = transaction.tx_type.name();
+    counter!(TRANSACTIONS, "type" => tx_type.clone(), "outcome" => result).increment(1);
+    histogram!(TRANSACTION_LATENCY, "type" => tx_type).record(elapsed);
+}
+
+/// Track a client task starting (`+1`) or finishing (`-1`)
+pub fn record_client_task(delta: f64) {
+    gauge!(ACTIVE_CLIENT_TASKS).increment(delta);
+}
+
+/// Record how many transactions are waiting on a client's channel
+pub fn record_channel_depth(client: ClientId, depth: usize) {
+    gauge!(CLIENT_CHANNEL_DEPTH, "client" => client.to_string()).set(depth as f64);
+}
+
+/// Record the latest estimate of the state's memory use
+pub fn record_state_memory(bytes: u64) {
+    gauge!(STATE_MEMORY).set(bytes as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{AccountsMap, Transaction, TransactionType, TransactionsMap};
+    use crate::transaction::handle_transaction;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_transactions_counted_by_type_and_outcome() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let accounts = AccountsMap::new();
+            let transactions = TransactionsMap::new();
+            for (tx, amount) in [(1, 5), (2, 10)] {
+                let _ = handle_transaction(
+                    Transaction::new(
+                        TransactionType::Withdrawal,
+                        1,
+                        tx,
+                        Some(Decimal::from(amount)),
+                    ),
+                    &accounts,
+                    &transactions,
+                );
+            }
+        });
+
+        let rejected = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find(|(key, _, _, _)| {
+                key.key().name() == TRANSACTIONS
+                    && key
+                        .key()
+                        .labels()
+                        .any(|l| l.key() == "outcome" && l.value() == "rejected")
+            })
+            .map(|(_, _, _, value)| value);
+        assert_eq!(rejected, Some(DebugValue::Counter(2)));
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::EngineError;
+
+/// One upstream provider's transactions file, written `NAME=PATH`.
+///
+/// Each tenant is processed with its own state, so client and transaction
+/// IDs only need to be unique within a tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    /// Letters, digits, `_` and `-`; it ends up in file names
+    pub name: String,
+    pub input: PathBuf,
+}
+
+impl Tenant {
+    /// The tenant's own copy of a per-run file: `rejects.csv` becomes
+    /// `rejects.NAME.csv`
+    pub fn path_for(&self, path: &Path) -> PathBuf {
+        let mut file_name = OsString::from(path.file_stem().unwrap_or_default());
+        file_name.push(".");
+        file_name.push(&self.name);
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        path.with_file_name(file_name)
+    }
+
+    /// Where the tenant's accounts are written within `dir`
+    pub fn accounts_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.csv", self.name))
+    }
+}
+
+impl FromStr for Tenant {
+    type Err = EngineError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EngineError::Usage(format!(
+                "invalid tenant '{raw}': expected NAME=PATH with a name of letters, digits, _ or -"
+            ))
+        };
+        let (name, input) = raw.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim();
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name || input.trim().is_empty() {
+            return Err(invalid());
+        }
+        Ok(Tenant {
+            name: name.to_string(),
+            input: PathBuf::from(input.trim()),
+        })
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.input.display())
+    }
+}
+
+/// Fail if two tenants share a name, since their output files would clash
+pub fn check_unique(tenants: &[Tenant]) -> Result<(), EngineError> {
+    let mut names = BTreeSet::new();
+    for tenant in tenants {
+        if !names.insert(tenant.name.as_str()) {
+            return Err(EngineError::Usage(format!(
+                "tenant {} is given twice",
+                tenant.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tenants_and_their_paths() {
+        let tenant: Tenant = "acme=in/acme transactions.csv".parse().unwrap();
+        assert_eq!(tenant.input, PathBuf::from("in/acme transactions.csv"));
+        assert_eq!(
+            tenant.path_for(Path::new("out/rejects.csv")),
+            PathBuf::from("out/rejects.acme.csv")
+        );
+        assert_eq!(
+            tenant.path_for(Path::new("state")),
+            PathBuf::from("state.acme")
+        );
+        assert_eq!(
+            tenant.accounts_path(Path::new("out")),
+            PathBuf::from("out/acme.csv")
+        );
+
+        for invalid in ["acme", "=a.csv", "acme=", "ac/me=a.csv", "a b=a.csv"] {
+            assert!(invalid.parse::<Tenant>().is_err(), "{invalid}");
+        }
+        let twice: Vec<Tenant> = ["a=1.csv", "b=2.csv", "a=3.csv"]
+            .iter()
+            .map(|raw| raw.parse().unwrap())
+            .collect();
+        assert!(check_unique(&twice).is_err());
+        assert!(check_unique(&twice[..2]).is_ok());
+    }
+}
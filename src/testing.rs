@@ -0,0 +1,264 @@
+use proptest::prelude::*;
+use proptest::sample::Index;
+use rust_decimal::Decimal;
+use std::fmt;
+
+use crate::engine::Engine;
+use crate::invariants::{InvariantViolation, check_account};
+use crate::models::{Account, ClientId, Transaction, TransactionType, TxId};
+use crate::outcome::TransactionOutcome;
+
+/// Types the generators pick from; conversions need an exchange-rate table
+/// and custom types a registered handler, so both are left out
+const TYPES: [TransactionType; 8] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Hold,
+    TransactionType::Release,
+    TransactionType::Close,
+];
+
+/// Positive amounts of up to four decimal places, below 100
+pub fn amount() -> impl Strategy<Value = Decimal> {
+    (1i64..1_000_000).prop_map(|units| Decimal::new(units, 4))
+}
+
+/// Sequences of up to `max_len` well-formed deposits, withdrawals, disputes,
+/// resolves and chargebacks by clients 1 to `clients`.
+///
+/// Deposits and withdrawals get fresh transaction IDs, and every dispute,
+/// resolve or chargeback names an earlier deposit of the same client. Rows
+/// may still be turned down, for example a withdrawal above the balance or
+/// a resolve of a deposit that is not under dispute.
+pub fn valid_transactions(
+    clients: ClientId,
+    max_len: usize,
+) -> impl Strategy<Value = Vec<Transaction>> {
+    // The first five of `TYPES`: the ones that move funds
+    let step = (
+        prop::sample::select(&TYPES[..5]),
+        1..=clients,
+        amount(),
+        any::<Index>(),
+    );
+    prop::collection::vec(step, 0..=max_len).prop_map(|steps| {
+        let mut deposits: Vec<(ClientId, TxId)> = Vec::new();
+        let mut next_tx: TxId = 1;
+        let mut transactions = Vec::with_capacity(steps.len());
+        for (tx_type, client, amount, pick) in steps {
+            let transaction = match tx_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => None,
+                _ if deposits.is_empty() => None,
+                _ => {
+                    let (client, tx) = deposits[pick.index(deposits.len())];
+                    Some(Transaction::new(tx_type.clone(), client, tx, None))
+                }
+            };
+            let transaction = transaction.unwrap_or_else(|| {
+                let tx = next_tx;
+                next_tx += 1;
+                if tx_type == TransactionType::Withdrawal {
+                    Transaction::new(tx_type, client, tx, Some(amount))
+                } else {
+                    deposits.push((client, tx));
+                    Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+                }
+            });
+            transactions.push(transaction);
+        }
+        transactions
+    })
+}
+
+/// Sequences of up to `max_len` rows of any built-in type except `convert`,
+/// with clients 1 to `clients`, transaction IDs 1 to `max_len` and amounts
+/// that may be missing, zero, negative or finer than four decimal places.
+/// Most rows are turned down; the point is that none of them may break an
+/// invariant on the way.
+pub fn arbitrary_transactions(
+    clients: ClientId,
+    max_len: usize,
+) -> impl Strategy<Value = Vec<Transaction>> {
+    let max_tx = TxId::try_from(max_len.max(1)).unwrap_or(TxId::MAX);
+    let amount = prop_oneof![
+        3 => amount().prop_map(Some),
+        1 => Just(None),
+        1 => Just(Some(Decimal::ZERO)),
+        1 => amount().prop_map(|amount| Some(-amount)),
+        1 => (1i64..1_000_000_000).prop_map(|units| Some(Decimal::new(units, 8))),
+    ];
+    let step = (
+        prop::sample::select(&TYPES[..]),
+        1..=clients,
+        1..=max_tx,
+        amount,
+    );
+    prop::collection::vec(
+        step.prop_map(|(tx_type, client, tx, amount)| {
+            Transaction::new(tx_type, client, tx, amount)
+        }),
+        0..=max_len,
+    )
+}
+
+/// A global invariant broken by one transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The account is inconsistent after the transaction: held funds below
+    /// zero, or a total that is not available plus held
+    Invariant(InvariantViolation),
+    /// A rejected transaction still changed the client's account
+    RejectedChangedAccount { client: ClientId, tx: TxId },
+    /// A locked account's balances moved outside the dispute, resolve and
+    /// chargeback flow of its earlier deposits
+    LockedBalanceChanged {
+        client: ClientId,
+        tx: TxId,
+        tx_type: TransactionType,
+    },
+    /// A locked account became unlocked
+    Unlocked { client: ClientId, tx: TxId },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Invariant(violation) => violation.fmt(f),
+            Violation::RejectedChangedAccount { client, tx } => {
+                write!(f, "Account {client} changed although Tx: {tx} was rejected")
+            }
+            Violation::LockedBalanceChanged {
+                client,
+                tx,
+                tx_type,
+            } => write!(
+                f,
+                "Locked account {client} changed balance on {} Tx: {tx}",
+                tx_type.as_str()
+            ),
+            Violation::Unlocked { client, tx } => {
+                write!(f, "Account {client} was unlocked by Tx: {tx}")
+            }
+        }
+    }
+}
+
+/// Check the transaction's client account before and after it was applied
+pub fn check_step(
+    before: Option<&Account>,
+    transaction: &Transaction,
+    outcome: &TransactionOutcome,
+    after: Option<&Account>,
+) -> Vec<Violation> {
+    let (client, tx) = (transaction.client, transaction.tx);
+    let mut violations: Vec<_> = after
+        .map(|account| check_account(account, Some(tx)))
+        .unwrap_or_default()
+        .into_iter()
+        .map(Violation::Invariant)
+        .collect();
+    // A client's first row opens an empty account even when it is rejected
+    let opened = |account: Option<&Account>| account.cloned().unwrap_or(Account::new(client));
+    if outcome.is_err() && opened(before) != opened(after) {
+        violations.push(Violation::RejectedChangedAccount { client, tx });
+    }
+    if let (Some(before), Some(after)) = (before, after)
+        && before.is_locked()
+    {
+        let dispute_flow = matches!(
+            transaction.tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if !dispute_flow && before.balances != after.balances {
+            violations.push(Violation::LockedBalanceChanged {
+                client,
+                tx,
+                tx_type: transaction.tx_type.clone(),
+            });
+        }
+        if !after.is_locked() {
+            violations.push(Violation::Unlocked { client, tx });
+        }
+    }
+    violations
+}
+
+/// Submit every transaction to the engine, checking each step, and stop at
+/// the first one that breaks an invariant
+pub fn check_sequence(
+    engine: &mut Engine,
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> Result<(), Violation> {
+    for transaction in transactions {
+        let before = engine.account(transaction.client);
+        let outcome = engine.submit(transaction.clone());
+        let after = engine.account(transaction.client);
+        let violations = check_step(before.as_ref(), &transaction, &outcome, after.as_ref());
+        if let Some(violation) = violations.into_iter().next() {
+            return Err(violation);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DuplicateTxPolicy, Rules};
+    use crate::models::AccountStatus;
+    use crate::outcome::Applied;
+    use rust_decimal_macros::dec;
+
+    proptest! {
+        #[test]
+        fn valid_sequences_keep_invariants(transactions in valid_transactions(4, 60)) {
+            let mut engine = Engine::new(Rules::default());
+            if let Err(violation) = check_sequence(&mut engine, transactions) {
+                prop_assert!(false, "{}", violation);
+            }
+        }
+
+        #[test]
+        fn arbitrary_sequences_keep_invariants(
+            transactions in arbitrary_transactions(3, 40),
+            per_client in any::<bool>(),
+        ) {
+            let mut rules = Rules::default();
+            if per_client {
+                rules.duplicate_tx = DuplicateTxPolicy::PerClient;
+            }
+            let mut engine = Engine::new(rules);
+            if let Err(violation) = check_sequence(&mut engine, transactions) {
+                prop_assert!(false, "{}", violation);
+            }
+        }
+    }
+
+    #[test]
+    fn test_detects_locked_balance_change() {
+        let mut before = Account::new(1);
+        before.status = AccountStatus::ChargebackLocked;
+        let mut after = before.clone();
+        after.balance_mut(None).available = dec!(5);
+        after.balance_mut(None).total = dec!(5);
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 2, Some(dec!(5)));
+        let outcome = TransactionOutcome::Ok(Applied {
+            transaction: deposit.clone(),
+            amount: dec!(5),
+            conversion: None,
+            payouts: Vec::new(),
+            shortfall: None,
+        });
+        assert_eq!(
+            check_step(Some(&before), &deposit, &outcome, Some(&after)),
+            vec![Violation::LockedBalanceChanged {
+                client: 1,
+                tx: 2,
+                tx_type: TransactionType::Deposit,
+            }]
+        );
+    }
+}
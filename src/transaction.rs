@@ -1,297 +1,1051 @@
 use dashmap::mapref::entry::Entry;
-use log::warn;
+use dashmap::mapref::one::RefMut;
 use rust_decimal::Decimal;
-use std::error::Error;
-
-use crate::account::mutate_account_balance;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use tracing::{debug, debug_span};
+// The standard clock panics in the browser
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+use crate::account::{mutate_account_balance, round_amount};
+use crate::compact;
+use crate::config::{ClientMismatchPolicy, ClosePolicy, DisputeShortfallPolicy, Rules};
+use crate::error::EngineError;
+use crate::middleware::MapsContext;
 use crate::models::{
-    Account, AccountsMap, Transaction, TransactionRecord, TransactionType, TransactionsMap,
+    Account, AccountStatus, AccountsMap, ClientId, Conversion, Currency, Payout, Transaction,
+    TransactionRecord, TransactionType, TransactionsMap, TxKey,
 };
-
+use crate::outcome::{Applied, Rejected, Shortfall, TransactionOutcome};
+use crate::telemetry;
+
+/// Apply a transaction and report whether it was applied or rejected.
+///
+/// An applied outcome carries the amount that was moved, which for disputes,
+/// resolves and chargebacks is the amount of the referenced deposit. Their
+/// `currency` is likewise filled in from the referenced deposit.
 pub fn handle_transaction(
     transaction: Transaction,
     accounts: &AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let client_id = transaction.client;
+) -> TransactionOutcome {
+    handle_transaction_with(transaction, accounts, transactions, &Rules::default())
+}
 
-    // Check if account exists and is locked
-    if let Some(account) = accounts.get(&client_id) {
-        if account.locked
-            && !matches!(
-                transaction.tx_type,
-                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
-            )
-        {
-            warn!(
-                "Transaction ignored: Account {} is locked (Tx ID: {})",
-                client_id, transaction.tx
-            );
-            return Ok(());
+/// Like `handle_transaction`, but applying the given business rules.
+///
+/// Runs inside a `transaction` span carrying the tx ID, client and type.
+pub fn handle_transaction_with(
+    transaction: Transaction,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> TransactionOutcome {
+    let _span = transaction_span(&transaction).entered();
+    let started = Instant::now();
+    let outcome = match &rules.middleware {
+        Some(chain) => {
+            let context = MapsContext {
+                accounts,
+                transactions,
+            };
+            let outcome = match chain.before(transaction, &context) {
+                Ok(transaction) => {
+                    let result = apply_transaction(&transaction, accounts, transactions, rules);
+                    outcome_of(transaction, result)
+                }
+                Err((transaction, reason)) => {
+                    let error = EngineError::MiddlewareRejected {
+                        client: transaction.client,
+                        tx: transaction.tx,
+                        reason,
+                    };
+                    outcome_of(transaction, Err(error))
+                }
+            };
+            chain.after(&outcome);
+            outcome
+        }
+        None => {
+            let result = apply_transaction(&transaction, accounts, transactions, rules);
+            outcome_of(transaction, result)
         }
+    };
+    telemetry::record_outcome(&outcome, started.elapsed());
+    outcome
+}
+
+/// Apply a run of one client's deposits and withdrawals in order, taking the
+/// account's lock once for the whole run.
+///
+/// Each row is checked and recorded as `handle_transaction_with` would, so
+/// the outcomes are the same as handling the rows one at a time. A row of any
+/// other type or client releases the lock and is handled on its own, and
+/// with middleware every row is, since its layers may look at the account.
+pub fn handle_run(
+    run: Vec<Transaction>,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> Vec<TransactionOutcome> {
+    if rules.middleware.is_some() {
+        return run
+            .into_iter()
+            .map(|transaction| handle_transaction_with(transaction, accounts, transactions, rules))
+            .collect();
     }
+    let Some(client_id) = run.first().map(|transaction| transaction.client) else {
+        return Vec::new();
+    };
+    let mut account_entry = None;
+    run.into_iter()
+        .map(|transaction| {
+            let _span = transaction_span(&transaction).entered();
+            let started = Instant::now();
+            let result = match transaction.tx_type {
+                TransactionType::Deposit | TransactionType::Withdrawal
+                    if transaction.client == client_id =>
+                {
+                    apply_in_run(
+                        &transaction,
+                        &mut account_entry,
+                        accounts,
+                        transactions,
+                        rules,
+                    )
+                }
+                _ => {
+                    account_entry = None;
+                    apply_transaction(&transaction, accounts, transactions, rules)
+                }
+            };
+            let outcome = outcome_of(transaction, result);
+            telemetry::record_outcome(&outcome, started.elapsed());
+            outcome
+        })
+        .collect()
+}
+
+fn transaction_span(transaction: &Transaction) -> tracing::Span {
+    debug_span!(
+        "transaction",
+        tx = transaction.tx,
+        client = transaction.client,
+        r#type = transaction.tx_type.as_str()
+    )
+}
 
-    match transaction.tx_type {
-        TransactionType::Deposit => handle_deposit(transaction, accounts, transactions),
-        TransactionType::Withdrawal => handle_withdrawal(transaction, accounts, transactions),
-        TransactionType::Dispute => handle_dispute(transaction, accounts, transactions),
-        TransactionType::Resolve => handle_resolve(transaction, accounts, transactions),
-        TransactionType::Chargeback => handle_chargeback(transaction, accounts, transactions),
+/// Turn what applying a transaction did into its outcome
+fn outcome_of(
+    mut transaction: Transaction,
+    result: Result<Moved, EngineError>,
+) -> TransactionOutcome {
+    match result {
+        Ok(moved) => {
+            debug!(amount = %moved.amount, "transaction applied");
+            transaction.currency = moved.currency;
+            Ok(Applied {
+                transaction,
+                amount: moved.amount,
+                conversion: moved.conversion,
+                payouts: moved.payouts,
+                shortfall: moved.shortfall,
+            })
+        }
+        Err(error) => {
+            debug!(
+                reason = error.reason_code(),
+                "transaction rejected: {error}"
+            );
+            Err(Rejected { transaction, error })
+        }
     }
 }
 
-fn handle_deposit(
-    transaction: Transaction,
+/// Funds moved by a transaction that was applied
+struct Moved {
+    amount: Decimal,
+    /// Currency `amount` was moved in
+    currency: Option<Currency>,
+    conversion: Option<Conversion>,
+    payouts: Vec<Payout>,
+    shortfall: Option<Shortfall>,
+}
+
+fn apply_transaction(
+    transaction: &Transaction,
     accounts: &AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if let Some(amount) = transaction.amount {
-        if amount <= Decimal::ZERO {
-            return Ok(());
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let client_id = transaction.client;
+
+    if let Some(account) = accounts.get(&client_id) {
+        check_status(&account, transaction, rules)?;
+    }
+
+    let handler = match transaction.tx_type {
+        TransactionType::Deposit => handle_deposit,
+        TransactionType::Withdrawal => handle_withdrawal,
+        TransactionType::Resolve => handle_resolve,
+        TransactionType::Hold => handle_hold,
+        TransactionType::Release => handle_release,
+        TransactionType::Dispute => {
+            return handle_dispute(transaction, accounts, transactions, rules);
         }
-    } else {
+        TransactionType::Chargeback => {
+            return handle_chargeback(transaction, accounts, transactions, rules);
+        }
+        TransactionType::Convert => {
+            return handle_convert(transaction, accounts, transactions, rules);
+        }
+        TransactionType::Close => return handle_close(transaction, accounts, rules),
+        TransactionType::Custom(_) => {
+            return handle_custom(transaction, accounts, transactions, rules);
+        }
+    };
+    let (amount, currency) = handler(transaction, accounts, transactions, rules)?;
+    Ok(Moved {
+        amount,
+        currency,
+        conversion: None,
+        payouts: Vec::new(),
+        shortfall: None,
+    })
+}
+
+/// Check that the account's status lets a transaction through, see
+/// `AccountStatus::allows`; frozen and chargeback-locked accounts take only
+/// the dispute-flow rows in `rules.locked_allows`
+fn check_status(
+    account: &Account,
+    transaction: &Transaction,
+    rules: &Rules,
+) -> Result<(), EngineError> {
+    let allowed = match account.status {
+        AccountStatus::Frozen | AccountStatus::ChargebackLocked => {
+            rules.locked_allows.allows(&transaction.tx_type)
+        }
+        status => status.allows(&transaction.tx_type),
+    };
+    if allowed {
         return Ok(());
     }
+    let (client, tx) = (transaction.client, transaction.tx);
+    Err(match account.status {
+        AccountStatus::Closed => EngineError::AccountClosed { client, tx },
+        AccountStatus::ReviewHold => EngineError::AccountOnHold { client, tx },
+        _ => EngineError::AccountLocked { client, tx },
+    })
+}
 
+/// Extract a strictly positive amount or fail with `InvalidAmount`.
+///
+/// Parsed rows never have more than four decimal places, but transactions
+/// submitted through the library can, and `rules.rounding` may keep fewer.
+/// The amount is rounded here the way balances are; applied as it is, it
+/// would be rounded out of each balance field separately and leave `total`
+/// off from `available + held` after a dispute.
+fn positive_amount(transaction: &Transaction, rules: &Rules) -> Result<Decimal, EngineError> {
+    match transaction
+        .amount
+        .map(|amount| round_amount(amount, rules.rounding))
+    {
+        Some(amount) if amount > Decimal::ZERO => Ok(amount),
+        _ => Err(EngineError::InvalidAmount {
+            client: transaction.client,
+            tx: transaction.tx,
+        }),
+    }
+}
+
+fn handle_deposit(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> Result<(Decimal, Option<Currency>), EngineError> {
+    let amount = positive_amount(transaction, rules)?;
     let client_id = transaction.client;
 
     if let Some(account) = accounts.get(&client_id) {
-        if account.locked {
-            warn!(
-                "Deposit ignored: Account {} is locked (Tx ID: {})",
-                client_id, transaction.tx
-            );
-            return Ok(());
-        }
+        check_status(&account, transaction, rules)?;
     }
 
-    let mut account_entry = accounts.entry(client_id).or_insert_with(|| Account {
-        client: client_id,
-        ..Default::default()
+    let mut account_entry = accounts.entry(client_id).or_insert_with(|| {
+        debug!("opening account");
+        Account {
+            client: client_id,
+            ..Default::default()
+        }
     });
 
-    if let Some(amount) = transaction.amount {
-        if insert_transaction(transactions, transaction.tx, client_id, amount) {
-            mutate_account_balance(&mut account_entry, amount, Decimal::ZERO, amount);
-        } else {
-            warn!(
-                "Duplicate transaction ID {} for deposit - skipping (Client ID: {})",
-                transaction.tx, client_id
-            );
-        }
+    let currency = transaction.currency;
+    let key = rules.duplicate_tx.key(client_id, transaction.tx);
+    if !insert_transaction(transactions, key, new_record(transaction, amount), rules) {
+        return Err(EngineError::DuplicateTx {
+            client: client_id,
+            tx: transaction.tx,
+        });
+    }
+    if let Err(e) = mutate_account_balance(
+        &mut account_entry,
+        currency,
+        amount,
+        Decimal::ZERO,
+        amount,
+        rules.rounding,
+    ) {
+        transactions.remove(&key);
+        return Err(e);
     }
 
-    Ok(())
+    account_entry.counts.deposits += 1;
+
+    Ok((amount, currency))
 }
 
 fn handle_withdrawal(
-    transaction: Transaction,
+    transaction: &Transaction,
     accounts: &AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if let Some(amount) = transaction.amount {
-        if amount <= Decimal::ZERO {
-            return Ok(());
-        }
-    } else {
-        return Ok(());
-    }
-
+    rules: &Rules,
+) -> Result<(Decimal, Option<Currency>), EngineError> {
+    let amount = positive_amount(transaction, rules)?;
     let client_id = transaction.client;
 
     if let Some(account) = accounts.get(&client_id) {
-        if account.locked {
-            warn!(
-                "Withdrawal ignored: Account {} is locked (Tx ID: {})",
-                client_id, transaction.tx
-            );
-            return Ok(());
-        }
+        check_status(&account, transaction, rules)?;
     }
 
-    let mut account_entry = accounts.entry(client_id).or_insert_with(|| Account {
-        client: client_id,
-        ..Default::default()
+    let mut account_entry = accounts.entry(client_id).or_insert_with(|| {
+        debug!("opening account");
+        Account {
+            client: client_id,
+            ..Default::default()
+        }
     });
 
-    if let Some(amount) = transaction.amount {
-        if account_entry.available >= amount {
-            if insert_transaction(transactions, transaction.tx, client_id, -amount) {
-                mutate_account_balance(&mut account_entry, -amount, Decimal::ZERO, -amount);
-            } else {
-                warn!(
-                    "Duplicate transaction ID {} for withdrawal - skipping (Client ID: {})",
-                    transaction.tx, client_id
-                );
-            }
-        } else {
-            warn!(
-                "Insufficient funds for withdrawal. Client: {}, Tx: {}, Amount: {}, Available: {}",
-                client_id, transaction.tx, amount, account_entry.available
-            );
-        }
+    let currency = transaction.currency;
+    let available = account_entry.balance(currency).available;
+    if available < amount {
+        debug!(%available, "withdrawal exceeds available funds");
+        return Err(EngineError::InsufficientFunds {
+            client: client_id,
+            tx: transaction.tx,
+            amount,
+            available,
+        });
+    }
+    let key = rules.duplicate_tx.key(client_id, transaction.tx);
+    if !insert_transaction(transactions, key, new_record(transaction, -amount), rules) {
+        return Err(EngineError::DuplicateTx {
+            client: client_id,
+            tx: transaction.tx,
+        });
+    }
+    if let Err(e) = mutate_account_balance(
+        &mut account_entry,
+        currency,
+        -amount,
+        Decimal::ZERO,
+        -amount,
+        rules.rounding,
+    ) {
+        transactions.remove(&key);
+        return Err(e);
     }
 
-    Ok(())
+    account_entry.counts.withdrawals += 1;
+
+    Ok((amount, currency))
 }
 
-fn handle_dispute(
-    transaction: Transaction,
-    accounts: &AccountsMap,
+/// Apply one deposit or withdrawal of a run, opening the client's account
+/// entry on the first row that gets that far and keeping it for the rest
+fn apply_in_run<'a>(
+    transaction: &Transaction,
+    account_entry: &mut Option<RefMut<'a, ClientId, Account>>,
+    accounts: &'a AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let amount = positive_amount(transaction, rules)?;
     let client_id = transaction.client;
-    let mut account_entry = accounts.entry(client_id).or_insert_with(|| Account {
-        client: client_id,
-        ..Default::default()
-    });
-
-    match transactions.get_mut(&transaction.tx) {
-        Some(mut tx_record) if tx_record.client == client_id && !tx_record.disputed => {
-            if tx_record.amount <= Decimal::ZERO {
-                warn!(
-                    "Dispute ignored: transaction {} is not a deposit (Client: {})",
-                    transaction.tx, client_id
-                );
-                return Ok(());
+    let account = match account_entry {
+        Some(account) => account,
+        None => account_entry.insert(accounts.entry(client_id).or_insert_with(|| {
+            debug!("opening account");
+            Account {
+                client: client_id,
+                ..Default::default()
             }
+        })),
+    };
+    check_status(account, transaction, rules)?;
+
+    let currency = transaction.currency;
+    let delta = if transaction.tx_type == TransactionType::Withdrawal {
+        let available = account.balance(currency).available;
+        if available < amount {
+            debug!(%available, "withdrawal exceeds available funds");
+            return Err(EngineError::InsufficientFunds {
+                client: client_id,
+                tx: transaction.tx,
+                amount,
+                available,
+            });
+        }
+        -amount
+    } else {
+        amount
+    };
+    let key = rules.duplicate_tx.key(client_id, transaction.tx);
+    if !insert_transaction(transactions, key, new_record(transaction, delta), rules) {
+        return Err(EngineError::DuplicateTx {
+            client: client_id,
+            tx: transaction.tx,
+        });
+    }
+    if let Err(e) = mutate_account_balance(
+        account,
+        currency,
+        delta,
+        Decimal::ZERO,
+        delta,
+        rules.rounding,
+    ) {
+        transactions.remove(&key);
+        return Err(e);
+    }
+    if transaction.tx_type == TransactionType::Withdrawal {
+        account.counts.withdrawals += 1;
+    } else {
+        account.counts.deposits += 1;
+    }
 
-            let dispute_amount = tx_record.amount;
-            tx_record.disputed = true;
+    Ok(Moved {
+        amount,
+        currency,
+        conversion: None,
+        payouts: Vec::new(),
+        shortfall: None,
+    })
+}
 
-            mutate_account_balance(
-                &mut account_entry,
-                -dispute_amount,
-                dispute_amount,
-                Decimal::ZERO,
-            );
-        }
-        _ => {
-            warn!(
-                "Dispute failed. Transaction not found or already disputed. Tx: {}, Client: {}",
-                transaction.tx, client_id
-            );
+/// Apply a row of a custom type through the handler registered for it.
+///
+/// The handler's signed amount is added to available and total funds and
+/// kept as the row's record, so its ID is taken like any other; a positive
+/// record can be disputed like a deposit.
+fn handle_custom(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let client_id = transaction.client;
+    let tx_type = transaction.tx_type.as_str();
+    let Some(handler) = rules.handlers.as_ref().and_then(|h| h.get(tx_type)) else {
+        return Err(EngineError::UnhandledType {
+            client: client_id,
+            tx: transaction.tx,
+            tx_type: tx_type.to_string(),
+        });
+    };
+
+    let mut account_entry = accounts.entry(client_id).or_insert_with(|| {
+        debug!("opening account");
+        Account {
+            client: client_id,
+            ..Default::default()
         }
+    });
+    let amount = handler.apply(transaction, &account_entry)?;
+
+    let currency = transaction.currency;
+    let key = rules.duplicate_tx.key(client_id, transaction.tx);
+    if !insert_transaction(transactions, key, new_record(transaction, amount), rules) {
+        return Err(EngineError::DuplicateTx {
+            client: client_id,
+            tx: transaction.tx,
+        });
+    }
+    if let Err(e) = mutate_account_balance(
+        &mut account_entry,
+        currency,
+        amount,
+        Decimal::ZERO,
+        amount,
+        rules.rounding,
+    ) {
+        transactions.remove(&key);
+        return Err(e);
     }
 
-    Ok(())
+    Ok(Moved {
+        amount,
+        currency,
+        conversion: None,
+        payouts: Vec::new(),
+        shortfall: None,
+    })
 }
 
-fn handle_resolve(
-    transaction: Transaction,
+/// Move funds between two of a client's currencies at the configured rate.
+///
+/// The source amount is truncated to 4 decimal places after conversion. The
+/// record keeps the negative source amount, like a withdrawal, plus the rate
+/// and converted amount for audit; it cannot be disputed.
+fn handle_convert(
+    transaction: &Transaction,
     accounts: &AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let amount = positive_amount(transaction, rules)?;
     let client_id = transaction.client;
-    let mut account_entry = accounts.entry(client_id).or_insert_with(|| Account {
+    let (from, to) = (transaction.currency, transaction.to_currency);
+    let no_rate = || EngineError::NoFxRate {
         client: client_id,
-        ..Default::default()
+        tx: transaction.tx,
+        from,
+        to,
+    };
+    let (Some(source), Some(target)) = (from, to) else {
+        return Err(no_rate());
+    };
+    let rate = rules
+        .fx_rates
+        .as_ref()
+        .and_then(|rates| rates.rate(source, target))
+        .ok_or_else(no_rate)?;
+    let converted = amount
+        .checked_mul(rate)
+        .map(|converted| round_amount(converted, rules.rounding).normalize())
+        .ok_or(EngineError::Overflow { client: client_id })?;
+
+    let mut account_entry = accounts.entry(client_id).or_insert_with(|| {
+        debug!("opening account");
+        Account {
+            client: client_id,
+            ..Default::default()
+        }
     });
 
-    match transactions.get_mut(&transaction.tx) {
-        Some(mut tx_record) if tx_record.client == client_id && tx_record.disputed => {
-            if tx_record.amount <= Decimal::ZERO {
-                warn!(
-                    "Resolve ignored: transaction {} is not a deposit (Client: {})",
-                    transaction.tx, client_id
-                );
-                return Ok(());
+    let available = account_entry.balance(from).available;
+    if available < amount {
+        debug!(%available, "conversion exceeds available funds");
+        return Err(EngineError::InsufficientFunds {
+            client: client_id,
+            tx: transaction.tx,
+            amount,
+            available,
+        });
+    }
+    let conversion = Conversion {
+        to: target,
+        rate,
+        converted,
+    };
+    let key = rules.duplicate_tx.key(client_id, transaction.tx);
+    let record = TransactionRecord {
+        conversion: Some(conversion),
+        ..new_record(transaction, -amount)
+    };
+    if !insert_transaction(transactions, key, record, rules) {
+        return Err(EngineError::DuplicateTx {
+            client: client_id,
+            tx: transaction.tx,
+        });
+    }
+    if let Err(e) = mutate_account_balance(
+        &mut account_entry,
+        from,
+        -amount,
+        Decimal::ZERO,
+        -amount,
+        rules.rounding,
+    ) {
+        transactions.remove(&key);
+        return Err(e);
+    }
+    if let Err(e) = mutate_account_balance(
+        &mut account_entry,
+        to,
+        converted,
+        Decimal::ZERO,
+        converted,
+        rules.rounding,
+    ) {
+        // Undoing the debit cannot overflow since it just succeeded
+        let _ = mutate_account_balance(
+            &mut account_entry,
+            from,
+            amount,
+            Decimal::ZERO,
+            amount,
+            rules.rounding,
+        );
+        transactions.remove(&key);
+        return Err(e);
+    }
+    debug!(%rate, %converted, to = %target, "funds converted");
+
+    Ok(Moved {
+        amount,
+        currency: from,
+        conversion: Some(conversion),
+        payouts: Vec::new(),
+        shortfall: None,
+    })
+}
+
+/// Look up the deposit referenced by a dispute/resolve/chargeback and check
+/// that it belongs to the client and is in the expected dispute state.
+///
+/// A row that names a currency must name the deposit's; one that leaves the
+/// column empty refers to the deposit in whatever currency it was made.
+fn disputable_record<'a>(
+    transaction: &Transaction,
+    transactions: &'a TransactionsMap,
+    rules: &Rules,
+    expect_disputed: bool,
+) -> Result<RefMut<'a, TxKey, TransactionRecord>, EngineError> {
+    let client = transaction.client;
+    let tx = transaction.tx;
+    let key = rules.duplicate_tx.key(client, tx);
+    if let Some(spill) = &rules.spill {
+        spill.fault_in(key, client, transactions);
+    }
+    let Some(tx_record) = transactions.get_mut(&key) else {
+        // Another client's record stays on disk, but still names its owner,
+        // as does the tombstone of a compacted one
+        let spilled = rules.spill.as_ref().and_then(|s| s.owner(&key));
+        let compacted = rules.tombstones.as_ref().and_then(|t| t.owner(&key));
+        return Err(match (spilled, compacted) {
+            (Some(owner), _) => EngineError::ClientMismatch { client, tx, owner },
+            (None, Some(owner)) if owner != client => {
+                EngineError::ClientMismatch { client, tx, owner }
             }
+            (None, Some(_)) => EngineError::TxCompacted { client, tx },
+            (None, None) => EngineError::UnknownTx { client, tx },
+        });
+    };
+
+    if tx_record.client != client {
+        return Err(EngineError::ClientMismatch {
+            client,
+            tx,
+            owner: tx_record.client,
+        });
+    }
+    if transaction.currency.is_some() && transaction.currency != tx_record.currency {
+        return Err(EngineError::CurrencyMismatch {
+            client,
+            tx,
+            currency: transaction.currency,
+            expected: tx_record.currency,
+        });
+    }
+    if tx_record.disputed != expect_disputed {
+        return Err(if expect_disputed {
+            EngineError::NotDisputed { client, tx }
+        } else {
+            EngineError::AlreadyDisputed { client, tx }
+        });
+    }
+    if tx_record.amount <= Decimal::ZERO {
+        return Err(EngineError::NotADeposit { client, tx });
+    }
 
-            let resolve_amount = tx_record.amount;
-            tx_record.disputed = false;
+    Ok(tx_record)
+}
 
-            mutate_account_balance(
-                &mut account_entry,
-                resolve_amount,
-                -resolve_amount,
-                Decimal::ZERO,
-            );
-        }
-        Some(_) => {
-            warn!(
-                "Resolve ignored. Transaction not under dispute. Tx: {}, Client: {}",
-                transaction.tx, client_id
-            );
-        }
-        None => {
-            warn!(
-                "Resolve failed. Transaction not found. Tx: {}, Client: {}",
-                transaction.tx, client_id
-            );
+/// A client's account and the deposit one of its rows refers to, both held
+/// locked
+type Referenced<'a> = (
+    RefMut<'a, ClientId, Account>,
+    RefMut<'a, TxKey, TransactionRecord>,
+);
+
+/// The client's account and the deposit a dispute, resolve or chargeback
+/// refers to, checked as `disputable_record` does.
+///
+/// A rejected row leaves no account behind for the client who sent it,
+/// unless `rules.open_on_reference` asks for the old behaviour or the
+/// `review` client-mismatch policy puts that account on review hold. The
+/// account's map entry is taken first and held throughout, the same order
+/// deposits lock the two maps in.
+fn referenced_account<'a>(
+    transaction: &Transaction,
+    accounts: &'a AccountsMap,
+    transactions: &'a TransactionsMap,
+    rules: &Rules,
+    expect_disputed: bool,
+) -> Result<Referenced<'a>, EngineError> {
+    let client_id = transaction.client;
+    let entry = accounts.entry(client_id);
+    let open = |entry: Entry<'a, ClientId, Account>| {
+        entry.or_insert_with(|| {
+            debug!("opening account");
+            Account::new(client_id)
+        })
+    };
+    let error = match disputable_record(transaction, transactions, rules, expect_disputed) {
+        Ok(tx_record) => return Ok((open(entry), tx_record)),
+        Err(error) => error,
+    };
+    let review = rules.client_mismatch == ClientMismatchPolicy::Review
+        && matches!(error, EngineError::ClientMismatch { .. });
+    if review {
+        let mut account_entry = open(entry);
+        if account_entry.status.allows(&TransactionType::Hold) {
+            account_entry.status = AccountStatus::ReviewHold;
+            debug!("account placed on review hold for disputing another client's transaction");
         }
+    } else if rules.open_on_reference {
+        open(entry);
     }
+    Err(error)
+}
 
+/// Reject disputes filed more than `rules.dispute_window` after the deposit.
+///
+/// Disputes are allowed when either side lacks a timestamp.
+fn check_dispute_window(
+    transaction: &Transaction,
+    tx_record: &TransactionRecord,
+    rules: &Rules,
+) -> Result<(), EngineError> {
+    if let (Some(window), Some(deposited), Some(disputed)) = (
+        rules.dispute_window,
+        tx_record.timestamp,
+        transaction.timestamp,
+    ) && disputed - deposited > window
+    {
+        debug!(%deposited, %disputed, "dispute outside window");
+        return Err(EngineError::DisputeWindowExpired {
+            client: transaction.client,
+            tx: transaction.tx,
+            deposited,
+            disputed,
+        });
+    }
     Ok(())
 }
 
-fn handle_chargeback(
-    transaction: Transaction,
+/// Move the disputed deposit from `available` to `held`.
+///
+/// When the deposit is worth more than the account still has available,
+/// `rules.dispute_shortfall` decides what is held: the whole amount, taking
+/// `available` below zero, only what is available, or nothing while the
+/// account goes on review hold. The record keeps what was held so that the
+/// resolve or chargeback releases exactly that.
+fn handle_dispute(
+    transaction: &Transaction,
     accounts: &AccountsMap,
     transactions: &TransactionsMap,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let client_id = transaction.client;
-    let mut account_entry = accounts.entry(client_id).or_insert_with(|| Account {
-        client: client_id,
-        ..Default::default()
-    });
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let (mut account_entry, mut tx_record) =
+        referenced_account(transaction, accounts, transactions, rules, false)?;
+    check_dispute_window(transaction, &tx_record, rules)?;
+    let dispute_amount = tx_record.amount;
+    let currency = tx_record.currency;
+
+    let available = account_entry.balance(currency).available.max(Decimal::ZERO);
+    let uncovered = dispute_amount - available.min(dispute_amount);
+    let policy = rules.dispute_shortfall;
+    let hold = match policy {
+        DisputeShortfallPolicy::Cap if !uncovered.is_zero() => available,
+        DisputeShortfallPolicy::Review if !uncovered.is_zero() => Decimal::ZERO,
+        _ => dispute_amount,
+    };
+    mutate_account_balance(
+        &mut account_entry,
+        currency,
+        -hold,
+        hold,
+        Decimal::ZERO,
+        rules.rounding,
+    )?;
+    if policy == DisputeShortfallPolicy::Review && !uncovered.is_zero() {
+        account_entry.status = AccountStatus::ReviewHold;
+        debug!(%uncovered, "dispute exceeds available funds, account placed on review hold");
+    }
+    tx_record.disputed = true;
+    tx_record.held = Some(hold);
+    account_entry.counts.disputes += 1;
+    debug!(held = %account_entry.balance(currency).held, "funds moved to held");
+
+    Ok(Moved {
+        amount: hold,
+        currency,
+        conversion: None,
+        payouts: Vec::new(),
+        shortfall: (!uncovered.is_zero()).then_some(Shortfall { policy, uncovered }),
+    })
+}
 
-    match transactions.get_mut(&transaction.tx) {
-        Some(mut tx_record) if tx_record.client == client_id && tx_record.disputed => {
-            if tx_record.amount <= Decimal::ZERO {
-                warn!(
-                    "Chargeback ignored: transaction {} is not a deposit (Client: {})",
-                    transaction.tx, client_id
-                );
-                return Ok(());
-            }
+/// What the open dispute of `tx_record` holds
+fn disputed_hold(tx_record: &TransactionRecord) -> Decimal {
+    tx_record.held.unwrap_or(tx_record.amount)
+}
 
-            let chargeback_amount = tx_record.amount;
-            tx_record.disputed = false;
-            account_entry.locked = true;
+fn handle_resolve(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> Result<(Decimal, Option<Currency>), EngineError> {
+    let (mut account_entry, mut tx_record) =
+        referenced_account(transaction, accounts, transactions, rules, true)?;
+    let resolve_amount = disputed_hold(&tx_record);
+    let currency = tx_record.currency;
+
+    mutate_account_balance(
+        &mut account_entry,
+        currency,
+        resolve_amount,
+        -resolve_amount,
+        Decimal::ZERO,
+        rules.rounding,
+    )?;
+    tx_record.disputed = false;
+    tx_record.held = None;
+
+    Ok((resolve_amount, currency))
+}
 
-            mutate_account_balance(
-                &mut account_entry,
-                Decimal::ZERO,
-                -chargeback_amount,
-                -chargeback_amount,
-            );
-        }
-        Some(_) => {
-            warn!(
-                "Chargeback ignored. Transaction not under dispute. Tx: {}, Client: {}",
-                transaction.tx, client_id
-            );
+/// Reverse the disputed deposit and lock the account.
+///
+/// The whole deposit leaves `total`; a part its dispute could not hold comes
+/// out of `available` and is reported as the chargeback's shortfall.
+fn handle_chargeback(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    transactions: &TransactionsMap,
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let (mut account_entry, mut tx_record) =
+        referenced_account(transaction, accounts, transactions, rules, true)?;
+    let chargeback_amount = tx_record.amount;
+    let held = disputed_hold(&tx_record);
+    let uncovered = chargeback_amount - held;
+    let currency = tx_record.currency;
+
+    mutate_account_balance(
+        &mut account_entry,
+        currency,
+        -uncovered,
+        -held,
+        -chargeback_amount,
+        rules.rounding,
+    )?;
+    tx_record.disputed = false;
+    tx_record.held = None;
+    account_entry.status = AccountStatus::ChargebackLocked;
+    account_entry.counts.chargebacks += 1;
+    debug!("account locked after chargeback");
+    // Nothing can refer to a charged-back deposit again
+    let key = *tx_record.key();
+    drop(tx_record);
+    compact::compact(key, transactions, rules);
+
+    Ok(Moved {
+        amount: chargeback_amount,
+        currency,
+        conversion: None,
+        payouts: Vec::new(),
+        shortfall: (!uncovered.is_zero()).then_some(Shortfall {
+            policy: rules.dispute_shortfall,
+            uncovered,
+        }),
+    })
+}
+
+fn handle_hold(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    _transactions: &TransactionsMap,
+    _rules: &Rules,
+) -> Result<(Decimal, Option<Currency>), EngineError> {
+    if !place_on_review_hold(accounts, transaction.client) {
+        return Err(EngineError::AccountLocked {
+            client: transaction.client,
+            tx: transaction.tx,
+        });
+    }
+    Ok((Decimal::ZERO, None))
+}
+
+fn handle_release(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    _transactions: &TransactionsMap,
+    _rules: &Rules,
+) -> Result<(Decimal, Option<Currency>), EngineError> {
+    match accounts.get_mut(&transaction.client) {
+        Some(mut account) if account.status == AccountStatus::ReviewHold => {
+            account.status = AccountStatus::Active;
+            debug!("review hold released");
+            Ok((Decimal::ZERO, None))
         }
-        None => {
-            warn!(
-                "Chargeback failed. Transaction not found. Tx: {}, Client: {}",
-                transaction.tx, client_id
-            );
+        _ => Err(EngineError::NotOnHold {
+            client: transaction.client,
+            tx: transaction.tx,
+        }),
+    }
+}
+
+/// Close an existing account so it accepts nothing further.
+///
+/// Refused while any funds are held under dispute. A remaining available
+/// balance is refused too, unless the close policy is `payout`, in which case
+/// every positive balance is paid out in full and reported with the outcome.
+fn handle_close(
+    transaction: &Transaction,
+    accounts: &AccountsMap,
+    rules: &Rules,
+) -> Result<Moved, EngineError> {
+    let (client, tx) = (transaction.client, transaction.tx);
+    let Some(mut account) = accounts.get_mut(&client) else {
+        return Err(EngineError::UnknownClient { client });
+    };
+    let balances: Vec<_> = account.balances().collect();
+    if balances.iter().any(|(_, balance)| !balance.held.is_zero()) {
+        return Err(EngineError::FundsHeld { client, tx });
+    }
+    let payouts: Vec<_> = balances
+        .into_iter()
+        .filter(|(_, balance)| !balance.available.is_zero())
+        .map(|(currency, balance)| Payout {
+            currency,
+            amount: balance.available,
+        })
+        .collect();
+    // A negative balance is owed by the client, so it can never be paid out
+    if let Some(left) = payouts.iter().find(|payout| {
+        rules.close_policy == ClosePolicy::RequireEmpty || payout.amount < Decimal::ZERO
+    }) {
+        return Err(EngineError::BalanceRemaining {
+            client,
+            tx,
+            currency: left.currency,
+            amount: left.amount,
+        });
+    }
+    for payout in &payouts {
+        // With nothing held, total equals available, so both drop to zero
+        mutate_account_balance(
+            &mut account,
+            payout.currency,
+            -payout.amount,
+            Decimal::ZERO,
+            -payout.amount,
+            rules.rounding,
+        )?;
+    }
+    account.status = AccountStatus::Closed;
+    debug!(payouts = payouts.len(), "account closed");
+
+    Ok(Moved {
+        amount: Decimal::ZERO,
+        currency: transaction.currency,
+        conversion: None,
+        payouts,
+        shortfall: None,
+    })
+}
+
+/// Put a client's account on review hold, opening it if needed.
+///
+/// Returns `false`, leaving the account as it is, if its status does not
+/// allow a hold.
+pub fn place_on_review_hold(accounts: &AccountsMap, client_id: ClientId) -> bool {
+    let mut account_entry = accounts.entry(client_id).or_insert_with(|| {
+        debug!("opening account");
+        Account {
+            client: client_id,
+            ..Default::default()
         }
+    });
+    if !account_entry.status.allows(&TransactionType::Hold) {
+        return false;
     }
+    account_entry.status = AccountStatus::ReviewHold;
+    debug!("account placed on review hold");
+    true
+}
 
-    Ok(())
+/// Record to keep for a deposit, withdrawal or conversion moving `amount`
+fn new_record(transaction: &Transaction, amount: Decimal) -> TransactionRecord {
+    TransactionRecord {
+        client: transaction.client,
+        amount,
+        disputed: false,
+        timestamp: transaction.timestamp,
+        currency: transaction.currency,
+        conversion: None,
+        held: None,
+    }
 }
 
-/// Insert transaction into global map if not duplicate
-pub fn insert_transaction(tx_map: &TransactionsMap, tx: u32, client: u16, amount: Decimal) -> bool {
-    match tx_map.entry(tx) {
+/// Insert transaction into global map if not duplicate, counting records
+/// spilled to disk or compacted to a tombstone as present.
+///
+/// A key the bloom filter has never seen is inserted without the lookup.
+/// Only one task ever stores a given key, so no other insert can race it.
+pub fn insert_transaction(
+    tx_map: &TransactionsMap,
+    key: TxKey,
+    record: TransactionRecord,
+    rules: &Rules,
+) -> bool {
+    // Nothing will read the record back, so only a clash with a record
+    // restored from a snapshot makes it a duplicate
+    if rules
+        .retained
+        .as_ref()
+        .is_some_and(|ids| !ids.contains(key.tx))
+    {
+        return !tx_map.contains_key(&key) && !stored_elsewhere(&key, rules);
+    }
+    if let Some(filter) = &rules.tx_filter
+        && !filter.insert(&key)
+    {
+        tx_map.insert(key, record);
+        return true;
+    }
+    match tx_map.entry(key) {
         Entry::Occupied(_) => false,
+        Entry::Vacant(_) if stored_elsewhere(&key, rules) => false,
         Entry::Vacant(entry) => {
-            entry.insert(TransactionRecord {
-                client,
-                amount,
-                disputed: false,
-            });
+            entry.insert(record);
             true
         }
     }
 }
 
+/// Whether a key missing from the map was spilled to disk or compacted
+fn stored_elsewhere(key: &TxKey, rules: &Rules) -> bool {
+    rules.spill.as_ref().is_some_and(|s| s.contains(key))
+        || rules.tombstones.as_ref().is_some_and(|t| t.contains(key))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bloom::TxKeyFilter;
+    use crate::compact::Tombstones;
+    use crate::config::{CompactPolicy, DuplicateTxPolicy, LockedTypes, Rounding, RoundingMode};
+    use crate::fx::FxRates;
+    use crate::models::{Balance, TxCounts, TxId};
+    use crate::retain::RetainScan;
+    use crate::spill::SpillStore;
+    use chrono::DateTime;
     use rust_decimal::Decimal;
     use std::sync::Arc;
 
@@ -304,8 +1058,8 @@ mod tests {
 
     fn new_transaction(
         tx_type: TransactionType,
-        client: u16,
-        tx: u32,
+        client: ClientId,
+        tx: TxId,
         amount: Option<Decimal>,
     ) -> Transaction {
         Transaction {
@@ -313,6 +1067,10 @@ mod tests {
             client,
             tx,
             amount,
+            timestamp: None,
+            currency: None,
+            to_currency: None,
+            idempotency_key: None,
         }
     }
 
@@ -323,9 +1081,9 @@ mod tests {
         handle_transaction(deposit, &accounts, &transactions).unwrap();
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::from(100));
-        assert_eq!(account.total, Decimal::from(100));
-        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.balance(None).available, Decimal::from(100));
+        assert_eq!(account.balance(None).total, Decimal::from(100));
+        assert_eq!(account.balance(None).held, Decimal::ZERO);
     }
 
     #[tokio::test]
@@ -341,8 +1099,8 @@ mod tests {
         handle_transaction(withdrawal, &accounts, &transactions).unwrap();
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::from(50));
-        assert_eq!(account.total, Decimal::from(50));
+        assert_eq!(account.balance(None).available, Decimal::from(50));
+        assert_eq!(account.balance(None).total, Decimal::from(50));
     }
 
     #[tokio::test]
@@ -350,12 +1108,23 @@ mod tests {
         let (accounts, transactions) = setup_test_environment();
         let withdrawal =
             new_transaction(TransactionType::Withdrawal, 1, 100, Some(Decimal::from(50)));
-        handle_transaction(withdrawal, &accounts, &transactions).unwrap();
+        let result = handle_transaction(withdrawal, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::InsufficientFunds {
+                    client: 1,
+                    tx: 100,
+                    ..
+                },
+                ..
+            })
+        ));
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::ZERO);
-        assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.total, Decimal::ZERO);
+        assert_eq!(account.balance(None).available, Decimal::ZERO);
+        assert_eq!(account.balance(None).held, Decimal::ZERO);
+        assert_eq!(account.balance(None).total, Decimal::ZERO);
     }
 
     #[tokio::test]
@@ -368,10 +1137,10 @@ mod tests {
         handle_transaction(dispute, &accounts, &transactions).unwrap();
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::ZERO);
-        assert_eq!(account.held, Decimal::from(100));
+        assert_eq!(account.balance(None).available, Decimal::ZERO);
+        assert_eq!(account.balance(None).held, Decimal::from(100));
 
-        let tx_record = transactions.get(&100).unwrap();
+        let tx_record = transactions.get(&TxKey::global(100)).unwrap();
         assert!(tx_record.disputed);
     }
 
@@ -388,10 +1157,10 @@ mod tests {
         handle_transaction(resolve, &accounts, &transactions).unwrap();
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::from(100));
-        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.balance(None).available, Decimal::from(100));
+        assert_eq!(account.balance(None).held, Decimal::ZERO);
 
-        let tx_record = transactions.get(&100).unwrap();
+        let tx_record = transactions.get(&TxKey::global(100)).unwrap();
         assert!(!tx_record.disputed);
     }
 
@@ -408,9 +1177,9 @@ mod tests {
         handle_transaction(chargeback, &accounts, &transactions).unwrap();
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.held, Decimal::ZERO);
-        assert_eq!(account.total, Decimal::ZERO);
-        assert!(account.locked);
+        assert_eq!(account.balance(None).held, Decimal::ZERO);
+        assert_eq!(account.balance(None).total, Decimal::ZERO);
+        assert_eq!(account.status, AccountStatus::ChargebackLocked);
     }
 
     #[tokio::test]
@@ -420,10 +1189,17 @@ mod tests {
         handle_transaction(deposit1, &accounts, &transactions).unwrap();
 
         let deposit2 = new_transaction(TransactionType::Deposit, 1, 100, Some(Decimal::from(200)));
-        handle_transaction(deposit2, &accounts, &transactions).unwrap();
+        let result = handle_transaction(deposit2, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::DuplicateTx { client: 1, tx: 100 },
+                ..
+            })
+        ));
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.available, Decimal::from(100));
+        assert_eq!(account.balance(None).available, Decimal::from(100));
     }
 
     #[tokio::test]
@@ -441,17 +1217,201 @@ mod tests {
         // Try another deposit on locked account
         let new_deposit =
             new_transaction(TransactionType::Deposit, 1, 101, Some(Decimal::from(50)));
-        handle_transaction(new_deposit, &accounts, &transactions).unwrap();
+        let result = handle_transaction(new_deposit, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::AccountLocked { client: 1, tx: 101 },
+                ..
+            })
+        ));
 
         let account = accounts.get(&1).unwrap();
-        assert_eq!(account.total, Decimal::ZERO); // Should not have changed
+        assert_eq!(account.balance(None).total, Decimal::ZERO); // Should not have changed
+    }
+
+    #[tokio::test]
+    async fn test_rounding_applies_to_amounts_and_balances() {
+        let (accounts, transactions) = setup_test_environment();
+        let rules = Rules {
+            rounding: Rounding {
+                mode: RoundingMode::HalfUp,
+                precision: "2".parse().unwrap(),
+            },
+            ..Default::default()
+        };
+        for row in [
+            new_transaction(TransactionType::Deposit, 1, 1, Some(Decimal::new(10005, 3))),
+            new_transaction(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(Decimal::new(2004, 3)),
+            ),
+            new_transaction(TransactionType::Dispute, 1, 1, None),
+        ] {
+            handle_transaction_with(row, &accounts, &transactions, &rules).unwrap();
+        }
+        let balance = accounts.get(&1).unwrap().balance(None);
+        assert_eq!(balance.available, Decimal::new(-200, 2));
+        assert_eq!(balance.held, Decimal::new(1001, 2));
+        assert_eq!(balance.total, balance.available + balance.held);
+    }
+
+    #[tokio::test]
+    async fn test_locked_allows_limits_disputes_after_chargeback() {
+        let run = |locked_allows: LockedTypes| {
+            let (accounts, transactions) = setup_test_environment();
+            let rules = Rules {
+                locked_allows,
+                ..Default::default()
+            };
+            for row in [
+                new_transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(10))),
+                new_transaction(TransactionType::Deposit, 1, 2, Some(Decimal::from(5))),
+                new_transaction(TransactionType::Dispute, 1, 2, None),
+                new_transaction(TransactionType::Dispute, 1, 1, None),
+                new_transaction(TransactionType::Chargeback, 1, 1, None),
+            ] {
+                handle_transaction_with(row, &accounts, &transactions, &rules).unwrap();
+            }
+            let resolve = new_transaction(TransactionType::Resolve, 1, 2, None);
+            let result = handle_transaction_with(resolve, &accounts, &transactions, &rules);
+            let held = accounts.get(&1).unwrap().balance(None).held;
+            (result.is_ok(), held)
+        };
+
+        assert_eq!(run(LockedTypes::default()), (true, Decimal::ZERO));
+        assert_eq!(run("resolve".parse().unwrap()), (true, Decimal::ZERO));
+        assert_eq!(run(LockedTypes::NONE), (false, Decimal::from(5)));
+    }
+
+    #[tokio::test]
+    async fn test_counts_applied_rows_per_client() {
+        let (accounts, transactions) = setup_test_environment();
+        let rows = vec![
+            new_transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(10))),
+            new_transaction(TransactionType::Deposit, 1, 2, Some(Decimal::from(5))),
+            new_transaction(TransactionType::Withdrawal, 1, 3, Some(Decimal::from(50))),
+            new_transaction(TransactionType::Withdrawal, 1, 4, Some(Decimal::from(3))),
+        ];
+        handle_run(rows, &accounts, &transactions, &Rules::default());
+        for row in [
+            new_transaction(TransactionType::Dispute, 1, 1, None),
+            new_transaction(TransactionType::Resolve, 1, 1, None),
+            new_transaction(TransactionType::Dispute, 1, 2, None),
+            new_transaction(TransactionType::Chargeback, 1, 2, None),
+            new_transaction(TransactionType::Deposit, 1, 5, Some(Decimal::ONE)),
+        ] {
+            let _ = handle_transaction(row, &accounts, &transactions);
+        }
+
+        assert_eq!(
+            accounts.get(&1).unwrap().counts,
+            TxCounts {
+                deposits: 2,
+                withdrawals: 1,
+                disputes: 2,
+                chargebacks: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_review_hold_blocks_funds_until_release() {
+        let (accounts, transactions) = setup_test_environment();
+        let handle = |tx_type, tx, amount: Option<i64>| {
+            let transaction = new_transaction(tx_type, 1, tx, amount.map(Decimal::from));
+            handle_transaction(transaction, &accounts, &transactions)
+        };
+
+        handle(TransactionType::Deposit, 1, Some(100)).unwrap();
+        handle(TransactionType::Hold, 2, None).unwrap();
+        assert!(matches!(
+            handle(TransactionType::Withdrawal, 3, Some(10)),
+            Err(Rejected {
+                error: EngineError::AccountOnHold { client: 1, tx: 3 },
+                ..
+            })
+        ));
+        // Disputes still go through while the account is under review
+        handle(TransactionType::Dispute, 1, None).unwrap();
+
+        handle(TransactionType::Release, 4, None).unwrap();
+        assert_eq!(accounts.get(&1).unwrap().status, AccountStatus::Active);
+        assert!(matches!(
+            handle(TransactionType::Release, 5, None),
+            Err(Rejected {
+                error: EngineError::NotOnHold { .. },
+                ..
+            })
+        ));
+        handle(TransactionType::Deposit, 6, Some(10)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_pays_out_or_requires_empty_account() {
+        let (accounts, transactions) = setup_test_environment();
+        let payout = Rules {
+            close_policy: ClosePolicy::Payout,
+            ..Default::default()
+        };
+        let handle = |tx_type, tx, amount: Option<i64>, rules: &Rules| {
+            let transaction = new_transaction(tx_type, 1, tx, amount.map(Decimal::from));
+            handle_transaction_with(transaction, &accounts, &transactions, rules)
+        };
+        let default = Rules::default();
+
+        handle(TransactionType::Deposit, 1, Some(100), &default).unwrap();
+        assert!(matches!(
+            handle(TransactionType::Close, 2, None, &default),
+            Err(Rejected {
+                error: EngineError::BalanceRemaining { .. },
+                ..
+            })
+        ));
+        handle(TransactionType::Dispute, 1, None, &default).unwrap();
+        assert!(matches!(
+            handle(TransactionType::Close, 3, None, &payout),
+            Err(Rejected {
+                error: EngineError::FundsHeld { client: 1, tx: 3 },
+                ..
+            })
+        ));
+        handle(TransactionType::Resolve, 1, None, &default).unwrap();
+
+        let applied = handle(TransactionType::Close, 4, None, &payout).unwrap();
+        assert_eq!(
+            applied.payouts,
+            [Payout {
+                currency: None,
+                amount: Decimal::from(100)
+            }]
+        );
+        let account = accounts.get(&1).unwrap().clone();
+        assert_eq!(account.status, AccountStatus::Closed);
+        assert_eq!(account.balance(None).total, Decimal::ZERO);
+        assert!(matches!(
+            handle(TransactionType::Deposit, 5, Some(10), &default),
+            Err(Rejected {
+                error: EngineError::AccountClosed { client: 1, tx: 5 },
+                ..
+            })
+        ));
     }
 
     #[tokio::test]
     async fn test_negative_amount_deposit_ignored() {
         let (accounts, transactions) = setup_test_environment();
         let deposit = new_transaction(TransactionType::Deposit, 1, 100, Some(Decimal::from(-100)));
-        handle_transaction(deposit, &accounts, &transactions).unwrap();
+        let result = handle_transaction(deposit, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::InvalidAmount { .. },
+                ..
+            })
+        ));
 
         assert!(accounts.get(&1).is_none());
     }
@@ -460,8 +1420,690 @@ mod tests {
     async fn test_missing_amount_ignored() {
         let (accounts, transactions) = setup_test_environment();
         let deposit = new_transaction(TransactionType::Deposit, 1, 100, None);
-        handle_transaction(deposit, &accounts, &transactions).unwrap();
+        let result = handle_transaction(deposit, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::InvalidAmount { .. },
+                ..
+            })
+        ));
 
         assert!(accounts.get(&1).is_none());
     }
+
+    #[tokio::test]
+    async fn test_amount_cut_to_four_places() {
+        let (accounts, transactions) = setup_test_environment();
+        let deposit = new_transaction(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::new(2_00009, 5)),
+        );
+        handle_transaction(deposit, &accounts, &transactions).unwrap();
+        let small = new_transaction(TransactionType::Deposit, 1, 2, Some(Decimal::new(9, 5)));
+        assert!(matches!(
+            handle_transaction(small, &accounts, &transactions),
+            Err(Rejected {
+                error: EngineError::InvalidAmount { .. },
+                ..
+            })
+        ));
+
+        let dispute = new_transaction(TransactionType::Dispute, 1, 1, None);
+        handle_transaction(dispute, &accounts, &transactions).unwrap();
+        let balance = accounts.get(&1).unwrap().balance(None);
+        assert_eq!(balance.held, Decimal::new(20000, 4));
+        assert_eq!(balance.total, balance.available + balance.held);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_by_other_client_is_client_mismatch() {
+        let (accounts, transactions) = setup_test_environment();
+        let deposit = new_transaction(TransactionType::Deposit, 1, 100, Some(Decimal::from(100)));
+        handle_transaction(deposit, &accounts, &transactions).unwrap();
+
+        let dispute = new_transaction(TransactionType::Dispute, 2, 100, None);
+        let result = handle_transaction(dispute, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::ClientMismatch {
+                    client: 2,
+                    tx: 100,
+                    owner: 1
+                },
+                ..
+            })
+        ));
+        assert!(!transactions.get(&TxKey::global(100)).unwrap().disputed);
+        assert!(accounts.get(&2).is_none());
+
+        let review = Rules {
+            client_mismatch: ClientMismatchPolicy::Review,
+            ..Default::default()
+        };
+        let chargeback = new_transaction(TransactionType::Chargeback, 2, 100, None);
+        assert!(handle_transaction_with(chargeback, &accounts, &transactions, &review).is_err());
+        assert_eq!(accounts.get(&2).unwrap().status, AccountStatus::ReviewHold);
+        assert_eq!(accounts.get(&1).unwrap().status, AccountStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reference_opens_no_account() {
+        let (accounts, transactions) = setup_test_environment();
+        for tx_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            let row = new_transaction(tx_type, 7, 1, None);
+            assert!(handle_transaction(row, &accounts, &transactions).is_err());
+        }
+        assert!(accounts.is_empty());
+
+        let legacy = Rules {
+            open_on_reference: true,
+            ..Default::default()
+        };
+        let dispute = new_transaction(TransactionType::Dispute, 7, 1, None);
+        assert!(handle_transaction_with(dispute, &accounts, &transactions, &legacy).is_err());
+        assert_eq!(accounts.get(&7).unwrap().balance(None), Balance::default());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_shortfall_policies() {
+        let balance = |available: i64, held: i64, total: i64| Balance {
+            available: Decimal::from(available),
+            held: Decimal::from(held),
+            total: Decimal::from(total),
+        };
+        // Deposit 10, withdraw 6, then dispute the deposit
+        let disputed = |policy| {
+            let (accounts, transactions) = setup_test_environment();
+            let rules = Rules {
+                dispute_shortfall: policy,
+                ..Default::default()
+            };
+            for row in [
+                new_transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(10))),
+                new_transaction(TransactionType::Withdrawal, 1, 2, Some(Decimal::from(6))),
+            ] {
+                handle_transaction_with(row, &accounts, &transactions, &rules).unwrap();
+            }
+            let dispute = new_transaction(TransactionType::Dispute, 1, 1, None);
+            let applied =
+                handle_transaction_with(dispute, &accounts, &transactions, &rules).unwrap();
+            assert_eq!(
+                applied.shortfall,
+                Some(Shortfall {
+                    policy,
+                    uncovered: Decimal::from(6),
+                })
+            );
+            (accounts, transactions, rules)
+        };
+
+        let (accounts, transactions, rules) = disputed(DisputeShortfallPolicy::AllowNegative);
+        assert_eq!(accounts.get(&1).unwrap().balance(None), balance(-6, 10, 4));
+        let chargeback = new_transaction(TransactionType::Chargeback, 1, 1, None);
+        let applied =
+            handle_transaction_with(chargeback, &accounts, &transactions, &rules).unwrap();
+        assert_eq!(applied.shortfall, None);
+        assert_eq!(accounts.get(&1).unwrap().balance(None), balance(-6, 0, -6));
+
+        let (accounts, transactions, rules) = disputed(DisputeShortfallPolicy::Cap);
+        assert_eq!(accounts.get(&1).unwrap().balance(None), balance(0, 4, 4));
+        let resolve = new_transaction(TransactionType::Resolve, 1, 1, None);
+        let applied = handle_transaction_with(resolve, &accounts, &transactions, &rules).unwrap();
+        assert_eq!(applied.amount, Decimal::from(4));
+        assert_eq!(accounts.get(&1).unwrap().balance(None), balance(4, 0, 4));
+
+        let (accounts, transactions, rules) = disputed(DisputeShortfallPolicy::Review);
+        assert_eq!(accounts.get(&1).unwrap().balance(None), balance(4, 0, 4));
+        assert_eq!(accounts.get(&1).unwrap().status, AccountStatus::ReviewHold);
+        let chargeback = new_transaction(TransactionType::Chargeback, 1, 1, None);
+        let applied =
+            handle_transaction_with(chargeback, &accounts, &transactions, &rules).unwrap();
+        assert_eq!(applied.shortfall.unwrap().uncovered, Decimal::from(10));
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.balance(None), balance(-6, 0, -6));
+        assert_eq!(account.status, AccountStatus::ChargebackLocked);
+    }
+
+    #[tokio::test]
+    async fn test_overflowing_deposit_freezes_account() {
+        let (accounts, transactions) = setup_test_environment();
+        let deposit = new_transaction(TransactionType::Deposit, 1, 100, Some(Decimal::MAX));
+        handle_transaction(deposit, &accounts, &transactions).unwrap();
+
+        let overflow = new_transaction(TransactionType::Deposit, 1, 101, Some(Decimal::ONE));
+        let result = handle_transaction(overflow, &accounts, &transactions);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::Overflow { client: 1 },
+                ..
+            })
+        ));
+
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert_eq!(account.balance(None).total, Decimal::MAX);
+        assert!(transactions.get(&TxKey::global(101)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_outside_window_rejected() {
+        let (accounts, transactions) = setup_test_environment();
+        let rules = Rules {
+            dispute_window: Some(chrono::Duration::days(90)),
+            ..Default::default()
+        };
+        let day = |n: i64| DateTime::from_timestamp(n * 86_400, 0);
+
+        for tx in [100, 101] {
+            let mut deposit =
+                new_transaction(TransactionType::Deposit, 1, tx, Some(Decimal::from(10)));
+            deposit.timestamp = day(0);
+            handle_transaction_with(deposit, &accounts, &transactions, &rules).unwrap();
+        }
+
+        let mut late = new_transaction(TransactionType::Dispute, 1, 100, None);
+        late.timestamp = day(91);
+        let result = handle_transaction_with(late, &accounts, &transactions, &rules);
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::DisputeWindowExpired { tx: 100, .. },
+                ..
+            })
+        ));
+
+        let mut timely = new_transaction(TransactionType::Dispute, 1, 101, None);
+        timely.timestamp = day(90);
+        handle_transaction_with(timely, &accounts, &transactions, &rules).unwrap();
+
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.balance(None).held, Decimal::from(10));
+        assert!(!transactions.get(&TxKey::global(100)).unwrap().disputed);
+    }
+
+    #[tokio::test]
+    async fn test_per_client_policy_scopes_ids_to_client() {
+        let (accounts, transactions) = setup_test_environment();
+        let rules = Rules {
+            duplicate_tx: DuplicateTxPolicy::PerClient,
+            ..Default::default()
+        };
+
+        for client in [1, 2] {
+            let deposit = new_transaction(
+                TransactionType::Deposit,
+                client,
+                100,
+                Some(Decimal::from(10)),
+            );
+            handle_transaction_with(deposit, &accounts, &transactions, &rules).unwrap();
+        }
+
+        let dispute = new_transaction(TransactionType::Dispute, 2, 100, None);
+        handle_transaction_with(dispute, &accounts, &transactions, &rules).unwrap();
+
+        assert!(
+            !transactions
+                .get(&TxKey::per_client(1, 100))
+                .unwrap()
+                .disputed
+        );
+        assert!(
+            transactions
+                .get(&TxKey::per_client(2, 100))
+                .unwrap()
+                .disputed
+        );
+        assert_eq!(
+            accounts.get(&2).unwrap().balance(None).held,
+            Decimal::from(10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_currencies_only_net_within_themselves() {
+        let (accounts, transactions) = setup_test_environment();
+        let (eur, usd) = ("EUR".parse().ok(), "USD".parse().ok());
+        let in_currency = |tx_type, tx, amount: Option<i64>, currency| Transaction {
+            currency,
+            ..new_transaction(tx_type, 1, tx, amount.map(Decimal::from))
+        };
+
+        handle_transaction(
+            in_currency(TransactionType::Deposit, 1, Some(100), usd),
+            &accounts,
+            &transactions,
+        )
+        .unwrap();
+        handle_transaction(
+            in_currency(TransactionType::Deposit, 2, Some(10), eur),
+            &accounts,
+            &transactions,
+        )
+        .unwrap();
+
+        // Dollars cannot cover a euro withdrawal
+        let result = handle_transaction(
+            in_currency(TransactionType::Withdrawal, 3, Some(50), eur),
+            &accounts,
+            &transactions,
+        );
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::InsufficientFunds { .. },
+                ..
+            })
+        ));
+
+        let result = handle_transaction(
+            in_currency(TransactionType::Dispute, 2, None, usd),
+            &accounts,
+            &transactions,
+        );
+        assert!(matches!(
+            result,
+            Err(Rejected {
+                error: EngineError::CurrencyMismatch { tx: 2, .. },
+                ..
+            })
+        ));
+
+        // A dispute without a currency takes the deposit's
+        let applied = handle_transaction(
+            in_currency(TransactionType::Dispute, 2, None, None),
+            &accounts,
+            &transactions,
+        )
+        .unwrap();
+        assert_eq!(applied.transaction.currency, eur);
+
+        let account = accounts.get(&1).unwrap();
+        assert_eq!(account.balance(eur).held, Decimal::from(10));
+        assert_eq!(account.balance(usd).available, Decimal::from(100));
+        assert_eq!(account.balance(None), Default::default());
+    }
+
+    #[tokio::test]
+    async fn test_convert_between_currencies() {
+        let (accounts, transactions) = setup_test_environment();
+        let (eur, usd) = ("EUR".parse().ok(), "USD".parse().ok());
+        let rates = FxRates::from_reader("from,to,rate\nEUR,USD,1.1\n".as_bytes()).unwrap();
+        let rules = Rules {
+            fx_rates: Some(Arc::new(rates)),
+            ..Default::default()
+        };
+        let row = |tx_type, tx, amount, currency, to_currency| Transaction {
+            currency,
+            to_currency,
+            ..new_transaction(tx_type, 1, tx, Some(Decimal::from(amount)))
+        };
+
+        handle_transaction_with(
+            row(TransactionType::Deposit, 1, 100, eur, None),
+            &accounts,
+            &transactions,
+            &rules,
+        )
+        .unwrap();
+        let applied = handle_transaction_with(
+            row(TransactionType::Convert, 2, 40, eur, usd),
+            &accounts,
+            &transactions,
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(applied.conversion.unwrap().converted, Decimal::from(44));
+
+        let no_rate = handle_transaction_with(
+            row(TransactionType::Convert, 3, 1, eur, "GBP".parse().ok()),
+            &accounts,
+            &transactions,
+            &rules,
+        );
+        assert!(matches!(
+            no_rate,
+            Err(Rejected {
+                error: EngineError::NoFxRate { tx: 3, .. },
+                ..
+            })
+        ));
+        let too_much = handle_transaction_with(
+            row(TransactionType::Convert, 4, 61, eur, usd),
+            &accounts,
+            &transactions,
+            &rules,
+        );
+        assert!(matches!(
+            too_much,
+            Err(Rejected {
+                error: EngineError::InsufficientFunds { .. },
+                ..
+            })
+        ));
+
+        let balance = |currency| accounts.get(&1).unwrap().balance(currency);
+        assert_eq!(balance(eur).available, Decimal::from(60));
+        assert_eq!(balance(usd).total, Decimal::from(44));
+        let record = transactions.get(&TxKey::global(2)).unwrap().clone();
+        assert_eq!(record.conversion.unwrap().rate, Decimal::new(11, 1));
+
+        // Conversions cannot be disputed
+        let dispute = Transaction {
+            currency: eur,
+            ..new_transaction(TransactionType::Dispute, 1, 2, None)
+        };
+        assert!(handle_transaction_with(dispute, &accounts, &transactions, &rules).is_err());
+    }
+
+    #[test]
+    fn test_spilled_transactions_still_count() {
+        let (accounts, transactions) = setup_test_environment();
+        let spill = Arc::new(SpillStore::create().unwrap());
+        let rules = Rules {
+            spill: Some(Arc::clone(&spill)),
+            ..Default::default()
+        };
+        let handle = |tx_type, client, amount: Option<i64>| {
+            let transaction = new_transaction(tx_type, client, 100, amount.map(Decimal::from));
+            handle_transaction_with(transaction, &accounts, &transactions, &rules)
+        };
+        handle(TransactionType::Deposit, 1, Some(10)).unwrap();
+        assert_eq!(spill.spill(&transactions, 1).unwrap(), 1);
+        assert!(transactions.is_empty());
+
+        assert!(matches!(
+            handle(TransactionType::Deposit, 1, Some(5)),
+            Err(Rejected {
+                error: EngineError::DuplicateTx { .. },
+                ..
+            })
+        ));
+        assert!(matches!(
+            handle(TransactionType::Dispute, 2, None),
+            Err(Rejected {
+                error: EngineError::ClientMismatch { owner: 1, .. },
+                ..
+            })
+        ));
+        assert!(spill.contains(&TxKey::global(100)));
+
+        handle(TransactionType::Dispute, 1, None).unwrap();
+        assert!(spill.is_empty());
+        assert!(transactions.get(&TxKey::global(100)).unwrap().disputed);
+        assert_eq!(
+            accounts.get(&1).unwrap().balance(None).held,
+            Decimal::from(10)
+        );
+    }
+
+    #[test]
+    fn test_filtered_inserts_still_reject_duplicates() {
+        let (accounts, transactions) = setup_test_environment();
+        let rules = Rules {
+            tx_filter: Some(Arc::new(TxKeyFilter::with_capacity(100))),
+            ..Default::default()
+        };
+        for client in [1, 1, 2] {
+            let _ = handle_transaction_with(
+                new_transaction(TransactionType::Deposit, client, 7, Some(Decimal::ONE)),
+                &accounts,
+                &transactions,
+                &rules,
+            );
+        }
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(accounts.get(&1).unwrap().balance(None).total, Decimal::ONE);
+        assert!(accounts.get(&2).unwrap().balance(None).total.is_zero());
+    }
+
+    #[test]
+    fn test_two_pass_keeps_only_retained_records() {
+        use TransactionType::{Deposit, Dispute};
+        let (accounts, transactions) = setup_test_environment();
+        let rows = vec![
+            new_transaction(Deposit, 1, 1, Some(Decimal::ONE)),
+            new_transaction(Deposit, 1, 2, Some(Decimal::TWO)),
+            new_transaction(Dispute, 1, 2, None),
+            // Clashes with a record restored from a snapshot
+            new_transaction(Deposit, 1, 9, Some(Decimal::ONE)),
+        ];
+        let mut scan = RetainScan::default();
+        rows.iter().for_each(|row| scan.observe(row));
+        let rules = Rules {
+            retained: Some(Arc::new(scan.finish())),
+            ..Default::default()
+        };
+        transactions.insert(
+            rules.duplicate_tx.key(1, 9),
+            new_record(&rows[3], Decimal::ONE),
+        );
+
+        let outcomes: Vec<_> = rows
+            .into_iter()
+            .map(|row| handle_transaction_with(row, &accounts, &transactions, &rules).is_ok())
+            .collect();
+        assert_eq!(outcomes, [true, true, true, false]);
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(accounts.get(&1).unwrap().balance(None).held, Decimal::TWO);
+    }
+
+    #[test]
+    fn test_chargeback_compacts_the_record() {
+        use TransactionType::{Chargeback, Deposit, Dispute};
+        for (compaction, reused) in [
+            (CompactPolicy::Tombstone, false),
+            (CompactPolicy::Drop, true),
+        ] {
+            let (accounts, transactions) = setup_test_environment();
+            let rules = Rules {
+                compaction,
+                tombstones: Some(Arc::new(Tombstones::default())),
+                ..Default::default()
+            };
+            let apply = |tx_type, client, tx, amount| {
+                let row = new_transaction(tx_type, client, tx, amount);
+                handle_transaction_with(row, &accounts, &transactions, &rules)
+                    .map(|_| ())
+                    .map_err(|rejected| rejected.error.reason_code())
+            };
+            apply(Deposit, 1, 1, Some(Decimal::TWO)).unwrap();
+            apply(Dispute, 1, 1, None).unwrap();
+            apply(Chargeback, 1, 1, None).unwrap();
+            assert!(transactions.is_empty());
+
+            if reused {
+                assert_eq!(apply(Dispute, 1, 1, None), Err("unknown_tx"));
+                assert!(apply(Deposit, 2, 1, Some(Decimal::ONE)).is_ok());
+            } else {
+                assert_eq!(apply(Dispute, 1, 1, None), Err("tx_compacted"));
+                assert_eq!(apply(Dispute, 2, 1, None), Err("client_mismatch"));
+                assert_eq!(
+                    apply(Deposit, 2, 1, Some(Decimal::ONE)),
+                    Err("duplicate_tx")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_matches_rows_handled_one_at_a_time() {
+        use TransactionType::{Deposit, Dispute, Withdrawal};
+        let rows = || {
+            vec![
+                new_transaction(Withdrawal, 1, 1, Some(Decimal::ONE)),
+                new_transaction(Deposit, 1, 2, Some(Decimal::new(50, 1))),
+                new_transaction(Deposit, 1, 2, Some(Decimal::ONE)),
+                new_transaction(Deposit, 1, 3, Some(-Decimal::ONE)),
+                new_transaction(Withdrawal, 1, 4, Some(Decimal::new(15, 1))),
+                new_transaction(Dispute, 1, 2, None),
+                new_transaction(Withdrawal, 1, 5, Some(Decimal::ONE)),
+                new_transaction(Deposit, 2, 6, Some(Decimal::ONE)),
+            ]
+        };
+        let summary = |outcomes: Vec<TransactionOutcome>| -> Vec<_> {
+            outcomes
+                .into_iter()
+                .map(|outcome| match outcome {
+                    Ok(applied) => Ok((applied.transaction.tx, applied.amount)),
+                    Err(rejected) => Err(rejected.error.reason_code()),
+                })
+                .collect()
+        };
+
+        let (accounts, transactions) = setup_test_environment();
+        let one_by_one = rows()
+            .into_iter()
+            .map(|row| handle_transaction(row, &accounts, &transactions))
+            .collect();
+        let (run_accounts, run_transactions) = setup_test_environment();
+        let in_run = handle_run(rows(), &run_accounts, &run_transactions, &Rules::default());
+
+        assert_eq!(summary(in_run), summary(one_by_one));
+        assert_eq!(run_transactions.len(), transactions.len());
+        for client in [1, 2] {
+            assert_eq!(
+                *run_accounts.get(&client).unwrap(),
+                *accounts.get(&client).unwrap()
+            );
+        }
+        let balance = run_accounts.get(&1).unwrap().balance(None);
+        assert_eq!(balance.available, Decimal::new(-15, 1));
+        assert_eq!(balance.held, Decimal::new(50, 1));
+    }
+
+    #[test]
+    fn test_middleware_wraps_every_transaction() {
+        use crate::middleware::{Context, Decision, Middleware, MiddlewareChain};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Rejects withdrawals from accounts under 10 and counts outcomes
+        struct Floor(Arc<AtomicUsize>);
+        impl Middleware for Floor {
+            fn before(&self, transaction: &Transaction, context: &dyn Context) -> Decision {
+                let available = context
+                    .account(transaction.client)
+                    .map(|account| account.balance(None).available);
+                match transaction.tx_type {
+                    TransactionType::Withdrawal if available < Some(Decimal::TEN) => {
+                        Decision::Reject("below floor".to_string())
+                    }
+                    _ => Decision::Continue,
+                }
+            }
+            fn after(&self, _outcome: &TransactionOutcome) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let rules = Rules {
+            middleware: Some(Arc::new(
+                MiddlewareChain::default().with_middleware(Floor(Arc::clone(&handled))),
+            )),
+            ..Default::default()
+        };
+        let (accounts, transactions) = setup_test_environment();
+        let rows = [
+            new_transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(5))),
+            new_transaction(TransactionType::Withdrawal, 1, 2, Some(Decimal::ONE)),
+            new_transaction(TransactionType::Deposit, 1, 3, Some(Decimal::from(5))),
+        ];
+        let mut outcomes: Vec<_> = rows
+            .into_iter()
+            .map(|row| handle_transaction_with(row, &accounts, &transactions, &rules))
+            .collect();
+        outcomes.extend(handle_run(
+            vec![new_transaction(
+                TransactionType::Withdrawal,
+                1,
+                4,
+                Some(Decimal::ONE),
+            )],
+            &accounts,
+            &transactions,
+            &rules,
+        ));
+        let reasons: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| outcome.as_ref().err().map(|r| r.error.reason_code()))
+            .collect();
+        assert_eq!(reasons, [None, Some("middleware_rejected"), None, None]);
+        assert_eq!(handled.load(Ordering::Relaxed), 4);
+        assert_eq!(
+            accounts.get(&1).unwrap().balance(None).total,
+            Decimal::from(9)
+        );
+    }
+
+    #[test]
+    fn test_custom_types_go_through_registered_handlers() {
+        use crate::handlers::HandlerRegistry;
+
+        let handlers = HandlerRegistry::default()
+            .with_handler("bonus", |transaction: &Transaction, _: &Account| {
+                positive_amount(transaction, &Rules::default())
+            })
+            .with_handler("fee", |transaction: &Transaction, account: &Account| {
+                let fee = positive_amount(transaction, &Rules::default())?;
+                let available = account.balance(transaction.currency).available;
+                if available < fee {
+                    return Err(EngineError::InsufficientFunds {
+                        client: transaction.client,
+                        tx: transaction.tx,
+                        amount: fee,
+                        available,
+                    });
+                }
+                Ok(-fee)
+            });
+        let rules = Rules {
+            handlers: Some(Arc::new(handlers)),
+            ..Default::default()
+        };
+        let (accounts, transactions) = setup_test_environment();
+        let custom = |name: &str, tx, amount| Transaction {
+            tx_type: TransactionType::Custom(name.to_string()),
+            ..new_transaction(TransactionType::Deposit, 1, tx, Some(Decimal::from(amount)))
+        };
+        let rows = [
+            custom("fee", 1, 2),
+            custom("bonus", 2, 5),
+            custom("fee", 3, 2),
+            custom("bonus", 3, 1),
+            custom("refund", 4, 1),
+        ];
+        let outcomes: Vec<_> = rows
+            .into_iter()
+            .map(|row| handle_transaction_with(row, &accounts, &transactions, &rules))
+            .map(|outcome| match outcome {
+                Ok(applied) => Ok(applied.amount),
+                Err(rejected) => Err(rejected.error.reason_code()),
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                Err("insufficient_funds"),
+                Ok(Decimal::from(5)),
+                Ok(Decimal::from(-2)),
+                Err("duplicate_tx"),
+                Err("unknown_type"),
+            ]
+        );
+        assert_eq!(
+            accounts.get(&1).unwrap().balance(None).total,
+            Decimal::from(3)
+        );
+        assert_eq!(transactions.len(), 2);
+    }
 }
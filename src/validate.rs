@@ -0,0 +1,684 @@
+use chrono::{DateTime, Utc};
+use csv_async::{ByteRecord, StringRecord};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::config::{AmountFormat, AmountMode, AmountPrecision, ColumnMap, TypeAliases};
+use crate::error::{EngineError, RowErrorKind};
+use crate::models::Transaction;
+use crate::outcome::MalformedRow;
+
+/// Maximum number of decimal places accepted on input amounts
+pub const MAX_DECIMAL_PLACES: u32 = 4;
+
+/// Names of the known columns, in canonical order
+pub const COLUMNS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "currency",
+    "to_currency",
+    "idempotency_key",
+];
+
+/// A record's known fields in canonical order; absent columns are empty
+type Fields<'r> = [&'r str; COLUMNS.len()];
+
+/// Positions of the known columns within the header row, in canonical order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnIndex {
+    positions: [Option<usize>; COLUMNS.len()],
+}
+
+impl ColumnIndex {
+    /// Locate the columns by name; `amount`, `timestamp`, `currency`,
+    /// `to_currency` and `idempotency_key` are optional, the rest are
+    /// required
+    pub fn from_headers(headers: &StringRecord) -> Result<Self, EngineError> {
+        Self::mapped(headers, &ColumnMap::default())
+    }
+
+    /// Locate the columns under the headers `columns` maps them to
+    pub fn mapped(headers: &StringRecord, columns: &ColumnMap) -> Result<Self, EngineError> {
+        let positions = COLUMNS.map(|name| {
+            let header = columns.header(name);
+            headers.iter().position(|h| h == header)
+        });
+        if let Some(missing) = (0..3).find(|&i| positions[i].is_none()) {
+            return Err(EngineError::InvalidRow {
+                line: 1,
+                column: COLUMNS[missing],
+                value: String::new(),
+                kind: RowErrorKind::MissingField,
+            });
+        }
+        Ok(ColumnIndex { positions })
+    }
+
+    /// Every column in canonical order, for files without a header row
+    pub fn in_order() -> Self {
+        ColumnIndex {
+            positions: std::array::from_fn(Some),
+        }
+    }
+
+    /// Raw field values in
+    /// `type,client,tx,amount,timestamp,currency,to_currency,idempotency_key`
+    /// order, for error reporting
+    pub fn canonical_fields(&self, record: &StringRecord) -> Vec<String> {
+        self.positions
+            .iter()
+            .map(|index| {
+                index
+                    .and_then(|i| record.get(i))
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// The same for a raw byte record, replacing any invalid UTF-8
+    fn canonical_bytes(&self, record: &ByteRecord) -> Vec<String> {
+        self.positions
+            .iter()
+            .map(|index| {
+                let raw = index.and_then(|i| record.get(i)).unwrap_or_default();
+                String::from_utf8_lossy(raw).into_owned()
+            })
+            .collect()
+    }
+}
+
+/// Options controlling how raw field values are interpreted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub amount_precision: AmountPrecision,
+    pub amount_format: AmountFormat,
+    pub amount_mode: AmountMode,
+    pub type_aliases: Arc<TypeAliases>,
+}
+
+/// Validates CSV records against a header layout and parse options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowParser {
+    columns: ColumnIndex,
+    options: ParseOptions,
+}
+
+impl RowParser {
+    /// Build a parser for files with the given header row
+    pub fn new(headers: &StringRecord, options: ParseOptions) -> Result<Self, EngineError> {
+        Ok(RowParser {
+            columns: ColumnIndex::from_headers(headers)?,
+            options,
+        })
+    }
+
+    /// Build a parser for columns already located
+    pub fn with_columns(columns: ColumnIndex, options: ParseOptions) -> Self {
+        RowParser { columns, options }
+    }
+
+    /// Validate one CSV record and convert it into a `Transaction`.
+    ///
+    /// Errors carry the record's line number, the offending column and the raw
+    /// value so they can be reported back to whoever produced the file.
+    pub fn parse_record(&self, record: &StringRecord) -> Result<Transaction, EngineError> {
+        let line = record.position().map_or(0, |p| p.line());
+        let fields = self
+            .columns
+            .positions
+            .map(|index| index.and_then(|i| record.get(i)).unwrap_or(""));
+        self.parse_fields(line, &fields)
+    }
+
+    /// Validate a raw byte record without first converting the whole row to
+    /// a `StringRecord`.
+    ///
+    /// Only the known columns are checked for UTF-8, so unlike
+    /// `parse_record` invalid bytes in any other column are ignored.
+    pub fn parse_bytes(&self, record: &ByteRecord) -> Result<Transaction, EngineError> {
+        let line = record.position().map_or(0, |p| p.line());
+        let mut fields: Fields = [""; COLUMNS.len()];
+        for (i, index) in self.columns.positions.iter().enumerate() {
+            let raw = index
+                .and_then(|index| record.get(index))
+                .unwrap_or_default();
+            fields[i] = std::str::from_utf8(raw).map_err(|_| EngineError::InvalidRow {
+                line,
+                column: COLUMNS[i],
+                value: String::from_utf8_lossy(raw).into_owned(),
+                kind: RowErrorKind::InvalidUtf8,
+            })?;
+        }
+        self.parse_fields(line, &fields)
+    }
+
+    /// Convert a record's known fields into a `Transaction`
+    fn parse_fields(&self, line: u64, fields: &Fields) -> Result<Transaction, EngineError> {
+        let [
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp,
+            currency,
+            to_currency,
+            idempotency_key,
+        ] = *fields;
+        let invalid =
+            |column: &'static str, value: &str, kind: RowErrorKind| EngineError::InvalidRow {
+                line,
+                column,
+                value: value.to_string(),
+                kind,
+            };
+
+        let aliased = match &*self.options.type_aliases {
+            aliases if aliases.is_empty() => None,
+            aliases => aliases.get(&tx_type.to_ascii_lowercase()).cloned(),
+        };
+        let tx_type = match aliased {
+            Some(tx_type) => tx_type,
+            None => tx_type
+                .parse()
+                .map_err(|kind| invalid("type", tx_type, kind))?,
+        };
+
+        let client = parse_id(client).map_err(|kind| invalid("client", client, kind))?;
+        let tx = parse_id(tx).map_err(|kind| invalid("tx", tx, kind))?;
+
+        let amount = match (amount, self.options.amount_mode) {
+            ("", _) => None,
+            (raw, AmountMode::Decimal) => Some(
+                parse_amount(
+                    raw,
+                    self.options.amount_format,
+                    self.options.amount_precision,
+                )
+                .map_err(|kind| invalid("amount", raw, kind))?,
+            ),
+            (raw, AmountMode::MinorUnits) => {
+                Some(parse_minor_units(raw).map_err(|kind| invalid("amount", raw, kind))?)
+            }
+        };
+
+        let timestamp = match timestamp {
+            "" => None,
+            raw => Some(parse_timestamp(raw).map_err(|kind| invalid("timestamp", raw, kind))?),
+        };
+
+        let parse_currency = |column: &'static str, raw: &str| match raw {
+            "" => Ok(None),
+            raw => raw
+                .parse()
+                .map(Some)
+                .map_err(|kind| invalid(column, raw, kind)),
+        };
+        let to_currency = parse_currency("to_currency", to_currency)?;
+        let currency = parse_currency("currency", currency)?;
+        let idempotency_key = Some(idempotency_key)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+
+        Ok(Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            timestamp,
+            currency,
+            to_currency,
+            idempotency_key,
+        })
+    }
+
+    /// Validate a row read from the CSV stream, keeping the raw fields and line
+    /// number of anything that fails so it can be reported.
+    pub fn validate_row(
+        &self,
+        row: Result<StringRecord, csv_async::Error>,
+    ) -> Result<Transaction, MalformedRow> {
+        match row {
+            Ok(record) => self.parse_record(&record).map_err(|error| MalformedRow {
+                line: record.position().map(|p| p.line()),
+                fields: self.columns.canonical_fields(&record),
+                error,
+            }),
+            Err(e) => Err(malformed_read(e)),
+        }
+    }
+
+    /// `validate_row` for a byte record read into a reused buffer, the fast
+    /// path used by `process`
+    pub fn validate_bytes(
+        &self,
+        row: Result<&ByteRecord, csv_async::Error>,
+    ) -> Result<Transaction, MalformedRow> {
+        match row {
+            Ok(record) => self.parse_bytes(record).map_err(|error| MalformedRow {
+                line: record.position().map(|p| p.line()),
+                fields: self.columns.canonical_bytes(record),
+                error,
+            }),
+            Err(e) => Err(malformed_read(e)),
+        }
+    }
+}
+
+/// A row the CSV reader itself could not read
+fn malformed_read(e: csv_async::Error) -> MalformedRow {
+    MalformedRow {
+        line: e.position().map(|p| p.line()),
+        fields: Vec::new(),
+        error: e.into(),
+    }
+}
+
+/// Parse an unsigned identifier, distinguishing garbage from out-of-range numbers
+fn parse_id<T: TryFrom<u128>>(raw: &str) -> Result<T, RowErrorKind> {
+    if raw.is_empty() {
+        return Err(RowErrorKind::MissingField);
+    }
+    let wide = raw.parse::<u128>().map_err(|_| match raw.parse::<i128>() {
+        Ok(_) => RowErrorKind::OutOfRange,
+        Err(_) => RowErrorKind::InvalidInteger,
+    })?;
+    T::try_from(wide).map_err(|_| RowErrorKind::OutOfRange)
+}
+
+/// Parse an RFC 3339 timestamp or whole seconds since the Unix epoch
+fn parse_timestamp(raw: &str) -> Result<DateTime<Utc>, RowErrorKind> {
+    if let Ok(seconds) = raw.parse::<i64>() {
+        return DateTime::from_timestamp(seconds, 0).ok_or(RowErrorKind::OutOfRange);
+    }
+    DateTime::parse_from_rfc3339(raw)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| RowErrorKind::InvalidTimestamp)
+}
+
+/// Parse a decimal amount, applying the precision policy to anything with
+/// more than `MAX_DECIMAL_PLACES` significant decimals
+fn parse_amount(
+    raw: &str,
+    format: AmountFormat,
+    precision: AmountPrecision,
+) -> Result<Decimal, RowErrorKind> {
+    let amount = match (Decimal::from_str(raw), format) {
+        (Ok(amount), _) => amount,
+        (Err(_), AmountFormat::Lenient) => unformat_amount(raw)
+            .and_then(|plain| Decimal::from_str(&plain).ok())
+            .ok_or(RowErrorKind::InvalidDecimal)?,
+        (Err(_), AmountFormat::Strict) => return Err(RowErrorKind::InvalidDecimal),
+    }
+    .normalize();
+    if amount.scale() <= MAX_DECIMAL_PLACES {
+        return Ok(amount);
+    }
+    let strategy = match precision {
+        AmountPrecision::Reject => return Err(RowErrorKind::TooManyDecimals),
+        AmountPrecision::Truncate => RoundingStrategy::ToZero,
+        AmountPrecision::Round => RoundingStrategy::MidpointNearestEven,
+    };
+    let adjusted = amount
+        .round_dp_with_strategy(MAX_DECIMAL_PLACES, strategy)
+        .normalize();
+    warn!("Amount {raw} has more than {MAX_DECIMAL_PLACES} decimal places; using {adjusted}");
+    Ok(adjusted)
+}
+
+/// Parse a whole number of ten-thousandths into the amount it stands for,
+/// without going through decimal parsing
+fn parse_minor_units(raw: &str) -> Result<Decimal, RowErrorKind> {
+    let units = raw.parse::<i64>().map_err(|_| match raw.parse::<i128>() {
+        Ok(_) => RowErrorKind::OutOfRange,
+        Err(_) => RowErrorKind::InvalidInteger,
+    })?;
+    Ok(Decimal::new(units, MAX_DECIMAL_PLACES).normalize())
+}
+
+/// Currency symbols a lenient amount may start or end with
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// Rewrite a spreadsheet-style amount such as `"$1,234.50"` or `-€3` as a
+/// plain decimal number.
+///
+/// Strips one pair of surrounding quotes, a currency symbol before or after
+/// the number (a sign may come either side of a leading one) and commas
+/// between groups of three integer digits. Returns `None` if commas appear
+/// anywhere else, so `1,5` is never taken for `15`.
+fn unformat_amount(raw: &str) -> Option<String> {
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|&q| raw.strip_prefix(q)?.strip_suffix(q))
+        .unwrap_or(raw)
+        .trim();
+    let (sign, rest) = match unquoted.strip_prefix('-') {
+        Some(rest) => ("-", rest.trim_start()),
+        None => ("", unquoted.strip_prefix('+').unwrap_or(unquoted)),
+    };
+    let unsymboled = rest
+        .strip_prefix(CURRENCY_SYMBOLS)
+        .or_else(|| rest.strip_suffix(CURRENCY_SYMBOLS))
+        .unwrap_or(rest)
+        .trim();
+    let (sign, number) = match (sign, unsymboled.strip_prefix('-')) {
+        ("", Some(number)) => ("-", number),
+        (sign, _) => (sign, unsymboled),
+    };
+
+    let (integer, fraction) = match number.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (number, None),
+    };
+    let mut groups = integer.split(',');
+    let first = groups.next()?;
+    let mut digits = String::with_capacity(number.len() + 1);
+    digits.push_str(sign);
+    digits.push_str(first);
+    if integer.contains(',') {
+        if first.is_empty() || first.len() > 3 {
+            return None;
+        }
+        for group in groups {
+            if group.len() != 3 {
+                return None;
+            }
+            digits.push_str(group);
+        }
+    }
+    if let Some(fraction) = fraction {
+        digits.push('.');
+        digits.push_str(fraction);
+    }
+    Some(digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ClientId, TransactionType};
+    use csv_async::Position;
+
+    fn record(fields: &[&str], line: u64) -> StringRecord {
+        let mut record = StringRecord::from(fields.to_vec());
+        let mut position = Position::new();
+        position.set_line(line);
+        record.set_position(Some(position));
+        record
+    }
+
+    fn parser(amount_precision: AmountPrecision) -> RowParser {
+        RowParser::new(
+            &StringRecord::from(vec!["type", "client", "tx", "amount"]),
+            ParseOptions {
+                amount_precision,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn parse(fields: &[&str], line: u64) -> Result<Transaction, EngineError> {
+        parser(AmountPrecision::Reject).parse_record(&record(fields, line))
+    }
+
+    fn kind_of(result: Result<Transaction, EngineError>) -> (u64, &'static str, RowErrorKind) {
+        match result {
+            Err(EngineError::InvalidRow {
+                line, column, kind, ..
+            }) => (line, column, kind),
+            other => panic!("expected InvalidRow, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_valid_record() {
+        let tx = parse(&["deposit", "1", "2", "1.5"], 2).unwrap();
+        assert_eq!(tx.tx_type, TransactionType::Deposit);
+        assert_eq!(tx.client, 1);
+        assert_eq!(tx.tx, 2);
+        assert_eq!(tx.amount, Some(Decimal::from_str("1.5").unwrap()));
+
+        let tx = parse(&["dispute", "1", "2"], 3).unwrap();
+        assert_eq!(tx.amount, None);
+
+        let tx = parse(&["interest", "1", "2", "0.5"], 4).unwrap();
+        assert_eq!(tx.tx_type, TransactionType::Custom("interest".to_string()));
+    }
+
+    #[test]
+    fn test_parse_type_in_any_case_and_through_aliases() {
+        let aliases: TypeAliases = "withdraw=withdrawal,Charge_Back=chargeback"
+            .parse()
+            .unwrap();
+        let parser = RowParser::new(
+            &StringRecord::from(vec!["type", "client", "tx", "amount"]),
+            ParseOptions {
+                type_aliases: Arc::new(aliases),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let type_of = |raw: &str| {
+            parser
+                .parse_record(&record(&[raw, "1", "2", "1"], 2))
+                .map(|tx| tx.tx_type)
+        };
+
+        assert_eq!(type_of("DEPOSIT").unwrap(), TransactionType::Deposit);
+        assert_eq!(type_of("Withdraw").unwrap(), TransactionType::Withdrawal);
+        assert_eq!(type_of("charge_back").unwrap(), TransactionType::Chargeback);
+        assert_eq!(
+            type_of("Interest").unwrap(),
+            TransactionType::Custom("interest".to_string())
+        );
+        assert!("withdraw".parse::<TypeAliases>().is_err());
+        assert!("=deposit".parse::<TypeAliases>().is_err());
+    }
+
+    #[test]
+    fn test_parse_reports_line_and_column() {
+        let too_wide = (u128::from(ClientId::MAX) + 1).to_string();
+        assert_eq!(
+            kind_of(parse(&["re fund!", "1", "2", "1"], 4)),
+            (4, "type", RowErrorKind::UnknownType)
+        );
+        assert_eq!(
+            kind_of(parse(&["deposit", &too_wide, "2", "1"], 5)),
+            (5, "client", RowErrorKind::OutOfRange)
+        );
+        assert_eq!(
+            kind_of(parse(&["deposit", "1", "x", "1"], 6)),
+            (6, "tx", RowErrorKind::InvalidInteger)
+        );
+        assert_eq!(
+            kind_of(parse(&["deposit", "1", "2", "1.2.3"], 7)),
+            (7, "amount", RowErrorKind::InvalidDecimal)
+        );
+        assert_eq!(
+            kind_of(parse(&["deposit", "1", "2", "1.23456"], 8)),
+            (8, "amount", RowErrorKind::TooManyDecimals)
+        );
+    }
+
+    #[test]
+    fn test_trailing_zeros_are_not_excess_precision() {
+        let tx = parse(&["deposit", "1", "2", "1.50000"], 2).unwrap();
+        assert_eq!(tx.amount, Some(Decimal::from_str("1.5").unwrap()));
+    }
+
+    #[test]
+    fn test_amount_precision_policies() {
+        let row = record(&["deposit", "1", "2", "1.23456"], 2);
+
+        let truncated = parser(AmountPrecision::Truncate)
+            .parse_record(&row)
+            .unwrap();
+        assert_eq!(truncated.amount, Some(Decimal::from_str("1.2345").unwrap()));
+
+        let rounded = parser(AmountPrecision::Round).parse_record(&row).unwrap();
+        assert_eq!(rounded.amount, Some(Decimal::from_str("1.2346").unwrap()));
+    }
+
+    #[test]
+    fn test_lenient_amount_formats() {
+        let lenient = |raw: &str| {
+            parse_amount(raw, AmountFormat::Lenient, AmountPrecision::Reject)
+                .map(|amount| amount.to_string())
+        };
+        for (raw, expected) in [
+            ("1,234.50", "1234.5"),
+            ("$20", "20"),
+            ("\"1,000,000\"", "1000000"),
+            ("'-€3.10'", "-3.1"),
+            ("$-7", "-7"),
+            ("12.5 £", "12.5"),
+        ] {
+            assert_eq!(lenient(raw).as_deref(), Ok(expected), "{raw}");
+        }
+        for raw in ["1,5", "12,34.5", ",123", "$", "--5", "1,234,56", "US$5"] {
+            assert_eq!(lenient(raw), Err(RowErrorKind::InvalidDecimal), "{raw}");
+        }
+        assert_eq!(
+            parse_amount("$20", AmountFormat::Strict, AmountPrecision::Reject),
+            Err(RowErrorKind::InvalidDecimal)
+        );
+    }
+
+    #[test]
+    fn test_minor_unit_amounts() {
+        let parser = RowParser::new(
+            &StringRecord::from(vec!["type", "client", "tx", "amount"]),
+            ParseOptions {
+                amount_mode: AmountMode::MinorUnits,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let amount = |raw: &str| parser.parse_record(&record(&["deposit", "1", "2", raw], 2));
+        assert_eq!(amount("125000").unwrap().amount, Some(Decimal::new(125, 1)));
+        assert_eq!(amount("-1").unwrap().amount, Some(Decimal::new(-1, 4)));
+        assert_eq!(
+            kind_of(amount("12.5")),
+            (2, "amount", RowErrorKind::InvalidInteger)
+        );
+        assert_eq!(
+            kind_of(amount("99999999999999999999")),
+            (2, "amount", RowErrorKind::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_parse_optional_timestamp() {
+        let parser = RowParser::new(
+            &StringRecord::from(vec!["type", "client", "tx", "amount", "timestamp"]),
+            ParseOptions::default(),
+        )
+        .unwrap();
+
+        let rfc3339 = parser
+            .parse_record(&record(
+                &["deposit", "1", "2", "1", "2024-01-02T03:04:05Z"],
+                2,
+            ))
+            .unwrap();
+        let epoch = parser
+            .parse_record(&record(&["deposit", "1", "3", "1", "1704164645"], 3))
+            .unwrap();
+        assert_eq!(rfc3339.timestamp, epoch.timestamp);
+        assert!(rfc3339.timestamp.is_some());
+
+        assert_eq!(
+            kind_of(parser.parse_record(&record(&["deposit", "1", "4", "1", "yesterday"], 4))),
+            (4, "timestamp", RowErrorKind::InvalidTimestamp)
+        );
+    }
+
+    #[test]
+    fn test_parse_optional_currency() {
+        let parser = RowParser::new(
+            &StringRecord::from(vec!["type", "client", "tx", "amount", "currency"]),
+            ParseOptions::default(),
+        )
+        .unwrap();
+
+        let eur = parser
+            .parse_record(&record(&["deposit", "1", "2", "1", "eur"], 2))
+            .unwrap();
+        assert_eq!(eur.currency, "EUR".parse().ok());
+        let none = parser
+            .parse_record(&record(&["dispute", "1", "2", "", ""], 3))
+            .unwrap();
+        assert_eq!(none.currency, None);
+        assert_eq!(
+            kind_of(parser.parse_record(&record(&["deposit", "1", "4", "1", "E/R"], 4))),
+            (4, "currency", RowErrorKind::InvalidCurrency)
+        );
+    }
+
+    #[test]
+    fn test_parse_bytes_matches_parse_record() {
+        let parser = parser(AmountPrecision::Reject);
+        for fields in [
+            &["deposit", "1", "2", "1.5"][..],
+            &["dispute", "1", "2"],
+            &["withdrawal", "1", "x", "1"],
+        ] {
+            let record = record(fields, 2);
+            let mut bytes = ByteRecord::from(fields.to_vec());
+            bytes.set_position(record.position().cloned());
+            assert_eq!(
+                format!("{:?}", parser.parse_bytes(&bytes)),
+                format!("{:?}", parser.parse_record(&record))
+            );
+        }
+
+        let mut bytes = ByteRecord::new();
+        for field in [&b"deposit"[..], b"1", b"\xff", b"1"] {
+            bytes.push_field(field);
+        }
+        assert_eq!(
+            kind_of(parser.parse_bytes(&bytes)),
+            (0, "tx", RowErrorKind::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn test_missing_required_header() {
+        let headers = StringRecord::from(vec!["type", "client", "amount"]);
+        assert!(matches!(
+            ColumnIndex::from_headers(&headers),
+            Err(EngineError::InvalidRow { column: "tx", .. })
+        ));
+    }
+
+    #[test]
+    fn test_columns_under_mapped_headers_or_in_order() {
+        let headers = StringRecord::from(vec!["txn_id", "customer_id", "type", "amount"]);
+        let columns: ColumnMap = "client=customer_id,tx=txn_id".parse().unwrap();
+        let parser = RowParser::with_columns(
+            ColumnIndex::mapped(&headers, &columns).unwrap(),
+            ParseOptions::default(),
+        );
+        let tx = parser
+            .parse_record(&record(&["7", "3", "deposit", "2"], 2))
+            .unwrap();
+        assert_eq!((tx.client, tx.tx), (3, 7));
+        assert!(ColumnIndex::from_headers(&headers).is_err());
+        assert!("account=customer_id".parse::<ColumnMap>().is_err());
+
+        let parser = RowParser::with_columns(ColumnIndex::in_order(), ParseOptions::default());
+        let tx = parser
+            .parse_record(&record(&["withdrawal", "3", "7", "2"], 1))
+            .unwrap();
+        assert_eq!(
+            (tx.tx_type, tx.client, tx.tx, tx.timestamp),
+            (TransactionType::Withdrawal, 3, 7, None)
+        );
+    }
+}
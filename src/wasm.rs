@@ -0,0 +1,133 @@
+//! Browser bindings, built with the `wasm` feature and without the default
+//! `cli` one, for example with
+//! `wasm-pack build --target web -- --no-default-features --features wasm`.
+//!
+//! Everything runs in memory on the calling thread through
+//! [`Engine`](crate::engine::Engine), so the settlement rules are exactly
+//! those of a command-line run.
+
+use csv_async::StringRecord;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use wasm_bindgen::prelude::*;
+
+use crate::config::Rules;
+use crate::engine::Engine;
+use crate::models::{AccountStatus, ClientId, Currency};
+use crate::validate::{ParseOptions, RowParser};
+
+/// One balance of the accounts JSON
+#[derive(Debug, Serialize)]
+struct AccountJson {
+    client: ClientId,
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    status: AccountStatus,
+}
+
+/// What `processCsv` did with the rows, by reason code
+#[derive(Debug, Serialize)]
+struct TallyJson {
+    applied: u64,
+    rejected: BTreeMap<&'static str, u64>,
+    malformed: BTreeMap<&'static str, u64>,
+}
+
+fn js_error(e: impl std::fmt::Display) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// Settle a whole transactions CSV file and return the resulting accounts as
+/// JSON
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(bytes: &[u8]) -> Result<String, JsError> {
+    let mut engine = WasmEngine::new();
+    engine.0.process_csv(bytes).map_err(js_error)?;
+    engine.accounts()
+}
+
+/// An engine kept across calls, for feeding transactions one at a time
+#[wasm_bindgen(js_name = Engine)]
+pub struct WasmEngine(Engine);
+
+impl Default for WasmEngine {
+    fn default() -> Self {
+        WasmEngine::new()
+    }
+}
+
+#[wasm_bindgen(js_class = Engine)]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmEngine {
+        WasmEngine(Engine::new(Rules::default()))
+    }
+
+    /// Apply every row of a CSV file, returning `{applied, rejected,
+    /// malformed}` with the rejected and malformed rows counted by reason
+    /// code
+    #[wasm_bindgen(js_name = processCsv)]
+    pub fn process_csv(&mut self, bytes: &[u8]) -> Result<JsValue, JsError> {
+        let tally = self.0.process_csv(bytes).map_err(js_error)?;
+        serde_wasm_bindgen::to_value(&TallyJson {
+            applied: tally.applied,
+            rejected: tally.rejected,
+            malformed: tally.malformed,
+        })
+        .map_err(js_error)
+    }
+
+    /// Apply one transaction given as an object with the CSV columns as keys,
+    /// such as `{type: "deposit", client: 1, tx: 7, amount: "2.5"}`.
+    ///
+    /// Returns `undefined` if it was applied and the reason code if it was
+    /// rejected; a row that would be malformed in a file throws.
+    #[wasm_bindgen(js_name = processTransaction)]
+    pub fn process_transaction(&mut self, row: JsValue) -> Result<Option<String>, JsError> {
+        let fields: BTreeMap<String, serde_json::Value> =
+            serde_wasm_bindgen::from_value(row).map_err(js_error)?;
+        let headers: StringRecord = fields.keys().collect::<Vec<_>>().into();
+        let record: StringRecord = fields
+            .values()
+            .map(|value| match value {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let transaction = RowParser::new(&headers, ParseOptions::default())
+            .and_then(|parser| parser.parse_record(&record))
+            .map_err(js_error)?;
+        Ok(self
+            .0
+            .submit(transaction)
+            .err()
+            .map(|rejected| rejected.error.reason_code().to_string()))
+    }
+
+    /// Every balance as a JSON array in client ID order, amounts as strings
+    pub fn accounts(&self) -> Result<String, JsError> {
+        let balances: Vec<_> = self
+            .0
+            .accounts()
+            .iter()
+            .flat_map(|account| {
+                account.balances().map(|(currency, balance)| AccountJson {
+                    client: account.client,
+                    currency,
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total,
+                    locked: account.is_locked(),
+                    status: account.status,
+                })
+            })
+            .collect();
+        serde_json::to_string(&balances).map_err(js_error)
+    }
+}
@@ -0,0 +1,262 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::error::EngineError;
+use crate::models::{ClientId, Transaction, TransactionType, TxId};
+
+/// Deposits per client a generated dispute may pick from
+const RECENT_DEPOSITS: usize = 8;
+
+/// Shape of a synthetic workload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkloadSpec {
+    /// Clients 1 to `clients` take part
+    pub clients: ClientId,
+    /// Rows to generate
+    pub transactions: u64,
+    /// Share of rows disputing one of the client's recent deposits
+    pub dispute_rate: f64,
+    /// Share of the remaining rows that are withdrawals rather than deposits
+    pub withdrawal_rate: f64,
+    /// Share of disputes settled by a chargeback rather than a resolve
+    pub chargeback_rate: f64,
+    /// Zipf exponent of the client distribution: 0 spreads rows evenly,
+    /// larger values concentrate them on the lowest client IDs
+    pub zipf: f64,
+    pub seed: u64,
+}
+
+impl Default for WorkloadSpec {
+    fn default() -> Self {
+        WorkloadSpec {
+            clients: 1000,
+            transactions: 100_000,
+            dispute_rate: 0.01,
+            withdrawal_rate: 0.3,
+            chargeback_rate: 0.1,
+            zipf: 1.0,
+            seed: 0,
+        }
+    }
+}
+
+/// SplitMix64, which is plenty for spreading synthetic rows and keeps every
+/// seed reproducible across platforms
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, n)`
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Generates the rows of a workload, in order.
+///
+/// Deposits get fresh transaction IDs counting up from 1. A dispute names one
+/// of the client's last few deposits, and the client's next row settles it
+/// with a resolve or a chargeback, so every generated reference is valid.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    spec: WorkloadSpec,
+    rng: SplitMix64,
+    /// Cumulative client weights for the Zipf draw
+    cdf: Vec<f64>,
+    emitted: u64,
+    next_tx: TxId,
+    recent: HashMap<ClientId, Vec<TxId>>,
+    disputed: HashMap<ClientId, TxId>,
+}
+
+impl Workload {
+    pub fn new(spec: WorkloadSpec) -> Self {
+        let mut total = 0.0;
+        let cdf = (1..=u64::from(spec.clients))
+            .map(|rank| {
+                total += (rank as f64).powf(-spec.zipf);
+                total
+            })
+            .collect();
+        Workload {
+            spec,
+            rng: SplitMix64(spec.seed),
+            cdf,
+            emitted: 0,
+            next_tx: 1,
+            recent: HashMap::new(),
+            disputed: HashMap::new(),
+        }
+    }
+
+    fn pick_client(&mut self) -> ClientId {
+        let total = self.cdf.last().copied().unwrap_or_default();
+        let target = self.rng.next_f64() * total;
+        let rank = self.cdf.partition_point(|&weight| weight <= target);
+        ClientId::try_from(rank + 1).unwrap_or(self.spec.clients)
+    }
+
+    /// A transaction ID not used before, unless they have run out
+    fn fresh_tx(&mut self) -> Option<TxId> {
+        let tx = self.next_tx;
+        self.next_tx = tx.checked_add(1)?;
+        Some(tx)
+    }
+}
+
+impl Iterator for Workload {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.emitted >= self.spec.transactions || self.spec.clients == 0 {
+            return None;
+        }
+        self.emitted += 1;
+        let client = self.pick_client();
+
+        if let Some(tx) = self.disputed.remove(&client) {
+            let tx_type = if self.rng.next_f64() < self.spec.chargeback_rate {
+                TransactionType::Chargeback
+            } else {
+                TransactionType::Resolve
+            };
+            return Some(Transaction::new(tx_type, client, tx, None));
+        }
+
+        let recent = self.recent.entry(client).or_default();
+        if !recent.is_empty() && self.rng.next_f64() < self.spec.dispute_rate {
+            let pick = self.rng.below(recent.len() as u64) as usize;
+            let tx = recent.swap_remove(pick);
+            self.disputed.insert(client, tx);
+            return Some(Transaction::new(TransactionType::Dispute, client, tx, None));
+        }
+
+        let tx = self.fresh_tx()?;
+        if self.rng.next_f64() < self.spec.withdrawal_rate {
+            let amount = Decimal::new(self.rng.below(5_000) as i64 + 1, 2);
+            return Some(Transaction::new(
+                TransactionType::Withdrawal,
+                client,
+                tx,
+                Some(amount),
+            ));
+        }
+        let amount = Decimal::new(self.rng.below(100_000) as i64 + 1, 2);
+        let recent = self.recent.entry(client).or_default();
+        if recent.len() == RECENT_DEPOSITS {
+            recent.remove(0);
+        }
+        recent.push(tx);
+        Some(Transaction::new(
+            TransactionType::Deposit,
+            client,
+            tx,
+            Some(amount),
+        ))
+    }
+}
+
+/// One line of a generated file
+#[derive(Serialize)]
+struct Row {
+    #[serde(rename = "type")]
+    tx_type: Cow<'static, str>,
+    client: ClientId,
+    tx: TxId,
+    amount: Option<Decimal>,
+}
+
+/// Write a workload as a `type,client,tx,amount` CSV file
+pub fn write_workload<W: Write>(spec: WorkloadSpec, writer: W) -> Result<(), EngineError> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for transaction in Workload::new(spec) {
+        writer.serialize(Row {
+            tx_type: transaction.tx_type.name(),
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workload_is_reproducible_and_consistent() {
+        let spec = WorkloadSpec {
+            clients: 50,
+            transactions: 5_000,
+            dispute_rate: 0.1,
+            zipf: 1.2,
+            seed: 7,
+            ..WorkloadSpec::default()
+        };
+        let rows: Vec<_> = Workload::new(spec).collect();
+        assert_eq!(rows.len(), 5_000);
+        let fields = |rows: &[Transaction]| -> Vec<_> {
+            rows.iter()
+                .map(|t| (t.tx_type.name(), t.client, t.tx, t.amount))
+                .collect()
+        };
+        assert_eq!(
+            fields(&rows),
+            fields(&Workload::new(spec).collect::<Vec<_>>())
+        );
+
+        let mut deposits = HashMap::new();
+        let mut open = HashMap::new();
+        let mut per_client = HashMap::<ClientId, usize>::new();
+        for row in &rows {
+            *per_client.entry(row.client).or_default() += 1;
+            match row.tx_type {
+                TransactionType::Deposit => {
+                    deposits.insert(row.tx, row.client);
+                }
+                TransactionType::Dispute => {
+                    assert_eq!(deposits.get(&row.tx), Some(&row.client));
+                    assert!(open.insert(row.client, row.tx).is_none());
+                }
+                TransactionType::Resolve | TransactionType::Chargeback => {
+                    assert_eq!(open.remove(&row.client), Some(row.tx));
+                }
+                _ => {}
+            }
+        }
+        assert!(per_client[&1] > per_client.get(&50).copied().unwrap_or(0) * 5);
+
+        let mut csv = Vec::new();
+        write_workload(
+            WorkloadSpec {
+                transactions: 2,
+                ..spec
+            },
+            &mut csv,
+        )
+        .unwrap();
+        assert!(
+            String::from_utf8(csv)
+                .unwrap()
+                .starts_with("type,client,tx,amount\n")
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! End-to-end runs of `process`, covering what the binary does once rows
+//! are handed to client tasks.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use rust_transaction_engine::fixtures::normalize;
+
+const ENGINE: &str = env!("CARGO_BIN_EXE_rust-transaction-engine");
+
+/// Accounts and rejected rows of one run
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    accounts: String,
+    /// `(client, tx, reason)` of every rejected row, in that order
+    rejects: Vec<(u16, u64, String)>,
+}
+
+/// The engine writing rejected rows to `rejects`, with no `ENGINE_*`
+/// settings from the environment
+fn engine(rejects: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(ENGINE);
+    for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with("ENGINE_")) {
+        command.env_remove(key);
+    }
+    command
+        .env("RUST_LOG", "error")
+        .arg("--rejects")
+        .arg(rejects)
+        .args(args);
+    command
+}
+
+fn outcome(stdout: Vec<u8>, rejects: &Path) -> Outcome {
+    assert!(!stdout.is_empty(), "no accounts written");
+    let accounts = normalize(&String::from_utf8(stdout).unwrap()).unwrap();
+    let mut reader = csv::Reader::from_path(rejects).unwrap();
+    let mut rejects: Vec<(u16, u64, String)> = reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            (
+                record[2].parse().unwrap(),
+                record[3].parse().unwrap(),
+                record[5].to_string(),
+            )
+        })
+        .collect();
+    rejects.sort();
+    Outcome { accounts, rejects }
+}
+
+/// Process `input` with `args`
+fn run(input: &str, args: &[&str]) -> Outcome {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("input.csv");
+    std::fs::write(&path, input).unwrap();
+    let rejects = dir.path().join("rejects.csv");
+    let output = engine(&rejects, args).arg(&path).output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    outcome(output.stdout, &rejects)
+}
+
+/// Process `input` with `args` and check the result matches a run with the
+/// same settings that applies every row inline in file order
+fn run_in_order(input: &str, args: &[&str]) -> Outcome {
+    let outcome = run(input, args);
+    let in_order: Vec<_> = args.iter().copied().chain(["--deterministic"]).collect();
+    assert_eq!(outcome, run(input, &in_order));
+    outcome
+}
+
+fn accounts(rows: &str) -> String {
+    normalize(&format!("client,available,held,total,locked\n{rows}")).unwrap()
+}
+
+fn rejected(rows: &[(u16, u64, &str)]) -> Vec<(u16, u64, String)> {
+    rows.iter()
+        .map(|(client, tx, reason)| (*client, *tx, reason.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_coalesced_run_is_cut_short_by_a_guard_rejection() {
+    // The out-of-order deposit must not be applied ahead of the rows around
+    // it, and the withdrawal behind it must see only what came before
+    let outcome = run_in_order(
+        "type,client,tx,amount,timestamp,idempotency_key\n\
+         deposit,1,1,10,10,a\n\
+         withdrawal,1,2,5,30,\n\
+         deposit,1,3,100,20,\n\
+         withdrawal,1,4,8,40,\n\
+         deposit,1,5,1,50,a\n\
+         deposit,1,6,2,60,\n",
+        &["--coalesce-rows", "64", "--require-monotonic", "reject"],
+    );
+    assert_eq!(outcome.accounts, accounts("1,7,0,7,false\n"));
+    assert_eq!(
+        outcome.rejects,
+        rejected(&[
+            (1, 3, "out_of_order"),
+            (1, 4, "insufficient_funds"),
+            (1, 5, "duplicate_idempotency_key"),
+        ])
+    );
+}
+
+#[test]
+fn test_coalesced_runs_stop_at_other_clients_and_types() {
+    let outcome = run_in_order(
+        "type,client,tx,amount\n\
+         deposit,1,1,10\n\
+         deposit,1,2,5\n\
+         dispute,1,2,\n\
+         withdrawal,1,3,12\n\
+         deposit,2,4,3\n\
+         withdrawal,1,5,10\n\
+         resolve,1,2,\n\
+         withdrawal,1,6,5\n",
+        &["--coalesce-rows", "64"],
+    );
+    assert_eq!(outcome.accounts, accounts("1,0,0,0,false\n2,3,0,3,false\n"));
+    assert_eq!(outcome.rejects, rejected(&[(1, 3, "insufficient_funds")]));
+}
+
+#[test]
+fn test_client_tasks_settle_id_clashes_in_input_order() {
+    let outcome = run_in_order(
+        "type,client,tx,amount\n\
+         withdrawal,1,1,5\n\
+         deposit,2,1,3\n\
+         deposit,3,1,4\n\
+         dispute,1,1,\n",
+        &[],
+    );
+    assert_eq!(outcome.accounts, accounts("1,0,0,0,false\n2,3,0,3,false\n"));
+    assert_eq!(
+        outcome.rejects,
+        rejected(&[
+            (1, 1, "client_mismatch"),
+            (1, 1, "insufficient_funds"),
+            (3, 1, "duplicate_tx"),
+        ])
+    );
+}
+
+#[test]
+fn test_shards_settle_id_clashes_in_input_order() {
+    // The withdrawal is rejected, so the ID is free for the next client;
+    // client 3's deposit then clashes with client 2's record
+    let outcome = run_in_order(
+        "type,client,tx,amount\n\
+         withdrawal,1,1,5\n\
+         deposit,2,1,3\n\
+         deposit,3,1,4\n\
+         dispute,1,1,\n\
+         deposit,1,2,2\n",
+        &["--shards", "2"],
+    );
+    assert_eq!(outcome.accounts, accounts("1,2,0,2,false\n2,3,0,3,false\n"));
+    assert_eq!(
+        outcome.rejects,
+        rejected(&[
+            (1, 1, "client_mismatch"),
+            (1, 1, "insufficient_funds"),
+            (3, 1, "duplicate_tx"),
+        ])
+    );
+}
+
+#[test]
+fn test_shared_tasks_keep_guards_per_client() {
+    let outcome = run_in_order(
+        "type,client,tx,amount,idempotency_key\n\
+         deposit,1,1,10,a\n\
+         deposit,2,2,5,a\n\
+         deposit,3,3,1,a\n\
+         deposit,1,4,1,a\n\
+         withdrawal,3,5,1,\n",
+        &["--max-client-tasks", "2"],
+    );
+    assert_eq!(
+        outcome.accounts,
+        accounts("1,10,0,10,false\n2,5,0,5,false\n3,0,0,0,false\n")
+    );
+    assert_eq!(
+        outcome.rejects,
+        rejected(&[(1, 4, "duplicate_idempotency_key")])
+    );
+}
+
+#[test]
+fn test_priority_rows_wait_behind_rows_with_their_id() {
+    let outcome = run(
+        "type,client,tx,amount\n\
+         deposit,1,1,10\n\
+         deposit,1,2,5\n\
+         dispute,1,2,\n\
+         chargeback,1,2,\n\
+         deposit,1,3,1\n",
+        &["--priority-types", "chargeback"],
+    );
+    // The chargeback may go ahead of the last deposit, which the locked
+    // account then refuses, but never ahead of its dispute
+    assert_eq!(outcome.accounts, accounts("1,10,0,10,true\n"));
+    assert_eq!(outcome.rejects, rejected(&[(1, 3, "account_locked")]));
+}
+
+#[test]
+fn test_idle_task_hands_its_guards_to_the_next() {
+    let dir = tempfile::tempdir().unwrap();
+    let rejects = dir.path().join("rejects.csv");
+    let mut child = engine(
+        &rejects,
+        &["--client-idle-secs", "1", "--require-monotonic", "reject"],
+    )
+    .arg("/dev/stdin")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(b"type,client,tx,amount,timestamp,idempotency_key\ndeposit,1,1,10,10,a\n")
+        .unwrap();
+    stdin.flush().unwrap();
+    // Long enough for the client's task to shut down while idle
+    thread::sleep(Duration::from_millis(2500));
+    stdin
+        .write_all(b"deposit,1,2,1,20,a\ndeposit,1,3,1,5,\ndeposit,1,4,1,30,\n")
+        .unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let outcome = outcome(output.stdout, &rejects);
+    assert_eq!(outcome.accounts, accounts("1,11,0,11,false\n"));
+    assert_eq!(
+        outcome.rejects,
+        rejected(&[(1, 2, "duplicate_idempotency_key"), (1, 3, "out_of_order")])
+    );
+}